@@ -0,0 +1,39 @@
+// Serve files embedded in the binary, nothing is read from disk at runtime
+// Run with `cargo run --example embedded` and open http://localhost:8081
+
+#[macro_use]
+extern crate hteapot;
+
+use hteapot::{FileSource, Hteapot, HttpResponse, HttpStatus, VirtualFs};
+use std::io;
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next() {
+        Some("html") => "text/html",
+        Some("css") => "text/css",
+        _ => "application/octet-stream",
+    }
+}
+
+// Returns only when the server can't start, like with port 8081 in use
+fn main() -> io::Result<()> {
+    let files = VirtualFs::new()
+        .add("index.html", include_bytes!("assets/index.html"))
+        .add("style.css", include_bytes!("assets/style.css"));
+    let server = Hteapot::new("localhost", 8081);
+    server.listen(move |req| {
+        // Paths above the root never match, the tree has nothing outside it
+        let path = match files.resolve(&req.path, "index.html") {
+            Some(path) => path,
+            None => return HttpResponse::new(HttpStatus::NotFound, "Not found", None),
+        };
+        match files.read(&path) {
+            Ok(content) => HttpResponse::from_shared(
+                HttpStatus::OK,
+                content,
+                headers!("Content-Type" => content_type(&path)),
+            ),
+            Err(_) => HttpResponse::new(HttpStatus::NotFound, "Not found", None),
+        }
+    })
+}
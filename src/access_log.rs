@@ -0,0 +1,235 @@
+// Access log written to files chosen per path prefix and per host
+// A destination may contain {host}, files are opened on first use and kept open
+// until reopen, which logrotate asks for through SIGUSR1 once it has moved them
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::sync::Mutex;
+
+use hteapot::{HttpRequest, HttpResponse};
+use logger::Logger;
+
+pub const HOST_PLACEHOLDER: &str = "{host}";
+
+// What is logged about a request, taken before the handler consumes it
+pub struct AccessEntry {
+    client: String,
+    host: String,
+    method: String,
+    path: String,
+}
+
+impl AccessEntry {
+    pub fn new(req: &HttpRequest) -> AccessEntry {
+        AccessEntry {
+            client: req.peer_ip().map_or("-".to_string(), |ip| ip.to_string()),
+            host: req.headers.get("Host").cloned().unwrap_or_default(),
+            method: req.method.to_str().to_string(),
+            path: req.path.clone(),
+        }
+    }
+}
+
+pub struct AccessLog {
+    default: Option<String>,
+    prefixes: Vec<(String, String)>, // Longest prefix first
+    sinks: Mutex<HashMap<String, Logger<File>>>,
+}
+
+impl AccessLog {
+    pub fn new(default: Option<String>, prefixes: &HashMap<String, String>) -> AccessLog {
+        let mut prefixes: Vec<(String, String)> = prefixes
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        AccessLog {
+            default,
+            prefixes,
+            sinks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // File for a request, None when it isn't logged
+    fn destination(&self, entry: &AccessEntry) -> Option<String> {
+        let template = self
+            .prefixes
+            .iter()
+            .find(|(prefix, _)| entry.path.starts_with(prefix.as_str()))
+            .map(|(_, template)| template)
+            .or(self.default.as_ref())?;
+        Some(template.replace(HOST_PLACEHOLDER, &file_safe_host(&entry.host)))
+    }
+
+    pub fn record(&self, entry: &AccessEntry, response: &HttpResponse) {
+        let destination = match self.destination(entry) {
+            Some(destination) => destination,
+            None => return,
+        };
+        let status = if response.is_raw() {
+            "-".to_string()
+        } else {
+            (response.status as u16).to_string()
+        };
+        let line = format!(
+            "{} {} \"{} {}\" {} {}",
+            entry.client,
+            entry.host,
+            entry.method,
+            entry.path,
+            status,
+            response.body().len()
+        );
+        let mut sinks = self.sinks.lock().expect("Error locking access log");
+        if !sinks.contains_key(&destination) {
+            match open(&destination) {
+                Ok(file) => {
+                    sinks.insert(destination.clone(), Logger::new(file));
+                }
+                Err(e) => {
+                    eprintln!("Error opening access log {}: {}", destination, e);
+                    return;
+                }
+            }
+        }
+        if let Some(sink) = sinks.get_mut(&destination) {
+            sink.msg(line);
+        }
+    }
+
+    // Close every file and open its path again, creating the ones moved away
+    // Records wait on the lock meanwhile, so no line is lost in the swap
+    #[cfg(unix)]
+    pub fn reopen(&self) {
+        let mut sinks = self.sinks.lock().expect("Error locking access log");
+        let destinations: Vec<String> = sinks.keys().cloned().collect();
+        for destination in destinations {
+            if let Some(mut old) = sinks.remove(&destination) {
+                old.msg("Log file reopened, continuing in a new file".to_string());
+            }
+            match open(&destination) {
+                Ok(file) => {
+                    let mut sink = Logger::new(file);
+                    sink.msg("Log file reopened".to_string());
+                    sinks.insert(destination, sink);
+                }
+                // Tried again on the next record
+                Err(e) => eprintln!("Error reopening access log {}: {}", destination, e),
+            }
+        }
+    }
+}
+
+fn open(destination: &str) -> std::io::Result<File> {
+    if let Some(parent) = Path::new(destination).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(destination)
+}
+
+// Host without port and reduced to characters safe in a file name
+fn file_safe_host(host: &str) -> String {
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let host: String = host
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-')
+        .collect();
+    let host = host.trim_matches('.');
+    if host.is_empty() {
+        "default".to_string()
+    } else {
+        host.to_string()
+    }
+}
+
+#[test]
+fn test_access_log_destinations() {
+    use hteapot::{Hteapot, HttpStatus};
+    let dir = std::env::temp_dir().join(format!("hteapot-access-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut prefixes = HashMap::new();
+    prefixes.insert(
+        "/api".to_string(),
+        format!("{}/api/{{host}}.log", dir.display()),
+    );
+    let log = AccessLog::new(Some(format!("{}/{{host}}.log", dir.display())), &prefixes);
+
+    let requests = [
+        ("a.example.com", "/index.html"),
+        ("B.example.com:8080", "/"),
+        ("a.example.com", "/api/users"),
+        ("../..", "/"),
+    ];
+    let response = HttpResponse::new(HttpStatus::OK, "hi", None);
+    for (host, path) in requests.iter() {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host);
+        let req = Hteapot::request_parser(raw).unwrap();
+        log.record(&AccessEntry::new(&req), &response);
+    }
+
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+    let a = read("a.example.com.log");
+    assert!(a.contains("a.example.com \"GET /index.html\" 200 2"));
+    assert!(!a.contains("/api"));
+    assert!(read("b.example.com.log").contains("\"GET /\" 200"));
+    assert!(read("api/a.example.com.log").contains("\"GET /api/users\""));
+    assert!(read("default.log").contains("../.."));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_reopen_on_sigusr1() {
+    use hteapot::{Hteapot, HttpStatus};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    extern "C" {
+        fn raise(signum: i32) -> i32;
+    }
+
+    let dir = std::env::temp_dir().join(format!("hteapot-reopen-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("access.log");
+    let log = Arc::new(AccessLog::new(
+        Some(path.display().to_string()),
+        &HashMap::new(),
+    ));
+    let reopened = log.clone();
+    ::signals::on_reopen(move || reopened.reopen());
+
+    let response = HttpResponse::new(HttpStatus::OK, "hi", None);
+    let record = |path: &str| {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+        let req = Hteapot::request_parser(raw).unwrap();
+        log.record(&AccessEntry::new(&req), &response);
+    };
+    record("/before");
+    // What logrotate does, the old file is kept open until the signal
+    let rotated = dir.join("access.log.1");
+    fs::rename(&path, &rotated).unwrap();
+    record("/moved");
+    assert_eq!(unsafe { raise(::signals::SIGUSR1) }, 0);
+    let started = Instant::now();
+    while !path.exists() {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    record("/after");
+
+    let old = fs::read_to_string(&rotated).unwrap();
+    let new = fs::read_to_string(&path).unwrap();
+    assert!(old.contains("\"GET /before\"") && old.contains("\"GET /moved\""));
+    assert!(old.ends_with("Log file reopened, continuing in a new file\n"));
+    assert!(!old.contains("/after"));
+    assert!(new.contains("Log file reopened\n") && new.contains("\"GET /after\""));
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,113 @@
+// systemd socket activation, listeners bound by the service manager are passed from fd 3 on
+// Lets the server take port 80 without running as root, see sd_listen_fds(3)
+
+#[cfg(unix)]
+use std::env;
+use std::net::TcpListener;
+
+// First passed descriptor, SD_LISTEN_FDS_START
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// Number of descriptors passed to this process, 0 when they were meant for another one
+#[cfg(any(unix, test))]
+fn passed_count(
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+    pid: u32,
+) -> Result<usize, String> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+    if listen_pid.trim().parse::<u32>() != Ok(pid) {
+        return Ok(0);
+    }
+    listen_fds
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid LISTEN_FDS {}", listen_fds))
+}
+
+// Listeners passed by systemd, empty when the server wasn't socket activated
+#[cfg(unix)]
+pub fn listeners() -> Result<Vec<TcpListener>, String> {
+    let count = passed_count(
+        env::var("LISTEN_PID").ok(),
+        env::var("LISTEN_FDS").ok(),
+        std::process::id(),
+    )?;
+    // Not for the processes this one starts, like sd_listen_fds(1) does
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    (0..count as i32)
+        .map(|n| adopt(LISTEN_FDS_START + n))
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Vec<TcpListener>, String> {
+    Ok(Vec::new())
+}
+
+// Take over a passed descriptor, which must be a TCP socket already listening
+#[cfg(unix)]
+fn adopt(fd: i32) -> Result<TcpListener, String> {
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::FromRawFd;
+
+    // Borrowed only to look at it, the fd stays open
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let is_socket = file
+        .metadata()
+        .map_err(|e| format!("Passed fd {} is not usable: {}", fd, e))?
+        .file_type()
+        .is_socket();
+    if !is_socket {
+        return Err(format!("Passed fd {} is not a socket", fd));
+    }
+    if !is_listening(fd) {
+        return Err(format!("Passed fd {} is not listening", fd));
+    }
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    match listener.local_addr() {
+        Ok(_) => Ok(listener),
+        Err(e) => {
+            // Not ours to close, systemd keeps it
+            std::mem::forget(listener);
+            Err(format!("Passed fd {} is not a TCP socket: {}", fd, e))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_listening(fd: i32) -> bool {
+    extern "C" {
+        fn getsockopt(fd: i32, level: i32, name: i32, value: *mut i32, len: *mut u32) -> i32;
+    }
+    const SOL_SOCKET: i32 = 1;
+    const SO_ACCEPTCONN: i32 = 30;
+    let mut value = 0;
+    let mut len = std::mem::size_of::<i32>() as u32;
+    let result = unsafe { getsockopt(fd, SOL_SOCKET, SO_ACCEPTCONN, &mut value, &mut len) };
+    result == 0 && value != 0
+}
+
+// Other systems number SO_ACCEPTCONN differently, accept() fails later if it isn't
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_listening(_fd: i32) -> bool {
+    true
+}
+
+#[test]
+fn test_passed_count() {
+    let some = |s: &str| Some(s.to_string());
+    assert_eq!(passed_count(None, None, 42), Ok(0));
+    assert_eq!(passed_count(some("42"), some("2"), 42), Ok(2));
+    // Inherited from a parent that was activated
+    assert_eq!(passed_count(some("41"), some("2"), 42), Ok(0));
+    assert!(passed_count(some("42"), some("two"), 42).is_err());
+}
@@ -0,0 +1,308 @@
+// Runtime controls on the admin listener, taking effect without a restart
+// Every change answers with the previous and the new value as JSON, and is logged with who asked
+
+use std::io::Stdout;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use cache::Cache;
+use config::Config;
+use hteapot::{HttpMethod, HttpRequest, HttpResponse, HttpStatus};
+use logger::{self, Level, Logger, LoggerHealth};
+use recorder::Recorder;
+use state::{ReloadStatus, SharedState};
+
+// Components logging under their own name, each can get a level of its own
+pub const COMPONENTS: &[&str] = &["request", "files", "proxy", "body"];
+
+// What the controls change, the same cache and state the public listener uses
+pub struct Controls<'a> {
+    pub cache: &'a Mutex<Cache>,
+    pub state: &'a SharedState,
+    pub logger: &'a Mutex<Logger<Stdout>>,
+    pub recorder: &'a Recorder,
+}
+
+// A change made, the log line and the JSON answer
+type Change = (String, String);
+
+// None when the path is not one of the controls under base
+pub fn serve(base: &str, req: &HttpRequest, controls: &Controls) -> Option<HttpResponse> {
+    let name = req.path.strip_prefix(base)?.strip_prefix('/')?;
+    // How the log writer is doing, only read
+    if name == "status" {
+        if req.method != HttpMethod::GET {
+            let mut response = HttpResponse::new(HttpStatus::MethodNotAllowed, "", None);
+            response.add_header("Allow", "GET");
+            return Some(response);
+        }
+        let health = controls
+            .logger
+            .lock()
+            .expect("this doesnt work :C")
+            .health();
+        return Some(json_response(HttpStatus::OK, status_json(&health)));
+    }
+    let control: fn(&str, &Controls) -> Result<Change, String> = match name {
+        "log-level" => log_level,
+        "cache" => cache,
+        "drain" => drain,
+        "record" => record,
+        "reload" => reload,
+        _ => return None,
+    };
+    // How the last reload went, the only control answering a GET
+    if name == "reload" && req.method == HttpMethod::GET {
+        let status = controls.state.last_reload();
+        return Some(json_response(HttpStatus::OK, reload_json(status.as_ref())));
+    }
+    if req.method != HttpMethod::POST {
+        let mut response = HttpResponse::new(HttpStatus::MethodNotAllowed, "", None);
+        response.add_header("Allow", "POST");
+        return Some(response);
+    }
+    let (status, body) = match control(&req.body, controls) {
+        Ok((change, json)) => {
+            let requester = req
+                .remote_addr
+                .map_or("unknown".to_string(), |a| a.to_string());
+            controls
+                .logger
+                .lock()
+                .expect("this doesnt work :C")
+                .msg(format!("WARNING: {}, asked by {}", change, requester));
+            (HttpStatus::OK, json)
+        }
+        Err(e) => (HttpStatus::BadRequest, format!("{{\"error\":\"{}\"}}", e)),
+    };
+    Some(json_response(status, body))
+}
+
+fn json_response(status: HttpStatus, body: String) -> HttpResponse {
+    let mut response = HttpResponse::new(status, body, None);
+    response.add_header("Content-Type", "application/json");
+    response.add_header("Cache-Control", "no-store");
+    response
+}
+
+// {"level":"debug"} for every component, with "component":"proxy" for one only
+// A component given "level":null follows the global level again
+fn log_level(body: &str, _: &Controls) -> Result<Change, String> {
+    let level = match field(body, "level") {
+        Some("null") => None,
+        Some(name) => Some(Level::parse(name).ok_or("level is error, warn, info or debug")?),
+        None => return Err("missing level".to_string()),
+    };
+    let json = |previous: Level, current: Level| {
+        format!(
+            "{{\"previous\":\"{}\",\"current\":\"{}\"}}",
+            previous.as_str(),
+            current.as_str()
+        )
+    };
+    match field(body, "component") {
+        Some(component) => {
+            if !COMPONENTS.contains(&component) {
+                return Err(format!("component is one of {}", COMPONENTS.join(", ")));
+            }
+            let previous = logger::set_component_level(component, level);
+            let current = logger::component_level(component);
+            let change = format!(
+                "Log level of {} changed from {} to {}",
+                component,
+                previous.as_str(),
+                current.as_str()
+            );
+            Ok((change, json(previous, current)))
+        }
+        None => {
+            let level = level.ok_or("only a component can follow the global level")?;
+            let previous = logger::set_level(level);
+            let change = format!(
+                "Log level changed from {} to {}",
+                previous.as_str(),
+                level.as_str()
+            );
+            Ok((change, json(previous, level)))
+        }
+    }
+}
+
+// {"enabled":false} to stop using the cache, {"purge":true} to empty it, or both
+fn cache(body: &str, controls: &Controls) -> Result<Change, String> {
+    let enabled = field(body, "enabled").map(parse_bool).transpose()?;
+    let purge = field(body, "purge").map(parse_bool).transpose()?;
+    if enabled.is_none() && purge.is_none() {
+        return Err("missing enabled or purge".to_string());
+    }
+    let mut cache = controls.cache.lock().expect("Error locking cache");
+    let previous = cache.enabled();
+    let current = enabled.unwrap_or(previous);
+    cache.set_enabled(current);
+    let purged = if purge == Some(true) {
+        cache.clear()
+    } else {
+        0
+    };
+    let change = format!(
+        "Cache {} (was {}), {} entries purged",
+        on_off(current),
+        on_off(previous),
+        purged
+    );
+    let json = format!(
+        "{{\"previous\":{},\"current\":{},\"purged\":{}}}",
+        previous, current, purged
+    );
+    Ok((change, json))
+}
+
+// {"draining":true} closes every connection after its response, so clients move to other servers
+fn drain(body: &str, controls: &Controls) -> Result<Change, String> {
+    let draining = parse_bool(field(body, "draining").ok_or("missing draining")?)?;
+    let previous = controls.state.set_draining(draining);
+    let change = format!("Draining {} (was {})", on_off(draining), on_off(previous));
+    let json = format!("{{\"previous\":{},\"current\":{}}}", previous, draining);
+    Ok((change, json))
+}
+
+// {"enabled":true} records exchanges to record_dir, false stops until enabled again
+fn record(body: &str, controls: &Controls) -> Result<Change, String> {
+    let enabled = parse_bool(field(body, "enabled").ok_or("missing enabled")?)?;
+    let previous = controls.recorder.set_enabled(enabled)?;
+    let change = format!("Recording {} (was {})", on_off(enabled), on_off(previous));
+    let json = format!("{{\"previous\":{},\"current\":{}}}", previous, enabled);
+    Ok((change, json))
+}
+
+// {} reads the config file again, it replaces the running one only if every proxy rule passes
+// the reload checks, otherwise each failure is logged and the running config stays
+fn reload(_: &str, controls: &Controls) -> Result<Change, String> {
+    let snapshot = controls.state.snapshot();
+    let overrides = &snapshot.config.overrides;
+    let path = snapshot.config.path.clone();
+    let path = path.ok_or("not started from a config file")?;
+    match controls
+        .state
+        .try_reload(Config::reload_config(&path, overrides), controls.cache)
+    {
+        Ok(generation) => {
+            let change = format!("Reloaded {}, generation {}", path, generation);
+            Ok((change, reload_json(controls.state.last_reload().as_ref())))
+        }
+        Err(errors) => {
+            let mut logger = controls.logger.lock().expect("this doesnt work :C");
+            for error in errors.iter() {
+                logger.msg(format!(
+                    "ERROR: Reload of {} refused, keeping the running config: {}",
+                    path, error
+                ));
+            }
+            Err(format!("reload refused, {} errors", errors.len()))
+        }
+    }
+}
+
+// {"logger":{"failures":3,"dropped":0,"last_error":"..."}}, since the writer last worked
+fn status_json(health: &LoggerHealth) -> String {
+    let last_error = health.last_error.as_ref().map_or("null".to_string(), |e| {
+        format!("\"{}\"", e.replace('\\', "\\\\").replace('"', "\\\""))
+    });
+    format!(
+        "{{\"logger\":{{\"failures\":{},\"dropped\":{},\"last_error\":{}}}}}",
+        health.failures, health.dropped, last_error
+    )
+}
+
+// {"ok":false,"at":1700000000,"generation":3,"errors":["..."]}, ok and at are null before any reload
+fn reload_json(status: Option<&ReloadStatus>) -> String {
+    let status = match status {
+        Some(status) => status,
+        None => return "{\"ok\":null,\"at\":null,\"generation\":0,\"errors\":[]}".to_string(),
+    };
+    let at = status
+        .at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let errors: Vec<String> = status
+        .errors
+        .iter()
+        .map(|e| format!("\"{}\"", e.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!(
+        "{{\"ok\":{},\"at\":{},\"generation\":{},\"errors\":[{}]}}",
+        status.errors.is_empty(),
+        at,
+        status.generation,
+        errors.join(",")
+    )
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} is not true or false", value))
+}
+
+// Value of a key in a flat JSON object, strings without their quotes
+// Enough for the small bodies sent here, escapes inside strings are not handled
+pub fn field<'a>(body: &'a str, key: &str) -> Option<&'a str> {
+    let quoted = format!("\"{}\"", key);
+    let at = body.find(&quoted)? + quoted.len();
+    let rest = body[at..].trim_start().strip_prefix(':')?.trim_start();
+    match rest.strip_prefix('"') {
+        Some(string) => string.split('"').next(),
+        None => rest.split([',', '}']).next().map(|value| value.trim()),
+    }
+}
+
+// Strings of a list in the same kind of object, like "headers":["A: 1","B: 2"]
+pub fn strings<'a>(body: &'a str, key: &str) -> Option<Vec<&'a str>> {
+    let quoted = format!("\"{}\"", key);
+    let at = body.find(&quoted)? + quoted.len();
+    let rest = body[at..].trim_start().strip_prefix(':')?.trim_start();
+    let list = rest.strip_prefix('[')?.split(']').next()?;
+    // Every other piece between quotes is a string, the rest are the commas around them
+    Some(list.split('"').skip(1).step_by(2).collect())
+}
+
+#[test]
+fn test_json_field() {
+    let body = "{ \"level\" : \"debug\", \"component\":\"proxy\",\"enabled\":false}";
+    assert_eq!(field(body, "level"), Some("debug"));
+    assert_eq!(field(body, "component"), Some("proxy"));
+    assert_eq!(field(body, "enabled"), Some("false"));
+    assert_eq!(field("{\"purge\": true }", "purge"), Some("true"));
+    assert_eq!(field(body, "draining"), None);
+    assert_eq!(field("{\"level\"}", "level"), None);
+
+    let body = "{\"path\":\"/\", \"headers\": [\"A: 1\", \"B: 2\"]}";
+    assert_eq!(strings(body, "headers"), Some(vec!["A: 1", "B: 2"]));
+    assert_eq!(strings("{\"headers\":[]}", "headers"), Some(vec![]));
+    assert_eq!(strings(body, "path"), None);
+}
+
+#[test]
+fn test_status_json() {
+    assert_eq!(
+        status_json(&LoggerHealth::default()),
+        "{\"logger\":{\"failures\":0,\"dropped\":0,\"last_error\":null}}"
+    );
+    let health = LoggerHealth {
+        last_error: Some("No space \"left\"".to_string()),
+        failures: 4,
+        dropped: 1,
+    };
+    assert_eq!(
+        status_json(&health),
+        "{\"logger\":{\"failures\":4,\"dropped\":1,\"last_error\":\"No space \\\"left\\\"\"}}"
+    );
+}
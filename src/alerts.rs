@@ -0,0 +1,272 @@
+// Errors and fatal events posted as JSON to alert_webhook_url, so alerting doesn't need the logs
+// Events wait on a thread of their own for the batch window, repeats in a batch are counted
+// Failing to deliver is only logged, as a warning so it never turns into an alert itself
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use brew::fetch_request;
+use raw_status;
+
+// Events waiting to be sent, past this new ones are counted as dropped
+const MAX_QUEUED: usize = 100;
+// Attempts per batch, with a growing pause between them
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Event {
+    message: String,
+    count: usize, // Times it was reported within the batch
+    first: u64,   // Unix time of the first and the last of them
+    last: u64,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    dropped: usize,
+    since: Option<Instant>, // When the first event of the batch came in
+}
+
+pub struct Alerts {
+    url: String,
+    source: String, // Sent along to tell servers apart, the address they listen on
+    window: Duration,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Alerts {
+    pub fn new(url: &str, source: &str, window: Duration) -> Alerts {
+        Alerts {
+            url: url.to_string(),
+            source: source.to_string(),
+            window,
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Start sending batches from a thread of its own
+    pub fn start(self) -> Arc<Alerts> {
+        let alerts = Arc::new(self);
+        let sender = alerts.clone();
+        thread::spawn(move || loop {
+            if let Some(payload) = sender.wait_batch() {
+                sender.deliver(&payload);
+            }
+        });
+        alerts
+    }
+
+    // Queue an event, returns right away whatever the webhook does
+    pub fn report(&self, message: &str) {
+        let now = unix_now();
+        let mut queue = self.queue.lock().expect("Error locking alerts");
+        if let Some(event) = queue.events.iter_mut().find(|e| e.message == message) {
+            event.count += 1;
+            event.last = now;
+            return;
+        }
+        if queue.events.len() >= MAX_QUEUED {
+            queue.dropped += 1;
+            return;
+        }
+        queue.events.push_back(Event {
+            message: message.to_string(),
+            count: 1,
+            first: now,
+            last: now,
+        });
+        queue.since.get_or_insert_with(Instant::now);
+        self.ready.notify_one();
+    }
+
+    // Send what is queued now, without waiting for the window, like right before exiting
+    pub fn flush(&self) {
+        let payload = {
+            let mut queue = self.queue.lock().expect("Error locking alerts");
+            self.take_batch(&mut queue)
+        };
+        if let Some(payload) = payload {
+            self.deliver(&payload);
+        }
+    }
+
+    // Block until a batch is due, None when flush took it first
+    fn wait_batch(&self) -> Option<String> {
+        let mut queue = self.queue.lock().expect("Error locking alerts");
+        queue = self
+            .ready
+            .wait_while(queue, |queue| queue.since.is_none())
+            .expect("Error waiting for alerts");
+        while let Some(left) = queue
+            .since
+            .and_then(|since| self.window.checked_sub(since.elapsed()))
+        {
+            queue = self
+                .ready
+                .wait_timeout(queue, left)
+                .expect("Error waiting for alerts")
+                .0;
+        }
+        self.take_batch(&mut queue)
+    }
+
+    fn take_batch(&self, queue: &mut Queue) -> Option<String> {
+        queue.since = None;
+        if queue.events.is_empty() {
+            return None;
+        }
+        let events: Vec<Event> = queue.events.drain(..).collect();
+        let dropped = std::mem::take(&mut queue.dropped);
+        Some(payload(&self.source, &events, dropped))
+    }
+
+    fn deliver(&self, payload: &str) {
+        let headers = [("Content-Type", "application/json".to_string())];
+        let mut error = String::new();
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(RETRY_DELAY * attempt);
+            }
+            let sent = fetch_request(&self.url, "POST", &headers, payload.as_bytes());
+            match sent.map(|raw| raw_status(&raw)) {
+                Ok(Some(status)) if (200..300).contains(&status) => return,
+                Ok(Some(status)) => error = format!("status {}", status),
+                Ok(None) => error = "Invalid response".to_string(),
+                Err(e) => error = e.to_string(),
+            }
+        }
+        eprintln!(
+            "WARNING: alert not delivered to {} after {} attempts: {}",
+            self.url, ATTEMPTS, error
+        );
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+// {"source":...,"events":[{"message":...,"count":n,"first":t,"last":t}],"dropped":n}
+fn payload(source: &str, events: &[Event], dropped: usize) -> String {
+    let events: Vec<String> = events
+        .iter()
+        .map(|event| {
+            format!(
+                "{{\"message\":{},\"count\":{},\"first\":{},\"last\":{}}}",
+                json_string(&event.message),
+                event.count,
+                event.first,
+                event.last
+            )
+        })
+        .collect();
+    format!(
+        "{{\"source\":{},\"version\":{},\"events\":[{}],\"dropped\":{}}}",
+        json_string(source),
+        json_string(env!("CARGO_PKG_VERSION")),
+        events.join(","),
+        dropped
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[test]
+fn test_batching() {
+    let alerts = Alerts::new("http://127.0.0.1:1", "host", Duration::from_secs(60));
+    for _ in 0..3 {
+        alerts.report("FATAL: worker 0 died");
+    }
+    alerts.report("ERROR: \"quoted\"\nline");
+    for i in 0..MAX_QUEUED + 2 {
+        alerts.report(&format!("ERROR: {}", i));
+    }
+    let mut queue = alerts.queue.lock().unwrap();
+    assert_eq!(queue.events.len(), MAX_QUEUED);
+    assert_eq!(queue.events[0].count, 3);
+    let payload = alerts.take_batch(&mut queue).unwrap();
+    assert!(payload.starts_with("{\"source\":\"host\",\"version\":\""));
+    assert!(payload.contains("\"events\":[{\"message\":\"FATAL: worker 0 died\",\"count\":3,"));
+    assert!(payload.contains("{\"message\":\"ERROR: \\\"quoted\\\"\\nline\",\"count\":1,"));
+    assert!(payload.ends_with(",\"dropped\":4}"));
+    // The next batch starts empty
+    assert!(queue.events.is_empty() && queue.since.is_none());
+    assert_eq!(alerts.take_batch(&mut queue), None);
+}
+
+#[test]
+fn test_webhook_delivery() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    // Refuses the first post, so the batch only gets through on the retry
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (received, posts) = mpsc::channel();
+    thread::spawn(move || {
+        for (attempt, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = stream.read(&mut buffer).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..n]);
+            }
+            let status = if attempt == 0 {
+                "503 Busy"
+            } else {
+                "204 No Content"
+            };
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).unwrap();
+            received.send(String::from_utf8(request).unwrap()).unwrap();
+        }
+    });
+
+    let url = format!("http://127.0.0.1:{}/hook", port);
+    let alerts = Alerts::new(&url, "127.0.0.1:8081", Duration::from_millis(100)).start();
+    let started = Instant::now();
+    alerts.report("FATAL: worker 1 died");
+    alerts.report("FATAL: worker 1 died");
+    // Returns at once, whatever the webhook is doing
+    assert!(started.elapsed() < Duration::from_millis(50));
+
+    let first = posts.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(first.starts_with("POST /hook HTTP/1.1\r\n"));
+    assert!(first.contains("Content-Type: application/json\r\n"));
+    assert!(first.contains("\"message\":\"FATAL: worker 1 died\",\"count\":2,"));
+    let retried = posts.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(retried, first);
+
+    // Delivered, so the next event goes out in a batch of its own
+    alerts.report("ERROR: later");
+    alerts.flush();
+    let last = posts.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(last.contains("\"events\":[{\"message\":\"ERROR: later\",\"count\":1,"));
+    assert!(posts.recv_timeout(Duration::from_millis(300)).is_err());
+}
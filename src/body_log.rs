@@ -0,0 +1,145 @@
+// Request bodies written to the log, for seeing what a webhook or form actually posted
+// Textual types are shown up to a limit, anything else only by its size
+
+use hteapot::HttpRequest;
+
+// Log line for the body of a request, None without a body or under an excluded prefix
+pub fn describe(req: &HttpRequest, max_bytes: usize, exclude: &[String]) -> Option<String> {
+    if req.body.is_empty() || exclude.iter().any(|p| req.path.starts_with(p.as_str())) {
+        return None;
+    }
+    let content_type = req
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+        .map(|(_, value)| value.as_str());
+    let body = req.body.as_bytes();
+    let shown = if content_type.is_some_and(is_textual) {
+        let cut = body.len().min(max_bytes);
+        let mut shown = escape(&body[..cut]);
+        if cut < body.len() {
+            shown.push_str(&format!("... ({} more bytes)", body.len() - cut));
+        }
+        shown
+    } else {
+        format!("<binary, {} bytes>", body.len())
+    };
+    Some(format!(
+        "DEBUG: body of {} {} ({}): {}",
+        req.method.to_str(),
+        req.path,
+        content_type.unwrap_or("no type"),
+        shown
+    ))
+}
+
+// Text, json, xml and urlencoded forms, parameters like charset ignored
+fn is_textual(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime == "application/json"
+        || mime.ends_with("+json")
+        || mime == "application/xml"
+        || mime.ends_with("+xml")
+        || mime == "application/x-www-form-urlencoded"
+}
+
+// Control characters and backslashes escaped, bytes that aren't UTF-8 as \xNN
+// A cut in the middle of a character leaves such bytes at the end
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (text, invalid) = match std::str::from_utf8(rest) {
+            Ok(text) => (text, &[][..]),
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                let bad = e.error_len().unwrap_or(invalid.len());
+                rest = &invalid[bad..];
+                (
+                    std::str::from_utf8(valid).unwrap_or_default(),
+                    &invalid[..bad],
+                )
+            }
+        };
+        for c in text.chars() {
+            if c.is_control() || c == '\\' {
+                escaped.extend(c.escape_default());
+            } else {
+                escaped.push(c);
+            }
+        }
+        for b in invalid {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        }
+        if invalid.is_empty() {
+            break;
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_describe_body() {
+    use hteapot::Hteapot;
+
+    let post = |path: &str, content_type: Option<&str>, body: &str| {
+        let mut req =
+            Hteapot::request_parser(format!("POST {} HTTP/1.1\r\nHost: a\r\n\r\n", path)).unwrap();
+        if let Some(content_type) = content_type {
+            req.headers
+                .insert("content-type".to_string(), content_type.to_string());
+        }
+        req.body = body.to_string();
+        req
+    };
+    let describe_body = |req: &HttpRequest| describe(req, 16, &["/login".to_string()]);
+
+    for (content_type, body) in [
+        ("application/json", "{\"event\":\"push\"}"),
+        ("application/x-www-form-urlencoded", "a=1&b=two"),
+        ("application/xml; charset=utf-8", "<ok/>"),
+        ("application/atom+xml", "<feed/>"),
+        ("application/vnd.api+json", "{}"),
+        ("Text/Plain", "hello"),
+    ] {
+        assert_eq!(
+            describe_body(&post("/hook", Some(content_type), body)).unwrap(),
+            format!("DEBUG: body of POST /hook ({}): {}", content_type, body)
+        );
+    }
+    for content_type in [
+        Some("image/png"),
+        Some("multipart/form-data; boundary=x"),
+        None,
+    ] {
+        let line = describe_body(&post("/hook", content_type, "\u{1}PNG")).unwrap();
+        assert!(line.ends_with("): <binary, 4 bytes>"), "{}", line);
+    }
+    assert_eq!(describe_body(&post("/hook", None, "")), None);
+
+    // Cut at the limit, here in the middle of the é
+    let long = post("/hook", Some("text/plain"), "0123456789abcdeé and more");
+    assert_eq!(
+        describe_body(&long).unwrap(),
+        "DEBUG: body of POST /hook (text/plain): 0123456789abcde\\xc3... (10 more bytes)"
+    );
+    let controls = post("/hook", Some("text/plain"), "a\tb\\c\u{1b}[31m");
+    assert!(describe_body(&controls)
+        .unwrap()
+        .ends_with(": a\\tb\\\\c\\u{1b}[31m"));
+
+    assert_eq!(
+        describe_body(&post("/login", Some("text/plain"), "pw")),
+        None
+    );
+    assert_eq!(
+        describe_body(&post("/login/reset", Some("text/plain"), "pw")),
+        None
+    );
+}
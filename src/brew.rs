@@ -2,10 +2,102 @@
 // This is the HTTP client module, it will handle the requests and responses
 
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
 };
 
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+static OPEN_UPSTREAM_SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+// Url prefix for upstreams on a unix socket, like unix:/run/app.sock:/api
+pub const UNIX_PREFIX: &str = "unix:";
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// Upstream connection, shut down and untracked on every exit path
+struct Upstream {
+    stream: Stream,
+}
+
+impl Upstream {
+    fn connect(url: &Url) -> io::Result<Upstream> {
+        let stream = if url.scheme == "unix" {
+            Upstream::connect_unix(&url.domain)?
+        } else {
+            Stream::Tcp(TcpStream::connect(format!("{}:{}", url.domain, url.port))?)
+        };
+        OPEN_UPSTREAM_SOCKETS.fetch_add(1, Ordering::Relaxed);
+        Ok(Upstream { stream })
+    }
+
+    #[cfg(unix)]
+    fn connect_unix(path: &str) -> io::Result<Stream> {
+        Ok(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(_path: &str) -> io::Result<Stream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets are not supported on this platform",
+        ))
+    }
+}
+
+impl Read for Upstream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.stream {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Upstream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stream {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        let _ = match &self.stream {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        };
+        OPEN_UPSTREAM_SOCKETS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Upstream sockets currently open, should go back to 0 when idle
+pub fn open_upstream_sockets() -> usize {
+    OPEN_UPSTREAM_SOCKETS.load(Ordering::Relaxed)
+}
+
 #[derive(Debug)]
 struct Url {
     scheme: String,
@@ -14,24 +106,49 @@ struct Url {
     port: String,
 }
 
+// unix:<socket path>[:<request path>], the socket path itself can't contain ':'
+fn parse_unix_url(url: &str) -> Result<Url, &str> {
+    let rest = &url[UNIX_PREFIX.len()..];
+    let (socket, path) = match rest.split_once(':') {
+        Some((socket, path)) => (socket, path),
+        None => (rest, ""),
+    };
+    if socket.is_empty() {
+        return Err("Missing socket path");
+    }
+    Ok(Url {
+        scheme: "unix".to_string(),
+        domain: socket.to_string(),
+        path: path.trim_start_matches('/').to_string(),
+        port: String::new(),
+    })
+}
+
 fn parse_url(url: &str) -> Result<Url, &str> {
-    let url_parts = url.split(":").collect::<Vec<&str>>();
-    let prefix = url_parts[0];
-    let domain_path = url_parts[1].trim_start_matches("//");
-    let port = if url_parts.len() == 3 {
-        url_parts[2]
-    } else {
-        match prefix {
-            "tea" => "1234",
-            "https" => "443",
-            "http" => "80",
-            _ => "80",
-        }
+    if url.starts_with(UNIX_PREFIX) {
+        return parse_unix_url(url);
+    }
+    let (prefix, rest) = match url.split_once("://") {
+        Some((prefix, rest)) => (prefix, rest),
+        None => return Err("Missing scheme"),
     };
-    let (domain, path) = match domain_path.split_once('/') {
+    let (authority, path) = match rest.split_once('/') {
         Some((a, b)) => (a, b),
-        None => (domain_path, ""),
+        None => (rest, ""),
+    };
+    let default_port = match prefix {
+        "tea" => "1234",
+        "https" => "443",
+        "http" => "80",
+        _ => "80",
+    };
+    let (domain, port) = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => (&authority[..i], &authority[i + 1..]),
+        _ => (authority, default_port),
     };
+    if domain.is_empty() {
+        return Err("Missing host");
+    }
 
     Ok(Url {
         scheme: prefix.to_string(),
@@ -41,37 +158,696 @@ fn parse_url(url: &str) -> Result<Url, &str> {
     })
 }
 
-pub fn fetch(url: &str) -> Result<Vec<u8>, &str> {
-    let url = parse_url(url);
-    if url.is_err() {
-        return Err("Error parsing url");
+// Whether fetch could ever use this url, checked once at startup
+pub fn check_url(url: &str) -> Result<(), &'static str> {
+    let url = match parse_url(url) {
+        Ok(url) => url,
+        Err(_) => return Err("Not a url like http://host[:port][/path]"),
+    };
+    match url.scheme.as_str() {
+        "unix" => Ok(()),
+        "https" => Err("https upstreams are not supported yet"),
+        "http" | "tea" if url.port.parse::<u16>().is_err() => Err("Invalid port"),
+        "http" | "tea" => Ok(()),
+        _ => Err("Unknown scheme"),
     }
-    let url = url.unwrap();
-    if url.scheme == "https" {
-        return Err("not supported yet");
+}
+
+// Whether an upstream takes a connection, its host resolved first
+// Nothing is sent, the connection is closed right away
+pub fn probe_url(url: &str, timeout: Duration) -> Result<(), String> {
+    let url = parse_url(url).map_err(|e| e.to_string())?;
+    if url.scheme == "unix" {
+        return Upstream::connect_unix(&url.domain)
+            .map(|_| ())
+            .map_err(|e| format!("Cannot connect to {}: {}", url.domain, e));
     }
+    let authority = format!("{}:{}", url.domain, url.port);
+    let addr = authority
+        .to_socket_addrs()
+        .map_err(|e| format!("Cannot resolve {}: {}", url.domain, e))?
+        .next()
+        .ok_or(format!("Cannot resolve {}: no addresses", url.domain))?;
+    TcpStream::connect_timeout(&addr, timeout)
+        .map(|_| ())
+        .map_err(|e| format!("Cannot connect to {}: {}", authority, e))
+}
+
+#[allow(dead_code)]
+pub fn fetch(url: &str) -> Result<Vec<u8>, BrewError> {
+    fetch_with_headers(url, &[])
+}
+
+// Fetch with extra request headers, like X-Forwarded-For
+pub fn fetch_with_headers(url: &str, headers: &[(&str, String)]) -> Result<Vec<u8>, BrewError> {
+    let mut raw = Vec::new();
+    transfer(url, headers, &BrewOptions::default(), true, &mut raw)?;
+    Ok(raw)
+}
+
+// Limits on an upstream response head, past them it is not forwarded
+pub const MAX_HEADER_SIZE: usize = 64 * 1024;
+pub const MAX_HEADER_COUNT: usize = 100;
+// Bytes of an offending head kept for the log
+const HEADER_SAMPLE_SIZE: usize = 128;
 
-    let client = TcpStream::connect(format!("{}:{}", url.domain, url.port));
-    if client.is_err() {
-        return Err("Error fetching");
+#[derive(Clone, Debug, PartialEq)]
+pub enum BrewError {
+    TooLarge,                                   // More than max_response_size bytes
+    Truncated { expected: u64, received: u64 }, // Body shorter than Content-Length
+    BadHeaders(String),                         // Why and the start of the head
+    Connect,                                    // The upstream couldn't be reached
+    Other(&'static str),
+}
+
+impl BrewError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BrewError::TooLarge => "Response too large",
+            BrewError::Truncated { .. } => "Response truncated",
+            BrewError::BadHeaders(_) => "Invalid response headers",
+            BrewError::Connect => "Error fetching",
+            BrewError::Other(e) => e,
+        }
     }
-    let mut client = client.unwrap();
-    let http_request = format!(
-        "GET /{} HTTP/1.1\nHost: {}\nConnection: Close\n\n",
-        url.path, url.domain
+}
+
+impl std::fmt::Display for BrewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BrewError::Truncated { expected, received } => {
+                write!(f, "Response truncated, {} of {} bytes", received, expected)
+            }
+            BrewError::BadHeaders(detail) => write!(f, "{}: {}", self.as_str(), detail),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BrewOptions<'a> {
+    pub max_response_size: Option<u64>, // Whole response, head included
+    pub progress: Option<&'a dyn Fn(u64, Option<u64>)>, // Body bytes so far and Content-Length
+    pub method: Option<&'a str>,        // GET when not given
+    pub body: Option<&'a [u8]>,         // Sent with its Content-Length
+}
+
+// Fetch a url into memory, raw response included
+#[allow(dead_code)]
+pub fn fetch_with(url: &str, options: &BrewOptions) -> Result<Vec<u8>, BrewError> {
+    let mut raw = Vec::new();
+    transfer(url, &[], options, true, &mut raw)?;
+    Ok(raw)
+}
+
+// Send any method with headers and a body, like a recorded request, and return the raw response
+pub fn fetch_request(
+    url: &str,
+    method: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<Vec<u8>, BrewError> {
+    let options = BrewOptions {
+        method: Some(method),
+        body: Some(body),
+        ..BrewOptions::default()
+    };
+    let mut raw = Vec::new();
+    transfer(url, headers, &options, true, &mut raw)?;
+    Ok(raw)
+}
+
+// Stream only the body of a url to a writer, returns the body size
+#[allow(dead_code)]
+pub fn fetch_to_writer(
+    url: &str,
+    writer: &mut impl Write,
+    options: &BrewOptions,
+) -> Result<u64, BrewError> {
+    transfer(url, &[], options, false, writer)
+}
+
+fn bad_headers(reason: &str, head: &[u8]) -> BrewError {
+    let sample = String::from_utf8_lossy(&head[..head.len().min(HEADER_SAMPLE_SIZE)]);
+    BrewError::BadHeaders(format!(
+        "{}, starting \"{}\"",
+        reason,
+        sample.escape_debug()
+    ))
+}
+
+// A name made of token characters, a colon and a value without control characters
+fn valid_header_line(line: &[u8]) -> bool {
+    let colon = match line.iter().position(|&b| b == b':') {
+        Some(colon) => colon,
+        None => return false,
+    };
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    !name.is_empty()
+        && name
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+        && value
+            .iter()
+            .all(|&b| b == b'\t' || (b >= 0x20 && b != 0x7F))
+}
+
+// Check a response head, without the blank line, against the limits
+fn check_head(head: &[u8]) -> Result<(), BrewError> {
+    let lines: Vec<&[u8]> = head.split(|&b| b == b'\n').collect();
+    if lines.len() > MAX_HEADER_COUNT + 1 {
+        return Err(bad_headers("Too many headers", head));
+    }
+    // Every line but the last one ends in \r, a bare \n would split a header in two
+    let last = lines.len() - 1;
+    for (i, line) in lines.iter().enumerate() {
+        let line = match line.strip_suffix(b"\r") {
+            Some(line) if i < last => line,
+            None if i == last => line,
+            _ => return Err(bad_headers("Malformed header", head)),
+        };
+        if i > 0 && !valid_header_line(line) {
+            return Err(bad_headers("Malformed header", head));
+        }
+    }
+    Ok(())
+}
+
+// Status and Content-Length from a response head
+fn parse_head(head: &[u8]) -> (Option<u16>, Option<u64>) {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok());
+    let length = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok());
+    (status, length)
+}
+
+// Send the request and copy the response to out, the whole of it when raw or only the body
+fn transfer(
+    url: &str,
+    headers: &[(&str, String)],
+    options: &BrewOptions,
+    raw: bool,
+    out: &mut dyn Write,
+) -> Result<u64, BrewError> {
+    let url = parse_url(url).map_err(|_| BrewError::Other("Error parsing url"))?;
+    if url.scheme == "https" {
+        return Err(BrewError::Other("not supported yet"));
+    }
+
+    let mut stream = Upstream::connect(&url).map_err(|_| BrewError::Connect)?;
+    // A socket path is no host name
+    let host = if url.scheme == "unix" {
+        "localhost"
+    } else {
+        url.domain.as_str()
+    };
+    let mut http_request = format!(
+        "{} /{} HTTP/1.1\r\nHost: {}\r\nConnection: Close\r\n",
+        options.method.unwrap_or("GET"),
+        url.path,
+        host
     );
-    client.write(http_request.as_bytes()).unwrap();
-    let _ = client.flush();
-    let mut full_buffer: Vec<u8> = Vec::new();
+    for (key, value) in headers {
+        http_request.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    if let Some(body) = options.body {
+        http_request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    http_request.push_str("\r\n");
+    let mut http_request = http_request.into_bytes();
+    http_request.extend_from_slice(options.body.unwrap_or_default());
+    if stream.write_all(&http_request).is_err() {
+        return Err(BrewError::Other("Error sending request"));
+    }
+    let _ = stream.flush();
+
+    let mut head: Vec<u8> = Vec::new();
+    let mut scanned = 0usize; // Head already searched for the blank line
+    let mut status = None;
+    let mut content_length = None;
+    let mut in_body = false;
+    let mut total = 0u64;
+    let mut received = 0u64;
     let mut buffer = [0; 1024];
     loop {
-        match client.read(&mut buffer) {
+        let n = match stream.read(&mut buffer) {
             Ok(0) => break,
-            Ok(_n) => {
-                full_buffer.extend(buffer.iter().cloned());
+            Ok(n) => n,
+            Err(_) => return Err(BrewError::Other("Error reading response")),
+        };
+        total += n as u64;
+        if options
+            .max_response_size
+            .map(|max| total > max)
+            .unwrap_or(false)
+        {
+            return Err(BrewError::TooLarge);
+        }
+        if raw && out.write_all(&buffer[..n]).is_err() {
+            return Err(BrewError::Other("Error writing response"));
+        }
+        let rest;
+        let body = if in_body {
+            &buffer[..n]
+        } else {
+            head.extend_from_slice(&buffer[..n]);
+            let from = scanned.saturating_sub(3);
+            scanned = head.len();
+            match head[from..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|i| from + i)
+            {
+                Some(end) if end > MAX_HEADER_SIZE => {
+                    return Err(bad_headers("Headers too large", &head));
+                }
+                Some(end) => {
+                    in_body = true;
+                    rest = head.split_off(end + 4);
+                    check_head(&head[..end])?;
+                    let parsed = parse_head(&head);
+                    status = parsed.0;
+                    content_length = parsed.1;
+                    &rest[..]
+                }
+                None if head.len() > MAX_HEADER_SIZE => {
+                    return Err(bad_headers("Headers too large", &head));
+                }
+                None => continue,
+            }
+        };
+        received += body.len() as u64;
+        if !raw && out.write_all(body).is_err() {
+            return Err(BrewError::Other("Error writing response"));
+        }
+        if let Some(progress) = options.progress {
+            progress(received, content_length);
+        }
+    }
+    if total == 0 {
+        return Err(BrewError::Other("Empty response"));
+    }
+    if !in_body && !raw {
+        return Err(BrewError::Other("Invalid response"));
+    }
+    // These never carry a body whatever Content-Length says
+    let bodyless = matches!(status, Some(100..=199) | Some(204) | Some(304));
+    if let Some(expected) = content_length {
+        if received < expected && !bodyless {
+            return Err(BrewError::Truncated { expected, received });
+        }
+    }
+    Ok(if raw { total } else { received })
+}
+
+// Value of a header in the head of a raw response
+fn response_header(raw: &[u8], name: &str) -> Option<String> {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+    String::from_utf8_lossy(&raw[..end])
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// Urls remembered by a client, the oldest is forgotten past this
+const MAX_CACHED_URLS: usize = 64;
+// Responses bigger than this are not kept for conditional requests
+const MAX_CACHED_RESPONSE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    response: Vec<u8>, // The whole 200, head included
+}
+
+#[derive(Debug, Default)]
+struct Remembered {
+    validators: HashMap<String, Validators>,
+    order: VecDeque<String>, // Oldest first
+}
+
+// Raw responses of a conditional GET
+#[derive(Debug, PartialEq)]
+pub enum Fetched {
+    Fresh(Vec<u8>),       // From the server, whatever the status
+    NotModified(Vec<u8>), // 304, the 200 it revalidated instead
+}
+
+impl Fetched {
+    pub fn into_raw(self) -> Vec<u8> {
+        match self {
+            Fetched::Fresh(raw) | Fetched::NotModified(raw) => raw,
+        }
+    }
+}
+
+// Client that remembers ETag/Last-Modified per url and sends conditional GETs, like for
+// polling a feed or revalidating what a proxy rule forwards
+// Shared between threads, the lock is never held while a request is on the wire
+#[derive(Debug, Default)]
+pub struct BrewClient {
+    remembered: Mutex<Remembered>,
+}
+
+impl BrewClient {
+    pub fn new() -> BrewClient {
+        BrewClient::default()
+    }
+
+    // GET a url with If-None-Match and If-Modified-Since from its last 200
+    pub fn fetch(&self, url: &str, headers: &[(&str, String)]) -> Result<Fetched, BrewError> {
+        let mut headers = headers.to_vec();
+        if let Some(cached) = self.remembered().validators.get(url) {
+            if let Some(etag) = &cached.etag {
+                headers.push(("If-None-Match", etag.clone()));
+            }
+            if let Some(date) = &cached.last_modified {
+                headers.push(("If-Modified-Since", date.clone()));
+            }
+        }
+        let raw = fetch_with_headers(url, &headers)?;
+        let mut remembered = self.remembered();
+        match raw_status(&raw) {
+            Some(304) => match remembered.validators.get(url) {
+                Some(cached) => Ok(Fetched::NotModified(cached.response.clone())),
+                None => Ok(Fetched::Fresh(raw)),
+            },
+            Some(200) => {
+                remembered.remember(url, &raw);
+                Ok(Fetched::Fresh(raw))
+            }
+            _ => Ok(Fetched::Fresh(raw)),
+        }
+    }
+
+    fn remembered(&self) -> MutexGuard<'_, Remembered> {
+        self.remembered.lock().expect("Error locking validators")
+    }
+}
+
+impl Remembered {
+    fn remember(&mut self, url: &str, raw: &[u8]) {
+        if self.validators.remove(url).is_some() {
+            self.order.retain(|u| u != url);
+        }
+        let etag = response_header(raw, "ETag");
+        let last_modified = response_header(raw, "Last-Modified");
+        if (etag.is_none() && last_modified.is_none()) || raw.len() > MAX_CACHED_RESPONSE {
+            return;
+        }
+        if self.order.len() >= MAX_CACHED_URLS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.validators.remove(&oldest);
+            }
+        }
+        self.order.push_back(url.to_string());
+        self.validators.insert(
+            url.to_string(),
+            Validators {
+                etag,
+                last_modified,
+                response: raw.to_vec(),
+            },
+        );
+    }
+}
+
+// Status code of a raw response, from its status line
+pub fn raw_status(raw: &[u8]) -> Option<u16> {
+    let line_end = raw.iter().position(|b| *b == b'\r').unwrap_or(raw.len());
+    let line = std::str::from_utf8(&raw[..line_end]).ok()?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[test]
+fn test_parse_url() {
+    let url = parse_url("http://127.0.0.1:3000/api/users").unwrap();
+    assert_eq!(url.domain, "127.0.0.1");
+    assert_eq!(url.port, "3000");
+    assert_eq!(url.path, "api/users");
+    let url = parse_url("http://example.com").unwrap();
+    assert_eq!(url.domain, "example.com");
+    assert_eq!(url.port, "80");
+    assert_eq!(url.path, "");
+    assert!(parse_url("example.com/x").is_err());
+    let url = parse_url("unix:/run/app.sock:/api/v1").unwrap();
+    assert_eq!(url.scheme, "unix");
+    assert_eq!(url.domain, "/run/app.sock");
+    assert_eq!(url.path, "api/v1");
+    assert_eq!(parse_url("unix:/run/app.sock").unwrap().path, "");
+    assert!(parse_url("unix::/x").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fetch_unix_socket() {
+    use std::os::unix::net::UnixListener;
+    let path = std::env::temp_dir().join(format!("hteapot-brew-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    // Echo the request line back as the body
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer).unwrap();
+            let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+            let line = request.lines().next().unwrap_or("").to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                line.len(),
+                line
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let url = format!("unix:{}:/app/users", path.display());
+    let response = fetch(&url).unwrap();
+    assert!(response.ends_with(b"\r\n\r\nGET /app/users HTTP/1.1"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_conditional_fetch() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // Serves a feed with an ETag, body changes on the third request
+    std::thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer).unwrap();
+            let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+            let etag = if i < 2 { "\"v1\"" } else { "\"v2\"" };
+            let response = if request.contains(&format!("If-None-Match: {}", etag)) {
+                "HTTP/1.1 304 Not Modified\r\n\r\n".to_string()
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: 4\r\n\r\nfeed",
+                    etag
+                )
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let url = format!("http://127.0.0.1:{}/feed.json", port);
+    let client = BrewClient::new();
+    let v1 = b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\n\r\nfeed".to_vec();
+    let v2 = b"HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 4\r\n\r\nfeed".to_vec();
+    assert_eq!(client.fetch(&url, &[]), Ok(Fetched::Fresh(v1.clone())));
+    assert_eq!(client.fetch(&url, &[]), Ok(Fetched::NotModified(v1)));
+    assert_eq!(client.fetch(&url, &[]), Ok(Fetched::Fresh(v2)));
+    let remembered = client.remembered();
+    assert_eq!(remembered.validators[&url].etag.as_deref(), Some("\"v2\""));
+}
+
+#[test]
+fn test_conditional_fetch_bounds() {
+    // Nothing kept without a validator, a 304 then has nothing to stand for
+    let port = canned_upstream(b"HTTP/1.1 304 Not Modified\r\n\r\n");
+    let url = format!("http://127.0.0.1:{}/", port);
+    let client = BrewClient::new();
+    let not_modified = client.fetch(&url, &[]).unwrap();
+    assert_eq!(
+        not_modified.into_raw(),
+        b"HTTP/1.1 304 Not Modified\r\n\r\n"
+    );
+
+    // Only so many urls, the oldest goes first
+    let mut remembered = Remembered::default();
+    let raw = b"HTTP/1.1 200 OK\r\nLast-Modified: today\r\n\r\n";
+    for i in 0..=MAX_CACHED_URLS {
+        remembered.remember(&format!("http://a/{}", i), raw);
+    }
+    assert_eq!(remembered.validators.len(), MAX_CACHED_URLS);
+    assert!(!remembered.validators.contains_key("http://a/0"));
+    remembered.remember("http://a/none", b"HTTP/1.1 200 OK\r\n\r\n");
+    assert!(!remembered.validators.contains_key("http://a/none"));
+}
+
+// Serve one canned response per connection
+#[cfg(test)]
+pub fn canned_upstream(response: &'static [u8]) -> u16 {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(response);
+        }
+    });
+    port
+}
+
+#[test]
+fn test_fetch_limits_and_progress() {
+    use std::cell::RefCell;
+    let port = canned_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+    let url = format!("http://127.0.0.1:{}/", port);
+
+    let seen = RefCell::new(Vec::new());
+    let progress = |bytes, length| seen.borrow_mut().push((bytes, length));
+    let options = BrewOptions {
+        progress: Some(&progress),
+        ..BrewOptions::default()
+    };
+    let mut body = Vec::new();
+    assert_eq!(fetch_to_writer(&url, &mut body, &options), Ok(10));
+    assert_eq!(body, b"0123456789");
+    assert_eq!(seen.borrow().last(), Some(&(10, Some(10))));
+
+    let options = BrewOptions {
+        max_response_size: Some(20),
+        ..BrewOptions::default()
+    };
+    assert_eq!(fetch_with(&url, &options), Err(BrewError::TooLarge));
+
+    let port = canned_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123");
+    let url = format!("http://127.0.0.1:{}/", port);
+    assert_eq!(
+        fetch_with(&url, &BrewOptions::default()),
+        Err(BrewError::Truncated {
+            expected: 10,
+            received: 4
+        })
+    );
+    assert!(fetch(&url).is_err());
+
+    let port = canned_upstream(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\n");
+    assert!(fetch(&format!("http://127.0.0.1:{}/", port)).is_ok());
+}
+
+#[test]
+fn test_pathological_headers() {
+    let huge = format!(
+        "HTTP/1.1 200 OK\r\nX-Big: {}\r\n\r\nbody",
+        "a".repeat(MAX_HEADER_SIZE)
+    );
+    let many = format!(
+        "HTTP/1.1 200 OK\r\n{}\r\nbody",
+        "X-A: 1\r\n".repeat(MAX_HEADER_COUNT + 1)
+    );
+    let cases: Vec<(&'static [u8], &str)> = vec![
+        (Box::leak(huge.into_bytes().into_boxed_slice()), "too large"),
+        (Box::leak(many.into_bytes().into_boxed_slice()), "Too many"),
+        (
+            b"HTTP/1.1 200 OK\r\nX-A: 1\nX-Injected: 1\r\n\r\n",
+            "Malformed",
+        ),
+        (b"HTTP/1.1 200 OK\r\nX A: 1\r\n\r\n", "Malformed"),
+        (b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n", "Malformed"),
+        (b"HTTP/1.1 200 OK\r\nX-A: \x1b[31m\r\n\r\n", "Malformed"),
+    ];
+    for (response, reason) in cases {
+        let url = format!("http://127.0.0.1:{}/", canned_upstream(response));
+        match fetch(&url) {
+            Err(BrewError::BadHeaders(detail)) => {
+                assert!(detail.contains(reason), "{}", detail);
+                assert!(detail.len() < HEADER_SAMPLE_SIZE * 2 + 64, "{}", detail);
             }
-            Err(_) => break,
+            other => panic!("{:?} for {:?}", other, reason),
         }
     }
-    Ok(full_buffer)
+
+    let fine = b"HTTP/1.1 200 OK\r\nX-A:\tplain, \"quoted\"\r\nX-B:\r\n\r\nbody";
+    let url = format!("http://127.0.0.1:{}/", canned_upstream(fine));
+    assert_eq!(fetch(&url), Ok(fine.to_vec()));
+}
+
+#[test]
+fn test_head_in_pieces() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // The blank line split between writes, still found once
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).unwrap();
+        stream.set_nodelay(true).unwrap();
+        for piece in [
+            "HTTP/1.1 200 OK\r\nContent-Le",
+            "ngth: 4\r\n\r",
+            "\nfe",
+            "ed",
+        ]
+        .iter()
+        {
+            stream.write_all(piece.as_bytes()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+    });
+
+    let url = format!("http://127.0.0.1:{}/", port);
+    let mut body = Vec::new();
+    assert_eq!(
+        fetch_to_writer(&url, &mut body, &BrewOptions::default()),
+        Ok(4)
+    );
+    assert_eq!(body, b"feed");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_failing_fetches_release_sockets() {
+    use std::net::TcpListener;
+    let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+    // Upstream that hangs up without answering
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            drop(stream);
+        }
+    });
+    let dead_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let before = open_fds();
+    for _ in 0..1000 {
+        assert!(fetch(&format!("http://127.0.0.1:{}/", port)).is_err());
+        assert!(fetch(&format!("http://127.0.0.1:{}/", dead_port)).is_err());
+    }
+    let after = open_fds();
+    // Other tests share the process, so allow some noise
+    assert!(after < before + 50, "fds before {} after {}", before, after);
 }
@@ -0,0 +1,48 @@
+// What this binary is, for --version, the startup log and the version endpoint
+// Commit and build time come from build.rs
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const COMMIT: &str = env!("HTEAPOT_GIT_COMMIT");
+pub const BUILD_TIME: &str = env!("HTEAPOT_BUILD_TIME"); // Seconds since the epoch
+
+// Optional parts compiled into this binary
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(unix) {
+        features.push("unix-upstreams");
+    }
+    if cfg!(debug_assertions) {
+        features.push("debug");
+    }
+    features
+}
+
+// One line, like "0.3.1 (c8c613f, built 1760000000)"
+pub fn summary() -> String {
+    format!("{} ({}, built {})", VERSION, COMMIT, BUILD_TIME)
+}
+
+// Every value is known to need no escaping, so it is written by hand
+pub fn to_json(threads: u16) -> String {
+    let features: Vec<String> = features().iter().map(|f| format!("\"{}\"", f)).collect();
+    format!(
+        "{{\"version\":\"{}\",\"commit\":\"{}\",\"build_time\":{},\"features\":[{}],\"threads\":{}}}",
+        VERSION,
+        COMMIT,
+        BUILD_TIME,
+        features.join(","),
+        threads
+    )
+}
+
+#[test]
+fn test_build_info_json() {
+    let json = to_json(4);
+    for key in ["version", "commit", "build_time", "features", "threads"].iter() {
+        assert!(json.contains(&format!("\"{}\":", key)), "{}", key);
+    }
+    assert!(json.contains(&format!("\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))));
+    assert!(json.contains("\"threads\":4"));
+    assert!(BUILD_TIME.parse::<u64>().is_ok());
+    assert!(!COMMIT.is_empty() && !COMMIT.contains('"'));
+}
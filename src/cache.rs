@@ -2,24 +2,81 @@
 // Config module: handles application configuration setup and parsing.
 // This module defines structs and functions to load and validate
 // configuration settings from files, environment variables, or other sources.
+use brew::BrewError;
+use digests::Digests;
+use single_flight::SingleFlight;
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 use std::time;
+use std::time::Duration;
 use std::time::SystemTime;
+use {FileError, LoadedFile};
+
+// Longest a request waits for another one loading the same entry
+const LOAD_WAIT: Duration = Duration::from_secs(5);
+
+// Start of a saved cache file, the last byte is the format version
+const PERSIST_MAGIC: &[u8] = b"HTPC\x01";
+
+// Keys of stored responses to Idempotency-Key requests, never written by save
+// They are answers to one client, a cache file outlives the process and may be read by others
+pub const IDEMPOTENCY_PREFIX: &str = "idempotency:";
+
+pub type FileLoads = SingleFlight<Result<LoadedFile, FileError>>;
+pub type UpstreamLoads = SingleFlight<Result<Vec<u8>, BrewError>>;
 
 pub struct Cache {
     //TODO: consider make it generic
-    data: HashMap<String, (Vec<u8>, u64)>,
+    data: HashMap<String, (Arc<Vec<u8>>, u64)>, // Shared with the responses sending them
+    modified: HashMap<String, SystemTime>,      // Of the files in data, read along with them
     max_ttl: u64,
+    enabled: bool, // Switched off at runtime through the admin api, config.cache still has to allow it
+    bytes: usize,  // Keys and data of every entry, expired ones until they are dropped
+    // Loads in progress, used without holding the cache lock
+    file_loads: Arc<FileLoads>,
+    upstream_loads: Arc<UpstreamLoads>,
+    digests: Arc<Digests>, // Of the files served, whether or not their content is cached
 }
 
 impl Cache {
     pub fn new(max_ttl: u64) -> Self {
         Cache {
             data: HashMap::new(),
+            modified: HashMap::new(),
             max_ttl,
+            enabled: true,
+            bytes: 0,
+            file_loads: Arc::new(SingleFlight::new(LOAD_WAIT)),
+            upstream_loads: Arc::new(SingleFlight::new(LOAD_WAIT)),
+            digests: Arc::new(Digests::new()),
         }
     }
 
+    pub fn file_loads(&self) -> Arc<FileLoads> {
+        self.file_loads.clone()
+    }
+
+    pub fn upstream_loads(&self) -> Arc<UpstreamLoads> {
+        self.upstream_loads.clone()
+    }
+
+    pub fn digests(&self) -> Arc<Digests> {
+        self.digests.clone()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Returns whether it was enabled before
+    pub fn set_enabled(&mut self, enabled: bool) -> bool {
+        std::mem::replace(&mut self.enabled, enabled)
+    }
+
     fn validate_ttl(&self, ttl: u64) -> bool {
         let now = SystemTime::now();
         let since_epoch = now
@@ -38,18 +95,106 @@ impl Cache {
         secs + self.max_ttl
     }
 
-    pub fn set(&mut self, key: String, data: Vec<u8>) {
-        self.data.insert(key, (data, self.get_ttl()));
+    // Only affects entries stored from now on
+    #[allow(dead_code)]
+    pub fn set_max_ttl(&mut self, max_ttl: u64) {
+        self.max_ttl = max_ttl;
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
     }
 
-    pub fn get(&mut self, key: String) -> Option<Vec<u8>> {
+    // Every change to data goes through these two, so bytes stays exact
+    fn insert(&mut self, key: String, data: Arc<Vec<u8>>, ttl: u64) {
+        self.modified.remove(&key);
+        self.bytes += key.len() + data.len();
+        if let Some((old, _)) = self.data.insert(key.clone(), (data, ttl)) {
+            self.bytes -= key.len() + old.len();
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.modified.remove(key);
+        if let Some((old, _)) = self.data.remove(key) {
+            self.bytes -= key.len() + old.len();
+        }
+    }
+
+    // Returns how many entries were dropped
+    pub fn clear(&mut self) -> usize {
+        let dropped = self.data.len();
+        self.data.clear();
+        self.modified.clear();
+        self.bytes = 0;
+        dropped
+    }
+
+    pub fn set(&mut self, key: String, data: impl Into<Arc<Vec<u8>>>) {
+        let ttl = self.get_ttl();
+        self.insert(key, data.into(), ttl);
+    }
+
+    // A file with its modification time, None where its source has none
+    pub fn set_file(&mut self, key: String, file: (Arc<Vec<u8>>, Option<SystemTime>)) {
+        let (data, modified) = file;
+        self.set(key.clone(), data);
+        if let Some(modified) = modified {
+            self.modified.insert(key, modified);
+        }
+    }
+
+    // Like set, with a lifetime of its own instead of max_ttl
+    pub fn set_with_ttl(&mut self, key: String, data: impl Into<Arc<Vec<u8>>>, ttl: Duration) {
+        let now = SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .expect("Time went backwards");
+        self.insert(key, data.into(), (now + ttl).as_secs());
+    }
+
+    // Write the entries still valid to path, through a temporary file so a crash never leaves half of one
+    pub fn save(&self, path: &Path) -> io::Result<usize> {
+        let entries: Vec<(&String, &Arc<Vec<u8>>, u64)> = self
+            .data
+            .iter()
+            .filter(|(key, (_, ttl))| {
+                self.validate_ttl(*ttl) && !key.starts_with(IDEMPOTENCY_PREFIX)
+            })
+            .map(|(key, (data, ttl))| (key, data, *ttl))
+            .collect();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, encode(&entries))?;
+        fs::rename(&tmp, path)?;
+        Ok(entries.len())
+    }
+
+    // Add the entries of a saved file that haven't expired, keeping their expiry
+    // A damaged file is rejected as a whole
+    pub fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let mut loaded = 0;
+        for (key, data, ttl) in decode(&bytes)? {
+            if self.validate_ttl(ttl) {
+                self.insert(key, Arc::new(data), ttl);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    // A file stored by set_file, the time is None for entries stored otherwise like loaded ones
+    pub fn get_file(&mut self, key: String) -> Option<(Arc<Vec<u8>>, Option<SystemTime>)> {
+        let data = self.get(key.clone())?;
+        Some((data, self.modified.get(&key).copied()))
+    }
+
+    pub fn get(&mut self, key: String) -> Option<Arc<Vec<u8>>> {
         let r = self.data.get(&key);
-        if r.is_some() {
-            let (data, ttl) = r.unwrap();
+        if let Some((data, ttl)) = r {
             if self.validate_ttl(*ttl) {
                 Some(data.clone())
             } else {
-                self.data.remove(&key);
+                self.remove(&key);
                 None
             }
         } else {
@@ -57,3 +202,110 @@ impl Cache {
         }
     }
 }
+
+// Each entry is the key, its expiry and the body, lengths as little endian u64
+fn encode(entries: &[(&String, &Arc<Vec<u8>>, u64)]) -> Vec<u8> {
+    let mut out = PERSIST_MAGIC.to_vec();
+    for (key, data, ttl) in entries {
+        out.extend((key.len() as u64).to_le_bytes());
+        out.extend(key.as_bytes());
+        out.extend(ttl.to_le_bytes());
+        out.extend((data.len() as u64).to_le_bytes());
+        out.extend(data.iter());
+    }
+    out
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>, u64)>, String> {
+    let mut rest = bytes
+        .strip_prefix(PERSIST_MAGIC)
+        .ok_or("not a cache file or from another version")?;
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let key = take_field(&mut rest)?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| "key is not utf-8")?;
+        let ttl = take_u64(&mut rest)?;
+        let data = take_field(&mut rest)?;
+        entries.push((key, data.to_vec(), ttl));
+    }
+    Ok(entries)
+}
+
+fn take<'a>(rest: &mut &'a [u8], n: u64) -> Result<&'a [u8], String> {
+    match usize::try_from(n) {
+        Ok(n) if n <= rest.len() => {
+            let (taken, left) = rest.split_at(n);
+            *rest = left;
+            Ok(taken)
+        }
+        _ => Err("truncated".to_string()),
+    }
+}
+
+fn take_u64(rest: &mut &[u8]) -> Result<u64, String> {
+    let bytes = take(rest, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().expect("took 8 bytes")))
+}
+
+// A length followed by that many bytes
+fn take_field<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = take_u64(rest)?;
+    take(rest, len)
+}
+
+#[test]
+fn test_persistence() {
+    let dir = std::env::temp_dir().join(format!("hteapot-cache-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cache.bin");
+
+    let mut cache = Cache::new(60);
+    cache.set("/index.html".to_string(), b"<h1>hi</h1>".to_vec());
+    cache.set("/empty".to_string(), Vec::new());
+    cache.set_max_ttl(0);
+    cache.set("/expired".to_string(), b"old".to_vec());
+    let replay = format!("{}ip:127.0.0.1 a POST /orders", IDEMPOTENCY_PREFIX);
+    cache.set_with_ttl(replay.clone(), b"order".to_vec(), Duration::from_secs(60));
+    assert_eq!(cache.save(&path).unwrap(), 2);
+
+    let mut restored = Cache::new(60);
+    assert_eq!(restored.load(&path), Ok(2));
+    assert_eq!(
+        *restored.get("/index.html".to_string()).unwrap(),
+        b"<h1>hi</h1>"
+    );
+    assert!(restored.get("/empty".to_string()).unwrap().is_empty());
+    assert!(restored.get("/expired".to_string()).is_none());
+    assert!(restored.get(replay).is_none());
+
+    // Any damage throws the whole file away
+    let bytes = fs::read(&path).unwrap();
+    for len in [3, PERSIST_MAGIC.len() + 5, bytes.len() - 1] {
+        fs::write(&path, &bytes[..len]).unwrap();
+        assert!(Cache::new(60).load(&path).is_err(), "{}", len);
+    }
+    let mut other_version = bytes.clone();
+    other_version[PERSIST_MAGIC.len() - 1] = 2;
+    fs::write(&path, &other_version).unwrap();
+    assert!(Cache::new(60).load(&path).is_err());
+    assert!(Cache::new(60).load(&dir.join("missing")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_bytes() {
+    let mut cache = Cache::new(60);
+    cache.set("/a".to_string(), vec![0; 100]);
+    cache.set_with_ttl("/b".to_string(), vec![0; 10], Duration::from_secs(60));
+    assert_eq!(cache.bytes(), 2 + 100 + 2 + 10);
+    // Replacing an entry counts only the new data
+    cache.set("/a".to_string(), vec![0; 50]);
+    assert_eq!(cache.bytes(), 2 + 50 + 2 + 10);
+    // Expired entries are given back once a get drops them
+    cache.set_max_ttl(0);
+    cache.set("/a".to_string(), vec![0; 50]);
+    assert!(cache.get("/a".to_string()).is_none());
+    assert_eq!(cache.bytes(), 2 + 10);
+    cache.clear();
+    assert_eq!(cache.bytes(), 0);
+}
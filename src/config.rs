@@ -2,7 +2,18 @@
 // This is the config module, it will load the configuration
 // file and provide the settings
 
-use std::{any::Any, collections::HashMap, fs};
+use std::{any::Any, collections::HashMap, fmt, fs, path::Path, sync::Arc};
+
+use brew::UNIX_PREFIX;
+use hteapot::{
+    unbracket, AuthBackend, Chaos, ConnectionOverflow, Htpasswd, HttpStatus, ParsingRules,
+    TokenList,
+};
+use logger::Level;
+use noise::{NoisePolicy, NoiseResponse};
+use proxy::{ProxyRule, Sticky};
+use std::net::IpAddr;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub enum TOMLtype {
@@ -10,6 +21,7 @@ pub enum TOMLtype {
     Number(u16),
     Float(f64),
     Boolean(bool),
+    Table(HashMap<String, TOMLtype>),
 }
 
 type TOMLSchema = HashMap<String, TOMLtype>;
@@ -26,6 +38,7 @@ impl Schema for TOMLSchema {
             TOMLtype::Number(d) => Box::new(d),
             TOMLtype::Float(d) => Box::new(d),
             TOMLtype::Boolean(d) => Box::new(d),
+            TOMLtype::Table(d) => Box::new(d),
         };
         let r = any_value.downcast_ref::<T>().cloned();
         if r.is_none() {
@@ -35,6 +48,100 @@ impl Schema for TOMLSchema {
     }
 }
 
+// Position of the first `pattern` that is not inside a quoted string
+fn find_unquoted(text: &str, pattern: char) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == pattern => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+// Parse an inline table like { body = "hi", status = 200 }
+fn parse_inline_table(value: &str) -> TOMLSchema {
+    let mut table = HashMap::new();
+    let mut rest = value.trim_start_matches('{').trim_end_matches('}').trim();
+    while !rest.is_empty() {
+        let (item, next) = match find_unquoted(rest, ',') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        rest = next.trim();
+        let split = find_unquoted(item, '=');
+        if split.is_none() {
+            continue;
+        }
+        let (key, value) = item.split_at(split.unwrap());
+        let key = key.trim().trim_matches('"');
+        if key.is_empty() {
+            continue;
+        }
+        table.insert(key.to_string(), parse_value(value[1..].trim()));
+    }
+    table
+}
+
+fn parse_value(value: &str) -> TOMLtype {
+    if value.starts_with('{') && value.ends_with('}') {
+        TOMLtype::Table(parse_inline_table(value))
+    } else if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        TOMLtype::Text(unescape(&value[1..value.len() - 1]))
+    } else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        TOMLtype::Text(value[1..value.len() - 1].to_string())
+    } else if value.contains('\'') || value.contains('"') {
+        let value = value.trim_matches('"').trim();
+        TOMLtype::Text(value.to_string())
+    } else if value.to_lowercase() == "true" || value.to_lowercase() == "false" {
+        let value = value.to_lowercase() == "true";
+        TOMLtype::Boolean(value)
+    } else if value.contains('.') {
+        let value = value.parse::<f64>();
+        if value.is_err() {
+            panic!("Error parsing toml");
+        }
+        TOMLtype::Float(value.unwrap())
+    } else {
+        let value = value.parse::<u16>();
+        if value.is_err() {
+            panic!("Error parsing toml");
+        }
+        TOMLtype::Number(value.unwrap())
+    }
+}
+
 pub fn toml_parser(content: &str) -> HashMap<String, TOMLSchema> {
     let mut map = HashMap::new();
     let mut submap = HashMap::new();
@@ -44,130 +151,1252 @@ pub fn toml_parser(content: &str) -> HashMap<String, TOMLSchema> {
         if line.starts_with("#") || line.is_empty() {
             continue;
         }
-        let line = if line.contains('#') {
-            let parts = line.split("#").collect::<Vec<&str>>();
-            parts[0].trim()
-        } else {
-            line.trim()
+        let line = match find_unquoted(line, '#') {
+            Some(i) => line[..i].trim(),
+            None => line.trim(),
         };
         if line.starts_with("[") && line.ends_with("]") {
             let key = line.trim_matches('[').trim_matches(']').trim();
-            if submap.len() != 0 && title.len() != 0 {
+            if !submap.is_empty() && !title.is_empty() {
                 map.insert(title.clone(), submap.clone());
             }
             title = key.to_string();
             submap = HashMap::new();
             continue;
         }
-        let parts = line.split("=").collect::<Vec<&str>>();
-        if parts.len() != 2 {
+        let split = find_unquoted(line, '=');
+        if split.is_none() {
             continue;
         }
-        let key = parts[0]
-            .trim()
-            .trim_end_matches('"')
-            .trim_start_matches('"');
+        let (key, value) = line.split_at(split.unwrap());
+        let key = key.trim().trim_end_matches('"').trim_start_matches('"');
         if key.is_empty() {
             continue;
         }
-        let value = parts[1].trim();
-        let value = if value.contains('\'') || value.contains('"') {
-            let value = value.trim_matches('"').trim();
-            TOMLtype::Text(value.to_string())
-        } else if value.to_lowercase() == "true" || value.to_lowercase() == "false" {
-            let value = value.to_lowercase() == "true";
-            TOMLtype::Boolean(value)
-        } else if value.contains('.') {
-            let value = value.parse::<f64>();
-            if value.is_err() {
-                panic!("Error parsing toml");
-            }
-            TOMLtype::Float(value.unwrap())
-        } else {
-            let value = value.parse::<u16>();
-            if value.is_err() {
-                panic!("Error parsing toml");
-            }
-            TOMLtype::Number(value.unwrap())
-        };
+        let value = parse_value(value[1..].trim());
         submap.insert(key.to_string(), value);
     }
     map.insert(title, submap.clone());
     map
 }
 
+// Extensions are matched without the dot and case insensitive
+fn normalize_extension(extension: &str) -> String {
+    extension.trim_start_matches('.').to_lowercase()
+}
+
+// Byte count with an optional K, M or G suffix (powers of 1024), like "512K"
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&size[..i], 1 << 10),
+        (i, 'M') | (i, 'm') => (&size[..i], 1 << 20),
+        (i, 'G') | (i, 'g') => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(unit)
+}
+
+// Host names are matched without case, browsers resolve any *.localhost to loopback
+fn dev_host(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    if host.contains('.') {
+        host
+    } else {
+        format!("{}.localhost", host)
+    }
+}
+
+// Guess the type of a payload from its first characters
+fn infer_content_type(body: &str) -> &'static str {
+    let start = body.trim_start().to_lowercase();
+    if start.starts_with("<?xml") {
+        "text/xml"
+    } else if start.starts_with('<') {
+        "text/html"
+    } else if start.starts_with('{') || start.starts_with('[') {
+        "application/json"
+    } else {
+        "text/plain"
+    }
+}
+
+// Inline body served for an exact path, configured in [responses]
+#[derive(Clone, Debug)]
+pub struct StaticResponse {
+    pub body: String,
+    pub status: u16,
+    pub content_type: String,
+}
+
+// Credentials asked for under a path prefix, configured in [auth]
+pub struct AuthRule {
+    pub realm: String,
+    pub backend: Arc<dyn AuthBackend>,
+}
+
+impl fmt::Debug for AuthRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthRule")
+            .field("realm", &self.realm)
+            .field("scheme", &self.backend.scheme())
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     pub port: u16,    // Port number to listen
-    pub host: String, // Host name or IP
+    pub host: String, // Host name or IP, several separated by commas like "127.0.0.1,[::1]"
     pub root: String, // Root directory to serve files
     pub cache: bool,
     pub cache_ttl: u16,
+    pub cache_ignore_query: bool, // Cache keys without the query, for content that never depends on it
+    pub cache_ignore_params: Vec<String>, // Query parameters left out of cache keys, like utm_source
+    pub cache_file: Option<String>, // Cache saved here on a graceful stop and loaded at startup
     pub threads: u16,
-    pub index: String, // Index file to serve by default
+    pub index: String,                // Index file to serve by default
+    pub keep_alive: bool,             // Off closes every connection after its response
+    pub connection_max_lifetime: u16, // Seconds before closing a keep-alive connection, 0 disables
+    pub keep_alive_ttl: u16, // Seconds a kept connection may wait for its next request, 0 disables
+    pub header_read_timeout: u16, // Seconds to send a whole request head, else a 408, 0 disables
+    pub keep_alive_max_requests: u16, // Requests per connection before it is closed, 0 disables
+    pub shutdown_grace_secs: u16, // Seconds a graceful stop waits for requests in flight
+    pub accept_queue_limit: u16, // Connections waiting for a worker before new ones get a 503, 0 disables
+    pub max_connections: u16,    // Open connections at once, queued ones included, 0 disables
+    pub max_connections_overflow: ConnectionOverflow, // From "reject", the default, or "backlog"
+    pub max_connections_per_ip: u16, // Open connections from one client address, 0 disables
+    pub max_connections_per_ip_exempt: Vec<IpAddr>, // Addresses not limited, like trusted proxies
+    pub queue_wait_warning_ms: u16, // Wait for a worker past which a warning is logged
+    pub queue_depth_warning: u16, // Connections waiting for a worker past which a warning is logged
+    pub worker_restart_budget: u16, // Dead workers started again per hour, one more stops the server
+    pub stream_workers: u16, // Threads running streamed (hijacked) responses, 0 starts one per response
+    pub stream_queue: u16,   // Streamed responses waiting for a worker before the rest get a 503
+    pub log_handshake_failures: bool, // Warn about TLS clients reaching this plain HTTP port
+    pub proxy_protocol: bool, // Every connection starts with a PROXY header from a load balancer
+    pub parsing: ParsingRules, // From "strict", the default refusing every deviation, or "lenient"
+    pub max_request_head_bytes: u16, // Request line and headers together, bigger heads get a 431
+    pub max_request_line_bytes: u16, // Request target (else 414) and each header line (else 431)
+    pub max_header_count: u16,
+    pub allow_methods_extra: Vec<String>, // Methods outside the standard ones let through, others get a 501
+    pub max_body_size: u64, // Bytes of request body, bigger bodies get a 413, 0 disables
+    pub max_bandwidth: Option<u64>, // Bytes per second written across all connections
+    pub max_bandwidth_per_conn: Option<u64>, // Bytes per second written to each connection
+    // Bytes held by the cache, by requests being read and by responses being written,
+    // past which a summary is logged
+    pub memory_warning_cache: Option<u64>,
+    pub memory_warning_read: Option<u64>,
+    pub memory_warning_response: Option<u64>,
+    pub acme_challenge_dir: Option<String>, // Directory with ACME HTTP-01 challenge files
+    pub redirect_to_https: bool,            // Redirect every request to https
+    pub https_port: u16,                    // Port used in the https redirects
+    pub canonical_host: Option<String>,     // Host name every other Host is redirected to
+    pub cors_origin: Option<String>,        // Origin allowed to make cross origin requests, or "*"
+    pub server_timing: bool, // Send a Server-Timing header with the time spent per phase
+    pub timing_allow_origin: Option<String>, // Origins also shown Server-Timing, or "*"
+    pub version_path: Option<String>, // Path answering with build and version info, off by default
+    pub route_test_path: Option<String>, // Path explaining how a described request would be routed
+    pub metrics_path: Option<String>, // Path answering with upstream stats in Prometheus format
+    pub upstream_stats_interval: u16, // Minutes between upstream stats lines in the log, 0 disables
+    pub admin_port: Option<u16>, // Separate listener for admin endpoints, they 404 on port when set
+    pub admin_host: String,  // Address of the admin listener, local only by default
+    pub admin_api_path: Option<String>, // Prefix of the runtime controls on the admin listener
+    pub reload_verify_upstreams: bool, // Reloads also resolve and connect to every upstream first
+    pub alert_webhook_url: Option<String>, // Errors and fatal events are posted here as JSON
+    pub path: Option<String>, // File the config was read from, read again on reload
+    pub overrides: Overrides, // Environment and command line options over the file
+    pub log_level: Level, // Most detailed messages logged, changed at runtime through the admin api
+    pub log_request_body: u16, // Bytes of textual request bodies logged for debugging, 0 disables
+    pub log_request_body_exclude: Vec<String>, // Path prefixes whose bodies are never logged
+    pub noise: NoisePolicy, // Scanner paths answered before routing, from noise_paths
+    pub record_dir: Option<String>, // Whole requests and responses written here, for --replay
+    pub record_sample_rate: f64, // Share of requests recorded, from 0 to 1
+    pub record_paths: Vec<String>, // Path prefixes recorded, every path when empty
+    pub record_redact_headers: Vec<String>, // Headers recorded without their value
+    pub inject_html_before_end: Option<String>, // Snippet added before </body> in html responses
     //pub error: String, // Error file to serve when a file is not found
-    pub proxy_rules: HashMap<String, String>,
+    pub proxy_rules: HashMap<String, ProxyRule>,
+    pub dev_routes: HashMap<String, ProxyRule>, // Local port per Host, from [dev_router]
+    pub responses: HashMap<String, StaticResponse>,
+    pub auth: HashMap<String, AuthRule>, // Credentials per path prefix, from [auth]
+    pub default_charset: Option<String>, // Charset added to text/* types without one
+    pub mime_types: HashMap<String, String>, // Content type per file extension, from [mime]
+    pub mime_sniffing: bool, // Guess the type from the content when the extension is unknown
+    pub emit_digest: bool,   // Repr-Digest and Digest headers with the SHA-256 of files
+    pub etag_from_content: bool, // ETag of files from their SHA-256, 304 when it matches
+    pub serve_files: bool,   // Serve files from root, off when only inline responses are wanted
+    pub access_log: Option<String>, // Access log file, {host} is replaced by the request host
+    pub access_logs: HashMap<String, String>, // Access log file per path prefix, from [access_log]
+    pub preloads: HashMap<String, String>, // Link sent as 103 Early Hints per path or "prefix*", from [preload]
+    pub chaos: Option<Chaos>,              // Fault injection, only with chaos.enabled = true
 }
 
-impl Config {
-    // pub fn new(port: u16, host: String, root: String, index: String, error: String) -> Config {
-    //     Config {
-    //       port: port,
-    //       host: host,
-    //       root: root,
-    //       index: index,
-    //       error: error,
-    //       proxy_rules: HashMap::new()
-    //     }
-    //   }
+// Options that can be given over the config file, as HTEAPOT_PORT=9000 or --port 9000
+const OVERRIDABLE: [&str; 4] = ["port", "host", "root", "threads"];
 
-    pub fn new_default() -> Config {
-        Config {
-            port: 8080,
-            host: "localhost".to_string(),
-            root: "./".to_string(),
-            index: "index.html".to_string(),
-            //error: "error.html".to_string(),
-            threads: 1,
-            cache: false,
-            cache_ttl: 0,
-            proxy_rules: HashMap::new(),
-        }
-    }
-
-    pub fn load_config(path: &str) -> Config {
-        let content = fs::read_to_string(path);
-        if content.is_err() {
-            return Config::new_default();
-        }
-        let content = content.unwrap();
-        let map = toml_parser(&content);
-        let mut proxy_rules: HashMap<String, String> = HashMap::new();
-        let proxy_map = map.get("proxy");
-        if proxy_map.is_some() {
-            let proxy_map = proxy_map.unwrap();
-            for k in proxy_map.keys() {
-                let url = proxy_map.get2(k);
-                if url.is_none() {
-                    println!();
-                    continue;
+// Options from the environment and the command line, applied again on every reload
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub env: Vec<(String, String)>,
+    pub args: Vec<(String, String)>,
+}
+
+impl Overrides {
+    // HTEAPOT_* variables of vars, and the flags of args which are taken out of them
+    pub fn new<I: Iterator<Item = (String, String)>>(
+        vars: I,
+        args: &mut Vec<String>,
+    ) -> Result<Overrides, String> {
+        let vars: HashMap<String, String> = vars.collect();
+        let env = OVERRIDABLE
+            .iter()
+            .filter_map(|name| {
+                let var = format!("HTEAPOT_{}", name.to_uppercase());
+                vars.get(&var)
+                    .map(|value| (name.to_string(), value.clone()))
+            })
+            .collect();
+        let mut overrides = Overrides {
+            env,
+            args: Vec::new(),
+        };
+        while let Some(i) = args
+            .iter()
+            .position(|a| OVERRIDABLE.iter().any(|name| *a == format!("--{}", name)))
+        {
+            if i + 1 >= args.len() {
+                return Err(format!("{} needs a value", args[i]));
+            }
+            let value = args.remove(i + 1);
+            let name = args.remove(i).trim_start_matches('-').to_string();
+            overrides.args.push((name, value));
+        }
+        Ok(overrides)
+    }
+
+    // The file (or defaults), then the environment, then the command line
+    pub fn apply(&self, file: ConfigBuilder) -> Result<ConfigBuilder, String> {
+        let mut builder = file
+            .merge(ConfigBuilder::from_options(&self.env)?)
+            .merge(ConfigBuilder::from_options(&self.args)?);
+        builder.overrides = self.clone();
+        Ok(builder)
+    }
+}
+
+// Generates ConfigBuilder with an optional value and a setter per option
+macro_rules! config_builder {
+    ($($field:ident: $type:ty),* $(,)*) => {
+        // Fluent construction of a Config, unset options keep their defaults
+        #[derive(Default)]
+        pub struct ConfigBuilder {
+            $($field: Option<$type>,)*
+            proxy_rules: HashMap<String, ProxyRule>,
+            dev_routes: HashMap<String, ProxyRule>,
+            responses: HashMap<String, StaticResponse>,
+            auth: HashMap<String, AuthRule>,
+            mime_types: HashMap<String, String>,
+            access_logs: HashMap<String, String>,
+            preloads: HashMap<String, String>,
+            overrides: Overrides,
+        }
+
+        // Not every setter is used by the binary itself
+        #[allow(dead_code)]
+        impl ConfigBuilder {
+            $(
+                pub fn $field(mut self, $field: $type) -> Self {
+                    self.$field = Some($field);
+                    self
+                }
+            )*
+
+            // Options set in `other` win, tables like proxy rules and responses are combined
+            pub fn merge(mut self, other: ConfigBuilder) -> Self {
+                $(
+                    if other.$field.is_some() {
+                        self.$field = other.$field;
+                    }
+                )*
+                self.proxy_rules.extend(other.proxy_rules);
+                self.dev_routes.extend(other.dev_routes);
+                self.responses.extend(other.responses);
+                self.auth.extend(other.auth);
+                self.mime_types.extend(other.mime_types);
+                self.access_logs.extend(other.access_logs);
+                self.preloads.extend(other.preloads);
+                self
+            }
+        }
+    };
+}
+
+config_builder! {
+    port: u16,
+    host: String,
+    root: String,
+    cache: bool,
+    cache_ttl: u16,
+    cache_file: String,
+    cache_ignore_query: bool,
+    cache_ignore_params: String,
+    threads: u16,
+    index: String,
+    keep_alive: bool,
+    connection_max_lifetime: u16,
+    keep_alive_ttl: u16,
+    keep_alive_max_requests: u16,
+    shutdown_grace_secs: u16,
+    header_read_timeout: u16,
+    accept_queue_limit: u16,
+    max_connections: u16,
+    max_connections_overflow: String,
+    max_connections_per_ip: u16,
+    max_connections_per_ip_exempt: String,
+    queue_wait_warning_ms: u16,
+    queue_depth_warning: u16,
+    worker_restart_budget: u16,
+    stream_workers: u16,
+    stream_queue: u16,
+    log_handshake_failures: bool,
+    proxy_protocol: bool,
+    parsing: String,
+    max_request_head_bytes: u16,
+    max_request_line_bytes: u16,
+    max_header_count: u16,
+    allow_methods_extra: String,
+    max_body_size: u64,
+    max_bandwidth: u64,
+    max_bandwidth_per_conn: u64,
+    memory_warning_cache: u64,
+    memory_warning_read: u64,
+    memory_warning_response: u64,
+    acme_challenge_dir: String,
+    redirect_to_https: bool,
+    https_port: u16,
+    canonical_host: String,
+    cors_origin: String,
+    server_timing: bool,
+    timing_allow_origin: String,
+    version_path: String,
+    route_test_path: String,
+    metrics_path: String,
+    upstream_stats_interval: u16,
+    admin_port: u16,
+    admin_host: String,
+    admin_api_path: String,
+    reload_verify_upstreams: bool,
+    alert_webhook_url: String,
+    log_level: String,
+    log_request_body: u16,
+    log_request_body_exclude: String,
+    noise_paths: String,
+    noise_response: String,
+    record_dir: String,
+    record_sample_rate: f64,
+    record_paths: String,
+    record_redact_headers: String,
+    inject_html_before_end: String,
+    default_charset: String,
+    mime_sniffing: bool,
+    emit_digest: bool,
+    etag_from_content: bool,
+    serve_files: bool,
+    access_log: String,
+    chaos: Chaos,
+}
+
+#[allow(dead_code)]
+impl ConfigBuilder {
+    pub fn proxy_rule(mut self, prefix: &str, rule: ProxyRule) -> Self {
+        self.proxy_rules.insert(prefix.to_string(), rule);
+        self
+    }
+
+    // Requests for host go to 127.0.0.1:port, a name without dots stands for name.localhost
+    pub fn dev_route(mut self, host: &str, port: u16) -> Self {
+        let rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+        self.dev_routes.insert(dev_host(host), rule);
+        self
+    }
+
+    // Builder with the named options set, numbers parsed like in a config file
+    pub fn from_options(options: &[(String, String)]) -> Result<ConfigBuilder, String> {
+        let mut builder = ConfigBuilder::default();
+        for (name, value) in options.iter() {
+            let number = || {
+                value
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid {} {}", name, value))
+            };
+            match name.as_str() {
+                "port" => builder.port = Some(number()?),
+                "host" => builder.host = Some(value.clone()),
+                "root" => builder.root = Some(value.clone()),
+                "threads" => builder.threads = Some(number()?),
+                _ => return Err(format!("{} can't be overridden", name)),
+            }
+        }
+        Ok(builder)
+    }
+
+    pub fn response(mut self, path: &str, response: StaticResponse) -> Self {
+        self.responses.insert(path.to_string(), response);
+        self
+    }
+
+    pub fn auth(mut self, prefix: &str, rule: AuthRule) -> Self {
+        self.auth.insert(prefix.to_string(), rule);
+        self
+    }
+
+    pub fn mime_type(mut self, extension: &str, content_type: &str) -> Self {
+        self.mime_types
+            .insert(normalize_extension(extension), content_type.to_string());
+        self
+    }
+
+    // Read the options of a parsed toml file
+    pub fn from_toml(map: &HashMap<String, TOMLSchema>) -> Result<ConfigBuilder, String> {
+        let mut builder = ConfigBuilder::default();
+        if let Some(proxy_map) = map.get("proxy") {
+            for (k, value) in proxy_map.iter() {
+                let rule = match value {
+                    TOMLtype::Text(urls) => ProxyRule::from_list(urls),
+                    TOMLtype::Table(table) => {
+                        let mut rule =
+                            ProxyRule::from_list(&table.get2::<String>("url").unwrap_or_default());
+                        rule.slow_start = Duration::from_secs(
+                            table.get2::<u16>("slow_start").unwrap_or(0) as u64,
+                        );
+                        rule.keep_alive = table.get2("keep_alive").unwrap_or(true);
+                        rule.retries = table.get2::<u16>("retries").unwrap_or(0) as u32;
+                        if let Some(statuses) = table.get2::<String>("retry_on") {
+                            rule.retry_on = statuses
+                                .split(',')
+                                .map(|s| s.trim().parse::<u16>())
+                                .collect::<Result<_, _>>()
+                                .map_err(|_| format!("Invalid retry_on {} for {}", statuses, k))?;
+                        }
+                        if let Some(percent) = table.get2::<u16>("retry_budget") {
+                            rule.retry_ratio = percent as f64 / 100.0;
+                        }
+                        rule.default_cache_control = table.get2("default_cache_control");
+                        rule.force_no_store = table.get2("force_no_store").unwrap_or(false);
+                        rule.idempotency_window = table
+                            .get2::<u16>("idempotency_window")
+                            .map(|secs| Duration::from_secs(secs as u64));
+                        rule.stale_if_error = table
+                            .get2::<u16>("stale_if_error")
+                            .map(|secs| Duration::from_secs(secs as u64));
+                        rule.fallback_file = table.get2("fallback_file");
+                        rule.rewrite_body_urls = table.get2("rewrite_body_urls").unwrap_or(false);
+                        rule.revalidate = table.get2("revalidate").unwrap_or(false);
+                        rule.sticky = match table.get2::<String>("sticky").as_deref() {
+                            Some("cookie") => Some(Sticky::Cookie),
+                            Some("ip_hash") => Some(Sticky::IpHash),
+                            Some(other) => {
+                                return Err(format!("Unknown sticky mode {} for {}", other, k))
+                            }
+                            None => None,
+                        };
+                        rule
+                    }
+                    _ => return Err(format!("Invalid proxy rule for {}", k)),
+                };
+                builder.proxy_rules.insert(k.clone(), rule);
+            }
+        }
+
+        if let Some(router_map) = map.get("dev_router") {
+            for (host, value) in router_map.iter() {
+                match value {
+                    TOMLtype::Number(port) => builder = builder.dev_route(host, *port),
+                    _ => return Err(format!("Invalid dev_router port for {}", host)),
+                }
+            }
+        }
+
+        if let Some(responses_map) = map.get("responses") {
+            for (path, value) in responses_map.iter() {
+                let response = match value {
+                    TOMLtype::Text(body) => StaticResponse {
+                        body: body.clone(),
+                        status: 200,
+                        content_type: "text/plain".to_string(),
+                    },
+                    TOMLtype::Table(table) => StaticResponse {
+                        body: table.get2("body").unwrap_or_default(),
+                        status: table.get2("status").unwrap_or(200),
+                        content_type: table.get2("type").unwrap_or("text/plain".to_string()),
+                    },
+                    _ => return Err(format!("Invalid response for {}", path)),
+                };
+                builder.responses.insert(path.clone(), response);
+            }
+        }
+
+        if let Some(auth_map) = map.get("auth") {
+            for (prefix, value) in auth_map.iter() {
+                let table = match value {
+                    TOMLtype::Table(table) => table,
+                    _ => return Err(format!("Invalid auth for {}", prefix)),
+                };
+                let file = table.get2::<String>("file");
+                let tokens = table.get2::<String>("tokens");
+                let backend: Arc<dyn AuthBackend> = match (file, tokens) {
+                    (Some(file), None) => Arc::new(Htpasswd::open(&file).map_err(|e| {
+                        format!("Error reading auth file {} for {}: {}", file, prefix, e)
+                    })?),
+                    (None, Some(tokens)) => {
+                        let tokens: Vec<String> = tokens
+                            .split(',')
+                            .map(|t| t.trim().to_string())
+                            .filter(|t| !t.is_empty())
+                            .collect();
+                        if tokens.is_empty() {
+                            return Err(format!("Auth tokens for {} are empty", prefix));
+                        }
+                        Arc::new(TokenList::new(tokens))
+                    }
+                    _ => return Err(format!("Auth for {} needs either file or tokens", prefix)),
+                };
+                let realm = table.get2("realm").unwrap_or("hteapot".to_string());
+                builder
+                    .auth
+                    .insert(prefix.clone(), AuthRule { realm, backend });
+            }
+        }
+
+        if let Some(mime_map) = map.get("mime") {
+            for (extension, value) in mime_map.iter() {
+                match value {
+                    TOMLtype::Text(content_type) => {
+                        builder
+                            .mime_types
+                            .insert(normalize_extension(extension), content_type.clone());
+                    }
+                    _ => return Err(format!("Invalid mime type for {}", extension)),
                 }
-                let url = url.unwrap();
-                proxy_rules.insert(k.clone(), url);
             }
         }
 
-        let map = map.get("HTEAPOT").unwrap();
-        Config {
-            port: map.get2("port").unwrap_or(8080),
-            host: map.get2("host").unwrap_or("".to_string()),
-            root: map.get2("root").unwrap_or("./".to_string()),
-            threads: map.get2("threads").unwrap_or(1),
-            cache: map.get2("cache").unwrap_or(false),
-            cache_ttl: map.get2("cache_ttl").unwrap_or(3600),
-            index: map.get2("index").unwrap_or("index.html".to_string()),
-            //error: map.get2("error").unwrap_or("error.html".to_string()),
-            proxy_rules,
+        if let Some(access_map) = map.get("access_log") {
+            for (prefix, value) in access_map.iter() {
+                match value {
+                    TOMLtype::Text(destination) => {
+                        builder
+                            .access_logs
+                            .insert(prefix.clone(), destination.clone());
+                    }
+                    _ => return Err(format!("Invalid access log for {}", prefix)),
+                }
+            }
+        }
+
+        if let Some(preload_map) = map.get("preload") {
+            for (pattern, value) in preload_map.iter() {
+                match value {
+                    TOMLtype::Text(link) => {
+                        builder.preloads.insert(pattern.clone(), link.clone());
+                    }
+                    _ => return Err(format!("Invalid preload for {}", pattern)),
+                }
+            }
+        }
+
+        if let Some(chaos_map) = map.get("chaos") {
+            if chaos_map.get2("enabled").unwrap_or(false) {
+                let delay_min: u16 = chaos_map.get2("delay_min_ms").unwrap_or(0);
+                let delay_max: u16 = chaos_map.get2("delay_max_ms").unwrap_or(0);
+                builder.chaos = Some(Chaos {
+                    seed: chaos_map.get2::<u16>("seed").unwrap_or(0) as u64,
+                    delay: if delay_max > 0 {
+                        Some((
+                            Duration::from_millis(delay_min as u64),
+                            Duration::from_millis(delay_max as u64),
+                        ))
+                    } else {
+                        None
+                    },
+                    reset_percent: chaos_map.get2::<u16>("reset_percent").unwrap_or(0) as u8,
+                    truncate_percent: chaos_map.get2::<u16>("truncate_percent").unwrap_or(0) as u8,
+                    error_percent: chaos_map.get2::<u16>("error_percent").unwrap_or(0) as u8,
+                    drip_bytes_per_sec: chaos_map.get2::<u16>("drip_bytes_per_sec").unwrap_or(0)
+                        as usize,
+                });
+            }
+        }
+
+        if let Some(map) = map.get("HTEAPOT") {
+            builder.port = map.get2("port");
+            // Config files have always defaulted to these, unlike runs without one
+            builder.host = Some(map.get2("host").unwrap_or_default());
+            builder.root = map.get2("root");
+            builder.threads = map.get2("threads");
+            builder.cache = map.get2("cache");
+            builder.cache_ttl = Some(map.get2("cache_ttl").unwrap_or(3600));
+            builder.cache_file = map.get2("cache_file");
+            builder.cache_ignore_query = map.get2("cache_ignore_query");
+            builder.cache_ignore_params = map.get2("cache_ignore_params");
+            builder.index = map.get2("index");
+            builder.connection_max_lifetime = map.get2("connection_max_lifetime");
+            builder.keep_alive_ttl = map.get2("keep_alive_ttl");
+            builder.keep_alive_max_requests = map.get2("keep_alive_max_requests");
+            builder.shutdown_grace_secs = map.get2("shutdown_grace_secs");
+            builder.header_read_timeout = map.get2("header_read_timeout");
+            builder.keep_alive = map.get2("keep_alive");
+            builder.accept_queue_limit = map.get2("accept_queue_limit");
+            builder.max_connections = map.get2("max_connections");
+            builder.max_connections_overflow = map.get2("max_connections_overflow");
+            builder.max_connections_per_ip = map.get2("max_connections_per_ip");
+            builder.max_connections_per_ip_exempt = map.get2("max_connections_per_ip_exempt");
+            builder.queue_wait_warning_ms = map.get2("queue_wait_warning_ms");
+            builder.queue_depth_warning = map.get2("queue_depth_warning");
+            builder.worker_restart_budget = map.get2("worker_restart_budget");
+            builder.stream_workers = map.get2("stream_workers");
+            builder.stream_queue = map.get2("stream_queue");
+            builder.log_handshake_failures = map.get2("log_handshake_failures");
+            builder.proxy_protocol = map.get2("proxy_protocol");
+            builder.parsing = map.get2("parsing");
+            builder.max_request_head_bytes = map.get2("max_request_head_bytes");
+            builder.max_request_line_bytes = map.get2("max_request_line_bytes");
+            builder.max_header_count = map.get2("max_header_count");
+            builder.allow_methods_extra = map.get2("allow_methods_extra");
+            // Numbers in the toml stop at 65535, so rates and sizes are strings like "512K" or "10M"
+            for (key, field) in [
+                ("max_body_size", &mut builder.max_body_size),
+                ("max_bandwidth", &mut builder.max_bandwidth),
+                (
+                    "max_bandwidth_per_conn",
+                    &mut builder.max_bandwidth_per_conn,
+                ),
+                ("memory_warning_cache", &mut builder.memory_warning_cache),
+                ("memory_warning_read", &mut builder.memory_warning_read),
+                (
+                    "memory_warning_response",
+                    &mut builder.memory_warning_response,
+                ),
+            ] {
+                if let Some(rate) = map.get2::<String>(key) {
+                    *field = Some(parse_size(&rate).ok_or(format!("Invalid {} {}", key, rate))?);
+                }
+            }
+            builder.acme_challenge_dir = map.get2("acme_challenge_dir");
+            builder.redirect_to_https = map.get2("redirect_to_https");
+            builder.https_port = map.get2("https_port");
+            builder.canonical_host = map.get2("canonical_host");
+            builder.cors_origin = map.get2("cors_origin");
+            builder.server_timing = map.get2("server_timing");
+            builder.timing_allow_origin = map.get2("timing_allow_origin");
+            builder.version_path = map.get2("version_path");
+            builder.route_test_path = map.get2("route_test_path");
+            builder.metrics_path = map.get2("metrics_path");
+            builder.upstream_stats_interval = map.get2("upstream_stats_interval");
+            builder.admin_port = map.get2("admin_port");
+            builder.admin_host = map.get2("admin_host");
+            builder.admin_api_path = map.get2("admin_api_path");
+            builder.reload_verify_upstreams = map.get2("reload_verify_upstreams");
+            builder.alert_webhook_url = map.get2("alert_webhook_url");
+            builder.log_level = map.get2("log_level");
+            builder.inject_html_before_end = map.get2("inject_html_before_end");
+            builder.log_request_body = map.get2("log_request_body");
+            builder.log_request_body_exclude = map.get2("log_request_body_exclude");
+            builder.noise_paths = map.get2("noise_paths");
+            builder.noise_response = map.get2("noise_response");
+            builder.record_dir = map.get2("record_dir");
+            // 1 is read as a whole number, not as a float
+            builder.record_sample_rate = match map.get("record_sample_rate") {
+                Some(TOMLtype::Float(rate)) => Some(*rate),
+                Some(TOMLtype::Number(rate)) => Some(*rate as f64),
+                Some(_) => return Err("record_sample_rate is a number from 0 to 1".to_string()),
+                None => None,
+            };
+            builder.record_paths = map.get2("record_paths");
+            builder.record_redact_headers = map.get2("record_redact_headers");
+            builder.default_charset = map.get2("default_charset");
+            builder.mime_sniffing = map.get2("mime_sniffing");
+            builder.emit_digest = map.get2("emit_digest");
+            builder.etag_from_content = map.get2("etag_from_content");
+            builder.access_log = map.get2("access_log");
+        }
+        Ok(builder)
+    }
+
+    pub fn build(self) -> Result<Config, String> {
+        let log_level = match &self.log_level {
+            Some(name) => Level::parse(name).ok_or(format!("Invalid log_level {}", name))?,
+            None => Level::Info,
+        };
+        let parsing = match self.parsing.as_deref() {
+            None | Some("strict") => ParsingRules::strict(),
+            Some("lenient") => ParsingRules::lenient(),
+            Some(other) => {
+                return Err(format!(
+                    "Invalid parsing {}, expected strict or lenient",
+                    other
+                ))
+            }
+        };
+        let max_connections_overflow = match self.max_connections_overflow.as_deref() {
+            None | Some("reject") => ConnectionOverflow::Reject,
+            Some("backlog") => ConnectionOverflow::Backlog,
+            Some(other) => {
+                return Err(format!(
+                    "Invalid max_connections_overflow {}, expected reject or backlog",
+                    other
+                ))
+            }
+        };
+        let mut max_connections_per_ip_exempt = Vec::new();
+        for address in self
+            .max_connections_per_ip_exempt
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+        {
+            match unbracket(address).parse::<IpAddr>() {
+                Ok(ip) => max_connections_per_ip_exempt.push(ip),
+                Err(_) => {
+                    return Err(format!(
+                        "Invalid max_connections_per_ip_exempt address {}",
+                        address
+                    ))
+                }
+            }
+        }
+        let noise_response = match self.noise_response.as_deref() {
+            None | Some("close") => NoiseResponse::Close,
+            Some("404") => NoiseResponse::NotFound,
+            Some(other) => {
+                return Err(format!(
+                    "Invalid noise_response {}, expected close or 404",
+                    other
+                ))
+            }
+        };
+        let noise_paths: Vec<String> = self
+            .noise_paths
+            .unwrap_or_default()
+            .split(',')
+            .map(|pattern| pattern.trim().to_string())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+        let config = Config {
+            port: self.port.unwrap_or(8080),
+            host: self.host.unwrap_or("localhost".to_string()),
+            root: self.root.unwrap_or("./".to_string()),
+            index: normalize_index(&self.index.unwrap_or("index.html".to_string()))?,
+            //error: "error.html".to_string(),
+            threads: self.threads.unwrap_or(1),
+            cache: self.cache.unwrap_or(false),
+            cache_ttl: self.cache_ttl.unwrap_or(0),
+            cache_file: self.cache_file,
+            cache_ignore_query: self.cache_ignore_query.unwrap_or(false),
+            cache_ignore_params: self
+                .cache_ignore_params
+                .unwrap_or_default()
+                .split(',')
+                .map(|param| param.trim().to_string())
+                .filter(|param| !param.is_empty())
+                .collect(),
+            keep_alive: self.keep_alive.unwrap_or(true),
+            connection_max_lifetime: self.connection_max_lifetime.unwrap_or(0),
+            keep_alive_ttl: self.keep_alive_ttl.unwrap_or(10),
+            keep_alive_max_requests: self.keep_alive_max_requests.unwrap_or(0),
+            shutdown_grace_secs: self.shutdown_grace_secs.unwrap_or(3),
+            header_read_timeout: self.header_read_timeout.unwrap_or(30),
+            accept_queue_limit: self.accept_queue_limit.unwrap_or(0),
+            max_connections: self.max_connections.unwrap_or(0),
+            max_connections_overflow,
+            max_connections_per_ip: self.max_connections_per_ip.unwrap_or(0),
+            max_connections_per_ip_exempt,
+            queue_wait_warning_ms: self.queue_wait_warning_ms.unwrap_or(250),
+            queue_depth_warning: self.queue_depth_warning.unwrap_or(256),
+            worker_restart_budget: self.worker_restart_budget.unwrap_or(20),
+            stream_workers: self.stream_workers.unwrap_or(0),
+            stream_queue: self.stream_queue.unwrap_or(64),
+            log_handshake_failures: self.log_handshake_failures.unwrap_or(true),
+            proxy_protocol: self.proxy_protocol.unwrap_or(false),
+            parsing,
+            max_request_head_bytes: self.max_request_head_bytes.unwrap_or(16 * 1024),
+            max_request_line_bytes: self.max_request_line_bytes.unwrap_or(8 * 1024),
+            max_header_count: self.max_header_count.unwrap_or(100),
+            allow_methods_extra: self
+                .allow_methods_extra
+                .unwrap_or_default()
+                .split(',')
+                .map(|method| method.trim().to_string())
+                .filter(|method| !method.is_empty())
+                .collect(),
+            max_body_size: self.max_body_size.unwrap_or(10 << 20),
+            max_bandwidth: self.max_bandwidth,
+            max_bandwidth_per_conn: self.max_bandwidth_per_conn,
+            memory_warning_cache: self.memory_warning_cache,
+            memory_warning_read: self.memory_warning_read,
+            memory_warning_response: self.memory_warning_response,
+            acme_challenge_dir: self.acme_challenge_dir,
+            redirect_to_https: self.redirect_to_https.unwrap_or(false),
+            https_port: self.https_port.unwrap_or(443),
+            // Host names are case insensitive, requests are compared against lowercase
+            canonical_host: self.canonical_host.map(|h| h.to_lowercase()),
+            cors_origin: self.cors_origin,
+            server_timing: self.server_timing.unwrap_or(false),
+            timing_allow_origin: self.timing_allow_origin,
+            version_path: self.version_path,
+            route_test_path: self.route_test_path,
+            metrics_path: self.metrics_path,
+            upstream_stats_interval: self.upstream_stats_interval.unwrap_or(0),
+            admin_port: self.admin_port,
+            admin_host: self.admin_host.unwrap_or("127.0.0.1".to_string()),
+            admin_api_path: self.admin_api_path,
+            reload_verify_upstreams: self.reload_verify_upstreams.unwrap_or(false),
+            alert_webhook_url: self.alert_webhook_url,
+            path: None,
+            overrides: self.overrides,
+            log_level,
+            inject_html_before_end: self.inject_html_before_end,
+            log_request_body: self.log_request_body.unwrap_or(0),
+            log_request_body_exclude: self
+                .log_request_body_exclude
+                .unwrap_or_default()
+                .split(',')
+                .map(|prefix| prefix.trim().to_string())
+                .filter(|prefix| !prefix.is_empty())
+                .collect(),
+            noise: NoisePolicy::new(&noise_paths, noise_response),
+            record_dir: self.record_dir,
+            record_sample_rate: self.record_sample_rate.unwrap_or(1.0),
+            record_paths: self
+                .record_paths
+                .unwrap_or_default()
+                .split(',')
+                .map(|prefix| prefix.trim().to_string())
+                .filter(|prefix| !prefix.is_empty())
+                .collect(),
+            record_redact_headers: self
+                .record_redact_headers
+                .unwrap_or("Authorization,Proxy-Authorization,Cookie,Set-Cookie".to_string())
+                .split(',')
+                .map(|header| header.trim().to_string())
+                .filter(|header| !header.is_empty())
+                .collect(),
+            proxy_rules: self.proxy_rules,
+            dev_routes: self.dev_routes,
+            responses: self.responses,
+            auth: self.auth,
+            default_charset: self.default_charset,
+            mime_types: self.mime_types,
+            mime_sniffing: self.mime_sniffing.unwrap_or(true),
+            emit_digest: self.emit_digest.unwrap_or(false),
+            etag_from_content: self.etag_from_content.unwrap_or(false),
+            preloads: self.preloads,
+            serve_files: self.serve_files.unwrap_or(true),
+            access_log: self.access_log,
+            access_logs: self.access_logs,
+            chaos: self.chaos,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// The index is looked up in every directory, so it must be a bare file name
+// "./index.html" is taken as index.html, anything naming another directory is refused
+fn normalize_index(index: &str) -> Result<String, String> {
+    if index.starts_with(['/', '\\']) || Path::new(index).is_absolute() {
+        return Err(format!(
+            "index {} is an absolute path, it must be a file name like index.html",
+            index
+        ));
+    }
+    let segments: Vec<&str> = index
+        .split(['/', '\\'])
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+    if segments.contains(&"..") {
+        return Err(format!("index {} can't contain ..", index));
+    }
+    match segments[..] {
+        [name] => Ok(name.to_string()),
+        [] => Err("index must be a file name like index.html".to_string()),
+        _ => Err(format!(
+            "index {} is looked up in every directory, set root to its directory instead",
+            index
+        )),
+    }
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    pub fn new_default() -> Config {
+        Config::builder().build().expect("default config is valid")
+    }
+
+    // Every address in host, IPv6 ones without their brackets so they can be bound
+    pub fn hosts(&self) -> Vec<String> {
+        self.host
+            .split(',')
+            .map(|host| unbracket(host.trim()))
+            .filter(|host| !host.is_empty())
+            .map(|host| host.to_string())
+            .collect()
+    }
+
+    // Serve a single file or a directory on all interfaces
+    // Paths like ./dir/../file.html are resolved first, a missing one is an error rather than 404s
+    pub fn new_serve(path: &str, overrides: &Overrides) -> Result<Config, String> {
+        let serving_path = fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+        let builder = Config::builder().host("0.0.0.0".to_string());
+        let builder = if serving_path.is_dir() {
+            builder.root(serving_path.to_string_lossy().to_string())
+        } else {
+            let index = serving_path
+                .file_name()
+                .ok_or(format!("Invalid path {}", path))?
+                .to_string_lossy()
+                .to_string();
+            let root = serving_path
+                .parent()
+                .ok_or(format!("Invalid path {}", path))?
+                .to_string_lossy()
+                .to_string();
+            builder.index(index).root(root)
+        };
+        overrides.apply(builder)?.build()
+    }
+
+    // Serve a single in memory payload at / and nothing else
+    pub fn new_payload(
+        body: String,
+        content_type: Option<String>,
+        overrides: &Overrides,
+    ) -> Result<Config, String> {
+        let content_type = content_type.unwrap_or_else(|| infer_content_type(&body).to_string());
+        let builder = Config::builder()
+            .host("0.0.0.0".to_string())
+            .serve_files(false)
+            .response(
+                "/",
+                StaticResponse {
+                    body,
+                    status: 200,
+                    content_type,
+                },
+            );
+        overrides.apply(builder)?.build()
+    }
+
+    pub fn load_config(path: &str, overrides: &Overrides) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => Config::parse_file(path, &content, overrides),
+            Err(_) => overrides.apply(Config::builder())?.build(),
         }
     }
+
+    // Like load_config, except a file that can't be read is an error instead of the defaults
+    pub fn reload_config(path: &str, overrides: &Overrides) -> Result<Config, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse_file(path, &content, overrides)
+    }
+
+    fn parse_file(path: &str, content: &str, overrides: &Overrides) -> Result<Config, String> {
+        let map = toml_parser(content);
+        let mut config = overrides.apply(ConfigBuilder::from_toml(&map)?)?.build()?;
+        config.path = Some(path.to_string());
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.hosts().is_empty() {
+            return Err("host is empty".to_string());
+        }
+        for (prefix, rule) in self.proxy_rules.iter() {
+            if rule.upstreams.is_empty() {
+                return Err(format!("Proxy rule {} has no upstreams", prefix));
+            }
+            if rule.weights.contains(&0) {
+                return Err(format!(
+                    "Proxy rule {} has an invalid option, only weight=N with N > 0 is known",
+                    prefix
+                ));
+            }
+            if rule.default_cache_control.as_ref().is_some_and(|value| {
+                value.trim().is_empty() || value.contains(|c: char| c.is_control())
+            }) {
+                return Err(format!("Invalid default_cache_control for {}", prefix));
+            }
+            if cfg!(not(unix)) && rule.upstreams.iter().any(|u| u.starts_with(UNIX_PREFIX)) {
+                return Err(format!(
+                    "Proxy rule {} uses a unix socket, not supported on this platform",
+                    prefix
+                ));
+            }
+        }
+        for (path, response) in self.responses.iter() {
+            if HttpStatus::try_from_u16(response.status).is_none() {
+                return Err(format!("Invalid status {} for {}", response.status, path));
+            }
+        }
+        if let Some(host) = &self.canonical_host {
+            if host.is_empty() || host.contains(|c: char| c == '/' || c.is_whitespace()) {
+                return Err(format!("Invalid canonical_host {}", host));
+            }
+        }
+        for (pattern, link) in self.preloads.iter() {
+            if link.trim().is_empty() || link.contains(|c: char| c.is_control()) {
+                return Err(format!("Invalid preload link for {}", pattern));
+            }
+        }
+        if self.max_bandwidth == Some(0) || self.max_bandwidth_per_conn == Some(0) {
+            return Err("Bandwidth limits must be above 0".to_string());
+        }
+        if self.max_request_head_bytes == 0
+            || self.max_request_line_bytes == 0
+            || self.max_header_count == 0
+        {
+            return Err("Request head limits must be above 0".to_string());
+        }
+        if self.admin_port == Some(self.port) {
+            return Err(format!("admin_port {} is also the public port", self.port));
+        }
+        if self.admin_api_path.is_some() && self.admin_port.is_none() {
+            return Err("admin_api_path is only served on admin_port, set one".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.record_sample_rate) {
+            return Err("record_sample_rate must be between 0 and 1".to_string());
+        }
+        if let Some(chaos) = &self.chaos {
+            let percents = [
+                chaos.reset_percent,
+                chaos.truncate_percent,
+                chaos.error_percent,
+            ];
+            if percents.iter().any(|p| *p > 100) {
+                return Err("Chaos percentages must be between 0 and 100".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_responses_table() {
+    let content = r#"
+[responses]
+"/robots.txt" = { body = "User-agent: *\nDisallow:", type = "text/plain" }
+"/version" = "1.0 # not a comment"
+"/gone" = { body = "bye", status = 404 }
+"#;
+    let map = toml_parser(content);
+    let responses = map.get("responses").unwrap();
+    let robots: TOMLSchema = responses.get2("/robots.txt").unwrap();
+    assert_eq!(
+        robots.get2::<String>("body").unwrap(),
+        "User-agent: *\nDisallow:"
+    );
+    assert_eq!(robots.get2::<String>("type").unwrap(), "text/plain");
+    let version: String = responses.get2("/version").unwrap();
+    assert_eq!(version, "1.0 # not a comment");
+    let gone: TOMLSchema = responses.get2("/gone").unwrap();
+    assert_eq!(gone.get2::<u16>("status").unwrap(), 404);
+}
+
+#[test]
+fn test_auth_table() {
+    let path = std::env::temp_dir().join(format!("hteapot-auth-{}", std::process::id()));
+    fs::write(&path, "alice:secret\n").unwrap();
+    let content = format!(
+        r#"
+[auth]
+"/admin" = {{ file = "{}", realm = "Admin area" }}
+"/api" = {{ tokens = "t1, t2" }}
+"#,
+        path.display()
+    );
+    let config = ConfigBuilder::from_toml(&toml_parser(&content))
+        .unwrap()
+        .build()
+        .unwrap();
+    let admin = &config.auth["/admin"];
+    assert_eq!(admin.realm, "Admin area");
+    assert!(admin.backend.verify("Basic YWxpY2U6c2VjcmV0"));
+    let api = &config.auth["/api"];
+    assert_eq!(api.realm, "hteapot");
+    assert!(api.backend.verify("Bearer t2"));
+    fs::remove_file(&path).unwrap();
+
+    for invalid in [
+        "\"/a\" = { tokens = \" , \" }",
+        "\"/a\" = { realm = \"x\" }",
+        "\"/a\" = { file = \"/nonexistent/htpasswd\" }",
+        "\"/a\" = \"t1\"",
+    ] {
+        let map = toml_parser(&format!("[auth]\n{}\n", invalid));
+        assert!(ConfigBuilder::from_toml(&map).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_hosts() {
+    let hosts = |host: &str| {
+        Config::builder()
+            .host(host.to_string())
+            .build()
+            .map(|c| c.hosts())
+    };
+    assert_eq!(hosts("localhost").unwrap(), vec!["localhost"]);
+    assert_eq!(hosts("127.0.0.1, [::1]").unwrap(), vec!["127.0.0.1", "::1"]);
+    assert_eq!(hosts(" , ").unwrap_err(), "host is empty");
+}
+
+#[test]
+fn test_dev_router_table() {
+    let map = toml_parser("[dev_router]\napp = 3000\n\"API.test\" = 4000\n");
+    let config = ConfigBuilder::from_toml(&map).unwrap().build().unwrap();
+    let mut hosts: Vec<&String> = config.dev_routes.keys().collect();
+    hosts.sort();
+    assert_eq!(hosts, ["api.test", "app.localhost"]);
+    assert_eq!(
+        config.dev_routes["app.localhost"].upstreams,
+        ["http://127.0.0.1:3000"]
+    );
+    let map = toml_parser("[dev_router]\napp = \"3000\"\n");
+    assert!(ConfigBuilder::from_toml(&map).is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1500"), Some(1500));
+    assert_eq!(parse_size("512K"), Some(512 * 1024));
+    assert_eq!(parse_size("10 m"), Some(10 * 1024 * 1024));
+    assert_eq!(parse_size("1G"), Some(1 << 30));
+    assert_eq!(parse_size("K"), None);
+    assert_eq!(parse_size("fast"), None);
+    assert_eq!(parse_size("99999999999G"), None);
+}
+
+#[test]
+fn test_builder_merge_precedence() {
+    let file = r#"
+[HTEAPOT]
+port = 8081
+host = "127.0.0.1"
+root = "public"
+cache = true
+max_body_size = "2M"
+[proxy]
+"/api" = "http://127.0.0.1:3000"
+"#;
+    let file = || ConfigBuilder::from_toml(&toml_parser(file)).unwrap();
+    let cli = Config::builder()
+        .port(9000)
+        .proxy_rule("/app", ProxyRule::from_list("http://127.0.0.1:4000"));
+    let config = file().merge(cli).build().unwrap();
+    assert_eq!(config.port, 9000);
+    assert_eq!(config.root, "public");
+    assert!(config.cache);
+    assert_eq!(config.host, "127.0.0.1");
+    assert_eq!(config.cache_ttl, 3600);
+    assert_eq!(config.max_body_size, 2 << 20);
+    assert_eq!(config.proxy_rules.len(), 2);
+
+    // File, then HTEAPOT_* variables, then flags
+    let vars = vec![
+        ("HTEAPOT_PORT".to_string(), "9001".to_string()),
+        ("HTEAPOT_ROOT".to_string(), "env".to_string()),
+        ("HOME".to_string(), "/root".to_string()),
+    ];
+    let mut args: Vec<String> = ["hteapot", "--port", "9002", "config.toml"]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    let overrides = Overrides::new(vars.into_iter(), &mut args).unwrap();
+    assert_eq!(args, ["hteapot", "config.toml"]);
+    let config = overrides.apply(file()).unwrap().build().unwrap();
+    assert_eq!(config.port, 9002);
+    assert_eq!(config.root, "env");
+    assert_eq!(config.host, "127.0.0.1");
+    assert_eq!(config.overrides, overrides);
+
+    let mut args = vec!["hteapot".to_string(), "--threads".to_string()];
+    assert!(Overrides::new(Vec::new().into_iter(), &mut args).is_err());
+    let mut args = vec!["--threads".to_string(), "many".to_string()];
+    let overrides = Overrides::new(Vec::new().into_iter(), &mut args).unwrap();
+    assert!(overrides.apply(file()).is_err());
+
+    // Runs without a file keep their own defaults
+    assert_eq!(Config::new_default().host, "localhost");
+    assert_eq!(Config::new_default().cache_ttl, 0);
+
+    let invalid = Config::builder().response(
+        "/x",
+        StaticResponse {
+            body: "".to_string(),
+            status: 299,
+            content_type: "text/plain".to_string(),
+        },
+    );
+    assert!(invalid.build().is_err());
+}
+
+#[test]
+fn test_index_setting() {
+    let index = |index: &str| Config::builder().index(index.to_string()).build();
+    assert_eq!(index("./home.html").unwrap().index, "home.html");
+    assert_eq!(index("home.html").unwrap().index, "home.html");
+    for refused in [
+        "/etc/passwd",
+        "../secret.html",
+        "a/../../b.html",
+        "..",
+        "public/index.html",
+        "",
+        "./",
+    ] {
+        assert!(index(refused).is_err(), "{}", refused);
+    }
+    assert!(index("public/index.html")
+        .unwrap_err()
+        .contains("set root to its directory"));
+
+    let dir = std::env::temp_dir().join(format!("hteapot-serve-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("page.html"), "<p>hi</p>").unwrap();
+    let dotted = format!("{}/sub/../page.html", dir.display());
+    let config = Config::new_serve(&dotted, &Overrides::default()).unwrap();
+    assert_eq!(config.index, "page.html");
+    assert_eq!(
+        Path::new(&config.root),
+        fs::canonicalize(&dir).unwrap().as_path()
+    );
+    let config =
+        Config::new_serve(&format!("{}/sub/..", dir.display()), &Overrides::default()).unwrap();
+    assert_eq!(config.index, "index.html");
+    assert!(Config::new_serve(
+        &format!("{}/missing.html", dir.display()),
+        &Overrides::default()
+    )
+    .is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_max_connections_per_ip_exempt() {
+    let exempt = |list: &str| {
+        Config::builder()
+            .max_connections_per_ip_exempt(list.to_string())
+            .build()
+            .map(|config| config.max_connections_per_ip_exempt)
+    };
+    let expected: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+    assert_eq!(exempt("10.0.0.1, [::1]").unwrap(), expected);
+    assert!(exempt(" ,").unwrap().is_empty());
+    assert!(exempt("10.0.0.1, proxy.local")
+        .unwrap_err()
+        .contains("proxy.local"));
 }
@@ -0,0 +1,220 @@
+// Explains why the server can't listen, naming whoever already holds the port when possible
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use hteapot::{format_addr, unbracket};
+
+// Exit code when the port is taken, so wrapper scripts can tell it from other failures
+pub const EXIT_ADDR_IN_USE: i32 = 98;
+
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq)]
+pub enum PortHolder {
+    Hteapot(String),      // Another instance, with its version
+    Http(Option<String>), // Some other web server, with its Server header
+    Unknown,              // Accepted the connection but didn't answer HTTP
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    Process(u32, String), // Found in /proc, pid and name
+}
+
+impl fmt::Display for PortHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortHolder::Hteapot(version) => write!(f, "another hteapot {}", version),
+            PortHolder::Http(Some(server)) => write!(f, "an HTTP server ({})", server),
+            PortHolder::Http(None) => write!(f, "an HTTP server"),
+            PortHolder::Unknown => write!(f, "a service that doesn't speak HTTP"),
+            PortHolder::Process(pid, name) => write!(f, "{} (pid {})", name, pid),
+        }
+    }
+}
+
+// Message for a failed bind, and the exit code to use
+pub fn bind_error(host: &str, port: u16, error: &io::Error) -> (String, i32) {
+    if error.kind() != io::ErrorKind::AddrInUse {
+        return (
+            format!("Error binding to {}: {}", format_addr(host, port), error),
+            1,
+        );
+    }
+    let mut holders = Vec::new();
+    if let Some(addr) = probe_address(host, port) {
+        holders.extend(identify(addr));
+    }
+    holders.extend(owner_process(port));
+    let held_by = if holders.is_empty() {
+        String::new()
+    } else {
+        let holders: Vec<String> = holders.iter().map(|h| h.to_string()).collect();
+        format!(", held by {}", holders.join(", "))
+    };
+    let message = format!(
+        "Port {} on {} is already in use{}\nStop it or set another port in the config",
+        port, host, held_by
+    );
+    (message, EXIT_ADDR_IN_USE)
+}
+
+// Where to connect to reach whatever holds the address, wildcards through loopback
+fn probe_address(host: &str, port: u16) -> Option<SocketAddr> {
+    let mut addr = (unbracket(host), port).to_socket_addrs().ok()?.next()?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    Some(addr)
+}
+
+// Ask whoever listens at addr what it is, None when nothing accepts the connection
+pub fn identify(addr: SocketAddr) -> Option<PortHolder> {
+    let mut stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).ok()?;
+    let _ = stream.set_read_timeout(Some(PROBE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(PROBE_TIMEOUT));
+    let request = "HEAD / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    if stream.write_all(request.as_bytes()).is_err() {
+        return Some(PortHolder::Unknown);
+    }
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while head.len() < 8 * 1024 && !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => head.extend_from_slice(&buffer[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    if !head.starts_with("HTTP/") {
+        return Some(PortHolder::Unknown);
+    }
+    let server = head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("server") {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    });
+    Some(match server {
+        Some(server) => match server.strip_prefix("HTeaPot/") {
+            Some(version) => PortHolder::Hteapot(version.to_string()),
+            None => PortHolder::Http(Some(server)),
+        },
+        None => PortHolder::Http(None),
+    })
+}
+
+// Process listening on port, found through /proc when permissions allow
+#[cfg(target_os = "linux")]
+pub fn owner_process(port: u16) -> Option<PortHolder> {
+    use std::fs;
+    let inodes: Vec<String> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|table| fs::read_to_string(table).ok())
+        .flat_map(|table| listening_inodes(&table, port))
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|p| p.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let holds = fds.flatten().any(|fd| {
+            fs::read_link(fd.path())
+                .map(|link| {
+                    inodes
+                        .iter()
+                        .any(|i| link.to_string_lossy() == format!("socket:[{}]", i))
+                })
+                .unwrap_or(false)
+        });
+        if holds {
+            let name = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            return Some(PortHolder::Process(pid, name.trim().to_string()));
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn owner_process(_port: u16) -> Option<PortHolder> {
+    None
+}
+
+// Inodes of the sockets in LISTEN state on port, from a /proc/net/tcp table
+#[cfg(target_os = "linux")]
+fn listening_inodes(table: &str, port: u16) -> Vec<String> {
+    const LISTEN: &str = "0A";
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local_port = fields.get(1)?.rsplit(':').next()?;
+            let listening = *fields.get(3)? == LISTEN;
+            if listening && u16::from_str_radix(local_port, 16).ok()? == port {
+                fields.get(9).map(|inode| inode.to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_identify_port_holder() {
+    use hteapot::{Hteapot, HttpResponse, HttpStatus};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let hteapot = listener.local_addr().unwrap();
+    let server = Hteapot::new("127.0.0.1", hteapot.port());
+    thread::spawn(move || {
+        server.listen_on(listener, |_req| HttpResponse::new(HttpStatus::OK, "", None))
+    });
+    assert_eq!(
+        identify(hteapot),
+        Some(PortHolder::Hteapot(env!("CARGO_PKG_VERSION").to_string()))
+    );
+
+    let nginx = ::brew::canned_upstream(b"HTTP/1.1 200 OK\r\nserver: nginx\r\n\r\n");
+    let nginx = SocketAddr::from(([127, 0, 0, 1], nginx));
+    assert_eq!(
+        identify(nginx),
+        Some(PortHolder::Http(Some("nginx".to_string())))
+    );
+
+    // Accepts and never answers
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    assert_eq!(
+        identify(silent.local_addr().unwrap()),
+        Some(PortHolder::Unknown)
+    );
+
+    let error = TcpListener::bind(silent.local_addr().unwrap()).unwrap_err();
+    let (message, code) = bind_error("127.0.0.1", silent.local_addr().unwrap().port(), &error);
+    assert_eq!(code, EXIT_ADDR_IN_USE);
+    assert!(message.contains("already in use"), "{}", message);
+    #[cfg(target_os = "linux")]
+    assert!(
+        message.contains(&format!("pid {}", std::process::id())),
+        "{}",
+        message
+    );
+}
@@ -0,0 +1,123 @@
+// SHA-256 of the files served, for emit_digest and etag_from_content
+// Kept per path, modification time and length, so a changed file is hashed again
+// Files past INLINE_LIMIT are hashed on a thread of their own, responses go out without
+// the digest until it is done instead of holding a worker on it
+// Content ETags can't go without, see wait
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use hteapot::sha256;
+
+// Bigger files than this are hashed off the worker
+const INLINE_LIMIT: usize = 1024 * 1024;
+// Entries kept before starting over, old modification times are never asked for again
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DigestKey {
+    pub path: String,
+    pub modified: Option<SystemTime>,
+    pub len: usize,
+}
+
+#[derive(Default)]
+pub struct Digests {
+    entries: Mutex<HashMap<DigestKey, Option<[u8; 32]>>>, // None while a thread is hashing it
+    hashed: Condvar,                                      // A thread finished one
+}
+
+impl Digests {
+    pub fn new() -> Digests {
+        Digests::default()
+    }
+
+    // Digest of content, None while a large file is still being hashed
+    pub fn get(self: &Arc<Self>, key: DigestKey, content: &Arc<Vec<u8>>) -> Option<[u8; 32]> {
+        {
+            let mut entries = self.entries.lock().expect("Error locking digests");
+            if let Some(entry) = entries.get(&key) {
+                return *entry;
+            }
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+            if content.len() > INLINE_LIMIT {
+                entries.insert(key.clone(), None);
+                let (digests, content) = (self.clone(), content.clone());
+                thread::spawn(move || {
+                    let digest = sha256(&content);
+                    let mut entries = digests.entries.lock().expect("Error locking digests");
+                    entries.insert(key, Some(digest));
+                    digests.hashed.notify_all();
+                });
+                return None;
+            }
+        }
+        let digest = sha256(content);
+        let mut entries = self.entries.lock().expect("Error locking digests");
+        entries.insert(key, Some(digest));
+        Some(digest)
+    }
+
+    // Digest of content, waiting for a large file's thread when it isn't done
+    // For content ETags, a response without one would leave clients with two validators
+    pub fn wait(self: &Arc<Self>, key: DigestKey, content: &Arc<Vec<u8>>) -> [u8; 32] {
+        if let Some(digest) = self.get(key.clone(), content) {
+            return digest;
+        }
+        let mut entries = self.entries.lock().expect("Error locking digests");
+        loop {
+            match entries.get(&key) {
+                Some(Some(digest)) => return *digest,
+                Some(None) => {
+                    entries = self.hashed.wait(entries).expect("Error locking digests");
+                }
+                // Dropped past MAX_ENTRIES while waiting
+                None => return sha256(content),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_digests() {
+    use std::time::{Duration, Instant};
+
+    let digests = Arc::new(Digests::new());
+    let key = |path: &str, len: usize| DigestKey {
+        path: path.to_string(),
+        modified: None,
+        len,
+    };
+    let small = Arc::new(b"abc".to_vec());
+    let digest = digests.get(key("/a.txt", 3), &small).unwrap();
+    assert_eq!(digest, sha256(b"abc"));
+    // Cached, other content under the same key isn't looked at
+    let other = Arc::new(b"abd".to_vec());
+    assert_eq!(digests.get(key("/a.txt", 3), &other), Some(digest));
+
+    // A large file is hashed in the background, the first request goes without
+    let large = Arc::new(vec![b'a'; INLINE_LIMIT + 1]);
+    assert_eq!(digests.get(key("/big.bin", large.len()), &large), None);
+    let started = Instant::now();
+    let digest = loop {
+        if let Some(digest) = digests.get(key("/big.bin", large.len()), &large) {
+            break digest;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(digest, sha256(&large));
+
+    // Waited for instead, the first request has it too
+    let changed = Arc::new(vec![b'b'; INLINE_LIMIT + 1]);
+    let key = DigestKey {
+        modified: Some(SystemTime::UNIX_EPOCH),
+        ..key("/big.bin", changed.len())
+    };
+    assert_eq!(digests.wait(key.clone(), &changed), sha256(&changed));
+    assert_eq!(digests.get(key, &changed), Some(sha256(&changed)));
+}
@@ -0,0 +1,71 @@
+// Host and port pairs as written in configs, IPv6 literals with or without brackets
+// "::" listens on both IPv4 and IPv6, with a second socket where the OS keeps them apart
+
+use std::io;
+use std::net::{Ipv4Addr, TcpListener};
+
+// The host without the brackets around an IPv6 literal, the way bind and connect take it
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+// host:port for logs and urls, IPv6 literals in brackets like [::1]:8080
+// Names can't have a colon, so any host with one is taken as IPv6
+pub fn format_addr(host: &str, port: u16) -> String {
+    let host = unbracket(host);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// Bind host, a name or an IP literal, and port
+// The IPv6 wildcard also gets 0.0.0.0 on the same port when the first socket is IPv6 only,
+// when it isn't that bind fails as the port is already taken for IPv4 too
+pub fn bind_host(host: &str, port: u16) -> io::Result<Vec<TcpListener>> {
+    let listener = TcpListener::bind((unbracket(host), port))?;
+    let addr = listener.local_addr()?;
+    let mut listeners = vec![listener];
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        if let Ok(v4) = TcpListener::bind((Ipv4Addr::UNSPECIFIED, addr.port())) {
+            listeners.push(v4);
+        }
+    }
+    Ok(listeners)
+}
+
+#[test]
+fn test_format_addr() {
+    assert_eq!(format_addr("localhost", 8081), "localhost:8081");
+    assert_eq!(format_addr("127.0.0.1", 80), "127.0.0.1:80");
+    assert_eq!(format_addr("::1", 8080), "[::1]:8080");
+    assert_eq!(format_addr("[::1]", 8080), "[::1]:8080");
+    assert_eq!(format_addr("::", 80), "[::]:80");
+    assert_eq!(format_addr("fe80::1%eth0", 80), "[fe80::1%eth0]:80");
+    assert_eq!(unbracket("[2001:db8::1]"), "2001:db8::1");
+    assert_eq!(unbracket("[broken"), "[broken");
+}
+
+#[test]
+fn test_bind_dual_stack() {
+    use std::net::TcpStream;
+
+    // Skipped where there is no IPv6
+    let listeners = match bind_host("::", 0) {
+        Ok(listeners) => listeners,
+        Err(_) => return,
+    };
+    let port = listeners[0].local_addr().unwrap().port();
+    assert!(listeners.len() <= 2);
+    // Reachable on both loopbacks, through whichever socket takes each
+    assert!(TcpStream::connect(("::1", port)).is_ok());
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+
+    let listeners = bind_host("[::1]", 0).unwrap();
+    assert_eq!(listeners.len(), 1);
+    let addr = listeners[0].local_addr().unwrap();
+    assert_eq!(addr.to_string(), format_addr("::1", addr.port()));
+}
@@ -0,0 +1,290 @@
+// Who may make a request, checked against the Authorization header
+// Backends decide, authorize turns a refusal into the 401 asking for credentials
+
+use super::digest::{apr1, base64_decode, base64_encode, sha1, sha256};
+use super::{Headers, HttpRequest, HttpResponse, HttpStatus};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub trait AuthBackend: Send + Sync {
+    // Whether the Authorization header value lets the request in
+    fn verify(&self, authorization: &str) -> bool;
+
+    // Scheme asked for in the WWW-Authenticate of a 401, like "Basic" or "Bearer"
+    fn scheme(&self) -> &str;
+}
+
+// None when the backend lets the request in, else the 401 to answer with
+pub fn authorize(
+    backend: &dyn AuthBackend,
+    req: &HttpRequest,
+    realm: &str,
+) -> Option<HttpResponse> {
+    let allowed = req
+        .headers
+        .get("Authorization")
+        .is_some_and(|value| backend.verify(value));
+    if allowed {
+        return None;
+    }
+    let mut headers = Headers::new();
+    let challenge = format!(
+        "{} realm=\"{}\"",
+        backend.scheme(),
+        realm.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    headers.insert("WWW-Authenticate", challenge);
+    Some(HttpResponse::new(
+        HttpStatus::Unauthorized,
+        "Unauthorized",
+        Some(headers),
+    ))
+}
+
+// Compares every byte whatever the first difference, so timing doesn't tell how close a guess was
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// User and password of a "Basic ..." header value
+pub fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Check a password against an htpasswd hash, None when its format isn't supported
+// Supported are "$apr1$" (htpasswd -m), "{SHA}" (htpasswd -s), "{SHA256}" with the digest in hex,
+// and plain text for anything else without a "$" or "{" prefix
+pub fn verify_password(hash: &str, password: &str) -> Option<bool> {
+    let computed = if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        apr1(password.as_bytes(), salt.as_bytes())
+    } else if hash.starts_with("{SHA}") {
+        format!("{{SHA}}{}", base64_encode(&sha1(password.as_bytes())))
+    } else if let Some(digest) = hash.strip_prefix("{SHA256}") {
+        let computed: String = sha256(password.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        return Some(constant_time_eq(
+            computed.as_bytes(),
+            digest.to_ascii_lowercase().as_bytes(),
+        ));
+    } else if hash.starts_with('$') || hash.starts_with('{') {
+        // bcrypt, SHA-crypt and the like
+        return None;
+    } else {
+        password.to_string()
+    };
+    Some(constant_time_eq(computed.as_bytes(), hash.as_bytes()))
+}
+
+// File size and modification time, a change in either means the file was written
+type Stamp = Option<(SystemTime, u64)>;
+
+// Users from an htpasswd file, read again whenever it changes
+pub struct Htpasswd {
+    path: PathBuf,
+    loaded: Mutex<(Stamp, HashMap<String, String>)>,
+}
+
+impl Htpasswd {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Htpasswd> {
+        let path = path.into();
+        let stamp = Self::stamp(&path);
+        let users = Self::read(&path)?;
+        Ok(Htpasswd {
+            path,
+            loaded: Mutex::new((stamp, users)),
+        })
+    }
+
+    fn stamp(path: &PathBuf) -> Stamp {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    // "user:hash" lines, blank lines and # comments skipped
+    fn read(path: &PathBuf) -> io::Result<HashMap<String, String>> {
+        let content = fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, hash)) if verify_password(hash, "").is_some() => {
+                    users.insert(user.to_string(), hash.to_string());
+                }
+                Some((user, _)) => eprintln!(
+                    "WARNING: {} in {} uses an unsupported hash, it can't log in",
+                    user,
+                    path.display()
+                ),
+                None => eprintln!("WARNING: invalid line in {}", path.display()),
+            }
+        }
+        Ok(users)
+    }
+
+    // Read the file again if it changed, keeping the old users when it can't be read
+    fn reload(&self) {
+        let stamp = Self::stamp(&self.path);
+        let mut loaded = self.loaded.lock().expect("Error locking htpasswd");
+        if stamp.is_none() || loaded.0 == stamp {
+            return;
+        }
+        match Self::read(&self.path) {
+            Ok(users) => *loaded = (stamp, users),
+            Err(e) => eprintln!("WARNING: error reading {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl AuthBackend for Htpasswd {
+    fn verify(&self, authorization: &str) -> bool {
+        let (user, password) = match basic_credentials(authorization) {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        self.reload();
+        let loaded = self.loaded.lock().expect("Error locking htpasswd");
+        match loaded.1.get(&user) {
+            Some(hash) => verify_password(hash, &password) == Some(true),
+            None => false,
+        }
+    }
+
+    fn scheme(&self) -> &str {
+        "Basic"
+    }
+}
+
+// Bearer tokens for API style access
+pub struct TokenList {
+    tokens: Vec<String>,
+}
+
+impl TokenList {
+    pub fn new(tokens: Vec<String>) -> TokenList {
+        TokenList { tokens }
+    }
+}
+
+impl AuthBackend for TokenList {
+    fn verify(&self, authorization: &str) -> bool {
+        let token = match authorization.trim().split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => token.trim(),
+            _ => return false,
+        };
+        // Every token is compared, so timing doesn't tell which one came close
+        self.tokens.iter().fold(false, |found, t| {
+            constant_time_eq(t.as_bytes(), token.as_bytes()) | found
+        })
+    }
+
+    fn scheme(&self) -> &str {
+        "Bearer"
+    }
+}
+
+#[test]
+fn test_verify_password() {
+    assert_eq!(
+        verify_password("$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/", "myPassword"),
+        Some(true)
+    );
+    assert_eq!(
+        verify_password("$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/", "mypassword"),
+        Some(false)
+    );
+    assert_eq!(
+        verify_password("{SHA}VBPuJHI7uixaa6LQGWx4s+5GKNE=", "myPassword"),
+        Some(true)
+    );
+    let sha256 = "{SHA256}BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+    assert_eq!(verify_password(sha256, "abc"), Some(true));
+    assert_eq!(verify_password(sha256, "abd"), Some(false));
+    assert_eq!(verify_password("plain", "plain"), Some(true));
+    assert_eq!(verify_password("plain", "plain "), Some(false));
+    assert_eq!(
+        verify_password(
+            "$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r7q1BB8I2s0BRqC",
+            "x"
+        ),
+        None
+    );
+
+    assert!(constant_time_eq(b"token", b"token"));
+    assert!(!constant_time_eq(b"token", b"tokem"));
+    assert!(!constant_time_eq(b"token", b"token2"));
+}
+
+#[test]
+fn test_auth_backends() {
+    use std::thread;
+    use std::time::Duration;
+
+    let basic = |credentials: &str| format!("Basic {}", base64_encode(credentials.as_bytes()));
+    assert_eq!(
+        basic_credentials(&basic("alice:pa:ss")),
+        Some(("alice".to_string(), "pa:ss".to_string()))
+    );
+    assert_eq!(basic_credentials("Basic !!!!"), None);
+    assert_eq!(basic_credentials("Bearer abc"), None);
+
+    let path = std::env::temp_dir().join(format!("hteapot-htpasswd-{}", std::process::id()));
+    fs::write(
+        &path,
+        "# users\nalice:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/\nbob:{SHA}VBPuJHI7uixaa6LQGWx4s+5GKNE=\n\
+         carol:$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r7q1BB8I2s0BRqC\n",
+    )
+    .unwrap();
+    let file = Htpasswd::open(&path).unwrap();
+    assert!(file.verify(&basic("alice:myPassword")));
+    assert!(file.verify(&basic("bob:myPassword")));
+    assert!(!file.verify(&basic("alice:wrong")));
+    assert!(!file.verify(&basic("carol:x")));
+    assert!(!file.verify(&basic("dave:myPassword")));
+    assert!(!file.verify("Bearer myPassword"));
+
+    // Picked up on the next request after the file changes
+    thread::sleep(Duration::from_millis(20));
+    fs::write(&path, "dave:plain\n").unwrap();
+    assert!(file.verify(&basic("dave:plain")));
+    assert!(!file.verify(&basic("alice:myPassword")));
+    // A missing file keeps the users last read
+    fs::remove_file(&path).unwrap();
+    assert!(file.verify(&basic("dave:plain")));
+
+    let tokens = TokenList::new(vec!["t0k3n".to_string(), "other".to_string()]);
+    assert!(tokens.verify("Bearer other"));
+    assert!(tokens.verify("bearer t0k3n"));
+    assert!(!tokens.verify("Bearer t0k3"));
+    assert!(!tokens.verify("Basic t0k3n"));
+
+    let mut req =
+        super::Hteapot::request_parser("GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_string()).unwrap();
+    let refused = authorize(&tokens, &req, "api \"v1\"").unwrap();
+    assert_eq!(refused.status as u16, 401);
+    assert_eq!(
+        refused.headers.get("WWW-Authenticate"),
+        Some("Bearer realm=\"api \\\"v1\\\"\"")
+    );
+    req.headers
+        .insert("Authorization".to_string(), "Bearer t0k3n".to_string());
+    assert!(authorize(&tokens, &req, "api").is_none());
+}
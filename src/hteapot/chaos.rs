@@ -0,0 +1,124 @@
+// Fault injection for testing clients against a misbehaving server
+// Every fault is opt-in and driven by a seedable generator so runs can be reproduced
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default)]
+pub struct Chaos {
+    pub seed: u64,
+    pub delay: Option<(Duration, Duration)>, // Random delay range before handling a request
+    pub reset_percent: u8,                   // Close the connection right after the headers
+    pub truncate_percent: u8,                // Close the connection halfway through the body
+    pub error_percent: u8,                   // Replace the response with a 500
+    pub drip_bytes_per_sec: usize,           // Slow down writes, 0 disables
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Fault {
+    None,
+    Error,
+    Reset,
+    Truncate,
+}
+
+// xorshift64*, good enough for picking faults
+pub(crate) struct ChaosRng {
+    state: u64,
+}
+
+impl ChaosRng {
+    pub fn new(seed: u64) -> Self {
+        ChaosRng {
+            state: if seed == 0 {
+                0x9E37_79B9_7F4A_7C15
+            } else {
+                seed
+            },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn percent(&mut self, percent: u8) -> bool {
+        percent > 0 && self.next_u64() % 100 < percent as u64
+    }
+
+    pub fn delay(&mut self, chaos: &Chaos) -> Option<Duration> {
+        let (min, max) = chaos.delay?;
+        if max <= min {
+            return Some(min);
+        }
+        let span = (max - min).as_millis() as u64;
+        Some(min + Duration::from_millis(self.next_u64() % (span + 1)))
+    }
+
+    pub fn fault(&mut self, chaos: &Chaos) -> Fault {
+        if self.percent(chaos.error_percent) {
+            Fault::Error
+        } else if self.percent(chaos.reset_percent) {
+            Fault::Reset
+        } else if self.percent(chaos.truncate_percent) {
+            Fault::Truncate
+        } else {
+            Fault::None
+        }
+    }
+}
+
+pub(crate) struct ChaosState {
+    pub config: Chaos,
+    rng: Mutex<ChaosRng>,
+}
+
+impl ChaosState {
+    pub fn new(config: Chaos) -> Self {
+        let rng = Mutex::new(ChaosRng::new(config.seed));
+        ChaosState { config, rng }
+    }
+
+    // Pick the delay and fault for the next response
+    pub fn roll(&self) -> (Option<Duration>, Fault) {
+        let mut rng = self.rng.lock().expect("Error locking chaos rng");
+        let delay = rng.delay(&self.config);
+        (delay, rng.fault(&self.config))
+    }
+}
+
+#[test]
+fn test_chaos_seeded_distribution() {
+    let chaos = Chaos {
+        seed: 42,
+        delay: Some((Duration::from_millis(10), Duration::from_millis(20))),
+        error_percent: 20,
+        reset_percent: 10,
+        ..Chaos::default()
+    };
+    let mut rng = ChaosRng::new(chaos.seed);
+    let mut errors = 0;
+    let mut resets = 0;
+    for _ in 0..10000 {
+        match rng.fault(&chaos) {
+            Fault::Error => errors += 1,
+            Fault::Reset => resets += 1,
+            Fault::Truncate => panic!("truncate is disabled"),
+            Fault::None => {}
+        }
+        let delay = rng.delay(&chaos).unwrap();
+        assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+    }
+    // 20% errors, then 10% of the remaining 80% resets
+    assert!((1800..2200).contains(&errors), "errors: {}", errors);
+    assert!((650..950).contains(&resets), "resets: {}", resets);
+
+    let mut a = ChaosRng::new(7);
+    let mut b = ChaosRng::new(7);
+    for _ in 0..100 {
+        assert_eq!(a.fault(&chaos), b.fault(&chaos));
+    }
+}
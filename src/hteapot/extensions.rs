@@ -0,0 +1,89 @@
+// Request scoped storage keyed by type
+// Lets code that runs before the handler pass data to it without touching headers
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    // Store a value, returning the previous one of the same type
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+// Values are opaque, only the count is shown
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[test]
+fn test_extensions() {
+    #[derive(Debug, PartialEq)]
+    struct User {
+        name: String,
+    }
+    struct RequestId(u64);
+
+    let mut ext = Extensions::new();
+    assert!(ext.get::<User>().is_none());
+    assert!(ext
+        .insert(User {
+            name: "tea".to_string()
+        })
+        .is_none());
+    ext.insert(RequestId(7));
+    assert_eq!(ext.len(), 2);
+    assert_eq!(ext.get::<User>().unwrap().name, "tea");
+    ext.get_mut::<RequestId>().unwrap().0 += 1;
+    assert_eq!(ext.get::<RequestId>().unwrap().0, 8);
+
+    let old = ext.insert(User {
+        name: "pot".to_string(),
+    });
+    assert_eq!(old.unwrap().name, "tea");
+    assert_eq!(ext.remove::<User>().unwrap().name, "pot");
+    assert!(ext.get::<User>().is_none());
+    assert_eq!(ext.len(), 1);
+}
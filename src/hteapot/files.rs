@@ -0,0 +1,202 @@
+// Where static files come from, a directory on disk or a set of files kept in memory
+// Paths are request paths like "/css/site.css", normalized before any lookup
+
+use super::normalize_path;
+use std::collections::HashMap;
+use std::io;
+use std::iter::FromIterator;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub trait FileSource: Send + Sync {
+    fn exists(&self, path: &str) -> bool;
+    fn is_dir(&self, path: &str) -> bool;
+    fn read(&self, path: &str) -> io::Result<Arc<Vec<u8>>>;
+
+    // Last change of the file, None where there is no such thing like files in memory
+    fn modified(&self, _path: &str) -> Option<SystemTime> {
+        None
+    }
+
+    // What a request path names, the index for directories, None when nothing is there
+    // The index is only joined when it is a bare file name, never one reaching another directory
+    fn resolve(&self, path: &str, index: &str) -> Option<String> {
+        let mut path = normalize_path(path).ok()?;
+        if self.is_dir(&path) {
+            if index.contains(['/', '\\']) || index.is_empty() || index == "." || index == ".." {
+                return None;
+            }
+            if !path.ends_with('/') {
+                path.push('/');
+            }
+            path.push_str(index);
+        }
+        if self.exists(&path) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+}
+
+// Files under a directory, nothing above it can be reached
+#[derive(Clone, Debug)]
+pub struct DiskFs {
+    root: String,
+}
+
+impl DiskFs {
+    pub fn new(root: &str) -> DiskFs {
+        DiskFs {
+            root: root.to_string(),
+        }
+    }
+
+    // Location on disk, None for paths climbing above the root
+    pub fn full_path(&self, path: &str) -> Option<PathBuf> {
+        let path = normalize_path(path).ok()?;
+        Some(PathBuf::from(format!("{}{}", self.root, path)))
+    }
+}
+
+impl FileSource for DiskFs {
+    fn exists(&self, path: &str) -> bool {
+        self.full_path(path).is_some_and(|p| p.exists())
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        self.full_path(path).is_some_and(|p| p.is_dir())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Arc<Vec<u8>>> {
+        match self.full_path(path) {
+            Some(full_path) => std::fs::read(full_path).map(Arc::new),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Path above root {}", path),
+            )),
+        }
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        std::fs::metadata(self.full_path(path)?)
+            .ok()?
+            .modified()
+            .ok()
+    }
+}
+
+// Files kept in memory, like ones embedded in the binary with include_bytes!
+// Directories are implied by the paths of the files in them
+#[derive(Clone, Debug, Default)]
+pub struct VirtualFs {
+    files: HashMap<String, Arc<Vec<u8>>>,
+}
+
+impl VirtualFs {
+    pub fn new() -> VirtualFs {
+        VirtualFs::default()
+    }
+
+    // Add a file, "index.html" and "/index.html" are the same path
+    // Paths climbing above the root are ignored
+    pub fn add(mut self, path: &str, content: impl AsRef<[u8]>) -> Self {
+        if let Ok(path) = normalize_path(&format!("/{}", path)) {
+            self.files.insert(path, Arc::new(content.as_ref().to_vec()));
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+// From pairs of path and content, a HashMap or an array of include_bytes! for instance
+impl<P: AsRef<str>, B: AsRef<[u8]>> FromIterator<(P, B)> for VirtualFs {
+    fn from_iter<I: IntoIterator<Item = (P, B)>>(files: I) -> Self {
+        files
+            .into_iter()
+            .fold(VirtualFs::new(), |fs, (path, content)| {
+                fs.add(path.as_ref(), content)
+            })
+    }
+}
+
+impl FileSource for VirtualFs {
+    fn exists(&self, path: &str) -> bool {
+        self.is_dir(path) || normalize_path(path).is_ok_and(|path| self.files.contains_key(&path))
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        let mut dir = match normalize_path(path) {
+            Ok(dir) => dir,
+            Err(_) => return false,
+        };
+        if !dir.ends_with('/') {
+            dir.push('/');
+        }
+        self.files.keys().any(|file| file.starts_with(&dir))
+    }
+
+    fn read(&self, path: &str) -> io::Result<Arc<Vec<u8>>> {
+        normalize_path(path)
+            .ok()
+            .and_then(|path| self.files.get(&path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No file {}", path)))
+    }
+}
+
+#[test]
+fn test_virtual_fs() {
+    let files = VirtualFs::from_iter([
+        ("index.html", &b"<h1>home</h1>"[..]),
+        ("/docs/index.html", b"<h1>docs</h1>"),
+        ("docs/guide.txt", b"guide"),
+        ("../outside.txt", b"ignored"),
+    ]);
+    assert_eq!(files.len(), 3);
+    assert_eq!(*files.read("/docs/guide.txt").unwrap(), b"guide");
+    assert!(files.is_dir("/docs") && files.is_dir("/docs/") && files.is_dir("/"));
+    assert!(!files.is_dir("/docs/guide.txt"));
+    assert_eq!(
+        files.resolve("/docs", "index.html").as_deref(),
+        Some("/docs/index.html")
+    );
+    assert_eq!(
+        files.resolve("/", "index.html").as_deref(),
+        Some("/index.html")
+    );
+    assert_eq!(files.resolve("/missing", "index.html"), None);
+    for index in ["../index.html", "docs/index.html", "..", ""] {
+        assert_eq!(files.resolve("/docs", index), None, "{}", index);
+    }
+    assert_eq!(
+        files.resolve("/docs/guide.txt", "../x").as_deref(),
+        Some("/docs/guide.txt")
+    );
+
+    // Traversal resolves inside the tree or not at all
+    assert_eq!(
+        files
+            .resolve("/docs/../index.html", "index.html")
+            .as_deref(),
+        Some("/index.html")
+    );
+    assert_eq!(*files.read("//docs/./guide.txt").unwrap(), b"guide");
+    for path in ["/../outside.txt", "/docs/../../outside.txt", "/.."] {
+        assert!(files.resolve(path, "index.html").is_none(), "{}", path);
+        assert!(files.read(path).is_err(), "{}", path);
+    }
+
+    let disk = DiskFs::new("public");
+    assert!(disk.full_path("/../Cargo.toml").is_none());
+    assert!(disk.read("/../Cargo.toml").is_err());
+    assert!(!disk.exists("/docs/../../Cargo.toml"));
+}
@@ -0,0 +1,59 @@
+// A server running on threads of its own, for programs embedding it and for tests
+// Stopping it closes the listeners and every connection, so the ports can be bound again
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>, // Accepts connections, joins the workers once stopped
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        stop: Arc<AtomicBool>,
+        thread: JoinHandle<()>,
+    ) -> ServerHandle {
+        ServerHandle {
+            addr,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    // The address actually bound, with the port picked when 0 was asked for
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Stop accepting, drop every connection and wait for the workers to finish
+    // Dropping the handle does the same
+    pub fn stop(mut self) {
+        self.shut();
+    }
+
+    fn shut(&mut self) {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return,
+        };
+        // The accept loop sees it within ACCEPT_POLL, no connection needed
+        self.stop.store(true, Ordering::SeqCst);
+        if thread.join().is_err() {
+            eprintln!(
+                "Error stopping the server on {}, accept thread panicked",
+                self.addr
+            );
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shut();
+    }
+}
@@ -0,0 +1,141 @@
+// Response headers that keep the order they were added in
+// Names are matched case insensitive but written as they were given
+
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use std::ops::Index;
+
+// Written before every other header, in this order
+const LEADING: [&str; 3] = ["date", "server", "connection"];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+    index: HashMap<String, usize>, // Lowercase name to position in entries
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    // Set a header, an existing one keeps its position and returns the old value
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Option<String> {
+        let key = key.into();
+        let value = value.into();
+        match self.index.get(&key.to_lowercase()) {
+            Some(&i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.index.insert(key.to_lowercase(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.index
+            .get(&key.to_lowercase())
+            .map(|&i| self.entries[i].1.as_str())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(&key.to_lowercase())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let i = self.index.remove(&key.to_lowercase())?;
+        let (_, value) = self.entries.remove(i);
+        for position in self.index.values_mut() {
+            if *position > i {
+                *position -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Headers in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    // Order used on the wire: Date, Server and Connection first, then insertion order
+    pub fn canonical(&self) -> Vec<(&str, &str)> {
+        let leading = LEADING.iter().filter_map(|name| {
+            self.index
+                .get(*name)
+                .map(|&i| (self.entries[i].0.as_str(), self.entries[i].1.as_str()))
+        });
+        let rest = self
+            .iter()
+            .filter(|(k, _)| !LEADING.contains(&k.to_lowercase().as_str()));
+        leading.chain(rest).collect()
+    }
+}
+
+impl Index<&str> for Headers {
+    type Output = str;
+
+    fn index(&self, key: &str) -> &str {
+        self.get(key)
+            .unwrap_or_else(|| panic!("no header named {}", key))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Headers {
+        let mut headers = Headers::new();
+        for (key, value) in iter {
+            headers.insert(key, value);
+        }
+        headers
+    }
+}
+
+// A HashMap has no order, names are sorted so the result is still stable
+impl From<HashMap<String, String>> for Headers {
+    fn from(map: HashMap<String, String>) -> Headers {
+        let mut entries: Vec<(String, String)> = map.into_iter().collect();
+        entries.sort();
+        let mut headers = Headers::new();
+        for (key, value) in entries {
+            headers.insert(key, value);
+        }
+        headers
+    }
+}
+
+#[test]
+fn test_headers_order() {
+    let mut headers = Headers::new();
+    headers.insert("X-B", "1");
+    headers.insert("Content-Type", "text/plain");
+    headers.insert("connection", "close");
+    headers.insert("X-A", "2");
+    headers.insert("Server", "HTeaPot");
+    assert_eq!(
+        headers.insert("content-type", "text/html"),
+        Some("text/plain".to_string())
+    );
+    assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+    assert_eq!(&headers["x-a"], "2");
+
+    let names: Vec<&str> = headers.canonical().iter().map(|(k, _)| *k).collect();
+    assert_eq!(
+        names,
+        ["Server", "connection", "X-B", "Content-Type", "X-A"]
+    );
+
+    assert_eq!(headers.remove("X-B"), Some("1".to_string()));
+    assert_eq!(headers.get("X-A"), Some("2"));
+    let names: Vec<&str> = headers.iter().map(|(k, _)| k).collect();
+    assert_eq!(names, ["Content-Type", "connection", "X-A", "Server"]);
+}
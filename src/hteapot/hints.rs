@@ -0,0 +1,67 @@
+// Preload links announced with a 103 Early Hints before the handler runs
+// The final response repeats them in its Link header
+
+#[derive(Clone, Debug)]
+pub(crate) struct Preload {
+    pattern: String, // Exact path, or a prefix when it ends in *
+    link: String,
+}
+
+impl Preload {
+    pub(crate) fn new(pattern: &str, link: &str) -> Preload {
+        Preload {
+            pattern: pattern.to_string(),
+            link: link.trim().to_string(),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.pattern,
+        }
+    }
+}
+
+// Links for a path, in the order they were added
+pub(crate) fn links(preloads: &[Preload], path: &str) -> Vec<String> {
+    preloads
+        .iter()
+        .filter(|p| p.matches(path))
+        .map(|p| p.link.clone())
+        .collect()
+}
+
+// HTTP/1.0 clients don't know 1xx responses and would take it as the final one
+pub(crate) fn accepts_interim(request_line: &str) -> bool {
+    request_line.trim_end().ends_with("HTTP/1.1")
+}
+
+pub(crate) fn early_hints(links: &[String]) -> Vec<u8> {
+    let mut interim = "HTTP/1.1 103 Early Hints\r\n".to_string();
+    for link in links {
+        interim.push_str(&format!("Link: {}\r\n", link));
+    }
+    interim.push_str("\r\n");
+    interim.into_bytes()
+}
+
+#[test]
+fn test_preload_links() {
+    let preloads = [
+        Preload::new("/", "</style.css>; rel=preload; as=style"),
+        Preload::new("/docs/*", "</docs.js>; rel=preload; as=script"),
+    ];
+    assert_eq!(
+        links(&preloads, "/"),
+        ["</style.css>; rel=preload; as=style"]
+    );
+    assert_eq!(links(&preloads, "/docs/a.html").len(), 1);
+    assert!(links(&preloads, "/index.html").is_empty());
+    assert!(accepts_interim("GET / HTTP/1.1"));
+    assert!(!accepts_interim("GET / HTTP/1.0"));
+    assert_eq!(
+        early_hints(&links(&preloads, "/")),
+        b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style\r\n\r\n"
+    );
+}
@@ -0,0 +1,126 @@
+// Bounds on the request head, checked before it is parsed
+// The head is the request line and the headers up to and including the blank line
+
+use super::HttpStatus;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestLimits {
+    pub max_head_bytes: usize,   // Request line and every header together
+    pub max_line_bytes: usize,   // Request target, and each header line
+    pub max_header_count: usize, // Header lines
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_head_bytes: 16 * 1024,
+            max_line_bytes: 8 * 1024,
+            max_header_count: 100,
+        }
+    }
+}
+
+impl RequestLimits {
+    // The status to refuse data with, 414 for the request target and 431 for the headers
+    // A head still incomplete is only refused once it has grown past max_head_bytes
+    pub(crate) fn check(&self, data: &[u8]) -> Result<(), HttpStatus> {
+        let head_end = data.windows(4).position(|w| w == b"\r\n\r\n");
+        let head = match head_end {
+            Some(end) => &data[..end],
+            None => data,
+        };
+        let mut lines = head.split(|b| *b == b'\n');
+        let request_line = lines.next().unwrap_or_default();
+        let target = request_line
+            .split(|b| *b == b' ')
+            .nth(1)
+            .unwrap_or_default();
+        if target.len() > self.max_line_bytes {
+            return Err(HttpStatus::URITooLong);
+        }
+        let mut count = 0;
+        for line in lines {
+            count += 1;
+            if trim_cr(line).len() > self.max_line_bytes || count > self.max_header_count {
+                return Err(HttpStatus::RequestHeaderFieldsTooLarge);
+            }
+        }
+        let head_len = head_end.map_or(head.len(), |end| end + 4);
+        if head_len > self.max_head_bytes {
+            return Err(HttpStatus::RequestHeaderFieldsTooLarge);
+        }
+        Ok(())
+    }
+
+    // Whether read bytes already hold more head than allowed, so reading can stop
+    // head_len counts the blank line, when it was read
+    pub(crate) fn head_too_long(&self, read: usize, head_len: Option<usize>) -> bool {
+        read > self.max_head_bytes && head_len.is_none_or(|len| len > self.max_head_bytes)
+    }
+}
+
+// What happens to connections past the cap of set_max_connections
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionOverflow {
+    Reject,  // Accepted, answered with a 503 and closed
+    Backlog, // Left in the listen backlog until a connection closes
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+fn head(target_len: usize, headers: &[usize]) -> Vec<u8> {
+    let mut head = format!("GET /{} HTTP/1.1\r\n", "a".repeat(target_len - 1));
+    for len in headers {
+        head.push_str(&format!("X: {}\r\n", "v".repeat(len - 3)));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+#[test]
+fn test_request_limits() {
+    let limits = RequestLimits {
+        max_head_bytes: 200,
+        max_line_bytes: 50,
+        max_header_count: 5,
+    };
+    let status = |data: &[u8]| limits.check(data).err().map(|s| s as u16);
+
+    // Request target
+    assert_eq!(status(&head(49, &[])), None);
+    assert_eq!(status(&head(50, &[])), None);
+    assert_eq!(status(&head(51, &[])), Some(414));
+
+    // Each header line, without its CRLF
+    assert_eq!(status(&head(1, &[49])), None);
+    assert_eq!(status(&head(1, &[50])), None);
+    assert_eq!(status(&head(1, &[51])), Some(431));
+
+    // Header count
+    assert_eq!(status(&head(1, &[10; 4])), None);
+    assert_eq!(status(&head(1, &[10; 5])), None);
+    assert_eq!(status(&head(1, &[10; 6])), Some(431));
+
+    // The whole head, 16 for the request line, 2 for the blank line and 2 per header
+    let at = head(1, &[50, 50, 50, 24]);
+    assert_eq!(at.len(), 200);
+    assert_eq!(status(&head(1, &[50, 50, 50, 23])), None);
+    assert_eq!(status(&at), None);
+    assert_eq!(status(&head(1, &[50, 50, 50, 25])), Some(431));
+
+    // A body doesn't count, an unfinished head does once it is too long
+    let mut with_body = head(1, &[10]);
+    with_body.extend_from_slice(&[b'x'; 500]);
+    assert_eq!(status(&with_body), None);
+    let unfinished = &head(1, &[10])[..30];
+    assert_eq!(status(unfinished), None);
+    assert!(!limits.head_too_long(unfinished.len(), None));
+    let endless = [b'x'; 201];
+    assert_eq!(status(&endless), Some(431));
+    assert!(limits.head_too_long(endless.len(), None));
+    assert!(!limits.head_too_long(with_body.len(), Some(30)));
+    assert!(limits.head_too_long(300, Some(201)));
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HttpMethod {
     GET,
     POST,
@@ -13,6 +13,7 @@ pub enum HttpMethod {
 }
 
 impl HttpMethod {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(method: &str) -> HttpMethod {
         match method {
             "GET" => HttpMethod::GET,
@@ -2,20 +2,72 @@
 // This is the HTTP server module, it will handle the requests and responses
 // Also provide utilities to parse the requests and build the responses
 
+mod address;
+mod auth;
+mod chaos;
+mod digest;
+mod extensions;
+mod files;
+mod handle;
+mod headers;
+mod hints;
+mod limits;
 mod methods;
+mod negotiate;
+mod parsing;
+mod postprocess;
+mod proxy_protocol;
+mod readiness;
 mod response;
+mod shutdown;
+mod stats;
 mod status;
+mod supervisor;
+mod throttle;
+mod tls;
+mod workers;
 
+pub use self::address::{bind_host, format_addr, unbracket};
+pub use self::auth::{
+    authorize, basic_credentials, constant_time_eq, verify_password, AuthBackend, Htpasswd,
+    TokenList,
+};
+pub use self::chaos::Chaos;
+pub use self::digest::{base64_encode, sha1, sha256};
+pub use self::extensions::Extensions;
+pub use self::files::{DiskFs, FileSource, VirtualFs};
+pub use self::handle::ServerHandle;
+pub use self::headers::Headers;
+pub use self::limits::{ConnectionOverflow, RequestLimits};
 pub use self::methods::HttpMethod;
-pub use self::response::HttpResponse;
+pub use self::negotiate::{
+    negotiate_encoding, negotiate_media_type, parse_accept, parse_quality_list, MediaRange,
+};
+pub use self::parsing::{Deviation, ParsingRules};
+pub use self::postprocess::{InjectHtml, ResponsePostProcessor};
+pub use self::response::{CacheLifetime, ChunkSender, HttpResponse, ResponseKind};
+pub use self::shutdown::{HookOutcome, InFlight, ShutdownHook, ShutdownHooks, ShutdownReport};
+pub use self::stats::{QueueWaits, ServerStats, QUEUE_WAIT_BUCKETS_MS};
 pub use self::status::HttpStatus;
 
+use self::chaos::{ChaosState, Fault};
+use self::hints::Preload;
+use self::parsing::BodyLength;
+use self::postprocess::PostProcessor;
+use self::readiness::{Interest, Waker};
+use self::response::OutBuffer;
+use self::shutdown::{panic_message, InFlightGuard};
+use self::supervisor::RestartBudget;
+use self::throttle::{Bucket, MAX_GRANT};
+use self::workers::HijackPool;
 use std::collections::{HashMap, VecDeque};
-use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -23,40 +75,357 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 macro_rules! headers {
     ( $($k:expr => $v:expr),*) => {
         {
-            use std::collections::HashMap;
-            let mut headers: HashMap<String, String> = HashMap::new();
-            $( headers.insert($k.to_string(), $v.to_string()); )*
-            Some(headers)
+            // Collected into Headers, in the order given
+            let headers: Vec<(String, String)> = vec![$( ($k.to_string(), $v.to_string()) ),*];
+            Some(headers.into_iter().collect())
         }
     };
 }
 
 pub struct HttpRequest {
     pub method: HttpMethod,
-    pub path: String,
+    pub path: String, // Normalized, without duplicate slashes or . and .. segments
+    pub raw_path: String, // As the client sent it, without the query
+    pub query: String,
     pub args: HashMap<String, String>,
     pub headers: HashMap<String, String>,
     pub body: String,
+    // The client, as named by the PROXY header when the server expects one
+    pub remote_addr: Option<SocketAddr>,
+    // Data attached while the request is handled, dropped with the request
+    pub extensions: Extensions,
+}
+
+impl HttpRequest {
+    // Address of the client without its port, like for logs and per client limits
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.remote_addr.map(|addr| addr.ip())
+    }
 }
 
 pub struct Hteapot {
     port: u16,
     address: String,
     threads: u16,
+    connection_max_lifetime: Option<Duration>,
+    keep_alive_ttl: Option<Duration>, // Idle time before a kept connection is closed
+    keep_alive_max_requests: Option<usize>,
+    header_read_timeout: Option<Duration>, // From accept or the last response to a complete head
+    chaos: Option<Arc<ChaosState>>,
+    stats: Arc<ServerStats>,
+    post_processors: Vec<PostProcessor>,
+    preloads: Vec<Preload>,
+    max_bandwidth: Option<u64>,
+    max_bandwidth_per_conn: Option<u64>,
+    accept_queue_limit: Option<usize>,
+    max_connections: Option<(usize, ConnectionOverflow)>,
+    max_connections_per_ip: Option<(usize, Vec<IpAddr>)>, // With the addresses left out of it
+    hijack_workers: Option<(usize, usize)>, // Workers and queue limit, None spawns a thread each
+    log_handshake_failures: bool,
+    request_limits: RequestLimits,
+    parsing_rules: ParsingRules,
+    proxy_protocol: bool,
+    worker_restart_budget: usize, // Dead workers started again per hour before the server stops
+    queue_wait_warning: Duration, // Longer waits for a worker are logged, rate limited
+    queue_depth_warning: usize,   // Same for more connections waiting than this
+    extra_listeners: Vec<(String, u16)>, // Addresses from add_listener, besides the one from new
+    bound: Mutex<Vec<SocketAddr>>, // What the listeners got, port 0 replaced by the real one
+    max_body_size: Option<usize>,
+    extra_methods: Vec<String>, // Let through to the handler, any other unknown method gets a 501
+    strict_lengths: bool,       // A handler's wrong Content-Length is a 500 instead of corrected
+    shutdown_hooks: Arc<ShutdownHooks>,
+    fatal_hook: Option<FatalHook>,
+    in_flight: Arc<InFlight>,
+}
+
+// New connections a worker takes from the queue per pass over its connections
+// Each connection it already has gets one read or write per pass
+const ACCEPT_PER_PASS: usize = 8;
+// Worker restarts per hour, past them the server stops
+const DEFAULT_RESTART_BUDGET: usize = 20;
+// Longest a worker waits on its connections before looking at the queue anyway
+// Short without a waker, since then nothing tells it a connection was queued
+const MAX_WAIT: Duration = Duration::from_secs(1);
+const MAX_WAIT_WITHOUT_WAKER: Duration = Duration::from_millis(50);
+// Pause before writing again to a connection whose last pass wrote nothing, like a throttled one
+const STALLED_WRITE_RETRY: Duration = Duration::from_millis(10);
+// Queue wait and depth past which the server warns that it may need more threads
+const DEFAULT_QUEUE_WAIT_WARNING: Duration = Duration::from_millis(250);
+const DEFAULT_QUEUE_DEPTH_WARNING: usize = 256;
+// Time to send a request head, slow clients can't keep a connection forever
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the accept loop looks again when max_connections keeps new ones in the backlog
+const BACKLOG_POLL: Duration = Duration::from_millis(10);
+// Longest the accept loop waits for a connection before looking at stop again
+const ACCEPT_POLL: Duration = Duration::from_millis(250);
+// Failed accepts in a row reported as fatal, like out of file descriptors, and the pause after each
+const ACCEPT_FAILURES_FATAL: usize = 100;
+const ACCEPT_RETRY: Duration = Duration::from_millis(10);
+
+// Called with the message of each fatal event, see set_fatal_hook
+pub type FatalHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+// Fatal events go to stderr, and to the hook when there is one
+pub(crate) fn report_fatal(hook: Option<&FatalHook>, message: String) {
+    eprintln!("{}", message);
+    if let Some(hook) = hook {
+        hook(&message);
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 struct SocketStatus {
     // TODO: write proper ttl
     reading: bool,
+    active: bool,
     data_readed: Vec<u8>,
-    data_write: Vec<u8>,
+    pipelined: Vec<u8>, // Read past the request being answered, the start of the next ones
+    head_len: Option<usize>, // Up to and including the blank line, once it was read
+    scanned: usize,     // How much of data_readed was searched for it
+    body: BodyLength,   // As the head says, once it was read
+    chunks_read: usize, // Body taken by the whole chunks read so far, the next one starts there
+    data_write: OutBuffer,
     index_writed: usize,
+    write_limit: Option<usize>,
+    write_started: Option<Instant>,
+    bucket: Option<Bucket>, // Per connection bandwidth, kept across requests
+    remote_addr: Option<SocketAddr>,
+    identified: bool, // remote_addr worked out, from the PROXY header when expected
+    requests: usize,  // Responses sent in full on this connection
+    idle_since: Instant, // Accepted or last response sent, while waiting for a request
+    in_flight: Option<InFlightGuard>, // From the complete head until the response is written
+}
+
+impl SocketStatus {
+    // Search only what was read since the last time, a head sent in small pieces stays linear
+    // Lines may end in a bare \n, which lenient parsing accepts
+    fn find_head_end(&mut self) {
+        if self.head_len.is_some() {
+            return;
+        }
+        let data = &self.data_readed;
+        for i in self.scanned.saturating_sub(2)..data.len() {
+            if data[i] != b'\n' {
+                continue;
+            }
+            let blank = match &data[i + 1..] {
+                [b'\n', ..] => 1,
+                [b'\r', b'\n', ..] => 2,
+                _ => continue,
+            };
+            self.head_len = Some(i + 1 + blank);
+            self.body = parsing::body_length(&data[..i + 1 + blank]);
+            break;
+        }
+        self.scanned = data.len();
+    }
+
+    // The whole head is in, or a TLS handshake that is refused as soon as it is seen
+    fn head_complete(&self) -> bool {
+        self.head_len.is_some() || tls::client_hello_version(&self.data_readed).is_some()
+    }
+
+    // The head, and the whole body when it has one
+    fn request_complete(&mut self) -> bool {
+        self.head_complete() && self.body_end() != Ok(None)
+    }
+
+    // Why the body can't be read as the head frames it, once the request is complete
+    fn framing_error(&mut self) -> Option<&'static str> {
+        self.body_end().err()
+    }
+
+    // Keep only the request being answered, anything sent after it waits in pipelined
+    fn split_pipelined(&mut self) {
+        let end = match (self.head_len, self.body_end()) {
+            (Some(head_len), Ok(Some(body_len))) => head_len + body_len,
+            _ => return,
+        };
+        self.pipelined = self.data_readed.split_off(end);
+    }
+
+    // Where the body ends, chunks are looked at once however many passes they take to arrive
+    fn body_end(&mut self) -> Result<Option<usize>, &'static str> {
+        let data = &self.data_readed;
+        let body = self.head_len.map_or(&[][..], |len| &data[len..]);
+        parsing::body_end(self.body, body, &mut self.chunks_read)
+    }
+
+    // Back to reading, starting with whatever was pipelined after the last request
+    fn next_request(&mut self) {
+        self.data_readed = std::mem::take(&mut self.pipelined);
+        self.reset_head();
+        self.find_head_end();
+        self.reading = true;
+        self.active = !self.data_readed.is_empty();
+    }
+
+    // What was read past the head, the body so far
+    fn body_read(&self) -> &[u8] {
+        self.head_len
+            .map_or(&[][..], |len| &self.data_readed[len..])
+    }
+
+    fn reset_head(&mut self) {
+        self.head_len = None;
+        self.scanned = 0;
+        self.body = BodyLength::Empty;
+        self.chunks_read = 0;
+    }
+}
+
+// What every connection needs besides the handler, shared by the workers
+struct WorkerSettings {
+    chaos: Option<Arc<ChaosState>>,
+    post_processors: Vec<PostProcessor>,
+    preloads: Vec<Preload>,
+    bandwidth: Option<Mutex<Bucket>>, // Shared by all connections
+    bandwidth_per_conn: Option<u64>,
+    hijacks: Option<HijackPool>,
+    stats: Arc<ServerStats>,
+    log_handshake_failures: bool,
+    request_limits: RequestLimits,
+    parsing_rules: ParsingRules,
+    proxy_protocol: bool,
+    keep_alive_ttl: Option<Duration>,
+    keep_alive_max_requests: Option<usize>,
+    queue_wait_warning: Duration,
+    max_body_size: Option<usize>,
+    extra_methods: Vec<String>,
+    strict_lengths: bool,
+    header_read_timeout: Option<Duration>,
+    in_flight: Arc<InFlight>,
+}
+
+impl WorkerSettings {
+    // Bytes a connection may write now, None when nothing is throttled
+    fn write_allowance(&self, socket_status: &mut SocketStatus) -> Option<usize> {
+        if self.bandwidth.is_none() && self.bandwidth_per_conn.is_none() {
+            return None;
+        }
+        let mut allowed = MAX_GRANT;
+        if let Some(rate) = self.bandwidth_per_conn {
+            let bucket = socket_status
+                .bucket
+                .get_or_insert_with(|| Bucket::new(rate));
+            allowed = allowed.min(bucket.available());
+        }
+        if let Some(bucket) = &self.bandwidth {
+            let mut bucket = bucket.lock().expect("Error locking bandwidth");
+            allowed = allowed.min(bucket.available());
+        }
+        Some(allowed)
+    }
+
+    // A declared length past max_body_size is refused before any of the body is read,
+    // a chunked one as soon as it grows past it
+    fn body_too_large(&self, socket_status: &SocketStatus) -> bool {
+        let max = match self.max_body_size {
+            Some(max) => max,
+            None => return false,
+        };
+        match socket_status.body {
+            BodyLength::Empty => false,
+            BodyLength::Fixed(len) => len > max,
+            BodyLength::Chunked => socket_status.body_read().len() > max,
+            BodyLength::Invalid(_) => false,
+        }
+    }
+
+    fn spend(&self, socket_status: &mut SocketStatus, bytes: usize) {
+        if let Some(bucket) = socket_status.bucket.as_mut() {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = &self.bandwidth {
+            bucket
+                .lock()
+                .expect("Error locking bandwidth")
+                .consume(bytes);
+        }
+    }
+}
+
+// Counts a connection as open until it is dropped, by the queue or a worker
+// Also accounts for the bytes its buffers hold, given back with it
+struct ConnectionSlot {
+    stats: Arc<ServerStats>,
+    buffers: (usize, usize), // Read and response bytes last reported
+    ip: Option<IpAddr>,      // Counted for max_connections_per_ip, released on drop
+}
+
+impl ConnectionSlot {
+    fn new(stats: &Arc<ServerStats>, ip: Option<IpAddr>) -> ConnectionSlot {
+        stats.connection_opened();
+        ConnectionSlot {
+            stats: stats.clone(),
+            buffers: (0, 0),
+            ip,
+        }
+    }
+
+    // After each pass over the connection, None once it is closed
+    fn held(&mut self, status: Option<&SocketStatus>) {
+        let buffers = status.map_or((0, 0), |s| (s.data_readed.len(), s.data_write.len()));
+        self.stats.buffers_changed(self.buffers, buffers);
+        self.buffers = buffers;
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.held(None);
+        self.stats.connection_closed();
+        if let Some(ip) = self.ip {
+            self.stats.ip_connection_closed(ip);
+        }
+    }
+}
+
+// An accepted connection waiting for a worker
+struct Queued {
+    stream: TcpStream,
+    queued_at: Instant,
+    slot: ConnectionSlot,
 }
 
 struct SocketData {
     stream: TcpStream,
+    slot: ConnectionSlot,
+    accepted: Instant,
     status: Option<SocketStatus>,
+    retry_at: Option<Instant>, // Handled again then without waiting for readiness
+}
+
+impl SocketData {
+    // When it started waiting for a request, None while one is read or answered
+    fn idle_since(&self) -> Option<Instant> {
+        match &self.status {
+            Some(status) if status.reading && !status.active => Some(status.idle_since),
+            _ => None,
+        }
+    }
+
+    // When the head being read was due to start, None once it is complete
+    // A kept connection without a byte of its next request is idle instead, see idle_since
+    fn head_started(&self) -> Option<Instant> {
+        match &self.status {
+            Some(status)
+                if status.reading
+                    && !status.head_complete()
+                    && (status.active || status.requests == 0) =>
+            {
+                Some(status.idle_since)
+            }
+            _ => None,
+        }
+    }
+
+    fn interest(&self) -> Interest {
+        match &self.status {
+            Some(status) if !status.reading => Interest::Write,
+            _ => Interest::Read,
+        }
+    }
 }
 
 impl Hteapot {
@@ -66,6 +435,35 @@ impl Hteapot {
             port,
             address: address.to_string(),
             threads: 1,
+            connection_max_lifetime: None,
+            keep_alive_ttl: None,
+            keep_alive_max_requests: None,
+            header_read_timeout: Some(DEFAULT_HEADER_READ_TIMEOUT),
+            chaos: None,
+            stats: Arc::new(ServerStats::default()),
+            post_processors: Vec::new(),
+            preloads: Vec::new(),
+            max_bandwidth: None,
+            max_bandwidth_per_conn: None,
+            accept_queue_limit: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            hijack_workers: None,
+            log_handshake_failures: true,
+            request_limits: RequestLimits::default(),
+            parsing_rules: ParsingRules::default(),
+            proxy_protocol: false,
+            worker_restart_budget: DEFAULT_RESTART_BUDGET,
+            queue_wait_warning: DEFAULT_QUEUE_WAIT_WARNING,
+            queue_depth_warning: DEFAULT_QUEUE_DEPTH_WARNING,
+            extra_listeners: Vec::new(),
+            bound: Mutex::new(Vec::new()),
+            max_body_size: None,
+            extra_methods: Vec::new(),
+            strict_lengths: false,
+            shutdown_hooks: Arc::new(ShutdownHooks::default()),
+            fatal_hook: None,
+            in_flight: Arc::new(InFlight::default()),
             //cache: HashMap::new(),
         }
     }
@@ -75,38 +473,364 @@ impl Hteapot {
             port,
             address: address.to_string(),
             threads: if threads == 0 { 1 } else { threads },
+            connection_max_lifetime: None,
+            keep_alive_ttl: None,
+            keep_alive_max_requests: None,
+            header_read_timeout: Some(DEFAULT_HEADER_READ_TIMEOUT),
+            chaos: None,
+            stats: Arc::new(ServerStats::default()),
+            post_processors: Vec::new(),
+            preloads: Vec::new(),
+            max_bandwidth: None,
+            max_bandwidth_per_conn: None,
+            accept_queue_limit: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            hijack_workers: None,
+            log_handshake_failures: true,
+            request_limits: RequestLimits::default(),
+            parsing_rules: ParsingRules::default(),
+            proxy_protocol: false,
+            worker_restart_budget: DEFAULT_RESTART_BUDGET,
+            queue_wait_warning: DEFAULT_QUEUE_WAIT_WARNING,
+            queue_depth_warning: DEFAULT_QUEUE_DEPTH_WARNING,
+            extra_listeners: Vec::new(),
+            bound: Mutex::new(Vec::new()),
+            max_body_size: None,
+            extra_methods: Vec::new(),
+            strict_lengths: false,
+            shutdown_hooks: Arc::new(ShutdownHooks::default()),
+            fatal_hook: None,
+            in_flight: Arc::new(InFlight::default()),
             //cache: HashMap::new(),
         }
     }
 
-    // Start the server
-    pub fn listen(&self, action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static) {
-        let addr = format!("{}:{}", self.address, self.port);
-        let listener = TcpListener::bind(addr);
-        let listener = match listener {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("Error L: {}", e);
-                return;
-            }
-        };
-        let pool: Arc<(Mutex<VecDeque<TcpStream>>, Condvar)> =
+    // Close keep-alive connections once they have been open for this long
+    // The in-flight response is completed and sent with Connection: close
+    pub fn set_connection_max_lifetime(&mut self, lifetime: Duration) {
+        self.connection_max_lifetime = Some(lifetime);
+    }
+
+    // Close kept connections left without a new request for this long
+    // Sent to clients as Keep-Alive: timeout
+    pub fn set_keep_alive_ttl(&mut self, ttl: Duration) {
+        self.keep_alive_ttl = Some(ttl);
+    }
+
+    // Requests served on one connection, the last one is sent with Connection: close
+    // Sent to clients as Keep-Alive: max, counting down
+    pub fn set_keep_alive_max_requests(&mut self, max: usize) {
+        self.keep_alive_max_requests = Some(max.max(1));
+    }
+
+    // Time a client has to send a whole request head, else it gets a 408 and is closed
+    // Counted from accept, or from the last response on a kept connection once a byte came in
+    pub fn set_header_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.header_read_timeout = timeout;
+    }
+
+    // Inject faults into the responses, only meant for testing clients
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(Arc::new(ChaosState::new(chaos)));
+    }
+
+    // Run a processor on every response with this content type, like "text/html" or "text/*"
+    // Processors run in the order they were added
+    pub fn add_post_processor(
+        &mut self,
+        content_type: &str,
+        processor: impl ResponsePostProcessor + 'static,
+    ) {
+        self.post_processors
+            .push(PostProcessor::new(content_type, Arc::new(processor)));
+    }
+
+    // Announce a Link, like "</style.css>; rel=preload; as=style", for an exact path or a "prefix*"
+    // HTTP/1.1 clients get it in a 103 Early Hints before the handler runs, everyone in the response
+    pub fn add_preload(&mut self, pattern: &str, link: &str) {
+        self.preloads.push(Preload::new(pattern, link));
+    }
+
+    // Cap on bytes per second written across all connections
+    pub fn set_max_bandwidth(&mut self, bytes_per_sec: u64) {
+        self.max_bandwidth = Some(bytes_per_sec);
+    }
+
+    // Cap on bytes per second written to each connection
+    pub fn set_max_bandwidth_per_conn(&mut self, bytes_per_sec: u64) {
+        self.max_bandwidth_per_conn = Some(bytes_per_sec);
+    }
+
+    // Most accepted connections waiting for a worker, the rest get a 503 and are closed
+    pub fn set_accept_queue_limit(&mut self, limit: usize) {
+        self.accept_queue_limit = Some(limit);
+    }
+
+    // Most connections open at once, queued and handled ones together
+    // Past it new ones get a 503 and are closed, or wait in the listen backlog
+    pub fn set_max_connections(&mut self, max: usize, overflow: ConnectionOverflow) {
+        self.max_connections = Some((max.max(1), overflow));
+    }
+
+    // Most connections open at once from one client address, past it new ones get a 429
+    // Addresses in exempt aren't limited, like proxies many clients come through
+    // With proxy_protocol it is the address of the connecting proxy that counts
+    pub fn set_max_connections_per_ip(&mut self, max: usize, exempt: Vec<IpAddr>) {
+        self.max_connections_per_ip = Some((max.max(1), exempt));
+    }
+
+    // Run hijack handlers on this many threads instead of a new one per response
+    // Up to queue_limit more wait for a free worker, the rest get a 503
+    pub fn set_hijack_workers(&mut self, workers: usize, queue_limit: usize) {
+        self.hijack_workers = Some((workers, queue_limit));
+    }
+
+    // Warn about each TLS handshake sent to this plain HTTP port, they are counted either way
+    // Internet facing ports see these from scanners all the time
+    pub fn set_log_handshake_failures(&mut self, log: bool) {
+        self.log_handshake_failures = log;
+    }
+
+    // Largest request heads accepted, bigger ones get a 414 or 431 and are closed
+    pub fn set_request_limits(&mut self, limits: RequestLimits) {
+        self.request_limits = limits;
+    }
+
+    // Largest request body accepted, bigger ones get a 413 and the connection is closed
+    // Chunked bodies are counted with their chunk sizes, as they come in
+    pub fn set_max_body_size(&mut self, bytes: usize) {
+        self.max_body_size = Some(bytes);
+    }
+
+    // Methods beyond the standard ones passed to the handler, like PROPFIND for WebDAV
+    // Any other method is answered with a 501 before the handler sees it
+    pub fn set_extra_methods(&mut self, methods: Vec<String>) {
+        self.extra_methods = methods;
+    }
+
+    // Buffered responses whose Content-Length doesn't match their body are corrected with a note
+    // Strict sends a 500 instead, so such bugs show up in development
+    pub fn set_strict_lengths(&mut self, strict: bool) {
+        self.strict_lengths = strict;
+    }
+
+    // Which deviations from the grammar are answered with a 400, see ParsingRules
+    pub fn set_parsing_rules(&mut self, rules: ParsingRules) {
+        self.parsing_rules = rules;
+    }
+
+    // Expect a PROXY protocol header, v1 or v2, at the start of every connection
+    // Connections without one are refused, only turn it on behind a balancer that sends it
+    pub fn set_proxy_protocol(&mut self, enabled: bool) {
+        self.proxy_protocol = enabled;
+    }
+
+    // Workers that die are started again, up to this many times per hour
+    // One more death runs the shutdown hooks and exits the process
+    pub fn set_worker_restart_budget(&mut self, restarts_per_hour: usize) {
+        self.worker_restart_budget = restarts_per_hour;
+    }
+
+    // Warn when a connection waits longer than this for a worker, or more than depth are waiting
+    // At most one warning a minute, the queue is in stats() either way
+    pub fn set_queue_warnings(&mut self, wait: Duration, depth: usize) {
+        self.queue_wait_warning = wait;
+        self.queue_depth_warning = depth;
+    }
+
+    // Also hand fatal events to hook: dead workers, handler panics included, running out of
+    // restarts and a listener failing to accept over and over
+    // It runs on the thread that hit the event, so it should queue the message and return
+    pub fn set_fatal_hook(&mut self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.fatal_hook = Some(Arc::new(hook));
+    }
+
+    // Shared counters, can be read while the server is listening
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    // Run by whoever stops the server, through shutdown_hooks().run()
+    pub fn add_shutdown_hook(&self, hook: ShutdownHook) {
+        self.shutdown_hooks.add(hook);
+    }
+
+    // The registered hooks, can be run while the server is listening
+    pub fn shutdown_hooks(&self) -> Arc<ShutdownHooks> {
+        self.shutdown_hooks.clone()
+    }
+
+    // Requests being answered, a graceful stop closes connections and waits for them to finish
+    pub fn in_flight(&self) -> Arc<InFlight> {
+        self.in_flight.clone()
+    }
+
+    // Also listen on this address, with the same handler and workers
+    pub fn add_listener(&mut self, address: &str, port: u16) {
+        self.extra_listeners.push((address.to_string(), port));
+    }
+
+    // The address and port given to new
+    pub fn get_addr(&self) -> (String, u16) {
+        (self.address.clone(), self.port)
+    }
+
+    // Every address listened on, the one given to new first
+    pub fn get_addrs(&self) -> Vec<(String, u16)> {
+        let mut addrs = vec![self.get_addr()];
+        addrs.extend(self.extra_listeners.iter().cloned());
+        addrs
+    }
+
+    // The address the first listener is bound to, once serving, with the port the OS picked for 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs().first().copied()
+    }
+
+    // Same for every listener, in the order of get_addrs
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.bound
+            .lock()
+            .expect("Error locking bound addresses")
+            .clone()
+    }
+
+    fn bind_all(&self) -> io::Result<Vec<TcpListener>> {
+        let mut listeners = Vec::new();
+        for (address, port) in self.get_addrs() {
+            listeners.extend(bind_host(&address, port)?);
+        }
+        Ok(listeners)
+    }
+
+    // Start the server, only returns when an address can't be bound, like a port in use
+    pub fn listen(
+        &self,
+        action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) -> io::Result<()> {
+        let listeners = self.bind_all()?;
+        self.listen_on_all(listeners, action);
+        Ok(())
+    }
+
+    // Serve on a listener bound by the caller, like one on port 0 whose address is read first
+    // The addresses given to new and add_listener are not used
+    pub fn listen_on(
+        &self,
+        listener: TcpListener,
+        action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) {
+        self.listen_on_all(vec![listener], action);
+    }
+
+    // Same for several listeners sharing the workers, there must be at least one
+    pub fn listen_on_all(
+        &self,
+        listeners: Vec<TcpListener>,
+        action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) {
+        assert!(!listeners.is_empty(), "listen_on_all needs a listener");
+        self.serve(listeners, action, Arc::new(AtomicBool::new(false)));
+    }
+
+    // Serve from threads of its own, the handle stops the server and tells the bound address
+    pub fn run_background(
+        self,
+        action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) -> io::Result<ServerHandle> {
+        let listeners = self.bind_all()?;
+        let addr = listeners[0].local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_stop = stop.clone();
+        let thread = thread::spawn(move || self.serve(listeners, action, server_stop));
+        Ok(ServerHandle::new(addr, stop, thread))
+    }
+
+    // Accept until stop is set, then wait for the workers to drop their connections
+    // Each accept loop looks at stop at least every ACCEPT_POLL
+    fn serve(
+        &self,
+        listeners: Vec<TcpListener>,
+        action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+        stop: Arc<AtomicBool>,
+    ) {
+        *self.bound.lock().expect("Error locking bound addresses") = listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect();
+        let pool: Arc<(Mutex<VecDeque<Queued>>, Condvar)> =
             Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
         //let statusPool = Arc::new(Mutex::new(HashMap::<String, socketStatus>::new()));
         let priority_list: Arc<Mutex<Vec<usize>>> = Arc::new(Mutex::new(Vec::new()));
         let arc_action = Arc::new(action);
-        for _tn in 0..self.threads {
-            let _tn = _tn as usize;
-            let pool_clone = pool.clone();
+        let settings = Arc::new(WorkerSettings {
+            chaos: self.chaos.clone(),
+            post_processors: self.post_processors.clone(),
+            preloads: self.preloads.clone(),
+            bandwidth: self.max_bandwidth.map(|rate| Mutex::new(Bucket::new(rate))),
+            bandwidth_per_conn: self.max_bandwidth_per_conn,
+            hijacks: self
+                .hijack_workers
+                .map(|(workers, queue_limit)| HijackPool::new(workers, queue_limit)),
+            stats: self.stats.clone(),
+            log_handshake_failures: self.log_handshake_failures,
+            request_limits: self.request_limits,
+            parsing_rules: self.parsing_rules,
+            proxy_protocol: self.proxy_protocol,
+            keep_alive_ttl: self.keep_alive_ttl,
+            keep_alive_max_requests: self.keep_alive_max_requests,
+            queue_wait_warning: self.queue_wait_warning,
+            max_body_size: self.max_body_size,
+            extra_methods: self.extra_methods.clone(),
+            strict_lengths: self.strict_lengths,
+            header_read_timeout: self.header_read_timeout,
+            in_flight: self.in_flight.clone(),
+        });
+        priority_list
+            .lock()
+            .expect("Error locking prority list")
+            .resize(self.threads as usize, 0);
+        let worker_pool = pool.clone();
+        // Without one, busy workers look at the queue every MAX_WAIT_WITHOUT_WAKER
+        let waker = match Waker::new() {
+            Ok(waker) => Some(Arc::new(waker)),
+            Err(e) => {
+                eprintln!(
+                    "WARNING: no waker for busy workers, new connections may wait: {}",
+                    e
+                );
+                None
+            }
+        };
+        let worker_waker = waker.clone();
+        let stats = self.stats.clone();
+        let max_lifetime = self.connection_max_lifetime;
+        let worker_stop = stop.clone();
+        // Starts the worker at an index, again when the supervisor finds it dead
+        let spawn_worker = move |_tn: usize| -> JoinHandle<()> {
+            let pool_clone = worker_pool.clone();
             let action_clone = arc_action.clone();
             let pl_clone = priority_list.clone();
+            let stats_clone = stats.clone();
+            let settings = settings.clone();
+            let waker = worker_waker.clone();
+            let stop = worker_stop.clone();
             {
                 let mut pl_lock = pl_clone.lock().expect("Error locking prority list");
-                pl_lock.push(0);
+                pl_lock[_tn] = 0;
             }
+            stats.worker_connections_changed(_tn, 0);
             thread::spawn(move || {
-                let mut streams_to_handle = Vec::new();
+                let mut streams_to_handle: Vec<SocketData> = Vec::new();
                 loop {
+                    // Its connections are closed as they are dropped
+                    if stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if let Some(waker) = waker.as_ref() {
+                        waker.drain();
+                    }
                     {
                         let (lock, cvar) = &*pool_clone;
                         let mut pool = lock.lock().expect("Error locking pool");
@@ -118,82 +842,344 @@ impl Hteapot {
 
                         if streams_to_handle.is_empty() {
                             pool = cvar
-                                .wait_while(pool, |pool| pool.is_empty())
-                                .expect("Error waiting on cvar");
-                        } else if pl_copy.len() != 1
-                            && streams_to_handle.len() < 10
-                            && pl_copy
-                                .iter()
-                                .find(|&&v| streams_to_handle.len() > v)
-                                .is_none()
-                        {
-                            (pool, _) = cvar
-                                .wait_timeout_while(pool, Duration::from_millis(500), |pool| {
-                                    pool.is_empty()
+                                .wait_while(pool, |pool| {
+                                    pool.is_empty() && !stop.load(Ordering::SeqCst)
                                 })
                                 .expect("Error waiting on cvar");
                         }
+                        // Busy workers leave new connections to the least loaded ones
+                        let least_loaded = pl_copy.iter().all(|&v| streams_to_handle.len() <= v);
 
-                        if !pool.is_empty() {
+                        // Take a batch so new connections don't wait behind busy ones
+                        let mut taken = 0;
+                        while least_loaded && taken < ACCEPT_PER_PASS {
+                            let Queued {
+                                stream,
+                                queued_at,
+                                slot,
+                            } = match pool.pop_back() {
+                                Some(queued) => queued,
+                                None => break,
+                            };
+                            let wait = queued_at.elapsed();
+                            stats_clone.queue_wait(wait);
+                            stats_clone.queue_depth_changed(pool.len());
+                            if wait > settings.queue_wait_warning && stats_clone.pool_warning_due()
+                            {
+                                eprintln!(
+                                    "WARNING: a connection waited {}ms for a worker with {} more queued, more threads may help",
+                                    wait.as_millis(),
+                                    pool.len()
+                                );
+                            }
                             let socket_status = SocketStatus {
                                 reading: true,
+                                active: false,
                                 data_readed: vec![],
-                                data_write: vec![],
+                                pipelined: vec![],
+                                head_len: None,
+                                scanned: 0,
+                                body: BodyLength::Empty,
+                                chunks_read: 0,
+                                data_write: OutBuffer::default(),
                                 index_writed: 0,
+                                write_limit: None,
+                                write_started: None,
+                                bucket: None,
+                                remote_addr: None,
+                                identified: false,
+                                requests: 0,
+                                idle_since: Instant::now(),
+                                in_flight: None,
                             };
                             let socket_data = SocketData {
-                                stream: pool.pop_back().unwrap(),
+                                stream,
+                                slot,
+                                accepted: Instant::now(),
                                 status: Some(socket_status),
+                                retry_at: None,
                             };
+                            stats_clone.connection_changed(None, Some(false));
                             streams_to_handle.push(socket_data);
+                            taken += 1;
+                        }
+                        if taken > 0 {
+                            let mut pl_lock = pl_clone.lock().expect("Errpr locking prority list");
+                            pl_lock[_tn] = streams_to_handle.len();
+                            stats_clone.worker_connections_changed(_tn, streams_to_handle.len());
+                        }
+                    }
 
-                            {
-                                let mut pl_lock =
-                                    pl_clone.lock().expect("Errpr locking prority list");
-                                pl_lock[_tn] = streams_to_handle.len();
-                            }
+                    // Wait until a connection can be read or written, a stalled write is due
+                    // or the waker says one was queued
+                    // max_lifetime needs no timer, it is checked when the next request arrives
+                    let now = Instant::now();
+                    let mut deadline = now
+                        + if waker.is_some() {
+                            MAX_WAIT
+                        } else {
+                            MAX_WAIT_WITHOUT_WAKER
+                        };
+                    let mut sockets = Vec::with_capacity(streams_to_handle.len() + 1);
+                    for stream_data in streams_to_handle.iter() {
+                        if let (Some(ttl), Some(since)) =
+                            (settings.keep_alive_ttl, stream_data.idle_since())
+                        {
+                            deadline = deadline.min(since + ttl);
+                        }
+                        if let (Some(timeout), Some(since)) =
+                            (settings.header_read_timeout, stream_data.head_started())
+                        {
+                            deadline = deadline.min(since + timeout);
+                        }
+                        if let Some(at) = stream_data.retry_at {
+                            deadline = deadline.min(at);
+                            continue;
                         }
+                        sockets.push((
+                            readiness::handle_of(&stream_data.stream),
+                            stream_data.interest(),
+                        ));
+                    }
+                    if let Some(waker) = waker.as_ref() {
+                        sockets.push((waker.handle(), Interest::Read));
                     }
+                    let timeout = deadline.saturating_duration_since(now);
+                    let mut ready = match readiness::wait(&sockets, timeout) {
+                        Ok(ready) => ready.into_iter(),
+                        Err(e) => {
+                            eprintln!("Error waiting on connections: {}", e);
+                            thread::sleep(MAX_WAIT_WITHOUT_WAKER);
+                            vec![true; sockets.len()].into_iter()
+                        }
+                    };
 
+                    let now = Instant::now();
                     for stream_data in streams_to_handle.iter_mut() {
-                        if stream_data.status.is_none() {
+                        let due = match stream_data.retry_at {
+                            Some(at) => at <= now,
+                            None => ready.next().unwrap_or(false),
+                        };
+                        // Nothing came in time, the client gets no answer, just the close
+                        let idle_for = stream_data.idle_since().map(|since| now - since);
+                        if let (false, Some(ttl), Some(idle_for)) =
+                            (due, settings.keep_alive_ttl, idle_for)
+                        {
+                            if idle_for >= ttl {
+                                let _ = stream_data.stream.shutdown(Shutdown::Both);
+                                stats_clone.connection_changed(Some(false), None);
+                                stream_data.status = None;
+                                continue;
+                            }
+                        }
+                        // Dribbling a head byte by byte doesn't buy more time
+                        let head_for = stream_data.head_started().map(|since| now - since);
+                        if let (Some(timeout), Some(head_for)) =
+                            (settings.header_read_timeout, head_for)
+                        {
+                            if head_for >= timeout {
+                                let before = stream_data.status.as_ref().map(|s| s.active);
+                                let response = HttpResponse::new(
+                                    HttpStatus::RequestTimeout,
+                                    "Request Timeout",
+                                    headers!("Connection" => "close"),
+                                );
+                                Hteapot::reject(&stream_data.stream, response);
+                                stats_clone.connection_changed(before, None);
+                                stream_data.status = None;
+                                continue;
+                            }
+                        }
+                        if !due || stream_data.status.is_none() {
                             continue;
                         }
-                        let r = Hteapot::handle_client(
+                        let expired = max_lifetime
+                            .map(|lifetime| stream_data.accepted.elapsed() >= lifetime)
+                            .unwrap_or(false);
+                        let before = stream_data.status.as_ref().map(|s| s.active);
+                        let written = stream_data.status.as_ref().map(|s| s.index_writed);
+                        let mut r = Hteapot::handle_client(
                             &stream_data.stream,
-                            stream_data.status.as_mut().unwrap().clone(),
+                            stream_data.status.take().unwrap(),
+                            expired,
+                            &settings,
                             &action_clone,
                         );
+                        stats_clone.connection_changed(before, r.as_ref().map(|s| s.active));
+                        // Still writing without having written anything, readiness won't tell when
+                        // the bandwidth limit lets it go on
+                        let stalled = r
+                            .as_ref()
+                            .is_some_and(|s| !s.reading && Some(s.index_writed) == written);
+                        // The next request was read with the last one, readiness won't tell either
+                        let pipelined = r
+                            .as_mut()
+                            .is_some_and(|s| s.reading && s.request_complete());
+                        stream_data.retry_at = if stalled {
+                            Some(now + STALLED_WRITE_RETRY)
+                        } else if pipelined {
+                            Some(now)
+                        } else {
+                            None
+                        };
                         stream_data.status = r;
+                        stream_data.slot.held(stream_data.status.as_ref());
                     }
                     streams_to_handle.retain(|s| s.status.is_some());
                     {
                         let mut pl_lock = pl_clone.lock().expect("Errpr locking prority list");
                         pl_lock[_tn] = streams_to_handle.len();
                     }
+                    stats_clone.worker_connections_changed(_tn, streams_to_handle.len());
                 }
-            });
-        }
+            })
+        };
+        let workers = (0..self.threads as usize).map(&spawn_worker).collect();
+        let hooks = self.shutdown_hooks.clone();
+        let fatal_hook = self.fatal_hook.clone();
+        let supervisor = supervisor::supervise(
+            workers,
+            RestartBudget::new(self.worker_restart_budget),
+            stop.clone(),
+            spawn_worker,
+            move |message| report_fatal(fatal_hook.as_ref(), message),
+            move || {
+                eprintln!("Stopping, {}", hooks.run(false));
+                std::process::exit(1);
+            },
+        );
 
+        // One accept thread per extra listener, the first one is accepted on here
+        thread::scope(|scope| {
+            for listener in listeners.iter().skip(1) {
+                let (pool, waker, stop) = (&pool, waker.as_deref(), &stop);
+                scope.spawn(move || self.accept(listener, pool, waker, stop));
+            }
+            self.accept(&listeners[0], &pool, waker.as_deref(), &stop);
+        });
         let pool_clone = pool.clone();
+
+        // Workers waiting for connections or on their own ones look at stop again
+        {
+            let (lock, cvar) = &*pool_clone;
+            let _pool = lock.lock().expect("Error locking pool");
+            cvar.notify_all();
+        }
+        if let Some(waker) = &waker {
+            waker.wake();
+        }
+        if supervisor.join().is_err() {
+            eprintln!("Error stopping the workers, supervisor panicked");
+        }
+    }
+
+    // Queue connections from one listener for the workers until stop is set
+    fn accept(
+        &self,
+        listener: &TcpListener,
+        pool: &(Mutex<VecDeque<Queued>>, Condvar),
+        waker: Option<&Waker>,
+        stop: &AtomicBool,
+    ) {
+        let mut failures = 0;
+        // Waited on with a timeout instead of blocking in accept, which only a connection ends
+        if let Err(e) = listener.set_nonblocking(true) {
+            eprintln!(
+                "WARNING: listener left blocking, stopping may wait for a connection: {}",
+                e
+            );
+        }
+        let waiting = [(readiness::handle_of(listener), Interest::Read)];
         loop {
-            let stream = listener.accept();
-            if stream.is_err() {
+            // At the cap, new connections wait in the listen backlog until one closes
+            if let Some((max, ConnectionOverflow::Backlog)) = self.max_connections {
+                while self.stats.open_connections() >= max && !stop.load(Ordering::SeqCst) {
+                    thread::sleep(BACKLOG_POLL);
+                }
+            }
+            let pending = readiness::wait(&waiting, ACCEPT_POLL).map_or(true, |ready| ready[0]);
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            if !pending {
                 continue;
             }
-            let (stream, _) = stream.unwrap();
+            let (stream, peer) = match listener.accept() {
+                Ok(stream) => stream,
+                // Reported ready but gone before it was accepted
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    failures += 1;
+                    if failures == ACCEPT_FAILURES_FATAL {
+                        let address = listener
+                            .local_addr()
+                            .map_or("a listener".to_string(), |addr| addr.to_string());
+                        report_fatal(
+                            self.fatal_hook.as_ref(),
+                            format!(
+                                "FATAL: accept on {} failed {} times in a row: {}",
+                                address, failures, e
+                            ),
+                        );
+                    }
+                    thread::sleep(ACCEPT_RETRY);
+                    continue;
+                }
+            };
+            failures = 0;
             stream
                 .set_nonblocking(true)
                 .expect("Error seting non blocking");
             stream.set_nodelay(true).expect("Error seting no delay");
-            {
-                let (lock, cvar) = &*pool_clone;
+            // Counted before the global cap, so one client can't take all of it
+            let ip = match &self.max_connections_per_ip {
+                Some((max, exempt)) if !exempt.contains(&peer.ip()) => {
+                    if !self.stats.ip_connection_opened(peer.ip(), *max) {
+                        Self::too_many_from_ip(stream);
+                        continue;
+                    }
+                    Some(peer.ip())
+                }
+                _ => None,
+            };
+            let at_cap = self
+                .max_connections
+                .is_some_and(|(max, _)| self.stats.open_connections() >= max);
+            let shed = {
+                let (lock, cvar) = pool;
                 let mut pool = lock.lock().expect("Error locking pool");
-
-                pool.push_front(stream);
-                cvar.notify_one();
+                if at_cap
+                    || self
+                        .accept_queue_limit
+                        .is_some_and(|limit| pool.len() >= limit)
+                {
+                    Some(stream)
+                } else {
+                    pool.push_front(Queued {
+                        stream,
+                        queued_at: Instant::now(),
+                        slot: ConnectionSlot::new(&self.stats, ip),
+                    });
+                    self.stats.queue_depth_changed(pool.len());
+                    if pool.len() > self.queue_depth_warning && self.stats.pool_warning_due() {
+                        eprintln!(
+                            "WARNING: {} connections waiting for a worker, more threads may help",
+                            pool.len()
+                        );
+                    }
+                    cvar.notify_one();
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                    None
+                }
+            };
+            if let Some(stream) = shed {
+                if let Some(ip) = ip {
+                    self.stats.ip_connection_closed(ip);
+                }
+                Self::shed(stream);
             }
-            // Notify one waiting thread
         }
     }
 
@@ -218,6 +1204,7 @@ impl Hteapot {
         }
         let mut path = path.unwrap().to_string();
         let mut headers: HashMap<String, String> = HashMap::new();
+        let mut last_key: Option<String> = None;
         loop {
             let line = lines.next();
             if line.is_none() {
@@ -227,10 +1214,24 @@ impl Hteapot {
             if line.is_empty() {
                 break;
             }
-            let mut parts = line.split(": ");
-            let key = parts.next().unwrap().to_string();
-            let value = parts.next().unwrap();
-            headers.insert(key, value.to_string());
+            // A folded line continues the header before it
+            if line.starts_with([' ', '\t']) {
+                let value = last_key.as_ref().and_then(|key| headers.get_mut(key));
+                match value {
+                    Some(value) => {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                        continue;
+                    }
+                    None => return Err("Invalid header".to_string()),
+                }
+            }
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_string(), value.trim()),
+                None => return Err("Invalid header".to_string()),
+            };
+            headers.insert(key.clone(), value.to_string());
+            last_key = Some(key);
         }
         let body = lines
             .collect::<Vec<&str>>()
@@ -239,6 +1240,7 @@ impl Hteapot {
             .trim_end_matches(char::from(0))
             .to_string();
         let mut args: HashMap<String, String> = HashMap::new();
+        let mut raw_query = String::new();
         //remove http or https from the path
         if path.starts_with("http://") {
             path = path.trim_start_matches("http://").to_string();
@@ -260,6 +1262,7 @@ impl Hteapot {
             let mut parts = _path.split('?');
             path = parts.next().unwrap().to_string();
             let query = parts.next().unwrap();
+            raw_query = query.to_string();
             let query_parts: Vec<&str> = query.split('&').collect();
             for part in query_parts {
                 let mut parts = part.split('=');
@@ -269,30 +1272,113 @@ impl Hteapot {
             }
         }
 
+        let raw_path = path;
+        let path = normalize_path(&raw_path)?;
+        // Known methods in any case, the strict rules refuse them before this
+        let method = match HttpMethod::from_str(&method.to_ascii_uppercase()) {
+            HttpMethod::Other(_) => HttpMethod::from_str(method),
+            known => known,
+        };
         Ok(HttpRequest {
-            method: HttpMethod::from_str(method),
-            path: path.to_string(),
+            method,
+            path,
+            raw_path,
+            query: raw_query,
             args,
             headers,
             body: body.trim_end().to_string(),
+            remote_addr: None,
+            extensions: Extensions::new(),
         })
     }
 
+    // Turn away a connection when the accept queue is full
+    fn shed(stream: TcpStream) {
+        let response = HttpResponse::new(
+            HttpStatus::ServiceUnavailable,
+            "Service Unavailable",
+            headers!("Retry-After" => "1", "Connection" => "close"),
+        );
+        Self::reject(&stream, response);
+    }
+
+    fn too_many_from_ip(stream: TcpStream) {
+        let response = HttpResponse::new(
+            HttpStatus::TooManyRequests,
+            "Too Many Requests",
+            headers!("Retry-After" => "1", "Connection" => "close"),
+        );
+        Self::reject(&stream, response);
+    }
+
+    // A panicking handler fails its own request with a 500, the worker and its other
+    // connections go on, and the restart budget is left for real worker failures
+    fn call_handler(
+        action: &Arc<impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        let path = request.path.clone();
+        match panic::catch_unwind(AssertUnwindSafe(|| action(request))) {
+            Ok(response) => response,
+            Err(payload) => {
+                eprintln!(
+                    "ERROR: handler panicked on {}: {}",
+                    path,
+                    panic_message(payload.as_ref())
+                );
+                HttpResponse::new(
+                    HttpStatus::InternalServerError,
+                    "Internal Server Error",
+                    None,
+                )
+            }
+        }
+    }
+
+    // Send a short response and close, best effort since the socket is non blocking
+    fn reject(mut stream: &TcpStream, response: HttpResponse) {
+        let _ = stream.write_all(&response.to_bytes());
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    // Run a hijack handler off the worker, on the pool when there is one
+    fn run_hijack(pool: Option<&HijackPool>, stream: TcpStream, hijack: response::Hijack) {
+        let pool = match pool {
+            Some(pool) => pool,
+            None => {
+                thread::spawn(move || workers::run(stream, hijack));
+                return;
+            }
+        };
+        if let Err((stream, _)) = pool.submit(stream, hijack) {
+            Self::shed(stream);
+        }
+    }
+
     // Handle the client when a request is received
+    // expired forces the connection to close after the current response
     fn handle_client(
         stream: &TcpStream,
         socket_status: SocketStatus,
+        expired: bool,
+        settings: &WorkerSettings,
         action: &Arc<impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static>,
     ) -> Option<SocketStatus> {
-        let mut reader = BufReader::new(stream);
+        let mut reader = stream;
         let mut writer = BufWriter::new(stream);
-        let mut socket_status = socket_status.clone();
+        let mut socket_status = socket_status;
+        let mut too_long = false;
+        let mut too_large = false;
         if socket_status.reading {
             loop {
                 let mut buffer = [0; 1024];
-                match reader.read(&mut buffer) {
+                let m = match reader.read(&mut buffer) {
                     Err(e) => match e.kind() {
                         io::ErrorKind::WouldBlock => {
+                            // Part of a request, the rest comes in a later pass
+                            if socket_status.request_complete() {
+                                break;
+                            }
                             return Some(socket_status);
                         }
                         io::ErrorKind::ConnectionReset => {
@@ -303,54 +1389,350 @@ impl Hteapot {
                             return None;
                         }
                     },
-                    Ok(m) => {
-                        if m == 0 {
-                            return None;
-                        }
-                    }
+                    // A client done sending may still wait for the answers to what it pipelined
+                    Ok(0) if socket_status.request_complete() => break,
+                    Ok(0) => return None,
+                    Ok(m) => m,
                 };
-                socket_status.data_readed.append(&mut buffer.to_vec());
-                //socket_status
-                if buffer[0] == 0 {
+                socket_status.active = true;
+                socket_status.data_readed.extend_from_slice(&buffer[..m]);
+                socket_status.find_head_end();
+                // Past the limit it is refused below, no point reading the rest
+                if settings
+                    .request_limits
+                    .head_too_long(socket_status.data_readed.len(), socket_status.head_len)
+                {
+                    too_long = true;
                     break;
-                };
-                if *buffer.last().unwrap() == 0 {
+                }
+                // Nothing more is read, not even what is left of the body
+                if settings.body_too_large(&socket_status) {
+                    too_large = true;
+                    break;
+                }
+                // A short read is all there was for now
+                if m < buffer.len() && socket_status.request_complete() {
                     break;
                 }
             }
-            socket_status.reading = false;
         }
 
-        let request_string = String::from_utf8(socket_status.data_readed.clone()).unwrap();
-        // let request_string = "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n".to_string();
-        let request = Self::request_parser(request_string);
-        if request.is_err() {
-            eprintln!("Request parse error {:?}", request.err().unwrap());
-            return None;
-        }
-        let request = request.unwrap();
-        let keep_alive = match request.headers.get("Connection") {
-            Some(ch) => ch == "keep-alive",
-            None => false,
-        };
-        if socket_status.data_write.len() == 0 {
-            let mut response = action(request);
-            if !response.headers.contains_key("Conection") && keep_alive {
-                response
-                    .headers
-                    .insert("Connection".to_string(), "keep_alive".to_string());
+        // Only the first request on a connection carries the PROXY header
+        if !socket_status.identified {
+            let peer = stream.peer_addr().ok();
+            let source = if settings.proxy_protocol {
+                match proxy_protocol::parse(&socket_status.data_readed) {
+                    Ok((source, len)) => {
+                        socket_status.data_readed.drain(..len);
+                        // The v2 signature starts with a blank line, the head is after the header
+                        socket_status.reset_head();
+                        socket_status.find_head_end();
+                        source
+                    }
+                    Err(e) => {
+                        let peer = peer.map_or("unknown".to_string(), |a| a.to_string());
+                        eprintln!("WARNING: connection from {} refused, {}", peer, e);
+                        let response = HttpResponse::new(
+                            HttpStatus::BadRequest,
+                            "Bad Request",
+                            headers!("Connection" => "close"),
+                        );
+                        Self::reject(stream, response);
+                        return None;
+                    }
+                }
             } else {
-                response
-                    .headers
-                    .insert("Connection".to_string(), "close".to_string());
+                None
+            };
+            socket_status.remote_addr = source.or(peer);
+            socket_status.identified = true;
+        }
+        if socket_status.reading {
+            if !socket_status.request_complete() && !too_long && !too_large {
+                return Some(socket_status);
             }
-            socket_status.data_write = response.to_bytes();
+            socket_status.reading = false;
+            socket_status.split_pipelined();
         }
-        for n in socket_status.index_writed..socket_status.data_write.len() {
-            let r = writer.write(&[socket_status.data_write[n]]);
-            if r.is_err() {
-                let error = r.err().unwrap();
+
+        if let Err(status) = settings.request_limits.check(&socket_status.data_readed) {
+            let response = HttpResponse::new(
+                status,
+                status.to_string(),
+                headers!("Connection" => "close"),
+            );
+            Self::reject(stream, response);
+            return None;
+        }
+        if let Some(reason) = socket_status.framing_error() {
+            let response = HttpResponse::new(
+                HttpStatus::BadRequest,
+                format!("Bad Request: {}", reason),
+                headers!("Connection" => "close"),
+            );
+            Self::reject(stream, response);
+            return None;
+        }
+        if settings.body_too_large(&socket_status) {
+            let response = HttpResponse::new(
+                HttpStatus::PayloadTooLarge,
+                "Payload Too Large",
+                headers!("Connection" => "close"),
+            );
+            Self::reject(stream, response);
+            return None;
+        }
+
+        if let Some(version) = tls::client_hello_version(&socket_status.data_readed) {
+            settings.stats.tls_handshake_refused(version);
+            if settings.log_handshake_failures {
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                eprintln!(
+                    "WARNING: {} handshake from {} refused, this port speaks plain HTTP",
+                    version, peer
+                );
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+            return None;
+        }
+
+        // The body reaches the handler as text, one that isn't UTF-8 can't be handed over
+        let request_string = match String::from_utf8(socket_status.data_readed.clone()) {
+            Ok(request) => request,
+            Err(_) => {
+                let response = HttpResponse::new(
+                    HttpStatus::BadRequest,
+                    "Bad Request: not UTF-8",
+                    headers!("Connection" => "close"),
+                );
+                Self::reject(stream, response);
+                return None;
+            }
+        };
+        let accepts_interim = hints::accepts_interim(request_string.lines().next().unwrap_or(""));
+        let deviations = parsing::deviations(&request_string);
+        let refused = deviations
+            .iter()
+            .find(|d| !settings.parsing_rules.allows(**d));
+        if let Some(deviation) = refused {
+            let response = HttpResponse::new(
+                HttpStatus::BadRequest,
+                format!("Bad Request: {}", deviation),
+                headers!("Connection" => "close"),
+            );
+            Self::reject(stream, response);
+            return None;
+        }
+        // Once per request, not again for each pass writing the response
+        if !deviations.is_empty() && socket_status.data_write.is_empty() {
+            let peer = socket_status
+                .remote_addr
+                .map_or("unknown".to_string(), |a| a.to_string());
+            let deviations: Vec<String> = deviations.iter().map(|d| d.to_string()).collect();
+            eprintln!(
+                "WARNING: request from {} tolerated, {}",
+                peer,
+                deviations.join(", ")
+            );
+        }
+        // A request that trips the parser is refused, it must not take the worker down with it
+        let request = panic::catch_unwind(|| Self::request_parser(request_string))
+            .unwrap_or_else(|payload| Err(panic_message(payload.as_ref())));
+        if let Err(e) = request {
+            eprintln!("Request parse error {:?}", e);
+            let response = HttpResponse::new(
+                HttpStatus::BadRequest,
+                "Bad Request",
+                headers!("Connection" => "close"),
+            );
+            Self::reject(stream, response);
+            return None;
+        }
+        let mut request = request.unwrap();
+        request.remote_addr = socket_status.remote_addr;
+        let head_only = request.method == HttpMethod::HEAD;
+        let mut keep_alive = match request.headers.get("Connection") {
+            Some(ch) => ch == "keep-alive" && !expired,
+            None => false,
+        };
+        // Requests left after this one, when they are counted
+        let remaining = settings
+            .keep_alive_max_requests
+            .map(|max| max.saturating_sub(socket_status.requests + 1));
+        if remaining == Some(0) || settings.in_flight.closing() {
+            keep_alive = false;
+        }
+        if socket_status.data_write.is_empty() {
+            socket_status.in_flight = Some(settings.in_flight.start());
+            let links = hints::links(&settings.preloads, &request.path);
+            let mut interim = Vec::new();
+            if !links.is_empty() && accepts_interim {
+                // Sent right away, whatever doesn't fit now goes out before the response
+                interim = hints::early_hints(&links);
+                let mut early = stream;
+                let written = early.write(&interim).unwrap_or(0);
+                interim.drain(..written);
+            }
+            let mut fault = Fault::None;
+            if let Some(chaos) = &settings.chaos {
+                let (delay, f) = chaos.roll();
+                if let Some(delay) = delay {
+                    thread::sleep(delay);
+                }
+                fault = f;
+            }
+            let path = request.path.clone();
+            let unknown_method = match &request.method {
+                HttpMethod::Other(method) => !settings.extra_methods.contains(method),
+                _ => false,
+            };
+            let mut processed = false;
+            let mut response = if fault == Fault::Error {
+                HttpResponse::new(
+                    HttpStatus::InternalServerError,
+                    "Internal Server Error",
+                    None,
+                )
+            } else if unknown_method {
+                HttpResponse::new(
+                    HttpStatus::NotImplemented,
+                    format!("Not Implemented: {}", request.method.to_str()),
+                    None,
+                )
+            } else if settings.post_processors.is_empty() {
+                Self::call_handler(action, request)
+            } else {
+                // The handler takes the request, processors get a copy without the body
+                let head = HttpRequest {
+                    method: request.method.clone(),
+                    path: request.path.clone(),
+                    raw_path: request.raw_path.clone(),
+                    query: request.query.clone(),
+                    args: request.args.clone(),
+                    headers: request.headers.clone(),
+                    body: String::new(),
+                    remote_addr: request.remote_addr,
+                    extensions: Extensions::new(),
+                };
+                let mut response = Self::call_handler(action, request);
+                processed = postprocess::run(&settings.post_processors, &head, &mut response);
+                response
+            };
+            // Processors are expected to change the length, anything else is a bug in the handler
+            // Checked before HEAD drops the body, its Content-Length is the one of a GET
+            if let Some(declared) = response.fix_length().filter(|_| !processed) {
+                let actual = response.body().len();
+                if settings.strict_lengths {
+                    eprintln!(
+                        "ERROR: response to {} has Content-Length {} for a body of {} bytes, sent a 500",
+                        path, declared, actual
+                    );
+                    response = HttpResponse::new(
+                        HttpStatus::InternalServerError,
+                        "Internal Server Error: wrong Content-Length",
+                        None,
+                    );
+                } else {
+                    eprintln!(
+                        "DEBUG: response to {} had Content-Length {} for a body of {} bytes, set by the handler, corrected",
+                        path, declared, actual
+                    );
+                }
+            }
+            // Without body bytes there is nothing to stream, and the connection can be kept
+            if head_only || response.streams_nothing() {
+                response.drop_body();
+            }
+            // Before a streamed response turns its head into bytes
+            if !links.is_empty() && !response.is_raw() {
+                let links = match response.headers.get("Link") {
+                    Some(own) => format!("{}, {}", own, links.join(", ")),
+                    None => links.join(", "),
+                };
+                response.headers.insert("Link", links);
+            }
+            if let Some(hijack) = response.take_hijack() {
+                match stream.try_clone() {
+                    Ok(owned) => {
+                        // Still in flight until the hijack returns
+                        let guard = socket_status.in_flight.take();
+                        let hijack: response::Hijack = Box::new(move |stream| {
+                            let _guard = guard;
+                            hijack(stream)
+                        });
+                        Self::run_hijack(settings.hijacks.as_ref(), owned, hijack);
+                        // The clone keeps the socket open, this side just stops polling it
+                        return None;
+                    }
+                    Err(e) => {
+                        response = HttpResponse::new(
+                            HttpStatus::BadGateway,
+                            format!("Error taking over the connection: {}", e),
+                            None,
+                        );
+                        keep_alive = false;
+                    }
+                }
+            }
+            if response.closes_connection() {
+                keep_alive = false;
+            }
+            // Raw responses get it too, whatever Connection the upstream sent is for its own hop
+            if keep_alive {
+                response.set_header("Connection", "keep-alive");
+                let mut limits = Vec::new();
+                if let Some(ttl) = settings.keep_alive_ttl {
+                    limits.push(format!("timeout={}", ttl.as_secs()));
+                }
+                if let Some(remaining) = remaining {
+                    limits.push(format!("max={}", remaining));
+                }
+                if !limits.is_empty() {
+                    response.set_header("Keep-Alive", &limits.join(", "));
+                }
+            } else {
+                response.set_header("Connection", "close");
+            }
+            let silent = response.is_silent();
+            socket_status.data_write = response.into_out_buffer();
+            socket_status.data_write.prepend(interim);
+            let head_len = socket_status.data_write.head_len();
+            let body_len = socket_status.data_write.len() - head_len;
+            socket_status.write_limit = match fault {
+                _ if silent => Some(0),
+                Fault::Reset => Some(head_len),
+                Fault::Truncate => Some(head_len + body_len / 2),
+                _ => None,
+            };
+        }
+        let total = socket_status.data_write.len();
+        let mut end = socket_status.write_limit.unwrap_or(total);
+        if let Some(chaos) = &settings.chaos {
+            let rate = chaos.config.drip_bytes_per_sec;
+            if rate > 0 {
+                let started = *socket_status.write_started.get_or_insert_with(Instant::now);
+                let allowed = (started.elapsed().as_secs_f64() * rate as f64) as usize + 1;
+                end = end.min(allowed);
+            }
+        }
+        // Out of bandwidth the write is left for a later pass, like a full socket
+        if let Some(allowed) = settings.write_allowance(&mut socket_status) {
+            end = end.min(socket_status.index_writed + allowed);
+        }
+        let write_from = socket_status.index_writed;
+        while socket_status.index_writed < end {
+            let chunk = socket_status
+                .data_write
+                .chunk(socket_status.index_writed, end);
+            let r = writer.write(chunk);
+            if r.is_err() {
+                let error = r.err().unwrap();
                 if error.kind() == io::ErrorKind::WouldBlock {
+                    let written = socket_status.index_writed - write_from;
+                    settings.spend(&mut socket_status, written);
                     return Some(socket_status);
                 } else {
                     eprintln!("W error: {:?}", error);
@@ -359,18 +1741,32 @@ impl Hteapot {
             }
             socket_status.index_writed += r.unwrap();
         }
+        let written = socket_status.index_writed - write_from;
+        settings.spend(&mut socket_status, written);
 
         let r = writer.flush();
         if r.is_err() {
             eprintln!("Error2: {}", r.err().unwrap());
             return Some(socket_status);
         }
+        if end < total {
+            if socket_status.write_limit == Some(end) {
+                // Injected fault or no_response, drop the connection here
+                let _ = stream.shutdown(Shutdown::Both);
+                return None;
+            }
+            return Some(socket_status);
+        }
         if keep_alive {
-            socket_status.reading = true;
-            socket_status.data_readed = vec![];
-            socket_status.data_write = vec![];
+            socket_status.next_request();
+            socket_status.data_write = OutBuffer::default();
             socket_status.index_writed = 0;
-            return Some(socket_status);
+            socket_status.write_limit = None;
+            socket_status.write_started = None;
+            socket_status.in_flight = None;
+            socket_status.requests += 1;
+            socket_status.idle_since = Instant::now();
+            Some(socket_status)
         } else {
             let _ = stream.shutdown(Shutdown::Both);
             None
@@ -378,6 +1774,31 @@ impl Hteapot {
     }
 }
 
+// Collapse duplicate slashes and resolve . and .. segments
+// A trailing slash is kept, climbing above the root is an error
+fn normalize_path(path: &str) -> Result<String, String> {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(format!("Path above root {}", path));
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    // "/a/b/.." names the directory /a/
+    let last = path.rsplit('/').next().unwrap_or("");
+    let directory = last.is_empty() || last == "." || last == "..";
+    let mut normalized = format!("/{}", segments.join("/"));
+    if directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
 #[cfg(test)]
 #[test]
 fn test_http_parser() {
@@ -391,13 +1812,2099 @@ fn test_http_parser() {
     assert_eq!(parsed_request.body, "");
 }
 
+#[test]
+fn test_path_normalization() {
+    let cases = [
+        ("/", "/"),
+        ("//index.html", "/index.html"),
+        ("/a//b", "/a/b"),
+        ("/a/./b", "/a/b"),
+        ("/a/b/../c", "/a/c"),
+        ("/a/b/..", "/a/"),
+        ("/docs/", "/docs/"),
+        ("/docs/.", "/docs/"),
+        ("/a/..", "/"),
+        ("/api//users?id=1", "/api/users"),
+    ];
+    for (raw, normalized) in cases.iter() {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", raw);
+        let request = Hteapot::request_parser(request).unwrap();
+        assert_eq!(request.path, *normalized, "{}", raw);
+        assert_eq!(request.raw_path, raw.split('?').next().unwrap());
+    }
+    for raw in ["/..", "/a/../../etc/passwd", "/./../x"].iter() {
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", raw);
+        assert!(Hteapot::request_parser(request).is_err(), "{}", raw);
+    }
+}
+
 #[test]
 fn test_http_response_maker() {
     let response = HttpResponse::new(HttpStatus::IAmATeapot, "Hello, World!", None);
     let response = String::from_utf8(response.to_bytes()).unwrap();
-    let expected_response = format!("HTTP/1.1 418 I'm a teapot\r\nContent-Length: 13\r\nServer: HTeaPot/{}\r\n\r\nHello, World!\r\n",VERSION);
+    let expected_response = format!("HTTP/1.1 418 I'm a teapot\r\nContent-Length: 13\r\nServer: HTeaPot/{}\r\n\r\nHello, World!",VERSION);
     let expected_response_list = expected_response.split("\r\n");
     for item in expected_response_list.into_iter() {
         assert!(response.contains(item));
     }
+    assert!(response.ends_with("\r\n\r\nHello, World!"));
+}
+
+#[test]
+fn test_shared_response_body() {
+    let body = Arc::new(b"shared body".to_vec());
+    let response = HttpResponse::from_shared(HttpStatus::OK, body.clone(), None);
+    assert_eq!(response.body(), b"shared body");
+    let bytes = response.to_bytes();
+    let out = response.into_out_buffer();
+    assert_eq!(Arc::strong_count(&body), 2);
+    let mut sent = Vec::new();
+    while sent.len() < out.len() {
+        sent.extend_from_slice(out.chunk(sent.len(), out.len()));
+    }
+    assert_eq!(sent, bytes);
+    assert_eq!(out.chunk(0, 4), b"HTTP");
+
+    let mut response = HttpResponse::from_shared(HttpStatus::OK, body.clone(), None);
+    response.make_owned();
+    assert_eq!(response.content, b"shared body");
+    assert_eq!(Arc::strong_count(&body), 2);
+}
+
+#[cfg(test)]
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[cfg(test)]
+fn read_response(stream: &mut TcpStream, body: &str) -> String {
+    let mut response = Vec::new();
+    let mut buffer = [0; 1024];
+    while !String::from_utf8_lossy(&response).contains(body) {
+        let n = stream.read(&mut buffer).unwrap();
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..n]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+// Read one response head, then exactly Content-Length body bytes
+#[cfg(test)]
+fn read_exact_response(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    let length: usize = head
+        .lines()
+        .find_map(|l| l.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+    (head, body)
+}
+
+#[test]
+fn test_response_framing() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Whatever follows the body on a kept alive connection must be the next status line
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let keep_alive = "Host: localhost\r\nConnection: keep-alive\r\n\r\n";
+    stream
+        .write_all(format!("GET /first HTTP/1.1\r\n{}", keep_alive).as_bytes())
+        .unwrap();
+    let (head, body) = read_exact_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert_eq!(body, b"/first");
+    stream
+        .write_all(format!("GET /second HTTP/1.1\r\n{}", keep_alive).as_bytes())
+        .unwrap();
+    let (head, body) = read_exact_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"), "{:?}", head);
+    assert_eq!(body, b"/second");
+
+    // And on a closed one, nothing at all
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET /last HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let (_, body) = read_exact_response(&mut stream);
+    assert_eq!(body, b"/last");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "{:?}", rest);
+}
+
+#[test]
+fn test_pipelined_requests() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.path + &req.body, None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Several requests in one write, bodies included, each gets its own response in order
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let keep_alive = "Host: localhost\r\nConnection: keep-alive\r\n";
+    let pipeline = format!(
+        "GET /a HTTP/1.1\r\n{0}\r\n\
+         POST /b HTTP/1.1\r\n{0}Content-Length: 3\r\n\r\n123\
+         POST /c HTTP/1.1\r\n{0}Transfer-Encoding: chunked\r\n\r\n2\r\n45\r\n0\r\n\r\n\
+         GET /d HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        keep_alive
+    );
+    stream.write_all(pipeline.as_bytes()).unwrap();
+    // Done sending, the answers still come
+    stream.shutdown(Shutdown::Write).unwrap();
+    // Only the path is checked for the chunked one, the body goes to the handler undecoded
+    for expected in ["/a", "/b123", "/c", "/d"] {
+        let (head, body) = read_exact_response(&mut stream);
+        assert!(head.starts_with("HTTP/1.1 200"), "{:?}", head);
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.starts_with(expected) && !body.contains("GET"),
+            "{:?}",
+            body
+        );
+    }
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "{:?}", rest);
+}
+
+#[test]
+fn test_worker_respawn() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    let fatal = Arc::new(Mutex::new(Vec::new()));
+    let reported = fatal.clone();
+    server.set_fatal_hook(move |message| reported.lock().unwrap().push(message.to_string()));
+    // Handler panics are caught, a post processor is server code running on the worker itself
+    struct Crash;
+    impl ResponsePostProcessor for Crash {
+        fn process(&self, req: &HttpRequest, _resp: &mut HttpResponse) {
+            if req.path == "/crash" {
+                panic!("worker crash on purpose");
+            }
+        }
+    }
+    server.add_post_processor("*", Crash);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).to_string()
+    };
+
+    // The only worker dies with its connection, and comes back for the next one
+    for _ in 0..2 {
+        assert_eq!(get("/crash"), "");
+        thread::sleep(Duration::from_millis(400));
+        assert!(get("/ok").ends_with("/ok"));
+    }
+    let fatal = fatal.lock().unwrap();
+    assert_eq!(fatal.len(), 2);
+    assert!(fatal[0].starts_with("FATAL: worker 0 died (panicked: worker crash on purpose)"));
+}
+
+#[test]
+fn test_client_errors_keep_workers() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_worker_restart_budget(1);
+    let fatal = Arc::new(Mutex::new(Vec::new()));
+    let reported = fatal.clone();
+    server.set_fatal_hook(move |message| reported.lock().unwrap().push(message.to_string()));
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                if req.path == "/panic" {
+                    panic!("handler bug");
+                }
+                HttpResponse::new(HttpStatus::OK, req.path, None)
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let send = |request: &[u8]| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).to_string()
+    };
+
+    // Well past the restart budget, each one only fails its own request
+    for _ in 0..25 {
+        let response = send(
+            b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\nConnection: close\r\n\r\n\xff\xfe",
+        );
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    }
+    for _ in 0..3 {
+        let response = send(b"GET /panic HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500"), "{}", response);
+    }
+    let response = send(b"GET /ok HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("/ok"), "{}", response);
+    assert!(fatal.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_early_hints() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.add_preload("/", "</style.css>; rel=preload; as=style");
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n";
+    for _ in 0..2 {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut interim = Vec::new();
+        let mut byte = [0; 1];
+        while !interim.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            interim.push(byte[0]);
+        }
+        assert_eq!(
+            interim,
+            b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style\r\n\r\n"
+        );
+        // The final response follows on the same connection, which stays usable
+        let (head, body) = read_exact_response(&mut stream);
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.contains("Link: </style.css>; rel=preload; as=style\r\n"));
+        assert_eq!(body, b"/");
+    }
+
+    // HTTP/1.0 clients only get the header on the final response
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(!response.contains("103"));
+    assert!(response.contains("Link: </style.css>"));
+
+    // Other paths get neither
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET /other HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, _) = read_exact_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200") && !head.contains("Link"));
+}
+
+#[test]
+fn test_early_hints_streamed() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.add_preload("/file", "</style.css>; rel=preload; as=style");
+    server.add_preload("/feed", "</app.js>; rel=preload; as=script");
+    thread::spawn(move || {
+        server
+            .listen(|req| match req.path.as_str() {
+                "/file" => HttpResponse::with_length(5, |body| body.write_all(b"Hello")),
+                _ => HttpResponse::chunked(|chunks| chunks.send(b"tick")),
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // The head of a streamed response is written by the hijack, it carries Link too
+    for (path, link) in [
+        ("/file", "Link: </style.css>; rel=preload; as=style\r\n"),
+        ("/feed", "Link: </app.js>; rel=preload; as=script\r\n"),
+    ] {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let head = &response[..response.find("\r\n\r\n").unwrap() + 2];
+        assert!(head.contains(link), "{}", response);
+        assert!(response.contains("Hello") || response.contains("tick"));
+    }
+}
+
+// Download the same body on several connections at once, returns how long it took
+#[cfg(test)]
+fn timed_downloads(port: u16, clients: usize, size: usize) -> Duration {
+    let start = Instant::now();
+    let downloads: Vec<_> = (0..clients)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                let (_, body) = read_exact_response(&mut stream);
+                assert_eq!(body.len(), size);
+            })
+        })
+        .collect();
+    for download in downloads {
+        download.join().unwrap();
+    }
+    start.elapsed()
+}
+
+#[test]
+fn test_bandwidth_limits() {
+    let size = 512 * 1024;
+    let body = Arc::new(vec![b'x'; size]);
+
+    // 1 MiB in total at 1 MiB/s, however many connections share it
+    let port = free_port();
+    let mut server = Hteapot::new_threaded("127.0.0.1", port, 2);
+    server.set_max_bandwidth(1024 * 1024);
+    let shared = body.clone();
+    thread::spawn(move || {
+        server
+            .listen(move |_req| HttpResponse::from_shared(HttpStatus::OK, shared.clone(), None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let elapsed = timed_downloads(port, 2, size);
+    assert!(
+        elapsed > Duration::from_millis(900) && elapsed < Duration::from_millis(1300),
+        "{:?}",
+        elapsed
+    );
+
+    // Each connection gets its own 512 KiB/s
+    let port = free_port();
+    let mut server = Hteapot::new_threaded("127.0.0.1", port, 1);
+    server.set_max_bandwidth_per_conn(512 * 1024);
+    thread::spawn(move || {
+        server
+            .listen(move |_req| HttpResponse::from_shared(HttpStatus::OK, body.clone(), None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let elapsed = timed_downloads(port, 2, size);
+    assert!(
+        elapsed > Duration::from_millis(900) && elapsed < Duration::from_millis(1300),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn test_connection_max_lifetime() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_connection_max_lifetime(Duration::from_millis(500));
+    let stats = server.stats();
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n";
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = read_response(&mut stream, "Hello");
+    assert!(!response.contains("Connection: close"));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stats.idle_connections(), 1);
+    assert_eq!(stats.active_connections(), 0);
+
+    thread::sleep(Duration::from_millis(500));
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = read_response(&mut stream, "Hello");
+    assert!(response.contains("Connection: close"));
+}
+
+#[test]
+fn test_response_closes_connection() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                let mut response = match req.path.as_str() {
+                    "/raw" | "/raw/poll" => HttpResponse::new_raw(
+                        b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nHello"
+                            .to_vec(),
+                    ),
+                    "/stream" => HttpResponse::with_length(5, |body| body.write_all(b"Hello")),
+                    _ => HttpResponse::new(HttpStatus::OK, "Hello", None),
+                };
+                if req.path.ends_with("/poll") {
+                    response.close_connection();
+                }
+                response
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let request = |path: &str| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            path
+        )
+    };
+    stream.write_all(request("/").as_bytes()).unwrap();
+    let response = read_response(&mut stream, "Hello");
+    assert!(!response.contains("Connection: close"));
+
+    // Closed by the server even though the client asked to keep it
+    stream.write_all(request("/poll").as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("Connection: close"));
+    assert!(response.ends_with("Hello"));
+
+    // Raw and streamed responses say so too, the upstream's own Connection is replaced
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    stream.write_all(request("/raw").as_bytes()).unwrap();
+    let response = read_response(&mut stream, "Hello");
+    assert!(
+        response.contains("Connection: keep-alive\r\n"),
+        "{:?}",
+        response
+    );
+    assert!(!response.contains("Connection: close"));
+    stream.write_all(request("/raw/poll").as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert_eq!(response.matches("Connection:").count(), 1, "{:?}", response);
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(response.ends_with("Hello"));
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(request("/stream").as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("Connection: close\r\n"), "{:?}", response);
+    assert!(response.ends_with("Hello"));
+}
+
+#[test]
+fn test_accept_fairness() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let port = free_port();
+    let server = Hteapot::new_threaded("127.0.0.1", port, 1);
+    thread::spawn(move || {
+        server
+            .listen(|_req| {
+                thread::sleep(Duration::from_millis(5));
+                HttpResponse::new(HttpStatus::OK, "Hello", None)
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Keep-alive clients that send a new request as soon as the last one is answered
+    let stop = Arc::new(AtomicBool::new(false));
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n";
+    let greedy: Vec<_> = (0..3)
+        .map(|_| {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                while !stop.load(Ordering::SeqCst) {
+                    stream.write_all(request.as_bytes()).unwrap();
+                    read_response(&mut stream, "Hello");
+                }
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(100));
+
+    for _ in 0..5 {
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_response(&mut stream, "Hello").contains("200 OK"));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+    stop.store(true, Ordering::SeqCst);
+    for t in greedy {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn test_accept_queue_limit() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    // Nothing may wait, so every connection is turned away
+    server.set_accept_queue_limit(0);
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("Retry-After: 1"));
+}
+
+#[test]
+fn test_header_read_timeout() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_header_read_timeout(Some(Duration::from_millis(500)));
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Each byte comes in time, the head as a whole doesn't
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    for byte in b"GET /slow".iter() {
+        if stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout"),
+        "{}",
+        response
+    );
+    assert!(started.elapsed() >= Duration::from_millis(450));
+    assert!(started.elapsed() < Duration::from_secs(3));
+
+    // A kept connection gets the time again for its next request
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n";
+    stream.write_all(request).unwrap();
+    read_response(&mut stream, "Hello");
+    thread::sleep(Duration::from_millis(600));
+    stream.write_all(request).unwrap();
+    read_response(&mut stream, "Hello");
+}
+
+#[test]
+fn test_max_connections() {
+    let serve = |overflow: ConnectionOverflow| {
+        let port = free_port();
+        let mut server = Hteapot::new("127.0.0.1", port);
+        server.set_max_connections(2, overflow);
+        let stats = server.stats();
+        thread::spawn(move || {
+            server
+                .listen(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+                .unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        (port, stats)
+    };
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n";
+    let open = |port: u16| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request).unwrap();
+        read_response(&mut stream, "Hello");
+        stream
+    };
+
+    // Past the cap connections are refused, the ones open keep being served
+    let (port, stats) = serve(ConnectionOverflow::Reject);
+    let mut kept = vec![open(port), open(port)];
+    assert_eq!(stats.open_connections(), 2);
+    for _ in 0..3 {
+        let mut extra = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut response = String::new();
+        extra.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    }
+    for stream in kept.iter_mut() {
+        stream.write_all(request).unwrap();
+        read_response(stream, "Hello");
+    }
+    // Closing one makes room again
+    drop(kept.pop());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stats.open_connections(), 1);
+    kept.push(open(port));
+
+    // Or they wait until one closes
+    let (port, _) = serve(ConnectionOverflow::Backlog);
+    let mut kept = vec![open(port), open(port)];
+    let mut waiting = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    waiting.write_all(request).unwrap();
+    waiting
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut buffer = [0; 16];
+    assert!(waiting.read(&mut buffer).is_err());
+    drop(kept.pop());
+    waiting.set_read_timeout(None).unwrap();
+    read_response(&mut waiting, "Hello");
+}
+
+#[test]
+fn test_max_connections_per_ip() {
+    let serve = |exempt: Vec<IpAddr>| {
+        let port = free_port();
+        let mut server = Hteapot::new("127.0.0.1", port);
+        server.set_max_connections_per_ip(2, exempt);
+        let stats = server.stats();
+        thread::spawn(move || {
+            server
+                .listen(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+                .unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        (port, stats)
+    };
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n";
+    let open = |port: u16| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request).unwrap();
+        read_response(&mut stream, "Hello");
+        stream
+    };
+
+    // The third connection from loopback is refused, the first two keep being served
+    let (port, stats) = serve(vec![]);
+    let mut kept = vec![open(port), open(port)];
+    let mut extra = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut response = String::new();
+    extra.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+    assert_eq!(stats.ip_rejected(), 1);
+    assert_eq!(stats.busiest_ip_connections(), 2);
+    kept[0].write_all(request).unwrap();
+    read_response(&mut kept[0], "Hello");
+
+    // Closing one makes room again, and the address goes once it has none open
+    drop(kept.pop());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stats.busiest_ip_connections(), 1);
+    kept.push(open(port));
+    drop(kept);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stats.tracked_ips(), 0);
+
+    // Exempt addresses aren't counted at all
+    let (port, stats) = serve(vec!["127.0.0.1".parse().unwrap()]);
+    let _kept = [open(port), open(port), open(port)];
+    assert_eq!(stats.ip_rejected(), 0);
+    assert_eq!(stats.tracked_ips(), 0);
+}
+
+#[test]
+fn test_post_processor() {
+    struct Tag;
+    impl ResponsePostProcessor for Tag {
+        fn process(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+            resp.content.extend_from_slice(req.path.as_bytes());
+        }
+    }
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.add_post_processor("text/html", Tag);
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                let content_type = if req.path == "/page" {
+                    "text/html"
+                } else {
+                    "text/plain"
+                };
+                HttpResponse::new(
+                    HttpStatus::OK,
+                    "<p>",
+                    headers!("Content-Type" => content_type),
+                )
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    for (path, body) in [("/page", "<p>/page"), ("/text", "<p>")].iter() {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let response = read_response(&mut stream, "never");
+        assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", body)));
+    }
+}
+
+#[test]
+fn test_content_length_verified() {
+    struct Shorten;
+    impl ResponsePostProcessor for Shorten {
+        fn process(&self, _req: &HttpRequest, resp: &mut HttpResponse) {
+            resp.content.truncate(2);
+            resp.headers.insert("Content-Length", "99");
+        }
+    }
+    let serve = |strict: bool| {
+        let port = free_port();
+        let mut server = Hteapot::new("127.0.0.1", port);
+        server.add_post_processor("text/html", Shorten);
+        server.set_strict_lengths(strict);
+        thread::spawn(move || {
+            server
+                .listen(|req| {
+                    let content_type = if req.path == "/page" {
+                        "text/html"
+                    } else {
+                        "text/plain"
+                    };
+                    let mut response = HttpResponse::new(
+                        HttpStatus::OK,
+                        "Hello",
+                        headers!("Content-Type" => content_type),
+                    );
+                    if req.path == "/wrong" {
+                        response.content.extend_from_slice(b", World");
+                    }
+                    response
+                })
+                .unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        port
+    };
+    let get = |port: u16, method: &str, path: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // A processor changing the body is measured again, whatever header it left
+    let port = serve(false);
+    let response = get(port, "GET", "/page");
+    assert!(response.contains("Content-Length: 2\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nHe"));
+    // A handler changing the body after building the response is corrected
+    let response = get(port, "GET", "/wrong");
+    assert!(response.contains("Content-Length: 12\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nHello, World"));
+    // HEAD keeps the length of the body it doesn't send
+    let response = get(port, "HEAD", "/wrong");
+    assert!(response.contains("Content-Length: 12\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n"));
+
+    // Strict only fails the handler's mistake
+    let port = serve(true);
+    assert!(get(port, "GET", "/wrong").starts_with("HTTP/1.1 500"));
+    assert!(get(port, "GET", "/page").ends_with("\r\n\r\nHe"));
+    assert!(get(port, "GET", "/").ends_with("\r\n\r\nHello"));
+}
+
+#[test]
+fn test_hijacked_response() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                if req.path == "/tunnel" {
+                    HttpResponse::hijack(|stream| {
+                        stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\nhello")
+                    })
+                } else {
+                    HttpResponse::hijack(|_stream| {
+                        Err(io::Error::new(
+                            io::ErrorKind::ConnectionRefused,
+                            "upstream refused",
+                        ))
+                    })
+                }
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let fetch = |path: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    // Only the handler writes, the server adds nothing around it
+    assert_eq!(
+        fetch("/tunnel"),
+        "HTTP/1.1 200 Connection Established\r\n\r\nhello"
+    );
+    let response = fetch("/broken");
+    assert!(response.starts_with("HTTP/1.1 502"));
+    assert!(response.contains("upstream refused"));
+}
+
+#[test]
+fn test_hijack_workers() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let port = free_port();
+    let mut server = Hteapot::new_threaded("127.0.0.1", port, 4);
+    server.set_hijack_workers(4, 1000);
+    let running = Arc::new(AtomicUsize::new(0));
+    let most_running = Arc::new(AtomicUsize::new(0));
+    let (running_c, most_c) = (running.clone(), most_running.clone());
+    thread::spawn(move || {
+        server
+            .listen(move |_req| {
+                let (running, most_running) = (running_c.clone(), most_c.clone());
+                HttpResponse::hijack(move |stream| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(1));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    stream.write_all(b"streamed")
+                })
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // All connected before any answer is read, so the handlers pile up
+    let streams: Vec<TcpStream> = (0..500)
+        .map(|_| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            stream
+        })
+        .collect();
+    for mut stream in streams {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "streamed");
+    }
+    assert!(most_running.load(Ordering::SeqCst) <= 4);
+
+    // With no queue, handlers beyond the workers are turned away
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_hijack_workers(1, 0);
+    thread::spawn(move || {
+        server
+            .listen(|_req| {
+                HttpResponse::hijack(|stream| {
+                    thread::sleep(Duration::from_millis(300));
+                    stream.write_all(b"streamed")
+                })
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let fetch = || {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        stream
+    };
+    let mut first = fetch();
+    thread::sleep(Duration::from_millis(100));
+    let mut response = String::new();
+    fetch().read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"));
+    response.clear();
+    first.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "streamed");
+}
+
+#[test]
+fn test_hijack_panic_keeps_worker() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_hijack_workers(1, 10);
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                let crash = req.path == "/crash";
+                HttpResponse::hijack(move |stream| {
+                    if crash {
+                        panic!("hijack crashed");
+                    }
+                    stream.write_all(b"streamed")
+                })
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let fetch = |path: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // The only pool thread survives each panic and serves what comes next
+    for _ in 0..3 {
+        assert!(fetch("/crash").starts_with("HTTP/1.1 502"));
+    }
+    assert_eq!(fetch("/"), "streamed");
+}
+
+#[test]
+fn test_streamed_with_length() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| match req.path.as_str() {
+                "/exact" => HttpResponse::with_length(10, |body| {
+                    for part in ["01234", "5678", "9"] {
+                        body.write_all(part.as_bytes())?;
+                    }
+                    Ok(())
+                }),
+                "/short" => HttpResponse::with_length(10, |body| body.write_all(b"01234")),
+                "/long" => HttpResponse::with_length(4, |body| {
+                    body.write_all(b"0123")?;
+                    let overrun = body.write_all(b"4");
+                    assert_eq!(overrun.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+                    Ok(())
+                }),
+                _ => HttpResponse::with_length(10, |_body| {
+                    Err(io::Error::new(io::ErrorKind::NotFound, "export failed"))
+                }),
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let fetch = |path: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = fetch("/exact");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("Content-Length: 10\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(response.ends_with("\r\n\r\n0123456789"));
+
+    // Both mismatches end with the connection closed after the bytes that fit
+    let response = fetch("/short");
+    assert!(response.contains("Content-Length: 10\r\n"));
+    assert!(response.ends_with("\r\n\r\n01234"));
+    let response = fetch("/long");
+    assert!(response.ends_with("\r\n\r\n0123"));
+
+    // Nothing was sent yet, so the failure can still be reported
+    let response = fetch("/failed");
+    assert!(response.starts_with("HTTP/1.1 502"));
+    assert!(response.contains("export failed"));
+}
+
+#[test]
+fn test_chunked_stops_on_disconnect() {
+    use std::sync::mpsc;
+
+    let (ended, producer_ended) = mpsc::channel();
+    let ended = Mutex::new(ended);
+    let handle = Hteapot::new("127.0.0.1", 0)
+        .run_background(move |req| {
+            let ended = ended.lock().unwrap().clone();
+            match req.path.as_str() {
+                "/feed" => HttpResponse::chunked(move |sender| {
+                    // Sends until told the client is gone, however long that takes
+                    let mut sent = 0;
+                    let result = loop {
+                        if let Err(e) = sender.send(format!("tick {}\n", sent).as_bytes()) {
+                            break e;
+                        }
+                        sent += 1;
+                        thread::sleep(Duration::from_millis(10));
+                    };
+                    assert_eq!(result.kind(), io::ErrorKind::BrokenPipe);
+                    assert!(sender.is_closed());
+                    ended.send(sent).unwrap();
+                    Ok(())
+                }),
+                _ => HttpResponse::chunked(|sender| {
+                    for part in ["abc", "", "defghijklmnopq"] {
+                        sender.send(part.as_bytes())?;
+                    }
+                    assert!(!sender.is_closed());
+                    Ok(())
+                }),
+            }
+        })
+        .unwrap();
+
+    let mut stream = TcpStream::connect(handle.addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    stream
+        .write_all(b"GET /done HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(!response.contains("Content-Length"));
+    assert!(response.ends_with("\r\n\r\n3\r\nabc\r\ne\r\ndefghijklmnopq\r\n0\r\n\r\n"));
+
+    // A client leaving mid feed stops the producer at its next send
+    let mut stream = TcpStream::connect(handle.addr()).unwrap();
+    stream
+        .write_all(b"GET /feed HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut stream, "tick 2\n").contains("tick 0\n"));
+    drop(stream);
+    let sent = producer_ended
+        .recv_timeout(Duration::from_secs(2))
+        .expect("producer kept going after the client left");
+    assert!(sent >= 3);
+}
+
+#[test]
+fn test_graceful_stop_waits_in_flight() {
+    let server = Hteapot::new_threaded("127.0.0.1", 0, 2);
+    let in_flight = server.in_flight();
+    let handle = server
+        .run_background(|req| match req.path.as_str() {
+            "/slow" => HttpResponse::chunked(|sender| {
+                for part in ["tea", "is", "ready"] {
+                    thread::sleep(Duration::from_millis(300));
+                    sender.send(part.as_bytes())?;
+                }
+                Ok(())
+            }),
+            _ => HttpResponse::new(HttpStatus::OK, req.path, None),
+        })
+        .unwrap();
+    let connect = || {
+        let stream = TcpStream::connect(handle.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    };
+    let mut kept = connect();
+    kept.write_all(b"GET /first HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n")
+        .unwrap();
+    let (head, _) = read_exact_response(&mut kept);
+    assert!(head.contains("Connection: keep-alive\r\n"));
+    // Done right after the last byte is written
+    assert!(in_flight.wait_idle(Duration::from_secs(1)));
+
+    let mut slow = connect();
+    slow.write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let started = Instant::now();
+    while in_flight.count() == 0 {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(5));
+    }
+    // Stopping right away still lets the stream finish
+    in_flight.close_connections();
+    assert!(in_flight.wait_idle(Duration::from_secs(5)));
+    assert!(started.elapsed() >= Duration::from_millis(800));
+    // Responses sent while stopping end their connection
+    kept.write_all(b"GET /second HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n")
+        .unwrap();
+    let (head, body) = read_exact_response(&mut kept);
+    assert!(head.contains("Connection: close\r\n"));
+    assert_eq!(body, b"/second");
+    assert!(in_flight.wait_idle(Duration::from_secs(5)));
+    handle.stop();
+
+    let mut response = String::new();
+    slow.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("\r\n\r\n3\r\ntea\r\n2\r\nis\r\n5\r\nready\r\n0\r\n\r\n"));
+}
+
+#[test]
+fn test_bodiless_responses_keep_alive() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| match req.path.as_str() {
+                "/stream" => HttpResponse::with_length(10, |body| body.write_all(b"0123456789")),
+                "/empty" => HttpResponse::with_length(0, |_body| Ok(())),
+                _ => HttpResponse::new(HttpStatus::OK, "hello", None),
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut send = |method: &str, path: &str| {
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            method, path
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        if method == "HEAD" {
+            // Only the head comes back, Content-Length still says what a GET would get
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            (String::from_utf8(head).unwrap(), Vec::new())
+        } else {
+            read_exact_response(&mut stream)
+        }
+    };
+
+    // Each is followed by a GET on the same connection, stray body bytes would break it
+    for (method, path, length) in [
+        ("HEAD", "/stream", 10),
+        ("GET", "/empty", 0),
+        ("HEAD", "/", 5),
+    ] {
+        let (head, body) = send(method, path);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}\r\n", length)));
+        assert!(!head.contains("Connection: close"));
+        assert!(body.is_empty());
+        let (head, body) = send("GET", "/");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert_eq!(body, b"hello");
+    }
+}
+
+#[test]
+fn test_chaos_truncate() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_chaos(Chaos {
+        truncate_percent: 100,
+        ..Chaos::default()
+    });
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "0123456789", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut stream, "never");
+    assert!(response.contains("Content-Length: 10"));
+    assert!(response.ends_with("\r\n\r\n01234"));
+}
+
+#[test]
+fn test_tls_on_plain_port() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_log_handshake_failures(false);
+    let stats = server.stats();
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "plain", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Closed without an answer, a 400 would only confuse the TLS client
+    for hello in [
+        tls::client_hello(0x0303, &[0x0304, 0x0303]),
+        tls::client_hello(0x0301, &[]),
+    ] {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&hello).unwrap();
+        let mut answer = Vec::new();
+        let _ = stream.read_to_end(&mut answer);
+        assert!(answer.is_empty());
+    }
+    assert_eq!(stats.tls_handshakes(), vec![("TLSv1.0", 1), ("TLSv1.3", 1)]);
+
+    // The worker is still there for plain requests
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut stream, "never").ends_with("plain"));
+}
+
+#[test]
+fn test_request_limits_refused() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_request_limits(RequestLimits {
+        max_head_bytes: 4096,
+        max_line_bytes: 1024,
+        max_header_count: 10,
+    });
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "fine", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let send = |head: String| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+    let long_path = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(2000));
+    assert!(send(long_path).starts_with("HTTP/1.1 414 URI Too Long"));
+    let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(11));
+    assert!(send(many).starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+    // Never finished, refused without waiting for the rest
+    let endless = format!("GET / HTTP/1.1\r\n{}", "X-A: b\r\n".repeat(600));
+    assert!(send(endless).starts_with("HTTP/1.1 431"));
+    assert!(send("GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_string()).ends_with("fine"));
+}
+
+#[test]
+fn test_max_body_size() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_max_body_size(1000);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.body.len().to_string(), None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let connect = || TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let response = |mut stream: TcpStream| {
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // Refused on the declared length, without waiting for a byte of the body
+    let mut stream = connect();
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10737418240\r\n\r\n")
+        .unwrap();
+    assert!(response(stream).starts_with("HTTP/1.1 413 Payload Too Large"));
+
+    // A chunked body is fine until it grows past the limit
+    let mut stream = connect();
+    let chunk = format!("258\r\n{}\r\n", "a".repeat(600));
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n")
+        .unwrap();
+    stream.write_all(chunk.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(chunk.as_bytes()).unwrap();
+    assert!(response(stream).starts_with("HTTP/1.1 413 Payload Too Large"));
+
+    // Under it the whole body reaches the handler, even sent in pieces
+    let mut stream = connect();
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 900\r\n\r\n")
+        .unwrap();
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&[b'a'; 300]).unwrap();
+    }
+    assert!(response(stream).ends_with("\r\n\r\n900"));
+}
+
+#[test]
+fn test_body_framing() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.body.len().to_string(), None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let send = |request: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // Framing a proxy in front could read another way is a 400, never a guess
+    let refused = [
+        ("Content-Length: abc\r\n\r\n", "invalid Content-Length"),
+        ("Content-Length: -1\r\n\r\n", "invalid Content-Length"),
+        (
+            "Content-Length: 3\r\nContent-Length: 4\r\n\r\nabcd",
+            "conflicting Content-Length",
+        ),
+        (
+            "Transfer-Encoding: xchunked\r\n\r\n4\r\nabcd\r\n0\r\n\r\n",
+            "Transfer-Encoding not ending in chunked",
+        ),
+        (
+            "Transfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\nabcd",
+            "both Transfer-Encoding and Content-Length",
+        ),
+        (
+            "Transfer-Encoding: chunked\r\n\r\nzz\r\nabcd\r\n0\r\n\r\n",
+            "invalid chunk size",
+        ),
+    ];
+    for (rest, reason) in refused.iter() {
+        let answer = send(&format!("POST / HTTP/1.1\r\nHost: a\r\n{}", rest));
+        assert!(answer.starts_with("HTTP/1.1 400"), "{}", answer);
+        assert!(answer.contains(reason), "{}", answer);
+    }
+    // Whitespace in the name is refused by the parsing rules before the body is looked at
+    let answer = send("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding : chunked\r\n\r\n");
+    assert!(answer.starts_with("HTTP/1.1 400"), "{}", answer);
+
+    // Repeating the same length is fine
+    let answer =
+        send("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\nabcd");
+    assert!(answer.ends_with("\r\n\r\n4"), "{}", answer);
+}
+
+#[test]
+fn test_proxy_protocol_connections() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_proxy_protocol(true);
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                let client = req.remote_addr.map(|a| a.to_string());
+                HttpResponse::new(HttpStatus::OK, client.unwrap_or_default(), None)
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n";
+
+    // Later requests on the connection keep the client from the header
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut first = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n".to_vec();
+    first.extend_from_slice(request);
+    stream.write_all(&first).unwrap();
+    assert!(read_response(&mut stream, "203.0.113.7:51234").ends_with("203.0.113.7:51234"));
+    stream.write_all(request).unwrap();
+    assert!(read_response(&mut stream, "203.0.113.7:51234").ends_with("203.0.113.7:51234"));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let source: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let destination: SocketAddr = "[2001:db8::2]:80".parse().unwrap();
+    let mut first = proxy_protocol::v2_header(1, source, destination);
+    first.extend_from_slice(request);
+    stream.write_all(&first).unwrap();
+    assert!(read_response(&mut stream, "[2001:db8::1]:443").ends_with("[2001:db8::1]:443"));
+
+    // Health checks from the balancer get its own address
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut first = proxy_protocol::v2_header(0, source, destination);
+    first.extend_from_slice(request);
+    stream.write_all(&first).unwrap();
+    assert!(read_response(&mut stream, "127.0.0.1:").contains("127.0.0.1:"));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(request).unwrap();
+    let mut answer = String::new();
+    let _ = stream.read_to_string(&mut answer);
+    assert!(answer.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+// Serve one hot 10 MB body to 100 concurrent clients, shared vs copied per request
+// cargo test --release bench_shared_body -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_shared_body() {
+    let body = Arc::new(vec![b'x'; 10 * 1024 * 1024]);
+    for shared in [true, false].iter() {
+        let shared = *shared;
+        let port = free_port();
+        let server = Hteapot::new_threaded("127.0.0.1", port, 4);
+        let body_clone = body.clone();
+        thread::spawn(move || {
+            server
+                .listen(move |_req| {
+                    if shared {
+                        HttpResponse::from_shared(HttpStatus::OK, body_clone.clone(), None)
+                    } else {
+                        HttpResponse::new(HttpStatus::OK, &body_clone[..], None)
+                    }
+                })
+                .unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let clients: Vec<_> = (0..100)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                    stream
+                        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                        .unwrap();
+                    // Count the bytes without keeping them
+                    let mut buffer = [0; 65536];
+                    let mut received = 0;
+                    loop {
+                        match stream.read(&mut buffer).unwrap() {
+                            0 => break received,
+                            n => received += n,
+                        }
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            assert!(client.join().unwrap() > 10 * 1024 * 1024);
+        }
+        // Peak memory of the process, shared runs first so its peak is its own
+        let peak = std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|s| s.lines().find(|l| l.starts_with("VmHWM")).map(String::from))
+            .unwrap_or_default();
+        println!(
+            "{}: 100 x 10 MB in {:?}, {}",
+            if shared { "shared" } else { "copied" },
+            started.elapsed(),
+            peak
+        );
+    }
+}
+
+#[test]
+fn test_idle_keep_alive_is_served() {
+    let port = free_port();
+    let server = Hteapot::new_threaded("127.0.0.1", port, 2);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // A worker waits on the idle connection, a second one opened meanwhile is still answered
+    let mut idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let request = |path: &str| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n",
+            path
+        )
+    };
+    idle.write_all(request("/first").as_bytes()).unwrap();
+    read_response(&mut idle, "/first");
+    for path in ["/other", "/again"] {
+        let mut other = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        other.write_all(request(path).as_bytes()).unwrap();
+        read_response(&mut other, path);
+    }
+    thread::sleep(Duration::from_secs(3));
+    idle.write_all(request("/later").as_bytes()).unwrap();
+    let response = read_response(&mut idle, "/later");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(!response.contains("Connection: close"));
+}
+
+#[test]
+fn test_listen_reports_bind_errors() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let server = Hteapot::new("127.0.0.1", port);
+    let e = server
+        .listen(|_req| HttpResponse::new(HttpStatus::OK, "", None))
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+
+    let server = Hteapot::new("not a host", 8080);
+    assert!(server
+        .listen(|_req| HttpResponse::new(HttpStatus::OK, "", None))
+        .is_err());
+}
+
+#[test]
+fn test_head_in_pieces() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_parsing_rules(ParsingRules::lenient());
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                let count = req.headers.len().to_string();
+                HttpResponse::new(HttpStatus::OK, count, None)
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Nothing is answered before the blank line, split or not
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    for piece in ["GET / HT", "TP/1.1\r\nHost: a\r", "\nX-One: 1\r\n\r", "\n"].iter() {
+        stream.write_all(piece.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert!(answer.starts_with("HTTP/1.1 200 OK"));
+    assert!(answer.ends_with("\r\n\r\n2"));
+
+    // Bare line feeds end a head too, when they are let through
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\nHost: a\n").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(b"\n").unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert!(answer.ends_with("\r\n\r\n1"));
+}
+
+// A 100 header request written one byte at a time, the head is searched only where it grew
+// cargo test --release bench_head_byte_at_a_time -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_head_byte_at_a_time() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.headers.len().to_string(), None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut request = "GET / HTTP/1.1\r\nHost: localhost\r\n".to_string();
+    for i in 1..100 {
+        request.push_str(&format!("X-Header-{}: {}\r\n", i, "v".repeat(40)));
+    }
+    request.push_str("\r\n");
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let started = Instant::now();
+    for byte in request.as_bytes() {
+        stream.write_all(&[*byte]).unwrap();
+    }
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert!(answer.ends_with("\r\n\r\n100"));
+    println!(
+        "{} bytes one at a time answered in {:?}",
+        request.len(),
+        started.elapsed()
+    );
+}
+
+#[test]
+fn test_run_background() {
+    let request = b"GET /hi HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n";
+    let mut port = 0;
+    // Twice on the same port, the first stop has to free it
+    for _ in 0..2 {
+        let server = Hteapot::new_threaded("127.0.0.1", port, 2);
+        let handle = server
+            .run_background(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+            .unwrap();
+        port = handle.addr().port();
+        assert_ne!(port, 0);
+
+        let mut stream = TcpStream::connect(handle.addr()).unwrap();
+        stream.write_all(request).unwrap();
+        assert!(read_response(&mut stream, "/hi").ends_with("/hi"));
+        handle.stop();
+
+        // Kept alive connections are closed and nothing accepts new ones
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap_or(0), 0);
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    // Dropping the handle stops it too
+    let handle = Hteapot::new("127.0.0.1", port)
+        .run_background(|_req| HttpResponse::new(HttpStatus::OK, "", None))
+        .unwrap();
+    drop(handle);
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn test_stop_while_idle() {
+    let mut server = Hteapot::new_threaded("127.0.0.1", 0, 2);
+    server.add_listener("127.0.0.1", 0);
+    let handle = server
+        .run_background(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+        .unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    // No client and no connection of its own, every accept loop still notices in time
+    let port = handle.addr().port();
+    let started = Instant::now();
+    handle.stop();
+    assert!(
+        started.elapsed() < ACCEPT_POLL * 3,
+        "stopping took {:?}",
+        started.elapsed()
+    );
+    assert!(TcpListener::bind(("127.0.0.1", port)).is_ok());
+}
+
+#[test]
+fn test_multiple_listeners() {
+    // IPv6 loopback when there is one, else a second port on IPv4
+    let second = match TcpListener::bind("[::1]:0") {
+        Ok(listener) => ("::1", listener.local_addr().unwrap().port()),
+        Err(_) => ("127.0.0.1", free_port()),
+    };
+    let mut server = Hteapot::new("127.0.0.1", free_port());
+    server.add_listener(second.0, second.1);
+    let addrs = server.get_addrs();
+    assert_eq!(addrs.len(), 2);
+    assert_eq!(addrs[0], server.get_addr());
+    assert_eq!(addrs[1], (second.0.to_string(), second.1));
+    let handle = server
+        .run_background(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+        .unwrap();
+
+    for (address, port) in addrs.iter() {
+        let mut stream = TcpStream::connect((address.as_str(), *port)).unwrap();
+        stream
+            .write_all(b"GET /both HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        assert!(read_response(&mut stream, "/both").ends_with("/both"));
+    }
+    // Every accept thread stops, so each port can be bound again
+    handle.stop();
+    for (address, port) in addrs.iter() {
+        assert!(TcpListener::bind((address.as_str(), *port)).is_ok());
+    }
+}
+
+#[test]
+fn test_local_addr_port_zero() {
+    let server = Arc::new(Hteapot::new("127.0.0.1", 0));
+    assert_eq!(server.local_addr(), None);
+    let serving = server.clone();
+    thread::spawn(move || {
+        serving
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.path, None))
+            .unwrap()
+    });
+    let started = Instant::now();
+    let addr = loop {
+        if let Some(addr) = server.local_addr() {
+            break addr;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(5));
+    };
+    assert_ne!(addr.port(), 0);
+    assert_eq!(server.get_addr(), ("127.0.0.1".to_string(), 0));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /picked HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut stream, "/picked").ends_with("/picked"));
+}
+
+#[test]
+fn test_cache_lifetime() {
+    let lifetime = |value: &str| CacheLifetime::from_cache_control(value);
+    assert_eq!(lifetime("public"), CacheLifetime::Default);
+    assert_eq!(lifetime("no-store"), CacheLifetime::NoStore);
+    assert_eq!(lifetime("max-age=60, Private"), CacheLifetime::NoStore);
+    assert_eq!(lifetime("max-age=0"), CacheLifetime::NoStore);
+    assert_eq!(
+        lifetime("public, max-age=\"60\""),
+        CacheLifetime::Ttl(Duration::from_secs(60))
+    );
+    // Caches in front of the handler are shared ones
+    assert_eq!(
+        lifetime("max-age=60, s-maxage=5"),
+        CacheLifetime::Ttl(Duration::from_secs(5))
+    );
+    assert_eq!(lifetime("max-age=soon"), CacheLifetime::Default);
+
+    // Set by the handler, or read from its header when it didn't
+    let mut response = HttpResponse::new(HttpStatus::OK, "", None);
+    assert_eq!(response.cache_lifetime(), CacheLifetime::Default);
+    response.add_header("Cache-Control", "max-age=30");
+    assert_eq!(
+        response.cache_lifetime(),
+        CacheLifetime::Ttl(Duration::from_secs(30))
+    );
+    response.cache_ttl(2);
+    assert_eq!(
+        response.cache_lifetime(),
+        CacheLifetime::Ttl(Duration::from_secs(2))
+    );
+    response.no_store();
+    assert_eq!(response.cache_lifetime(), CacheLifetime::NoStore);
+    let raw = b"HTTP/1.1 200 OK\r\ncache-control: no-store\r\n\r\nbody".to_vec();
+    assert_eq!(
+        HttpResponse::new_raw(raw).cache_lifetime(),
+        CacheLifetime::NoStore
+    );
+}
+
+#[test]
+fn test_peer_ip() {
+    let port = free_port();
+    let server = Hteapot::new("127.0.0.1", port);
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                let ip = req.peer_ip().map(|ip| ip.to_string()).unwrap_or_default();
+                HttpResponse::new(HttpStatus::OK, ip, None)
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert!(answer.ends_with("\r\n\r\n127.0.0.1"), "{}", answer);
+    // Parsed outside a connection there is no peer
+    let request = Hteapot::request_parser("GET / HTTP/1.1\r\n\r\n".to_string()).unwrap();
+    assert_eq!(request.peer_ip(), None);
+}
+
+#[test]
+fn test_parsing_modes() {
+    let start = |rules: ParsingRules| {
+        let port = free_port();
+        let mut server = Hteapot::new("127.0.0.1", port);
+        server.set_parsing_rules(rules);
+        thread::spawn(move || {
+            server
+                .listen(|req| {
+                    let mut headers: Vec<String> = req
+                        .headers
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, v))
+                        .collect();
+                    headers.sort();
+                    let body = format!("{} {}", req.method.to_str(), headers.join(";"));
+                    HttpResponse::new(HttpStatus::OK, body, None)
+                })
+                .unwrap();
+        });
+        port
+    };
+    let lenient = start(ParsingRules::lenient());
+    let strict = start(ParsingRules::strict());
+    thread::sleep(Duration::from_millis(100));
+    let send = |port: u16, request: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // What lenient makes of each, and what strict says instead
+    let fixtures = [
+        ("GET / HTTP/1.1\r\nHost: a\r\n\r\n", "GET Host=a", None),
+        (
+            "GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\r\n\t2\r\n",
+            "GET Host=a;X-A=1 2",
+            Some("folded header"),
+        ),
+        (
+            "GET / HTTP/1.1\nHost: a\n",
+            "GET Host=a",
+            Some("line ending without"),
+        ),
+        (
+            "GET / HTTP/1.1\r\nX-A: 1\r\n",
+            "GET X-A=1",
+            Some("without Host"),
+        ),
+        (
+            "get / HTTP/1.1\r\nHost: a\r\n",
+            "GET Host=a",
+            Some("method not in capitals"),
+        ),
+    ];
+    for (head, parsed, refusal) in fixtures.iter() {
+        let request = format!("{}\r\n", head);
+        let answer = send(lenient, &request);
+        assert!(
+            answer.ends_with(&format!("\r\n\r\n{}", parsed)),
+            "{}",
+            answer
+        );
+        let answer = send(strict, &request);
+        match refusal {
+            Some(reason) => {
+                assert!(answer.starts_with("HTTP/1.1 400"), "{}", answer);
+                assert!(answer.contains(reason), "{}", answer);
+            }
+            None => assert!(answer.ends_with(parsed), "{}", answer),
+        }
+    }
+
+    // No colon at all, or whitespace before it, is refused either way
+    for port in [lenient, strict].iter() {
+        let answer = send(*port, "GET / HTTP/1.1\r\nHost: a\r\nnonsense\r\n\r\n");
+        assert!(answer.starts_with("HTTP/1.1 400"), "{}", answer);
+        let answer = send(*port, "GET / HTTP/1.1\r\nHost : a\r\nX-A:1\r\n\r\n");
+        assert!(answer.starts_with("HTTP/1.1 400"), "{}", answer);
+        assert!(
+            answer.contains("whitespace before a header colon"),
+            "{}",
+            answer
+        );
+    }
+}
+
+#[test]
+fn test_keep_alive_limits() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_keep_alive_ttl(Duration::from_secs(1));
+    server.set_keep_alive_max_requests(3);
+    let stats = server.stats();
+    thread::spawn(move || {
+        server
+            .listen(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let request = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n";
+    let header = |response: &str, name: &str| {
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+            .map(String::from)
+    };
+
+    // The limits are announced, and the last request allowed closes the connection
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    for max in ["2", "1"].iter() {
+        stream.write_all(request).unwrap();
+        let response = read_response(&mut stream, "Hello");
+        assert_eq!(
+            header(&response, "Connection").as_deref(),
+            Some("keep-alive")
+        );
+        assert_eq!(
+            header(&response, "Keep-Alive"),
+            Some(format!("timeout=1, max={}", max))
+        );
+    }
+    stream.write_all(request).unwrap();
+    let response = read_response(&mut stream, "Hello");
+    assert_eq!(header(&response, "Connection").as_deref(), Some("close"));
+    assert_eq!(header(&response, "Keep-Alive"), None);
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+
+    // Left idle past the ttl it is closed without an answer
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(request).unwrap();
+    read_response(&mut stream, "Hello");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(stats.idle_connections(), 1);
+    let started = Instant::now();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    assert!(started.elapsed() >= Duration::from_millis(600));
+    assert!(started.elapsed() < Duration::from_secs(3));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stats.idle_connections(), 0);
+}
+
+#[test]
+fn test_queue_stats() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_queue_warnings(Duration::from_millis(100), 0);
+    let stats = server.stats();
+    thread::spawn(move || {
+        server
+            .listen(|req| {
+                if req.path == "/slow" {
+                    thread::sleep(Duration::from_millis(400));
+                }
+                HttpResponse::new(HttpStatus::OK, "Hello", None)
+            })
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+
+    // The only worker is busy, so the next connection waits in the queue
+    let mut slow = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    slow.write_all(request("/slow").as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut queued = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    queued.write_all(request("/").as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stats.queue_depth(), 1);
+    read_response(&mut slow, "Hello");
+    read_response(&mut queued, "Hello");
+
+    assert_eq!(stats.queue_depth(), 0);
+    assert_eq!(stats.worker_connections().len(), 1);
+    let waits = stats.queue_waits();
+    assert_eq!(waits.count, 2);
+    assert_eq!(waits.quantile(0.5), Some(Duration::from_millis(1)));
+    assert!(waits.quantile(1.0) >= Some(Duration::from_millis(250)));
+}
+
+#[test]
+fn test_buffer_accounting() {
+    let server = Hteapot::new("127.0.0.1", 0);
+    let stats = server.stats();
+    let handle = server
+        .run_background(|_req| HttpResponse::new(HttpStatus::OK, vec![b'a'; 8 << 20], None))
+        .unwrap();
+    let settle = || thread::sleep(Duration::from_millis(150));
+
+    // Half a head waits in the read buffer
+    let mut stream = TcpStream::connect(handle.addr()).unwrap();
+    let head = b"GET / HTTP/1.1\r\nHost: a\r\n";
+    stream.write_all(head).unwrap();
+    settle();
+    assert_eq!(stats.read_buffer_bytes(), head.len());
+    assert_eq!(stats.response_buffer_bytes(), 0);
+
+    // A response bigger than the socket buffers is held while the client doesn't read
+    stream.write_all(b"\r\n").unwrap();
+    settle();
+    assert!(stats.response_buffer_bytes() > 8 << 20);
+    let (_, body) = read_exact_response(&mut stream);
+    assert_eq!(body.len(), 8 << 20);
+
+    // Everything is given back once the connection is done with
+    drop(stream);
+    let started = Instant::now();
+    while stats.read_buffer_bytes() + stats.response_buffer_bytes() > 0
+        && started.elapsed() < Duration::from_secs(2)
+    {
+        settle();
+    }
+    assert_eq!(stats.read_buffer_bytes(), 0);
+    assert_eq!(stats.response_buffer_bytes(), 0);
+    handle.stop();
+}
+
+#[test]
+fn test_extra_methods() {
+    let port = free_port();
+    let mut server = Hteapot::new("127.0.0.1", port);
+    server.set_extra_methods(vec!["PROPFIND".to_string()]);
+    thread::spawn(move || {
+        server
+            .listen(|req| HttpResponse::new(HttpStatus::OK, req.method.to_str(), None))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let send = |method: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = format!("{} /secret.html HTTP/1.1\r\nHost: a\r\n\r\n", method);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // Unknown methods never reach the handler
+    let response = send("FOO");
+    assert!(
+        response.starts_with("HTTP/1.1 501 Not Implemented"),
+        "{}",
+        response
+    );
+    assert!(response.ends_with("Not Implemented: FOO"));
+    let response = send("PROPFIND");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nPROPFIND"));
+    assert!(send("GET").ends_with("\r\n\r\nGET"));
 }
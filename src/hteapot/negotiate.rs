@@ -0,0 +1,283 @@
+// Content negotiation helpers for the Accept-* request headers
+// Values are parsed with their quality so handlers can pick the best match
+
+use super::HttpRequest;
+
+// Parameters of a value, like level=1 in "text/html;level=1"
+type Params = Vec<(String, String)>;
+
+// Split "value;a=b;q=0.5" into the value, the parameters before q and the quality
+// None when the quality is invalid, parameters after q belong to the extension and are dropped
+fn split_quality(item: &str) -> Option<(&str, Params, f32)> {
+    let mut parts = item.split(';');
+    let value = parts.next().unwrap_or("").trim();
+    let mut params = Vec::new();
+    for param in parts {
+        let (key, v) = match param.split_once('=') {
+            Some((key, v)) => (key.trim().to_lowercase(), v.trim()),
+            None => continue,
+        };
+        if key == "q" {
+            let quality = v.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+            return Some((value, params, quality));
+        }
+        params.push((key, v.trim_matches('"').to_string()));
+    }
+    Some((value, params, 1.0))
+}
+
+// Parse a list like "gzip;q=0.8, br" into (value, quality) pairs
+// Entries with an invalid quality are ignored
+pub fn parse_quality_list(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(split_quality)
+        .filter(|(value, _, _)| !value.is_empty())
+        .map(|(value, _, quality)| (value.to_lowercase(), quality))
+        .collect()
+}
+
+// A media type from an Accept header, where the type and subtype can be "*"
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaRange {
+    pub kind: String,
+    pub subtype: String,
+    pub params: Params, // Names in lowercase, like "level"
+}
+
+impl MediaRange {
+    // "text/html;level=1", None for anything without a "/" or like "*/html"
+    pub fn parse(value: &str) -> Option<MediaRange> {
+        let (range, params, _) = split_quality(value)?;
+        let (kind, subtype) = range.split_once('/')?;
+        let (kind, subtype) = (kind.trim().to_lowercase(), subtype.trim().to_lowercase());
+        if kind.is_empty() || subtype.is_empty() || (kind == "*" && subtype != "*") {
+            return None;
+        }
+        Some(MediaRange {
+            kind,
+            subtype,
+            params,
+        })
+    }
+
+    // Whether a concrete type like "text/html" is in the range
+    pub fn matches(&self, media_type: &MediaRange) -> bool {
+        let kind = self.kind == "*" || self.kind == media_type.kind;
+        let subtype = self.subtype == "*" || self.subtype == media_type.subtype;
+        kind && subtype && self.params.iter().all(|p| media_type.params.contains(p))
+    }
+
+    // More specific ranges override less specific ones matching the same type
+    fn specificity(&self) -> usize {
+        match (self.kind.as_str(), self.subtype.as_str()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2 + self.params.len(),
+        }
+    }
+}
+
+// Parse an Accept header, most preferred first: by quality, then the more specific
+// Ranges with an invalid type or quality are ignored
+pub fn parse_accept(header: &str) -> Vec<(MediaRange, f32)> {
+    let mut list: Vec<(MediaRange, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let (_, _, quality) = split_quality(item)?;
+            Some((MediaRange::parse(item)?, quality))
+        })
+        .collect();
+    list.sort_by(|(a, qa), (b, qb)| {
+        qb.total_cmp(qa)
+            .then_with(|| b.specificity().cmp(&a.specificity()))
+    });
+    list
+}
+
+// Quality of a media type, taken from the most specific range matching it, 0 when none does
+fn quality(accepts: &[(MediaRange, f32)], media_type: &MediaRange) -> f32 {
+    accepts
+        .iter()
+        .filter(|(range, _)| range.matches(media_type))
+        .max_by_key(|(range, _)| range.specificity())
+        .map_or(0.0, |(_, q)| *q)
+}
+
+// Pick the best of the offered types, in server preference order on equal quality
+// Without an Accept header anything goes and the first offer wins
+pub fn negotiate_media_type<'a>(header: Option<&str>, offers: &[&'a str]) -> Option<&'a str> {
+    let accepts = match header {
+        Some(header) => parse_accept(header),
+        None => return offers.first().copied(),
+    };
+    let mut best: Option<(&'a str, f32)> = None;
+    for offer in offers {
+        let quality = MediaRange::parse(offer).map_or(0.0, |t| quality(&accepts, &t));
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((offer, quality));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+impl HttpRequest {
+    fn accept_header(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Accept"))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Media ranges the client accepts, most preferred first, "*/*" when it sent no Accept
+    pub fn accepts(&self) -> Vec<(MediaRange, f32)> {
+        parse_accept(self.accept_header().unwrap_or("*/*"))
+    }
+
+    // The offered type the client prefers, like prefers(&["application/json", "text/html"])
+    // None when it accepts none of them
+    pub fn prefers<'a>(&self, offers: &[&'a str]) -> Option<&'a str> {
+        negotiate_media_type(self.accept_header(), offers)
+    }
+}
+
+// Pick the best content coding for an Accept-Encoding header
+// `supported` is in server preference order, identity is always considered last
+// None means nothing is acceptable, not even identity
+pub fn negotiate_encoding<'a>(header: Option<&str>, supported: &[&'a str]) -> Option<&'a str> {
+    let header = match header {
+        Some(header) => header,
+        None => return Some("identity"),
+    };
+    let list = parse_quality_list(header);
+    let quality_of = |encoding: &str| -> Option<f32> {
+        list.iter()
+            .find(|(value, _)| value == encoding)
+            .or_else(|| list.iter().find(|(value, _)| value == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    let mut best: Option<(&'a str, f32)> = None;
+    for encoding in supported.iter() {
+        let quality = quality_of(&encoding.to_lowercase()).unwrap_or(0.0);
+        if quality > 0.0 && best.map(|(_, q)| quality > q).unwrap_or(true) {
+            best = Some((encoding, quality));
+        }
+    }
+    // identity is acceptable unless excluded, but loses against any explicit coding
+    let identity = quality_of("identity").unwrap_or(0.001);
+    if identity > 0.0 && best.map(|(_, q)| identity > q).unwrap_or(true) {
+        best = Some(("identity", identity));
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+#[test]
+fn test_negotiate_encoding() {
+    let supported = ["br", "gzip"];
+    let cases: [(Option<&str>, Option<&str>); 12] = [
+        (None, Some("identity")),
+        (Some(""), Some("identity")),
+        (Some("gzip"), Some("gzip")),
+        (Some("compress, gzip"), Some("gzip")),
+        (Some("gzip;q=0.8, br;q=1.0"), Some("br")),
+        (Some("gzip, br"), Some("br")),
+        (Some("*"), Some("br")),
+        (Some("br;q=0, *;q=0.5"), Some("gzip")),
+        (Some("gzip;q=1.0, identity; q=0.5, *;q=0"), Some("gzip")),
+        (Some("identity;q=0"), None),
+        (Some("*;q=0"), None),
+        (Some("deflate;q=0.5, identity;q=0.9"), Some("identity")),
+    ];
+    for (header, expected) in cases.iter() {
+        assert_eq!(
+            negotiate_encoding(*header, &supported),
+            *expected,
+            "header: {:?}",
+            header
+        );
+    }
+    assert_eq!(
+        parse_quality_list("GZIP;Q=0.5, br;q=2"),
+        vec![("gzip".to_string(), 0.5)]
+    );
+}
+
+#[test]
+fn test_negotiate_media_type() {
+    // The precedence example of RFC 7231, section 5.3.2
+    let accepts = parse_accept(
+        "text/*;q=0.3, text/html;q=0.7, text/html;level=1, text/html;level=2;q=0.4, */*;q=0.5",
+    );
+    for (media_type, expected) in [
+        ("text/html;level=1", 1.0),
+        ("text/html", 0.7),
+        ("text/plain", 0.3),
+        ("image/jpeg", 0.5),
+        ("text/html;level=2", 0.4),
+        ("text/html;level=3", 0.7),
+    ] {
+        let media_type = MediaRange::parse(media_type).unwrap();
+        assert_eq!(quality(&accepts, &media_type), expected, "{:?}", media_type);
+    }
+    let order: Vec<String> = accepts
+        .iter()
+        .map(|(range, _)| format!("{}/{} {}", range.kind, range.subtype, range.params.len()))
+        .collect();
+    assert_eq!(
+        order,
+        [
+            "text/html 1",
+            "text/html 0",
+            "*/* 0",
+            "text/html 1",
+            "text/* 0"
+        ]
+    );
+
+    let offers = ["application/json", "text/html"];
+    let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+    assert_eq!(
+        negotiate_media_type(Some(browser), &offers),
+        Some("text/html")
+    );
+    let client = "application/json, text/plain;q=0.5";
+    assert_eq!(
+        negotiate_media_type(Some(client), &offers),
+        Some("application/json")
+    );
+    // Equal quality goes to the server preference
+    assert_eq!(
+        negotiate_media_type(Some("*/*"), &offers),
+        Some("application/json")
+    );
+    assert_eq!(
+        negotiate_media_type(None, &offers),
+        Some("application/json")
+    );
+    assert_eq!(
+        negotiate_media_type(Some("application/*;q=0.2, TEXT/HTML"), &offers),
+        Some("text/html")
+    );
+    assert_eq!(negotiate_media_type(Some("image/png"), &offers), None);
+    assert_eq!(
+        negotiate_media_type(Some("text/html;q=0, */*"), &offers),
+        Some("application/json")
+    );
+    assert_eq!(
+        negotiate_media_type(Some("*/*;q=0.1, text/html;q=0"), &["text/html"]),
+        None
+    );
+    // Invalid ranges and qualities are skipped
+    assert!(parse_accept("html, */json, text/html;q=x, ;q=1").is_empty());
+
+    let mut req =
+        super::Hteapot::request_parser("GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_string()).unwrap();
+    assert_eq!(req.accepts().len(), 1);
+    req.headers
+        .insert("accept".to_string(), "application/json".to_string());
+    assert_eq!(
+        req.prefers(&["text/html", "application/json"]),
+        Some("application/json")
+    );
+}
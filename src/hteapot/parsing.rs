@@ -0,0 +1,421 @@
+// Deviations from the HTTP/1.1 grammar that clients get away with, or not
+// Strict, the default, answers each with a 400. Lenient takes them with a warning:
+//   obs_fold           a header line folded onto the previous one
+//   bare_lf            lines of the head ending in \n instead of \r\n
+//   missing_host       an HTTP/1.1 request without a Host header
+//   lowercase_method   a method not written in capitals, like get for GET
+// Header lines without a colon, and whitespace between a header name and its colon, are
+// refused in both modes (RFC 9112 5.1), a proxy could read that header differently
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParsingRules {
+    // Each one tolerates its deviation when true
+    pub obs_fold: bool,
+    pub bare_lf: bool,
+    pub missing_host: bool,
+    pub lowercase_method: bool,
+}
+
+impl ParsingRules {
+    pub fn strict() -> ParsingRules {
+        ParsingRules {
+            obs_fold: false,
+            bare_lf: false,
+            missing_host: false,
+            lowercase_method: false,
+        }
+    }
+
+    pub fn lenient() -> ParsingRules {
+        ParsingRules {
+            obs_fold: true,
+            bare_lf: true,
+            missing_host: true,
+            lowercase_method: true,
+        }
+    }
+
+    pub fn allows(&self, deviation: Deviation) -> bool {
+        match deviation {
+            Deviation::SpaceBeforeColon => false,
+            Deviation::ObsFold => self.obs_fold,
+            Deviation::BareLf => self.bare_lf,
+            Deviation::MissingHost => self.missing_host,
+            Deviation::LowercaseMethod => self.lowercase_method,
+        }
+    }
+}
+
+impl Default for ParsingRules {
+    fn default() -> Self {
+        ParsingRules::strict()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deviation {
+    SpaceBeforeColon,
+    ObsFold,
+    BareLf,
+    MissingHost,
+    LowercaseMethod,
+}
+
+impl fmt::Display for Deviation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Deviation::SpaceBeforeColon => "whitespace before a header colon",
+            Deviation::ObsFold => "folded header",
+            Deviation::BareLf => "line ending without \\r",
+            Deviation::MissingHost => "HTTP/1.1 request without Host",
+            Deviation::LowercaseMethod => "method not in capitals",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// How the body after a head ends
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BodyLength {
+    Empty,
+    Fixed(usize),          // Content-Length
+    Chunked,               // Transfer-Encoding: chunked, up to the last chunk and the trailers
+    Invalid(&'static str), // Framing that can't be trusted, answered with a 400 (RFC 9112 6.3)
+}
+
+// Anything a proxy in front could frame differently is Invalid, the request is smuggled
+// otherwise: both headers, lengths that disagree or aren't numbers, a coding after chunked
+pub(crate) fn body_length(head: &[u8]) -> BodyLength {
+    let head = String::from_utf8_lossy(head);
+    let (mut length, mut chunked) = (None, false);
+    for line in head.lines().skip(1) {
+        // Folded lines continue the previous header, they name none
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name, value.trim()),
+            None => continue,
+        };
+        let framing = name.trim_end().eq_ignore_ascii_case("transfer-encoding")
+            || name.trim_end().eq_ignore_ascii_case("content-length");
+        if framing && name.trim_end() != name {
+            return BodyLength::Invalid("whitespace in a header name");
+        }
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            let last = value.rsplit(',').next().unwrap_or_default().trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                return BodyLength::Invalid("Transfer-Encoding not ending in chunked");
+            }
+            chunked = true;
+        }
+        if name.eq_ignore_ascii_case("content-length") {
+            // A list of the same length is the same length, as is a repeated header
+            for value in value.split(',').map(str::trim) {
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return BodyLength::Invalid("invalid Content-Length");
+                }
+                // Too big for usize is still too big for any limit
+                let value = value.parse().unwrap_or(usize::MAX);
+                if length.is_some_and(|length| length != value) {
+                    return BodyLength::Invalid("conflicting Content-Length");
+                }
+                length = Some(value);
+            }
+        }
+    }
+    match (chunked, length) {
+        (true, Some(_)) => BodyLength::Invalid("both Transfer-Encoding and Content-Length"),
+        (true, None) => BodyLength::Chunked,
+        (false, Some(len)) => BodyLength::Fixed(len),
+        (false, None) => BodyLength::Empty,
+    }
+}
+
+// Bytes the body takes once all of it was read, None before that
+// What follows is the next request on the connection, Err when the chunks can't be made sense of
+// Chunks start at from, which is moved past each whole one so the next call doesn't walk them again
+pub(crate) fn body_end(
+    length: BodyLength,
+    body: &[u8],
+    from: &mut usize,
+) -> Result<Option<usize>, &'static str> {
+    match length {
+        BodyLength::Empty => Ok(Some(0)),
+        BodyLength::Fixed(len) => Ok(Some(len).filter(|len| body.len() >= *len)),
+        BodyLength::Invalid(reason) => Err(reason),
+        BodyLength::Chunked => {
+            let mut at = *from;
+            loop {
+                let line_end = match body[at..].iter().position(|b| *b == b'\n') {
+                    Some(end) => at + end,
+                    None => return Ok(None),
+                };
+                let line = String::from_utf8_lossy(&body[at..line_end]);
+                let size = line.split(';').next().unwrap_or_default().trim();
+                // from_str_radix would take a sign too
+                if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err("invalid chunk size");
+                }
+                let size = match usize::from_str_radix(size, 16) {
+                    Ok(size) => size,
+                    Err(_) => return Err("chunk size too large"),
+                };
+                at = line_end + 1;
+                if size == 0 {
+                    // Trailers, if any, then a blank line
+                    let rest = &body[at..];
+                    if rest.starts_with(b"\r\n") {
+                        return Ok(Some(at + 2));
+                    }
+                    if rest.starts_with(b"\n") {
+                        return Ok(Some(at + 1));
+                    }
+                    let blank = (0..rest.len()).find_map(|i| match &rest[i..] {
+                        [b'\n', b'\n', ..] => Some(i + 2),
+                        [b'\n', b'\r', b'\n', ..] => Some(i + 3),
+                        _ => None,
+                    });
+                    return Ok(blank.map(|end| at + end));
+                }
+                let end = match at.checked_add(size) {
+                    Some(end) => end,
+                    None => return Err("chunk size too large"),
+                };
+                // The data is followed by CRLF and nothing else, a lone \r may be its start
+                let after = body.get(end..).unwrap_or_default();
+                if !after.starts_with(b"\r\n") {
+                    if after.is_empty() || after == b"\r" {
+                        return Ok(None);
+                    }
+                    return Err("chunk data not followed by CRLF");
+                }
+                at = end + 2;
+                *from = at;
+            }
+        }
+    }
+}
+
+// Every deviation in the head of a request, in the order of the list above
+pub(crate) fn deviations(request: &str) -> Vec<Deviation> {
+    let mut lines = request.split('\n');
+    let request_line = lines.next().unwrap_or_default();
+    let mut bare_lf = !request_line.ends_with('\r');
+    let mut words = request_line.trim_end_matches('\r').split(' ');
+    let method = words.next().unwrap_or_default();
+    let version = words.next_back().unwrap_or_default();
+    let (mut space_before_colon, mut obs_fold, mut host) = (false, false, false);
+    for line in lines {
+        bare_lf |= !line.ends_with('\r');
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            obs_fold = true;
+        } else if let Some((name, _)) = line.split_once(':') {
+            space_before_colon |= name.trim_end() != name;
+            host |= name.trim_end().eq_ignore_ascii_case("host");
+        }
+    }
+    let found = [
+        (space_before_colon, Deviation::SpaceBeforeColon),
+        (obs_fold, Deviation::ObsFold),
+        (bare_lf, Deviation::BareLf),
+        (version == "HTTP/1.1" && !host, Deviation::MissingHost),
+        (
+            method.bytes().any(|b| b.is_ascii_lowercase()),
+            Deviation::LowercaseMethod,
+        ),
+    ];
+    found
+        .iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, deviation)| *deviation)
+        .collect()
+}
+
+#[test]
+fn test_parsing_rules() {
+    // Each fixture with the deviations it has, a strict server refuses all of them
+    let fixtures: [(&str, &[Deviation]); 7] = [
+        (
+            "GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\r\n\r\nbody\nwith\nlines",
+            &[],
+        ),
+        ("GET / HTTP/1.0\r\n\r\n", &[]),
+        (
+            "GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+            &[Deviation::SpaceBeforeColon],
+        ),
+        (
+            "GET / HTTP/1.1\r\nHost: a\r\nX-A: 1\r\n  2\r\n\r\n",
+            &[Deviation::ObsFold],
+        ),
+        ("GET / HTTP/1.1\nHost: a\r\n\r\n", &[Deviation::BareLf]),
+        (
+            "GET / HTTP/1.1\r\nX-A: 1\r\n\r\n",
+            &[Deviation::MissingHost],
+        ),
+        (
+            "get / HTTP/1.1\nhost:a\n\n",
+            &[Deviation::BareLf, Deviation::LowercaseMethod],
+        ),
+    ];
+    for (request, expected) in fixtures.iter() {
+        let found = deviations(request);
+        assert_eq!(&found[..], *expected, "{:?}", request);
+        let refused = |rules: ParsingRules| found.iter().find(|d| !rules.allows(**d)).copied();
+        // Whitespace before the colon is the one lenient refuses too
+        let lenient = expected
+            .first()
+            .filter(|d| **d == Deviation::SpaceBeforeColon);
+        assert_eq!(
+            refused(ParsingRules::lenient()),
+            lenient.copied(),
+            "{:?}",
+            request
+        );
+        assert_eq!(
+            refused(ParsingRules::strict()),
+            expected.first().copied(),
+            "{:?}",
+            request
+        );
+    }
+
+    // A single toggle lets its deviation through and nothing else
+    let mut rules = ParsingRules::strict();
+    rules.bare_lf = true;
+    assert!(rules.allows(Deviation::BareLf));
+    assert!(!rules.allows(Deviation::MissingHost));
+    assert_eq!(ParsingRules::default(), ParsingRules::strict());
+    assert!(!ParsingRules::lenient().allows(Deviation::SpaceBeforeColon));
+}
+
+#[test]
+fn test_body_length() {
+    let length = |head: &str| body_length(head.as_bytes());
+    assert_eq!(
+        length("GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+        BodyLength::Empty
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\ncontent-length: 12\r\n\r\n"),
+        BodyLength::Fixed(12)
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n"),
+        BodyLength::Fixed(usize::MAX)
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5, 5\r\n\r\n"),
+        BodyLength::Fixed(5)
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+        BodyLength::Chunked
+    );
+
+    // Each framing a proxy could read differently
+    let invalid = [
+        ("Content-Length: -1", "invalid Content-Length"),
+        ("Content-Length: +5", "invalid Content-Length"),
+        ("Content-Length:", "invalid Content-Length"),
+        (
+            "Content-Length: 5\r\nContent-Length: 6",
+            "conflicting Content-Length",
+        ),
+        ("Content-Length: 5, 6", "conflicting Content-Length"),
+        ("Transfer-Encoding : chunked", "whitespace in a header name"),
+        ("Content-Length\t: 5", "whitespace in a header name"),
+        (
+            "Transfer-Encoding: xchunked",
+            "Transfer-Encoding not ending in chunked",
+        ),
+        (
+            "Transfer-Encoding: chunked, gzip",
+            "Transfer-Encoding not ending in chunked",
+        ),
+        (
+            "Content-Length: 12\r\nTransfer-Encoding: chunked",
+            "both Transfer-Encoding and Content-Length",
+        ),
+    ];
+    for (headers, reason) in invalid.iter() {
+        let head = format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\n\r\n", headers);
+        assert_eq!(length(&head), BodyLength::Invalid(reason), "{}", headers);
+    }
+
+    assert_eq!(
+        body_end(BodyLength::Empty, b"GET / HTTP/1.1", &mut 0),
+        Ok(Some(0))
+    );
+    assert_eq!(body_end(BodyLength::Fixed(4), b"abcd", &mut 0), Ok(Some(4)));
+    assert_eq!(
+        body_end(BodyLength::Fixed(4), b"abcdGET", &mut 0),
+        Ok(Some(4))
+    );
+    assert_eq!(body_end(BodyLength::Fixed(4), b"abc", &mut 0), Ok(None));
+    let chunked = |body: &str| body_end(BodyLength::Chunked, body.as_bytes(), &mut 0);
+    assert_eq!(
+        chunked("4\r\nabcd\r\na;x=1\r\n0123456789\r\n0\r\n\r\n"),
+        Ok(Some(33))
+    );
+    assert_eq!(chunked("4\r\nabcd\r\n0\r\nX-Sum: 1\r\n\r\n"), Ok(Some(24)));
+    // The next request starts right after the blank line
+    assert_eq!(
+        chunked("4\r\nabcd\r\n0\r\n\r\nGET / HTTP/1.1\r\n"),
+        Ok(Some(14))
+    );
+    assert_eq!(chunked("4\r\nabcd\r\n"), Ok(None));
+    assert_eq!(chunked("4\r\nab"), Ok(None));
+    assert_eq!(chunked("4\r\nabcd\r\n0\r\n"), Ok(None));
+    assert_eq!(chunked("4\r\nabcd\r\n0\r\nX-Sum: 1\r\n"), Ok(None));
+    // Not a chunk size, the request is refused rather than cut there
+    assert_eq!(chunked("zz\r\n"), Err("invalid chunk size"));
+    assert_eq!(chunked("-4\r\nabcd\r\n"), Err("invalid chunk size"));
+    assert_eq!(
+        chunked("ffffffffffffffffff\r\n"),
+        Err("chunk size too large")
+    );
+    assert_eq!(chunked("4\r\nabcd\r"), Ok(None));
+    assert_eq!(
+        chunked("4\r\nabcdX"),
+        Err("chunk data not followed by CRLF")
+    );
+    assert_eq!(
+        chunked("4\r\nabcd\n0\r\n\r\n"),
+        Err("chunk data not followed by CRLF")
+    );
+    assert_eq!(
+        chunked("4\r\nabcd\rX"),
+        Err("chunk data not followed by CRLF")
+    );
+
+    // Read in pieces, each call carries on after the last whole chunk
+    let body = b"4\r\nabcd\r\n3\r\nxyz\r\n0\r\n\r\n";
+    let mut from = 0;
+    let ends: Vec<_> = [5, 9, 14, 20, body.len()]
+        .iter()
+        .map(|len| {
+            (
+                body_end(BodyLength::Chunked, &body[..*len], &mut from),
+                from,
+            )
+        })
+        .collect();
+    assert_eq!(
+        ends,
+        vec![
+            (Ok(None), 0),
+            (Ok(None), 9),
+            (Ok(None), 9),
+            (Ok(None), 17),
+            (Ok(Some(22)), 17)
+        ]
+    );
+}
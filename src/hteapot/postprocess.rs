@@ -0,0 +1,166 @@
+// Hooks that change responses after the handler built them
+// Each one is registered for a content type and only sees matching responses
+
+use super::{HttpRequest, HttpResponse, ResponseKind};
+use std::sync::Arc;
+
+pub trait ResponsePostProcessor: Send + Sync {
+    // req only carries the request line and headers, the body is already consumed
+    fn process(&self, req: &HttpRequest, resp: &mut HttpResponse);
+}
+
+#[derive(Clone)]
+pub(crate) struct PostProcessor {
+    content_type: String,
+    processor: Arc<dyn ResponsePostProcessor>,
+}
+
+impl PostProcessor {
+    pub(crate) fn new(content_type: &str, processor: Arc<dyn ResponsePostProcessor>) -> Self {
+        PostProcessor {
+            content_type: content_type.trim().to_lowercase(),
+            processor,
+        }
+    }
+
+    // "*" matches everything and "text/*" any text type
+    fn matches(&self, content_type: &str) -> bool {
+        let media_type = media_type(content_type);
+        match self.content_type.strip_suffix('*') {
+            Some(prefix) => media_type.starts_with(prefix),
+            None => media_type == self.content_type,
+        }
+    }
+}
+
+// Content type without parameters, lowercase
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase()
+}
+
+// Charset parameter of a content type, lowercase
+fn charset(content_type: &str) -> Option<String> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, v)| v.trim().trim_matches('"').to_lowercase())
+}
+
+// Run the matching processors, raw and hijacked responses are sent untouched
+// True when any of them ran, the engine sets Content-Length again since the body may have changed
+// Digests of the old body are dropped, they would no longer match
+pub(crate) fn run(
+    processors: &[PostProcessor],
+    req: &HttpRequest,
+    resp: &mut HttpResponse,
+) -> bool {
+    if resp.is_raw() || resp.kind() != ResponseKind::Buffered {
+        return false;
+    }
+    let mut changed = false;
+    for p in processors.iter() {
+        let content_type = resp.headers.get("Content-Type").unwrap_or("").to_string();
+        if p.matches(&content_type) {
+            resp.make_owned();
+            p.processor.process(req, resp);
+            changed = true;
+        }
+    }
+    if changed {
+        resp.headers.remove("Repr-Digest");
+        resp.headers.remove("Digest");
+    }
+    changed
+}
+
+// Insert a snippet before the last </body>, or at the end when there is none
+pub struct InjectHtml {
+    snippet: String,
+}
+
+impl InjectHtml {
+    pub fn new(snippet: &str) -> InjectHtml {
+        InjectHtml {
+            snippet: snippet.to_string(),
+        }
+    }
+}
+
+impl ResponsePostProcessor for InjectHtml {
+    fn process(&self, _req: &HttpRequest, resp: &mut HttpResponse) {
+        // The snippet is ASCII compatible bytes, wide charsets can't be patched in place
+        let content_type = resp.headers.get("Content-Type").unwrap_or("");
+        if let Some(charset) = charset(content_type) {
+            if charset.starts_with("utf-16") || charset.starts_with("utf-32") {
+                return;
+            }
+        }
+        let position = resp
+            .content
+            .windows(7)
+            .rposition(|w| w.eq_ignore_ascii_case(b"</body>"))
+            .unwrap_or(resp.content.len());
+        resp.content
+            .splice(position..position, self.snippet.bytes());
+    }
+}
+
+#[cfg(test)]
+fn html_response(content_type: &str, body: &[u8]) -> HttpResponse {
+    let mut headers = super::Headers::new();
+    headers.insert("Content-Type", content_type);
+    HttpResponse::new(super::HttpStatus::OK, body, Some(headers))
+}
+
+#[test]
+fn test_inject_html() {
+    let req = super::Hteapot::request_parser("GET / HTTP/1.1\r\n\r\n".to_string()).unwrap();
+    let processors = [PostProcessor::new(
+        "text/html",
+        Arc::new(InjectHtml::new("<script></script>")),
+    )];
+    let cases: [(&str, &[u8], &[u8]); 6] = [
+        (
+            "text/html",
+            b"<body>a</body></html>",
+            b"<body>a<script></script></body></html>",
+        ),
+        (
+            "text/html; charset=utf-8",
+            b"<p></body>x</BODY>",
+            b"<p></body>x<script></script></BODY>",
+        ),
+        ("TEXT/HTML", b"<p>no body", b"<p>no body<script></script>"),
+        (
+            "text/html; charset=iso-8859-1",
+            b"<p>\xF1</body>",
+            b"<p>\xF1<script></script></body>",
+        ),
+        ("text/html; charset=UTF-16", b"<\0/\0", b"<\0/\0"),
+        ("text/plain", b"</body>", b"</body>"),
+    ];
+    for (content_type, body, expected) in cases.iter() {
+        let mut resp = html_response(content_type, body);
+        let ran = run(&processors, &req, &mut resp);
+        assert_eq!(&resp.content[..], *expected, "{}", content_type);
+        assert_eq!(ran, *content_type != "text/plain", "{}", content_type);
+    }
+
+    // A digest of the file before the snippet went in is dropped
+    let mut resp = html_response("text/html", b"<body></body>");
+    resp.headers.insert("Repr-Digest", "sha-256=:AAAA:");
+    resp.headers.insert("Digest", "SHA-256=AAAA");
+    assert!(run(&processors, &req, &mut resp));
+    assert!(!resp.headers.contains_key("Repr-Digest") && !resp.headers.contains_key("Digest"));
+
+    let any_text = PostProcessor::new("text/*", Arc::new(InjectHtml::new("")));
+    assert!(any_text.matches("text/css; charset=utf-8"));
+    assert!(!any_text.matches("application/json"));
+}
@@ -0,0 +1,199 @@
+// PROXY protocol header a load balancer sends before the request, naming the real client
+// Version 1 is a text line, version 2 binary, both described at haproxy.org/download/2.0/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107; // CRLF included
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEAD_LEN: usize = 16;
+
+// Length of the header and the client it names
+// The client is None for health checks from the balancer itself and unknown families
+pub(crate) fn parse(data: &[u8]) -> Result<(Option<SocketAddr>, usize), &'static str> {
+    if data.starts_with(V2_SIGNATURE) {
+        parse_v2(data)
+    } else if data.starts_with(V1_PREFIX) {
+        parse_v1(data)
+    } else {
+        Err("missing PROXY header")
+    }
+}
+
+fn parse_v1(data: &[u8]) -> Result<(Option<SocketAddr>, usize), &'static str> {
+    let window = &data[..data.len().min(V1_MAX_LEN)];
+    let end = window
+        .windows(2)
+        .position(|pair| pair == b"\r\n")
+        .ok_or("PROXY v1 line not terminated")?;
+    let line = std::str::from_utf8(&data[..end]).map_err(|_| "PROXY v1 line not text")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.get(1) {
+        Some(&"UNKNOWN") => None,
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip: IpAddr = fields[2].parse().map_err(|_| "PROXY v1 bad address")?;
+            let _: IpAddr = fields[3].parse().map_err(|_| "PROXY v1 bad address")?;
+            if ip.is_ipv4() != (fields[1] == "TCP4") {
+                return Err("PROXY v1 address doesn't match the family");
+            }
+            let port = parse_port(fields[4])?;
+            parse_port(fields[5])?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err("PROXY v1 bad protocol"),
+    };
+    Ok((source, end + 2))
+}
+
+// Decimal without leading zeros, like the spec asks
+fn parse_port(port: &str) -> Result<u16, &'static str> {
+    if port.len() > 1 && port.starts_with('0') {
+        return Err("PROXY v1 bad port");
+    }
+    port.parse().map_err(|_| "PROXY v1 bad port")
+}
+
+fn parse_v2(data: &[u8]) -> Result<(Option<SocketAddr>, usize), &'static str> {
+    if data.len() < V2_HEAD_LEN {
+        return Err("PROXY v2 header truncated");
+    }
+    let version_command = data[12];
+    let family = data[13];
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err("PROXY v2 bad version");
+    }
+    let total = V2_HEAD_LEN + len;
+    let addresses = data
+        .get(V2_HEAD_LEN..total)
+        .ok_or("PROXY v2 header truncated")?;
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    let source = match (version_command & 0x0f, family >> 4) {
+        // LOCAL, the balancer talking for itself
+        (0, _) => None,
+        (1, 1) => {
+            if len < 12 {
+                return Err("PROXY v2 address block too short");
+            }
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port(8)))
+        }
+        (1, 2) => {
+            if len < 36 {
+                return Err("PROXY v2 address block too short");
+            }
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port(32),
+            ))
+        }
+        // Unix sockets and unspecified families carry no client address
+        (1, _) => None,
+        _ => return Err("PROXY v2 bad command"),
+    };
+    Ok((source, total))
+}
+
+// A version 2 header for tests, source and destination of the same family
+#[cfg(test)]
+pub(crate) fn v2_header(command: u8, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let mut addresses = Vec::new();
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            addresses.extend_from_slice(&s.octets());
+            addresses.extend_from_slice(&d.octets());
+            0x11
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            addresses.extend_from_slice(&s.octets());
+            addresses.extend_from_slice(&d.octets());
+            0x21
+        }
+        _ => panic!("Mixed address families"),
+    };
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+#[test]
+fn test_proxy_protocol_v1() {
+    let request = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\n\r\n";
+    let (source, len) = parse(request).unwrap();
+    assert_eq!(source, Some("203.0.113.7:51234".parse().unwrap()));
+    assert!(request[len..].starts_with(b"GET /"));
+
+    let (source, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 443 8080\r\n").unwrap();
+    assert_eq!(source, Some("[2001:db8::1]:443".parse().unwrap()));
+    assert_eq!(parse(b"PROXY UNKNOWN\r\n"), Ok((None, 15)));
+    assert_eq!(
+        parse(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").map(|(s, _)| s),
+        Ok(None)
+    );
+
+    for malformed in [
+        &b"GET / HTTP/1.1\r\n\r\n"[..],
+        b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\n",
+        b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n",
+        b"PROXY TCP4 2001:db8::1 10.0.0.1 51234 80\r\n",
+        b"PROXY TCP4 203.0.113.7 10.0.0.1 65536 80\r\n",
+        b"PROXY TCP4 203.0.113.7 10.0.0.1 051234 80\r\n",
+        b"PROXY TCP4 203.0.113.7 10.0.0.1  51234 80\r\n",
+        b"PROXY UDP4 203.0.113.7 10.0.0.1 51234 80\r\n",
+        b"PROXY \xff\r\n",
+    ] {
+        assert!(parse(malformed).is_err(), "{:?}", malformed);
+    }
+    // The line must end within the 107 bytes allowed
+    let mut long = b"PROXY UNKNOWN ".to_vec();
+    long.extend_from_slice(&[b'x'; 100]);
+    long.extend_from_slice(b"\r\n");
+    assert!(parse(&long).is_err());
+}
+
+#[test]
+fn test_proxy_protocol_v2() {
+    let source: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+    let destination: SocketAddr = "10.0.0.1:80".parse().unwrap();
+    let mut request = v2_header(1, source, destination);
+    let header_len = request.len();
+    request.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(parse(&request), Ok((Some(source), header_len)));
+
+    let source6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let destination6: SocketAddr = "[2001:db8::2]:8080".parse().unwrap();
+    let header = v2_header(1, source6, destination6);
+    assert_eq!(parse(&header), Ok((Some(source6), 52)));
+
+    // LOCAL health checks name no client
+    assert_eq!(parse(&v2_header(0, source, destination)), Ok((None, 28)));
+
+    // Extra TLVs after the addresses are skipped with them
+    let mut tlv = v2_header(1, source, destination);
+    tlv[15] += 4;
+    tlv.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+    assert_eq!(parse(&tlv), Ok((Some(source), 32)));
+
+    let mut bad_version = header.clone();
+    bad_version[12] = 0x11;
+    let mut bad_command = header.clone();
+    bad_command[12] = 0x22;
+    let mut short_block = v2_header(1, source, destination);
+    short_block[15] = 8;
+    for malformed in [
+        &header[..14],
+        &header[..40],
+        &bad_version[..],
+        &bad_command[..],
+        &short_block[..],
+    ] {
+        assert!(parse(malformed).is_err(), "{:?}", malformed);
+    }
+}
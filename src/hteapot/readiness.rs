@@ -0,0 +1,217 @@
+// Waiting for connections to become readable or writable, instead of trying each of them in a loop
+// poll on unix and WSAPoll on windows, elsewhere every socket is reported ready after a short sleep
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Interest {
+    Read,
+    Write,
+}
+
+#[cfg(unix)]
+pub(crate) type Handle = std::os::unix::io::RawFd;
+#[cfg(windows)]
+pub(crate) type Handle = std::os::windows::io::RawSocket;
+#[cfg(not(any(unix, windows)))]
+pub(crate) type Handle = ();
+
+#[cfg(unix)]
+pub(crate) fn handle_of(socket: &impl std::os::unix::io::AsRawFd) -> Handle {
+    socket.as_raw_fd()
+}
+
+#[cfg(windows)]
+pub(crate) fn handle_of(socket: &impl std::os::windows::io::AsRawSocket) -> Handle {
+    socket.as_raw_socket()
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn handle_of<T>(_socket: &T) -> Handle {}
+
+// Which sockets are ready, waiting up to timeout for the first one
+// Errors and hang ups count as ready, the next read or write finds out what happened
+pub(crate) fn wait(sockets: &[(Handle, Interest)], timeout: Duration) -> io::Result<Vec<bool>> {
+    // Rounded up, a wait shorter than a millisecond would return at once and spin
+    let millis = timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+    match sys::poll(sockets, millis) {
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(vec![false; sockets.len()]),
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::{Handle, Interest};
+    use std::io;
+
+    #[repr(C)]
+    struct PollFd {
+        fd: i32,
+        events: i16,
+        revents: i16,
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    type Nfds = std::os::raw::c_ulong;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    type Nfds = std::os::raw::c_uint;
+
+    const POLLIN: i16 = 0x1;
+    const POLLOUT: i16 = 0x4;
+
+    extern "C" {
+        #[link_name = "poll"]
+        fn c_poll(fds: *mut PollFd, nfds: Nfds, timeout: i32) -> i32;
+    }
+
+    pub(super) fn poll(sockets: &[(Handle, Interest)], millis: i32) -> io::Result<Vec<bool>> {
+        let mut fds: Vec<PollFd> = sockets
+            .iter()
+            .map(|(fd, interest)| PollFd {
+                fd: *fd,
+                events: match interest {
+                    Interest::Read => POLLIN,
+                    Interest::Write => POLLOUT,
+                },
+                revents: 0,
+            })
+            .collect();
+        if unsafe { c_poll(fds.as_mut_ptr(), fds.len() as Nfds, millis) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::{Handle, Interest};
+    use std::io;
+
+    #[repr(C)]
+    struct WsaPollFd {
+        fd: usize,
+        events: i16,
+        revents: i16,
+    }
+
+    const POLLRDNORM: i16 = 0x100;
+    const POLLWRNORM: i16 = 0x10;
+
+    #[link(name = "ws2_32")]
+    extern "system" {
+        fn WSAPoll(fds: *mut WsaPollFd, nfds: u32, timeout: i32) -> i32;
+    }
+
+    pub(super) fn poll(sockets: &[(Handle, Interest)], millis: i32) -> io::Result<Vec<bool>> {
+        // WSAPoll refuses an empty set
+        if sockets.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(millis as u64));
+            return Ok(Vec::new());
+        }
+        let mut fds: Vec<WsaPollFd> = sockets
+            .iter()
+            .map(|(socket, interest)| WsaPollFd {
+                fd: *socket as usize,
+                events: match interest {
+                    Interest::Read => POLLRDNORM,
+                    Interest::Write => POLLWRNORM,
+                },
+                revents: 0,
+            })
+            .collect();
+        if unsafe { WSAPoll(fds.as_mut_ptr(), fds.len() as u32, millis) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod sys {
+    use super::{Handle, Interest};
+    use std::io;
+    use std::time::Duration;
+
+    // No readiness here, a short nap keeps the workers from spinning
+    pub(super) fn poll(sockets: &[(Handle, Interest)], millis: i32) -> io::Result<Vec<bool>> {
+        std::thread::sleep(Duration::from_millis(millis.clamp(0, 10) as u64));
+        Ok(vec![true; sockets.len()])
+    }
+}
+
+// Wakes workers waiting on their connections when a new one is queued
+// A datagram to itself on loopback, so it can wait along with the connections
+pub(crate) struct Waker {
+    socket: UdpSocket,
+    address: SocketAddr,
+}
+
+impl Waker {
+    pub(crate) fn new() -> io::Result<Waker> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_nonblocking(true)?;
+        let address = socket.local_addr()?;
+        Ok(Waker { socket, address })
+    }
+
+    pub(crate) fn wake(&self) {
+        let _ = self.socket.send_to(&[1], self.address);
+    }
+
+    // Read every pending wake up, so the next wait blocks again
+    pub(crate) fn drain(&self) {
+        let mut buffer = [0; 16];
+        while self.socket.recv(&mut buffer).is_ok() {}
+    }
+
+    pub(crate) fn handle(&self) -> Handle {
+        handle_of(&self.socket)
+    }
+}
+
+#[test]
+fn test_wait_readiness() {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let sockets = [
+        (handle_of(&server), Interest::Read),
+        (handle_of(&client), Interest::Write),
+    ];
+
+    // Nothing to read yet, while there is room to write
+    assert_eq!(
+        wait(&sockets[..1], Duration::from_millis(50)).unwrap(),
+        vec![false]
+    );
+    assert_eq!(
+        wait(&sockets, Duration::from_secs(5)).unwrap(),
+        vec![false, true]
+    );
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(
+        wait(&sockets[..1], Duration::from_secs(5)).unwrap(),
+        vec![true]
+    );
+
+    let waker = Waker::new().unwrap();
+    let woken = [(waker.handle(), Interest::Read)];
+    let started = Instant::now();
+    assert_eq!(
+        wait(&woken, Duration::from_millis(50)).unwrap(),
+        vec![false]
+    );
+    assert!(started.elapsed() >= Duration::from_millis(40));
+    waker.wake();
+    assert_eq!(wait(&woken, Duration::from_secs(5)).unwrap(), vec![true]);
+    waker.drain();
+    assert_eq!(wait(&woken, Duration::ZERO).unwrap(), vec![false]);
+}
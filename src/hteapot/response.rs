@@ -1,70 +1,606 @@
+use super::Headers;
 use super::HttpStatus;
 use super::VERSION;
-use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Code that takes over the connection, see HttpResponse::hijack
+pub type Hijack = Box<dyn FnOnce(&mut TcpStream) -> io::Result<()> + Send>;
+
+// Code writing the body of a response with a known length, see HttpResponse::with_length
+pub type Producer = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+// Code sending the body of a response in chunks, see HttpResponse::chunked
+pub type ChunkProducer = Box<dyn FnOnce(&mut ChunkSender) -> io::Result<()> + Send>;
+
+// How the server sends a response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseKind {
+    Buffered, // Whole response in memory, written by the server
+    Hijacked, // The server hands the socket over and stops driving the connection
+    Streamed, // Headers, then the body as a producer writes it, sized or in chunks
+}
+
+// How long a cache in front of the handler may keep a response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheLifetime {
+    Default,       // As long as the cache keeps anything
+    NoStore,       // Never kept
+    Ttl(Duration), // Kept this long instead
+}
+
+impl CacheLifetime {
+    // From a Cache-Control value, no-store and private win, s-maxage over max-age
+    pub fn from_cache_control(value: &str) -> CacheLifetime {
+        let (mut max_age, mut shared_max_age) = (None, None);
+        for directive in value.split(',') {
+            let (name, seconds) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim(), arg.trim().trim_matches('"').parse().ok()),
+                None => (directive.trim(), None),
+            };
+            if name.eq_ignore_ascii_case("no-store") || name.eq_ignore_ascii_case("private") {
+                return CacheLifetime::NoStore;
+            } else if name.eq_ignore_ascii_case("s-maxage") {
+                shared_max_age = seconds.or(shared_max_age);
+            } else if name.eq_ignore_ascii_case("max-age") {
+                max_age = seconds.or(max_age);
+            }
+        }
+        match shared_max_age.or(max_age) {
+            Some(0) => CacheLifetime::NoStore,
+            Some(seconds) => CacheLifetime::Ttl(Duration::from_secs(seconds)),
+            None => CacheLifetime::Default,
+        }
+    }
+}
 
 pub struct HttpResponse {
     pub status: HttpStatus,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub content: Vec<u8>,
+    shared: Option<Arc<Vec<u8>>>, // Body shared with a cache, used instead of content
     raw: Option<Vec<u8>>,
     is_raw: bool,
+    hijack: Option<Hijack>,
+    producer: Option<(u64, Producer)>, // Declared length and the code writing the body
+    chunks: Option<ChunkProducer>,
+    close: bool,  // Close the connection after sending, whatever the client asked
+    silent: bool, // Close it without sending anything, see no_response
+    cache: Option<CacheLifetime>, // From no_store or cache_ttl, else read from Cache-Control
+}
+
+// Value of a header in the head of a raw response, the first one when repeated
+fn raw_header(raw: &[u8], name: &str) -> Option<String> {
+    let end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end])
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// Bytes to send for a response, the body is shared rather than copied
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OutBuffer {
+    head: Vec<u8>,
+    body: Arc<Vec<u8>>,
+}
+
+impl OutBuffer {
+    pub(crate) fn len(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Bytes to send before the response, like an interim 1xx
+    pub(crate) fn prepend(&mut self, mut bytes: Vec<u8>) {
+        if !bytes.is_empty() {
+            bytes.append(&mut self.head);
+            self.head = bytes;
+        }
+    }
+
+    pub(crate) fn head_len(&self) -> usize {
+        self.head.len()
+    }
+
+    // Longest contiguous slice starting at `from` and ending before `to`
+    pub(crate) fn chunk(&self, from: usize, to: usize) -> &[u8] {
+        let body_start = self.head.len();
+        if from < body_start {
+            &self.head[from..to.min(body_start)]
+        } else {
+            &self.body[from - body_start..to - body_start]
+        }
+    }
 }
 
 impl HttpResponse {
-    pub fn new<B: AsRef<[u8]>>(
+    pub fn new<B: AsRef<[u8]>>(status: HttpStatus, content: B, headers: Option<Headers>) -> Self {
+        let mut response = HttpResponse::empty(status, headers);
+        response.content = content.as_ref().to_owned();
+        response.set_length();
+        response
+    }
+
+    // Response with a body shared with other responses, it is never copied while sending
+    pub fn from_shared(
         status: HttpStatus,
-        content: B,
-        headers: Option<HashMap<String, String>>,
+        content: Arc<Vec<u8>>,
+        headers: Option<Headers>,
     ) -> Self {
-        let mut headers = headers.unwrap_or(HashMap::new());
-        let content = content.as_ref();
-        headers.insert("Content-Length".to_string(), content.len().to_string());
-        headers.insert(
-            "Server".to_string(),
-            format!("HTeaPot/{}", VERSION).to_string(),
-        );
+        let mut response = HttpResponse::empty(status, headers);
+        response.shared = Some(content);
+        response.set_length();
+        response
+    }
+
+    fn empty(status: HttpStatus, headers: Option<Headers>) -> Self {
+        let mut headers = headers.unwrap_or_default();
+        headers.insert("Server", format!("HTeaPot/{}", VERSION));
         HttpResponse {
             status,
             headers,
-            content: content.to_owned(),
+            content: vec![],
+            shared: None,
             raw: None,
             is_raw: false,
+            hijack: None,
+            producer: None,
+            chunks: None,
+            close: false,
+            silent: false,
+            cache: None,
+        }
+    }
+
+    // Take over the connection once the handler returns
+    // The closure runs on its own thread with a blocking socket and nothing is written for it
+    // Returning an error before writing anything sends a 502 with the error to the client
+    // The connection is closed when the closure ends, a response it writes should say Connection: close
+    pub fn hijack(handler: impl FnOnce(&mut TcpStream) -> io::Result<()> + Send + 'static) -> Self {
+        let mut response = HttpResponse::empty(HttpStatus::OK, None);
+        response.hijack = Some(Box::new(handler));
+        response
+    }
+
+    // Send a body of exactly length bytes, written by producer as it makes them
+    // Runs like a hijack, on its own thread once the status and headers are final
+    // Writing more than length is an error, the connection is closed when the producer ends
+    // and a short body is cut off there, so the client sees it didn't get everything
+    // Failing before writing anything sends a 502 instead
+    // With a length of 0, or for a HEAD request, the producer never runs and the connection is kept
+    pub fn with_length(
+        length: u64,
+        producer: impl FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        let mut response = HttpResponse::empty(HttpStatus::OK, None);
+        response
+            .headers
+            .insert("Content-Length", length.to_string());
+        response.producer = Some((length, Box::new(producer)));
+        response
+    }
+
+    // Send a body of unknown length, Transfer-Encoding: chunked, as producer makes it
+    // Runs on its own thread like with_length, for feeds like a log tail or server-sent events
+    // Each send is one chunk, once the client is gone it returns an error and the producer
+    // should stop, is_closed tells the same without sending
+    // Failing before the first chunk sends a 502, after it the body is left unterminated
+    // Clients have to speak HTTP/1.1 to read chunks
+    pub fn chunked(
+        producer: impl FnOnce(&mut ChunkSender) -> io::Result<()> + Send + 'static,
+    ) -> Self {
+        let mut response = HttpResponse::empty(HttpStatus::OK, None);
+        response.headers.insert("Transfer-Encoding", "chunked");
+        response.chunks = Some(Box::new(producer));
+        response
+    }
+
+    pub fn kind(&self) -> ResponseKind {
+        if self.hijack.is_some() {
+            ResponseKind::Hijacked
+        } else if self.producer.is_some() || self.chunks.is_some() {
+            ResponseKind::Streamed
+        } else {
+            ResponseKind::Buffered
         }
     }
 
+    // A streamed response with nothing to write, sent like a buffered one
+    pub(crate) fn streams_nothing(&self) -> bool {
+        matches!(self.producer, Some((0, _)))
+    }
+
+    // Keep the status and headers, Content-Length included, and drop the body, like for HEAD
+    // A streamed body is dropped without running its producer, hijacks are left alone
+    pub(crate) fn drop_body(&mut self) {
+        self.producer = None;
+        self.chunks = None;
+        self.content = vec![];
+        self.shared = None;
+        if let Some(raw) = &mut self.raw {
+            if let Some(end) = raw.windows(4).position(|w| w == b"\r\n\r\n") {
+                raw.truncate(end + 4);
+            }
+        }
+    }
+
+    // Streamed responses become a hijack writing the head before the body
+    pub(crate) fn take_hijack(&mut self) -> Option<Hijack> {
+        if let Some(producer) = self.chunks.take() {
+            self.headers.insert("Connection", "close");
+            return Some(chunked_hijack(self.head(), producer));
+        }
+        let (length, producer) = match self.producer.take() {
+            Some(producer) => producer,
+            None => return self.hijack.take(),
+        };
+        self.headers.insert("Connection", "close");
+        let head = self.head();
+        Some(Box::new(move |stream| {
+            let mut body = SizedBody {
+                stream,
+                head: Some(head),
+                remaining: length,
+            };
+            let result = producer(&mut body);
+            let error = match (result, body.remaining) {
+                (Ok(()), 0) => return body.flush_head(),
+                (Err(e), _) => e,
+                (Ok(()), remaining) => io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("body {} bytes short of its Content-Length", remaining),
+                ),
+            };
+            if body.head.is_some() {
+                return Err(error);
+            }
+            // Too late for an error response, closing is all that is left
+            eprintln!("Streamed response cut short: {}", error);
+            Ok(())
+        }))
+    }
+
+    // Make Content-Length match the body, for buffered responses built with new or from_shared
+    // Returns the wrong value it replaced, raw and streamed responses are left alone
+    pub(crate) fn fix_length(&mut self) -> Option<String> {
+        if self.is_raw() || self.kind() != ResponseKind::Buffered {
+            return None;
+        }
+        let actual = self.body().len().to_string();
+        match self.headers.get("Content-Length") {
+            Some(declared) if declared.trim() == actual => None,
+            Some(declared) => {
+                let declared = declared.to_string();
+                self.headers.insert("Content-Length", actual);
+                Some(declared)
+            }
+            None => {
+                self.headers.insert("Content-Length", actual);
+                None
+            }
+        }
+    }
+
+    fn set_length(&mut self) {
+        let length = self.body().len().to_string();
+        self.headers.insert("Content-Length", length);
+    }
+
     pub fn new_raw(raw: Vec<u8>) -> Self {
         HttpResponse {
             status: HttpStatus::IAmATeapot,
-            headers: HashMap::new(),
+            headers: Headers::new(),
             content: vec![],
+            shared: None,
             raw: Some(raw),
             is_raw: true,
+            hijack: None,
+            producer: None,
+            chunks: None,
+            close: false,
+            silent: false,
+            cache: None,
         }
     }
 
+    // Close the connection without writing a byte, like nginx's 444, for clients not worth an answer
+    pub fn no_response() -> Self {
+        let mut response = HttpResponse::empty(HttpStatus::OK, None);
+        response.close = true;
+        response.silent = true;
+        response
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.silent
+    }
+
+    // Close the connection once this response is sent, even if the client asked to keep it
+    pub fn close_connection(&mut self) {
+        self.close = true;
+    }
+
+    // Also true when the handler set Connection: close itself
+    pub fn closes_connection(&self) -> bool {
+        self.close
+            || self
+                .headers
+                .get("Connection")
+                .is_some_and(|c| c.eq_ignore_ascii_case("close"))
+    }
+
+    // Keep caches in front of the handler from storing this response
+    pub fn no_store(&mut self) {
+        self.cache = Some(CacheLifetime::NoStore);
+    }
+
+    // Let caches keep this response for seconds instead of their own ttl
+    pub fn cache_ttl(&mut self, seconds: u64) {
+        self.cache = Some(match seconds {
+            0 => CacheLifetime::NoStore,
+            _ => CacheLifetime::Ttl(Duration::from_secs(seconds)),
+        });
+    }
+
+    // What no_store or cache_ttl set, else what the handler's Cache-Control header says
+    pub fn cache_lifetime(&self) -> CacheLifetime {
+        if let Some(lifetime) = self.cache {
+            return lifetime;
+        }
+        let cache_control = match &self.raw {
+            Some(raw) => raw_header(raw, "Cache-Control"),
+            None => self.headers.get("Cache-Control").map(String::from),
+        };
+        cache_control.map_or(CacheLifetime::Default, |value| {
+            CacheLifetime::from_cache_control(&value)
+        })
+    }
+
     pub fn is_raw(&self) -> bool {
         self.is_raw
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        if self.is_raw() {
-            return self.raw.clone().unwrap();
+    // Set a header, in a raw response it is added right after the status line
+    pub fn add_header(&mut self, key: &str, value: &str) {
+        match &mut self.raw {
+            Some(raw) => {
+                if let Some(position) = raw.windows(2).position(|w| w == b"\r\n") {
+                    let header = format!("\r\n{}: {}", key, value);
+                    raw.splice(position..position, header.bytes());
+                }
+            }
+            None => {
+                self.headers.insert(key, value);
+            }
+        }
+    }
+
+    // Set a header replacing any of the same name, raw responses included
+    // For the hop-by-hop headers the server decides on, like Connection
+    pub(crate) fn set_header(&mut self, key: &str, value: &str) {
+        if let Some(raw) = &mut self.raw {
+            let head_end = raw
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .unwrap_or(raw.len());
+            // Each header line with the \r\n before it, the status line stays
+            let mut at = raw[..head_end]
+                .windows(2)
+                .position(|w| w == b"\r\n")
+                .unwrap_or(head_end);
+            let mut head_end = head_end;
+            while at < head_end {
+                let end = raw[at + 2..head_end]
+                    .windows(2)
+                    .position(|w| w == b"\r\n")
+                    .map_or(head_end, |p| at + 2 + p);
+                let line = String::from_utf8_lossy(&raw[at + 2..end]);
+                let same = line
+                    .split_once(':')
+                    .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case(key));
+                if same {
+                    raw.drain(at..end);
+                    head_end -= end - at;
+                } else {
+                    at = end;
+                }
+            }
+        }
+        self.add_header(key, value);
+    }
+
+    // The body, whether owned or shared
+    pub fn body(&self) -> &[u8] {
+        match &self.shared {
+            Some(shared) => shared,
+            None => &self.content,
         }
+    }
+
+    // Move a shared body into content so it can be changed, copying it if still in use
+    pub fn make_owned(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            self.content = Arc::try_unwrap(shared).unwrap_or_else(|shared| (*shared).clone());
+        }
+    }
+
+    fn head(&self) -> Vec<u8> {
         let mut headers_text = String::new();
-        for (key, value) in self.headers.iter() {
+        for (key, value) in self.headers.canonical() {
             headers_text.push_str(&format!("{}: {}\r\n", key, value));
         }
-        let response_header = format!(
+        format!(
             "HTTP/1.1 {} {}\r\n{}\r\n",
             self.status as u16,
             self.status.to_string(),
             headers_text
-        );
-        let mut response = Vec::new();
-        response.extend_from_slice(response_header.as_bytes());
-        response.append(&mut self.content.clone());
-        response.push(0x0D); // Carriage Return
-        response.push(0x0A); // Line Feed
+        )
+        .into_bytes()
+    }
+
+    pub(crate) fn into_out_buffer(self) -> OutBuffer {
+        if let Some(mut raw) = self.raw {
+            let head_end = raw
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|p| p + 4)
+                .unwrap_or(0);
+            let body = raw.split_off(head_end);
+            return OutBuffer {
+                head: raw,
+                body: Arc::new(body),
+            };
+        }
+        let head = self.head();
+        let body = match self.shared {
+            Some(shared) => shared,
+            None => Arc::new(self.content),
+        };
+        // Nothing follows the body, extra bytes would be read as the next response
+        OutBuffer { head, body }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.is_raw() {
+            return self.raw.clone().unwrap();
+        }
+        let mut response = self.head();
+        response.extend_from_slice(self.body());
         response
     }
 }
+
+// Body of a streamed response, the head goes out with the first bytes
+struct SizedBody<'a> {
+    stream: &'a mut TcpStream,
+    head: Option<Vec<u8>>,
+    remaining: u64,
+}
+
+impl<'a> SizedBody<'a> {
+    fn flush_head(&mut self) -> io::Result<()> {
+        match self.head.take() {
+            Some(head) => self.stream.write_all(&head),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Write for SizedBody<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "body longer than its Content-Length",
+            ));
+        }
+        self.flush_head()?;
+        let written = self.stream.write(buf)?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn chunked_hijack(head: Vec<u8>, producer: ChunkProducer) -> Hijack {
+    Box::new(move |stream| {
+        let mut sender = ChunkSender {
+            stream,
+            head: Some(head),
+            closed: false,
+        };
+        let error = match producer(&mut sender) {
+            Ok(()) => match sender.finish() {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        if sender.head.is_some() {
+            return Err(error);
+        }
+        // The client hanging up is how feeds usually end
+        if !sender.closed {
+            eprintln!("Chunked response cut short: {}", error);
+        }
+        Ok(())
+    })
+}
+
+// Body of a chunked response, the head goes out with the first chunk
+pub struct ChunkSender<'a> {
+    stream: &'a mut TcpStream,
+    head: Option<Vec<u8>>,
+    closed: bool, // The client went away, or a write to it failed
+}
+
+impl<'a> ChunkSender<'a> {
+    // Send one chunk, an empty one is skipped as it would end the body
+    // Errs with BrokenPipe once the client is gone, nothing sent after that reaches anyone
+    pub fn send(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "client closed the connection",
+            ));
+        }
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let mut framed = self.head.take().unwrap_or_default();
+        framed.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        framed.extend_from_slice(chunk);
+        framed.extend_from_slice(b"\r\n");
+        let written = self.stream.write_all(&framed);
+        self.closed = written.is_err();
+        written
+    }
+
+    // Whether the client went away, checked without blocking
+    // For producers with expensive steps between sends, to stop before doing the work
+    pub fn is_closed(&mut self) -> bool {
+        if !self.closed {
+            self.closed = peer_closed(self.stream);
+        }
+        self.closed
+    }
+
+    // The last chunk, also sends the head when no chunk was
+    fn finish(&mut self) -> io::Result<()> {
+        let mut last = self.head.take().unwrap_or_default();
+        last.extend_from_slice(b"0\r\n\r\n");
+        self.stream.write_all(&last)
+    }
+}
+
+// The client shut its side or the connection broke
+// Bytes it sent, like a pipelined request, still count as open
+fn peer_closed(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let closed = match stream.peek(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+    };
+    let _ = stream.set_nonblocking(false);
+    closed
+}
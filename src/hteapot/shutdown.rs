@@ -0,0 +1,282 @@
+// Work to do when the server stops, like flushing logs
+// Each hook runs on its own thread so a stuck or panicking one can't hold up the rest
+
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// Time every hook together gets on a forced shutdown
+const FORCED_BUDGET: Duration = Duration::from_secs(1);
+
+pub struct ShutdownHook {
+    name: String,
+    priority: i32, // Higher runs first, equal ones in the order they were added
+    timeout: Duration,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+impl ShutdownHook {
+    pub fn new(name: &str, run: impl FnOnce() + Send + 'static) -> ShutdownHook {
+        ShutdownHook {
+            name: name.to_string(),
+            priority: 0,
+            timeout: DEFAULT_TIMEOUT,
+            run: Box::new(run),
+        }
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HookOutcome {
+    Completed,
+    TimedOut, // Left running on its thread
+    Panicked(String),
+    Skipped, // The forced shutdown budget ran out before it started
+}
+
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    pub hooks: Vec<(String, HookOutcome)>, // In the order they ran
+}
+
+impl ShutdownReport {
+    fn count(&self, outcome: fn(&HookOutcome) -> bool) -> usize {
+        self.hooks.iter().filter(|(_, o)| outcome(o)).count()
+    }
+}
+
+// Like "3 hooks completed, 1 timed out"
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let completed = self.count(|o| *o == HookOutcome::Completed);
+        write!(f, "{} hooks completed", completed)?;
+        let others = [
+            (self.count(|o| *o == HookOutcome::TimedOut), "timed out"),
+            (
+                self.count(|o| matches!(o, HookOutcome::Panicked(_))),
+                "panicked",
+            ),
+            (self.count(|o| *o == HookOutcome::Skipped), "skipped"),
+        ];
+        for (count, what) in others.iter().filter(|(count, _)| *count > 0) {
+            write!(f, ", {} {}", count, what)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ShutdownHooks {
+    hooks: Mutex<Vec<ShutdownHook>>,
+}
+
+impl ShutdownHooks {
+    pub fn add(&self, hook: ShutdownHook) {
+        self.hooks.lock().expect("Error locking hooks").push(hook);
+    }
+
+    // Run every hook once, a forced shutdown gives all of them FORCED_BUDGET together
+    pub fn run(&self, forced: bool) -> ShutdownReport {
+        let mut hooks: Vec<ShutdownHook> = self
+            .hooks
+            .lock()
+            .expect("Error locking hooks")
+            .drain(..)
+            .collect();
+        hooks.sort_by_key(|hook| std::cmp::Reverse(hook.priority));
+        let deadline = Instant::now() + FORCED_BUDGET;
+        let mut report = ShutdownReport::default();
+        for hook in hooks {
+            let timeout = if forced {
+                hook.timeout
+                    .min(deadline.saturating_duration_since(Instant::now()))
+            } else {
+                hook.timeout
+            };
+            let outcome = if timeout.is_zero() {
+                HookOutcome::Skipped
+            } else {
+                run_hook(&hook.name, hook.run, timeout)
+            };
+            match &outcome {
+                HookOutcome::TimedOut => {
+                    eprintln!("Shutdown hook {} timed out after {:?}", hook.name, timeout)
+                }
+                HookOutcome::Panicked(e) => {
+                    eprintln!("Shutdown hook {} panicked: {}", hook.name, e)
+                }
+                _ => (),
+            }
+            report.hooks.push((hook.name, outcome));
+        }
+        report
+    }
+}
+
+// How often wait_idle looks at the count again
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+// Requests from a complete head until their response is written, streamed and hijacked ones
+// until their code returns, so a stop can wait for them instead of cutting them off
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+    closing: AtomicBool,
+}
+
+impl InFlight {
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // Every response from now on closes its connection once it is written
+    pub fn close_connections(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    // Wait until nothing is in flight, false when grace ran out first
+    pub fn wait_idle(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        while self.count() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(IDLE_POLL);
+        }
+        true
+    }
+
+    // Counted until the guard is dropped
+    pub(crate) fn start(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn run_hook(name: &str, run: Box<dyn FnOnce() + Send>, timeout: Duration) -> HookOutcome {
+    let (done, finished) = mpsc::channel();
+    let spawned = thread::Builder::new()
+        .name(format!("shutdown-{}", name))
+        .spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(run));
+            let _ = done.send(result);
+        });
+    if let Err(e) = spawned {
+        return HookOutcome::Panicked(format!("can't start thread: {}", e));
+    }
+    match finished.recv_timeout(timeout) {
+        Ok(Ok(())) => HookOutcome::Completed,
+        Ok(Err(payload)) => HookOutcome::Panicked(panic_message(payload.as_ref())),
+        Err(_) => HookOutcome::TimedOut,
+    }
+}
+
+// The message a panic was raised with
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[test]
+fn test_shutdown_hooks() {
+    use std::sync::Arc;
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let hooks = ShutdownHooks::default();
+    let log = |name: &'static str| {
+        let order = order.clone();
+        move || order.lock().unwrap().push(name)
+    };
+    hooks.add(ShutdownHook::new("late", log("late")).priority(-1));
+    hooks.add(ShutdownHook::new("first", log("first")));
+    hooks.add(
+        ShutdownHook::new("stuck", || thread::sleep(Duration::from_secs(10)))
+            .timeout(Duration::from_millis(100)),
+    );
+    hooks.add(ShutdownHook::new("broken", || panic!("disk gone")));
+    hooks.add(ShutdownHook::new("second", log("second")));
+    hooks.add(ShutdownHook::new("urgent", log("urgent")).priority(10));
+
+    let report = hooks.run(false);
+    assert_eq!(
+        *order.lock().unwrap(),
+        ["urgent", "first", "second", "late"]
+    );
+    let outcomes: Vec<(&str, &HookOutcome)> =
+        report.hooks.iter().map(|(n, o)| (n.as_str(), o)).collect();
+    assert_eq!(outcomes[2], ("stuck", &HookOutcome::TimedOut));
+    assert_eq!(
+        outcomes[3],
+        ("broken", &HookOutcome::Panicked("disk gone".to_string()))
+    );
+    assert_eq!(
+        report.to_string(),
+        "4 hooks completed, 1 timed out, 1 panicked"
+    );
+    // Hooks only run once
+    assert!(hooks.run(false).hooks.is_empty());
+
+    // A forced shutdown stops waiting once the budget is spent
+    hooks.add(ShutdownHook::new("slow", || {
+        thread::sleep(Duration::from_secs(10))
+    }));
+    hooks.add(ShutdownHook::new("after", || ()));
+    let start = Instant::now();
+    let report = hooks.run(true);
+    assert!(start.elapsed() < FORCED_BUDGET + Duration::from_millis(500));
+    assert_eq!(
+        report.to_string(),
+        "0 hooks completed, 1 timed out, 1 skipped"
+    );
+}
+
+#[test]
+fn test_in_flight() {
+    let in_flight = Arc::new(InFlight::default());
+    assert!(in_flight.wait_idle(Duration::ZERO));
+    let first = in_flight.start();
+    let second = in_flight.start();
+    assert_eq!(in_flight.count(), 2);
+    drop(first);
+    let started = Instant::now();
+    assert!(!in_flight.wait_idle(Duration::from_millis(50)));
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        drop(second);
+    });
+    assert!(in_flight.wait_idle(Duration::from_secs(5)));
+    assert!(!in_flight.closing());
+    in_flight.close_connections();
+    assert!(in_flight.closing());
+}
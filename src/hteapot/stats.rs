@@ -0,0 +1,264 @@
+// Shared counters about the server state
+// Workers update them as connections change and any thread can read them
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Upper bounds of the queue wait buckets in milliseconds, longer waits go in a last open bucket
+pub const QUEUE_WAIT_BUCKETS_MS: [u64; 11] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 5000];
+// Least time between two warnings about the queue
+const POOL_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    idle_connections: AtomicUsize,
+    active_connections: AtomicUsize,
+    tls_handshakes: Mutex<HashMap<&'static str, usize>>, // Refused on the plain port, by version
+    open_connections: AtomicUsize, // Accepted and not closed, queued ones included
+    // Open connections per client with max_connections_per_ip, an address leaves with its last one
+    ip_connections: Mutex<HashMap<IpAddr, usize>>,
+    ip_rejected: AtomicUsize,
+    queue_depth: AtomicUsize,
+    read_buffer_bytes: AtomicUsize, // Requests read and not answered yet, on every connection
+    response_buffer_bytes: AtomicUsize, // Responses not written in full yet, shared bodies included
+    worker_connections: Mutex<Vec<usize>>, // By worker index
+    queue_waits: Mutex<QueueWaits>,
+    last_pool_warning: Mutex<Option<Instant>>,
+}
+
+// Time accepted connections waited in the queue until a worker took them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueWaits {
+    pub count: u64,
+    pub total: Duration,
+    pub buckets: Vec<u64>, // Per QUEUE_WAIT_BUCKETS_MS, then the open bucket
+}
+
+impl QueueWaits {
+    fn record(&mut self, wait: Duration) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; QUEUE_WAIT_BUCKETS_MS.len() + 1];
+        }
+        self.count += 1;
+        self.total += wait;
+        let micros = wait.as_micros();
+        let bucket = QUEUE_WAIT_BUCKETS_MS
+            .iter()
+            .position(|bound| micros <= *bound as u128 * 1000)
+            .unwrap_or(QUEUE_WAIT_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    // Upper bound of the bucket holding the quantile, None without waits
+    // or when it falls in the open bucket
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (quantile * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return QUEUE_WAIT_BUCKETS_MS
+                    .get(i)
+                    .map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+}
+
+impl ServerStats {
+    // Connections waiting for the next request
+    pub fn idle_connections(&self) -> usize {
+        self.idle_connections.load(Ordering::Relaxed)
+    }
+
+    // Connections reading a request or writing a response
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    // TLS handshakes refused because the port speaks plain HTTP, by offered version
+    pub fn tls_handshakes(&self) -> Vec<(&'static str, usize)> {
+        let handshakes = self.tls_handshakes.lock().expect("Error locking stats");
+        let mut handshakes: Vec<(&'static str, usize)> =
+            handshakes.iter().map(|(v, n)| (*v, *n)).collect();
+        handshakes.sort();
+        handshakes
+    }
+
+    // Connections accepted and not closed yet, waiting in the queue or with a worker
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    // Most connections open from a single client, of those counted for max_connections_per_ip
+    pub fn busiest_ip_connections(&self) -> usize {
+        let ips = self.ip_connections.lock().expect("Error locking stats");
+        ips.values().max().copied().unwrap_or(0)
+    }
+
+    // Clients with connections open, of those counted for max_connections_per_ip
+    pub fn tracked_ips(&self) -> usize {
+        self.ip_connections
+            .lock()
+            .expect("Error locking stats")
+            .len()
+    }
+
+    // Connections refused for being past max_connections_per_ip
+    pub fn ip_rejected(&self) -> usize {
+        self.ip_rejected.load(Ordering::Relaxed)
+    }
+
+    // Accepted connections no worker has taken yet
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    // Bytes held in connection buffers, exact rather than estimated like RSS
+    pub fn read_buffer_bytes(&self) -> usize {
+        self.read_buffer_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn response_buffer_bytes(&self) -> usize {
+        self.response_buffer_bytes.load(Ordering::Relaxed)
+    }
+
+    // Connections each worker is handling, by worker index
+    pub fn worker_connections(&self) -> Vec<usize> {
+        self.worker_connections
+            .lock()
+            .expect("Error locking stats")
+            .clone()
+    }
+
+    pub fn queue_waits(&self) -> QueueWaits {
+        self.queue_waits
+            .lock()
+            .expect("Error locking stats")
+            .clone()
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Count a connection from ip unless it already has max, checked and counted under one lock
+    pub(crate) fn ip_connection_opened(&self, ip: IpAddr, max: usize) -> bool {
+        let mut ips = self.ip_connections.lock().expect("Error locking stats");
+        let open = ips.entry(ip).or_insert(0);
+        if *open >= max {
+            self.ip_rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        *open += 1;
+        true
+    }
+
+    pub(crate) fn ip_connection_closed(&self, ip: IpAddr) {
+        let mut ips = self.ip_connections.lock().expect("Error locking stats");
+        if let Some(open) = ips.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                ips.remove(&ip);
+            }
+        }
+    }
+
+    // A connection's buffers went from before to after, as (read, response) bytes
+    pub(crate) fn buffers_changed(&self, before: (usize, usize), after: (usize, usize)) {
+        for (counter, before, after) in [
+            (&self.read_buffer_bytes, before.0, after.0),
+            (&self.response_buffer_bytes, before.1, after.1),
+        ] {
+            if after > before {
+                counter.fetch_add(after - before, Ordering::Relaxed);
+            } else {
+                counter.fetch_sub(before - after, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn queue_depth_changed(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub(crate) fn worker_connections_changed(&self, worker: usize, connections: usize) {
+        let mut workers = self.worker_connections.lock().expect("Error locking stats");
+        if workers.len() <= worker {
+            workers.resize(worker + 1, 0);
+        }
+        workers[worker] = connections;
+    }
+
+    pub(crate) fn queue_wait(&self, wait: Duration) {
+        self.queue_waits
+            .lock()
+            .expect("Error locking stats")
+            .record(wait);
+    }
+
+    // Whether a warning about the queue may be logged now, at most one per POOL_WARNING_INTERVAL
+    pub(crate) fn pool_warning_due(&self) -> bool {
+        let mut last = self.last_pool_warning.lock().expect("Error locking stats");
+        let now = Instant::now();
+        if last.is_some_and(|at| now - at < POOL_WARNING_INTERVAL) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+
+    pub(crate) fn tls_handshake_refused(&self, version: &'static str) {
+        let mut handshakes = self.tls_handshakes.lock().expect("Error locking stats");
+        *handshakes.entry(version).or_insert(0) += 1;
+    }
+
+    // Move a connection between states, None means not tracked (new or closed)
+    pub(crate) fn connection_changed(&self, before: Option<bool>, after: Option<bool>) {
+        if before == after {
+            return;
+        }
+        match before {
+            Some(true) => self.active_connections.fetch_sub(1, Ordering::Relaxed),
+            Some(false) => self.idle_connections.fetch_sub(1, Ordering::Relaxed),
+            None => 0,
+        };
+        match after {
+            Some(true) => self.active_connections.fetch_add(1, Ordering::Relaxed),
+            Some(false) => self.idle_connections.fetch_add(1, Ordering::Relaxed),
+            None => 0,
+        };
+    }
+}
+
+#[test]
+fn test_queue_waits() {
+    let stats = ServerStats::default();
+    assert_eq!(stats.queue_waits().quantile(0.5), None);
+    for ms in [0, 1, 3, 3, 40, 40, 40, 90, 200, 7000] {
+        stats.queue_wait(Duration::from_millis(ms));
+    }
+    let waits = stats.queue_waits();
+    assert_eq!(waits.count, 10);
+    assert_eq!(waits.total, Duration::from_millis(7417));
+    assert_eq!(waits.quantile(0.2), Some(Duration::from_millis(1)));
+    assert_eq!(waits.quantile(0.5), Some(Duration::from_millis(50)));
+    assert_eq!(waits.quantile(0.9), Some(Duration::from_millis(250)));
+    assert_eq!(waits.quantile(0.99), None);
+
+    stats.worker_connections_changed(2, 5);
+    assert_eq!(stats.worker_connections(), vec![0, 0, 5]);
+    assert!(stats.pool_warning_due());
+    assert!(!stats.pool_warning_due());
+}
@@ -11,7 +11,13 @@ pub enum HttpStatus {
     Unauthorized = 401,
     Forbidden = 403,
     NotFound = 404,
+    MethodNotAllowed = 405,
+    RequestTimeout = 408,
+    PayloadTooLarge = 413,
+    URITooLong = 414,
     IAmATeapot = 418,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
     InternalServerError = 500,
     NotImplemented = 501,
     BadGateway = 502,
@@ -20,7 +26,14 @@ pub enum HttpStatus {
 
 impl HttpStatus {
     pub fn from_u16(status: u16) -> HttpStatus {
-        match status {
+        match HttpStatus::try_from_u16(status) {
+            Some(status) => status,
+            None => panic!("Invalid HTTP status"),
+        }
+    }
+
+    pub fn try_from_u16(status: u16) -> Option<HttpStatus> {
+        let status = match status {
             200 => HttpStatus::OK,
             201 => HttpStatus::Created,
             202 => HttpStatus::Accepted,
@@ -32,13 +45,20 @@ impl HttpStatus {
             401 => HttpStatus::Unauthorized,
             403 => HttpStatus::Forbidden,
             404 => HttpStatus::NotFound,
+            405 => HttpStatus::MethodNotAllowed,
+            408 => HttpStatus::RequestTimeout,
+            413 => HttpStatus::PayloadTooLarge,
+            414 => HttpStatus::URITooLong,
             418 => HttpStatus::IAmATeapot,
+            429 => HttpStatus::TooManyRequests,
+            431 => HttpStatus::RequestHeaderFieldsTooLarge,
             500 => HttpStatus::InternalServerError,
             501 => HttpStatus::NotImplemented,
             502 => HttpStatus::BadGateway,
             503 => HttpStatus::ServiceUnavailable,
-            _ => panic!("Invalid HTTP status"),
-        }
+            _ => return None,
+        };
+        Some(status)
     }
 
     pub fn to_string(&self) -> &str {
@@ -54,7 +74,13 @@ impl HttpStatus {
             HttpStatus::Unauthorized => "Unauthorized",
             HttpStatus::Forbidden => "Forbidden",
             HttpStatus::NotFound => "Not Found",
+            HttpStatus::MethodNotAllowed => "Method Not Allowed",
+            HttpStatus::RequestTimeout => "Request Timeout",
+            HttpStatus::PayloadTooLarge => "Payload Too Large",
+            HttpStatus::URITooLong => "URI Too Long",
             HttpStatus::IAmATeapot => "I'm a teapot",
+            HttpStatus::TooManyRequests => "Too Many Requests",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             HttpStatus::InternalServerError => "Internal Server Error",
             HttpStatus::NotImplemented => "Not Implemented",
             HttpStatus::BadGateway => "Bad Gateway",
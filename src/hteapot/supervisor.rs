@@ -0,0 +1,102 @@
+// Workers started again when they die, so a bug doesn't quietly take capacity away
+// Too many deaths stop the server instead, failing loudly beats limping
+
+use super::shutdown::panic_message;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Time between checks of the workers
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+const BUDGET_WINDOW: Duration = Duration::from_secs(3600);
+
+// Restarts allowed within the last hour
+pub(crate) struct RestartBudget {
+    per_hour: usize,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub(crate) fn new(per_hour: usize) -> RestartBudget {
+        RestartBudget {
+            per_hour,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    // Count a restart at now, false when the budget is already spent
+    pub(crate) fn take(&mut self, now: Instant) -> bool {
+        while self
+            .restarts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= BUDGET_WINDOW)
+        {
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.per_hour {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+// Watch the workers from a thread of its own, spawn(index) starts the one at that index again
+// exhausted runs once the budget is spent, with the workers left as they are
+// Each death is handed to fatal, before exhausted when it is the one over the budget
+// Once stop is set the workers are expected to return, and the thread ends after joining them
+pub(crate) fn supervise(
+    mut workers: Vec<JoinHandle<()>>,
+    mut budget: RestartBudget,
+    stop: Arc<AtomicBool>,
+    spawn: impl Fn(usize) -> JoinHandle<()> + Send + 'static,
+    fatal: impl Fn(String) + Send + 'static,
+    exhausted: impl FnOnce() + Send + 'static,
+) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        if stop.load(Ordering::SeqCst) {
+            for worker in workers {
+                let _ = worker.join();
+            }
+            return;
+        }
+        for index in 0..workers.len() {
+            if !workers[index].is_finished() {
+                continue;
+            }
+            let reason = match workers.remove(index).join() {
+                Ok(()) => "returned".to_string(),
+                Err(payload) => format!("panicked: {}", panic_message(payload.as_ref())),
+            };
+            if !budget.take(Instant::now()) {
+                fatal(format!(
+                    "FATAL: worker {} died ({}), over {} restarts in the last hour, stopping",
+                    index, reason, budget.per_hour
+                ));
+                exhausted();
+                return;
+            }
+            fatal(format!(
+                "FATAL: worker {} died ({}), starting it again",
+                index, reason
+            ));
+            workers.insert(index, spawn(index));
+        }
+    })
+}
+
+#[test]
+fn test_restart_budget() {
+    let start = Instant::now();
+    let mut budget = RestartBudget::new(2);
+    assert!(budget.take(start));
+    assert!(budget.take(start + Duration::from_secs(60)));
+    assert!(!budget.take(start + Duration::from_secs(120)));
+    // The first restart leaves the window after an hour
+    assert!(budget.take(start + BUDGET_WINDOW));
+    assert!(!budget.take(start + BUDGET_WINDOW + Duration::from_secs(1)));
+    assert!(!RestartBudget::new(0).take(start));
+}
@@ -0,0 +1,59 @@
+// Token buckets that cap how fast responses are written
+// One is shared by every connection, another is kept per connection
+
+use std::time::Instant;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Bucket {
+    rate: u64, // Bytes per second
+    tokens: f64,
+    last: Instant,
+}
+
+// Most bytes one connection writes per pass, so a single client can't drain a shared bucket
+pub(crate) const MAX_GRANT: usize = 16 * 1024;
+
+impl Bucket {
+    pub(crate) fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    // A tenth of a second of traffic can be saved up
+    fn burst(&self) -> f64 {
+        (self.rate as f64 / 10.0).max(1.0)
+    }
+
+    // Bytes that may be written now
+    pub(crate) fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst());
+        self.last = now;
+        self.tokens.max(0.0) as usize
+    }
+
+    // Bytes written, may go below zero when several writers checked at once
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+#[test]
+fn test_bucket_rate() {
+    use std::time::Duration;
+    let mut bucket = Bucket::new(100_000);
+    let start = Instant::now();
+    let mut sent = 0;
+    while start.elapsed() < Duration::from_millis(500) {
+        let n = bucket.available().min(MAX_GRANT);
+        bucket.consume(n);
+        sent += n;
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let rate = sent as f64 / start.elapsed().as_secs_f64();
+    assert!((rate - 100_000.0).abs() < 15_000.0, "{}", rate);
+}
@@ -0,0 +1,113 @@
+// Recognizes TLS handshakes sent to a plain HTTP port, a client configured for https
+// They are counted and turned away instead of being parsed as a request
+
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+// Highest TLS version offered by a ClientHello at the start of data, None for anything else
+// Newer clients offer TLS 1.3 in the supported_versions extension, not in the hello itself
+pub(crate) fn client_hello_version(data: &[u8]) -> Option<&'static str> {
+    if data.len() < 11 || data[0] != HANDSHAKE_RECORD || data[1] != 3 || data[5] != CLIENT_HELLO {
+        return None;
+    }
+    let legacy = u16::from_be_bytes([data[9], data[10]]);
+    let offered = supported_versions(&data[11..]).unwrap_or_default();
+    let highest = offered.into_iter().filter(|v| v >> 8 == 3).max();
+    version_name(highest.unwrap_or(legacy).max(legacy))
+}
+
+// Versions listed in the supported_versions extension, when the hello is complete enough
+fn supported_versions(hello: &[u8]) -> Option<Vec<u16>> {
+    let mut rest = hello.get(32..)?; // Random
+    rest = skip(rest, 1)?; // Session id
+    rest = skip(rest, 2)?; // Cipher suites
+    rest = skip(rest, 1)?; // Compression methods
+    let len = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+    let mut extensions = rest.get(2..2 + len)?;
+    while extensions.len() >= 4 {
+        let kind = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        let body = extensions.get(4..4 + len)?;
+        if kind == SUPPORTED_VERSIONS {
+            let list = body.get(1..1 + *body.first()? as usize)?;
+            return Some(
+                list.chunks(2)
+                    .filter(|v| v.len() == 2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]))
+                    .collect(),
+            );
+        }
+        extensions = &extensions[4 + len..];
+    }
+    None
+}
+
+// Skip a field prefixed by its length in len_size bytes
+fn skip(data: &[u8], len_size: usize) -> Option<&[u8]> {
+    let len = data
+        .get(..len_size)?
+        .iter()
+        .fold(0, |len, b| len << 8 | *b as usize);
+    data.get(len_size + len..)
+}
+
+fn version_name(version: u16) -> Option<&'static str> {
+    match version {
+        0x0300 => Some("SSLv3"),
+        0x0301 => Some("TLSv1.0"),
+        0x0302 => Some("TLSv1.1"),
+        0x0303 => Some("TLSv1.2"),
+        0x0304 => Some("TLSv1.3"),
+        _ => None,
+    }
+}
+
+// A ClientHello with the given legacy version and supported_versions list, for tests
+#[cfg(test)]
+pub(crate) fn client_hello(legacy: u16, supported: &[u16]) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if !supported.is_empty() {
+        let list: Vec<u8> = supported.iter().flat_map(|v| v.to_be_bytes()).collect();
+        extensions.extend_from_slice(&SUPPORTED_VERSIONS.to_be_bytes());
+        extensions.extend_from_slice(&(list.len() as u16 + 1).to_be_bytes());
+        extensions.push(list.len() as u8);
+        extensions.extend_from_slice(&list);
+    }
+    let mut hello = legacy.to_be_bytes().to_vec();
+    hello.extend_from_slice(&[0; 32]); // Random
+    hello.push(0); // Session id
+    hello.extend_from_slice(&[0, 2, 0x13, 0x01]); // One cipher suite
+    hello.extend_from_slice(&[1, 0]); // No compression
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+    let mut handshake = vec![CLIENT_HELLO, 0];
+    handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+    handshake.extend_from_slice(&hello);
+    let mut record = vec![HANDSHAKE_RECORD, 3, 1];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+#[test]
+fn test_client_hello_version() {
+    assert_eq!(
+        client_hello_version(&client_hello(0x0303, &[])),
+        Some("TLSv1.2")
+    );
+    assert_eq!(
+        client_hello_version(&client_hello(0x0301, &[])),
+        Some("TLSv1.0")
+    );
+    // GREASE values are skipped
+    let modern = client_hello(0x0303, &[0x3a3a, 0x0304, 0x0303]);
+    assert_eq!(client_hello_version(&modern), Some("TLSv1.3"));
+    // Cut short, the legacy version is still known
+    assert_eq!(client_hello_version(&modern[..20]), Some("TLSv1.2"));
+    assert_eq!(
+        client_hello_version(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+        None
+    );
+    assert_eq!(client_hello_version(&[0x16, 3]), None);
+}
@@ -0,0 +1,102 @@
+// Fixed set of threads running hijack handlers, instead of one new thread per response
+// Handlers that find every worker busy wait in a bounded queue or are turned away
+
+use super::response::Hijack;
+use super::shutdown::panic_message;
+use super::{Headers, HttpResponse, HttpStatus};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type Job = (TcpStream, Hijack);
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    idle: usize,  // Workers waiting for a job
+    closed: bool, // The pool was dropped, workers end once the queue is empty
+}
+
+pub(crate) struct HijackPool {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    queue_limit: usize, // Jobs that may wait once every worker is busy
+}
+
+impl HijackPool {
+    pub(crate) fn new(workers: usize, queue_limit: usize) -> HijackPool {
+        let queue: Arc<(Mutex<Queue>, Condvar)> = Arc::default();
+        for n in 0..workers.max(1) {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("hijack-{}", n))
+                .spawn(move || work(&queue))
+                .expect("Error starting hijack worker");
+        }
+        HijackPool { queue, queue_limit }
+    }
+
+    // Hand the job back when the workers and the queue are all taken
+    pub(crate) fn submit(&self, stream: TcpStream, hijack: Hijack) -> Result<(), Job> {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().expect("Error locking hijack queue");
+        if queue.jobs.len() >= queue.idle + self.queue_limit {
+            return Err((stream, hijack));
+        }
+        queue.jobs.push_back((stream, hijack));
+        cvar.notify_one();
+        Ok(())
+    }
+}
+
+fn work(queue: &(Mutex<Queue>, Condvar)) {
+    let (lock, cvar) = queue;
+    loop {
+        let (stream, hijack) = {
+            let mut queue = lock.lock().expect("Error locking hijack queue");
+            queue.idle += 1;
+            while queue.jobs.is_empty() {
+                if queue.closed {
+                    return;
+                }
+                queue = cvar.wait(queue).expect("Error waiting for hijack jobs");
+            }
+            queue.idle -= 1;
+            queue.jobs.pop_front().expect("Queue checked not empty")
+        };
+        run(stream, hijack);
+    }
+}
+
+// Handlers already running are left to finish on their own
+impl Drop for HijackPool {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.queue;
+        lock.lock().expect("Error locking hijack queue").closed = true;
+        cvar.notify_all();
+    }
+}
+
+// Run a hijack handler to the end, answering 502 if it fails
+// A handler that panics fails the same way, the pool thread running it goes on to the next job
+pub(crate) fn run(mut stream: TcpStream, hijack: Hijack) {
+    let _ = stream.set_nonblocking(false);
+    let error = match panic::catch_unwind(AssertUnwindSafe(|| hijack(&mut stream))) {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(payload) => {
+            let message = panic_message(payload.as_ref());
+            eprintln!("ERROR: hijack handler panicked: {}", message);
+            Some("Bad Gateway".to_string())
+        }
+    };
+    if let Some(error) = error {
+        let mut headers = Headers::new();
+        headers.insert("Connection", "close");
+        let response = HttpResponse::new(HttpStatus::BadGateway, error, Some(headers));
+        let _ = stream.write_all(&response.to_bytes());
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
@@ -1,6 +1,10 @@
 
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::io::{BufWriter, Write};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::cell::Cell;
 
 struct SimpleTime;
 impl SimpleTime {
@@ -63,29 +67,211 @@ impl SimpleTime {
 
 
 
+// Messages kept while a writer is failing, the oldest are dropped past this
+const MAX_PENDING: usize = 1000;
+// Consecutive failures before new messages are also sent to stderr
+const MAX_FAILURES: usize = 3;
+
+#[derive(Clone, Debug, Default)]
+pub struct LoggerHealth {
+  pub last_error: Option<String>,
+  pub failures: usize,
+  pub dropped: usize,
+}
+
+struct Sink<W: Write> {
+  writer: W,
+  pending: VecDeque<String>,
+  written: usize, // Bytes of the first pending message already written
+  health: LoggerHealth,
+  fallback: Box<dyn Write + Send>, // Stderr, gets new messages while the writer keeps failing
+}
+
+impl<W: Write> Sink<W> {
+  fn new(writer: W) -> Sink<W> {
+    Sink {
+      writer,
+      pending: VecDeque::new(),
+      written: 0,
+      health: LoggerHealth::default(),
+      fallback: Box::new(std::io::stderr()),
+    }
+  }
+
+  fn write(&mut self, content: String) {
+    if self.pending.len() >= MAX_PENDING {
+      // The one partly written is finished first, the next oldest goes instead
+      let oldest = if self.written > 0 { 1 } else { 0 };
+      self.pending.remove(oldest);
+      self.health.dropped += 1;
+    }
+    self.pending.push_back(content.clone());
+    while let Some(message) = self.pending.front() {
+      // Carry on where a short write stopped, the start isn't written twice
+      let mut r = Ok(());
+      while self.written < message.len() {
+        match self.writer.write(&message.as_bytes()[self.written..]) {
+          Ok(0) => {
+            r = Err(std::io::Error::from(std::io::ErrorKind::WriteZero));
+            break;
+          }
+          Ok(n) => self.written += n,
+          Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+          Err(e) => {
+            r = Err(e);
+            break;
+          }
+        }
+      }
+      if let Err(e) = r.and_then(|_| self.writer.flush()) {
+        self.health.failures += 1;
+        self.health.last_error = Some(e.to_string());
+        if self.health.failures >= MAX_FAILURES {
+          let _ = self.fallback.write_all(content.as_bytes());
+        }
+        return;
+      }
+      self.pending.pop_front();
+      self.written = 0;
+    }
+    if self.health.failures > 0 {
+      let note = format!("[{}] - Logger recovered after {} failures, {} messages dropped\n",
+        SimpleTime::get_current_timestamp(), self.health.failures, self.health.dropped);
+      let _ = self.writer.write_all(note.as_bytes());
+      let _ = self.writer.flush();
+      self.health = LoggerHealth::default();
+    }
+  }
+}
+
+// How much a message matters, from the least to the most detailed
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+}
+
+impl Level {
+  const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+  pub fn parse(name: &str) -> Option<Level> {
+    Self::ALL.iter().copied().find(|level| level.as_str().eq_ignore_ascii_case(name))
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Level::Error => "error",
+      Level::Warn => "warn",
+      Level::Info => "info",
+      Level::Debug => "debug",
+    }
+  }
+}
+
+// Most detailed level written, for every component without a level of its own
+// Changed at runtime, so it lives outside of any logger
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static COMPONENT_LEVELS: Mutex<Vec<(String, Level)>> = Mutex::new(Vec::new());
+
+pub fn level() -> Level {
+  Level::ALL[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+// Returns the level replaced
+pub fn set_level(level: Level) -> Level {
+  Level::ALL[LEVEL.swap(level as u8, Ordering::Relaxed) as usize]
+}
+
+// Level of a component, its own or the global one
+pub fn component_level(component: &str) -> Level {
+  let levels = COMPONENT_LEVELS.lock().expect("Error locking log levels");
+  match levels.iter().find(|(name, _)| name == component) {
+    Some((_, level)) => *level,
+    None => level(),
+  }
+}
+
+// Give a component its own level, or None to follow the global one again
+// Returns the level it had before
+pub fn set_component_level(component: &str, level: Option<Level>) -> Level {
+  let mut levels = COMPONENT_LEVELS.lock().expect("Error locking log levels");
+  let previous = match levels.iter().position(|(name, _)| name == component) {
+    Some(i) => levels.remove(i).1,
+    None => self::level(),
+  };
+  if let Some(level) = level {
+    levels.push((component.to_string(), level));
+  }
+  previous
+}
+
+// Also gets every error and fatal message, see set_alert_hook
+type AlertHook = Arc<dyn Fn(&str) + Send + Sync>;
+static ALERT_HOOK: Mutex<Option<AlertHook>> = Mutex::new(None);
+
+thread_local! {
+  // Set while the hook runs, whatever it logs isn't handed to it again
+  static ALERTING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Hand errors to hook as they are logged, by every logger, None stops it
+pub fn set_alert_hook(hook: Option<AlertHook>) {
+  *ALERT_HOOK.lock().expect("Error locking alert hook") = hook;
+}
+
+fn alert(content: &str) {
+  let hook = ALERT_HOOK.lock().expect("Error locking alert hook").clone();
+  if let Some(hook) = hook {
+    if !ALERTING.with(|alerting| alerting.replace(true)) {
+      hook(content);
+      ALERTING.with(|alerting| alerting.set(false));
+    }
+  }
+}
+
 pub struct Logger<W: Sized + Write> {
-  buffers: Vec<BufWriter<W>>,
+  sinks: Vec<Sink<W>>,
 }
 
 impl<W: Write> Logger<W> {
   pub fn new(writer: W) -> Logger<W> {
-    let mut buffers = Vec::new();
-    buffers.push(BufWriter::new(writer));
+    let sinks = vec![Sink::new(writer)];
     Logger {
-      buffers: buffers,
+      sinks,
     }
   }
 
-  fn log(&mut self, content: String) {
-    for b in self.buffers.iter_mut() {
-      let _ = b.write(content.as_bytes());
-      let _ = b.flush();
+  fn log(&mut self, level: Level, component: &str, content: String) {
+    if level > component_level(component) {
+      return;
+    }
+    for sink in self.sinks.iter_mut() {
+      sink.write(content.clone());
     };
 
   } 
 
+  // Startup and state changes, always written
   pub fn msg(&mut self, content: String) {
-    self.log(format!("[{}] - {}\n",SimpleTime::get_current_timestamp() ,content));
+    if content.starts_with("ERROR: ") || content.starts_with("FATAL: ") {
+      alert(&content);
+    }
+    self.log(Level::Error, "", format!("[{}] - {}\n",SimpleTime::get_current_timestamp() ,content));
+  }
+
+  // Written only when the level of the component allows it
+  pub fn at(&mut self, level: Level, component: &str, content: String) {
+    if level == Level::Error {
+      alert(&content);
+    }
+    self.log(level, component, format!("[{}] - {}\n",SimpleTime::get_current_timestamp() ,content));
+  }
+
+  // State of the first writer, for the metrics and the admin status
+  pub fn health(&self) -> LoggerHealth {
+    self.sinks[0].health.clone()
   }
 
 }
@@ -98,4 +284,139 @@ fn test_basic() {
 
     let mut logs = Logger::new(stdout()); 
     logs.msg("test".to_string());
-}
\ No newline at end of file
+}
+
+// Writer that fails once its byte budget is spent
+#[cfg(test)]
+struct FullDisk {
+  budget: std::rc::Rc<std::cell::Cell<usize>>,
+  written: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+}
+
+// Takes as much as the budget allows, a short write, and fails once nothing is left
+#[cfg(test)]
+impl Write for FullDisk {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    if self.budget.get() == 0 {
+      return Err(std::io::Error::other("No space left on device"));
+    }
+    let len = buf.len().min(self.budget.get());
+    self.budget.set(self.budget.get() - len);
+    self.written.borrow_mut().extend_from_slice(&buf[..len]);
+    Ok(len)
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn test_full_disk() {
+  use std::cell::{Cell, RefCell};
+  use std::rc::Rc;
+  let budget = Rc::new(Cell::new(0));
+  let written = Rc::new(RefCell::new(Vec::new()));
+  let mut logs = Logger::new(FullDisk { budget: budget.clone(), written: written.clone() });
+  // What would go to stderr, kept out of the test output
+  let fallback = SharedBuffer::default();
+  logs.sinks[0].fallback = Box::new(fallback.clone());
+
+  for _ in 0..MAX_PENDING + 5 {
+    logs.msg("lost".to_string());
+  }
+  let health = logs.health();
+  assert_eq!(health.failures, MAX_PENDING + 5);
+  assert_eq!(health.dropped, 5);
+  assert!(health.last_error.unwrap().contains("No space"));
+  assert_eq!(logs.sinks[0].pending.len(), MAX_PENDING);
+  let copied = String::from_utf8(fallback.0.lock().unwrap().clone()).unwrap();
+  assert_eq!(copied.matches("lost").count(), MAX_PENDING + 5 - (MAX_FAILURES - 1));
+
+  budget.set(usize::MAX);
+  logs.msg("back".to_string());
+  let written = String::from_utf8(written.borrow().clone()).unwrap();
+  assert_eq!(written.matches("lost").count(), MAX_PENDING - 1);
+  assert!(written.contains("back"));
+  assert!(written.contains("Logger recovered after 1005 failures, 6 messages dropped"));
+  assert_eq!(logs.health().failures, 0);
+  assert!(logs.sinks[0].pending.is_empty());
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.lock().unwrap().extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn test_short_write() {
+  use std::cell::{Cell, RefCell};
+  use std::rc::Rc;
+  let budget = Rc::new(Cell::new(10));
+  let written = Rc::new(RefCell::new(Vec::new()));
+  let mut logs = Logger::new(FullDisk { budget: budget.clone(), written: written.clone() });
+
+  // Cut off partway, the rest follows once there is room, nothing written twice
+  logs.msg("first message".to_string());
+  assert_eq!(logs.health().failures, 1);
+  budget.set(usize::MAX);
+  logs.msg("second".to_string());
+  let written = String::from_utf8(written.borrow().clone()).unwrap();
+  let lines: Vec<&str> = written.lines().collect();
+  assert_eq!(lines.len(), 3, "{}", written);
+  assert!(lines[0].ends_with("] - first message"), "{}", written);
+  assert!(lines[1].ends_with("] - second"), "{}", written);
+  assert!(lines[2].contains("Logger recovered after 1 failures"), "{}", written);
+}
+
+#[test]
+fn test_component_levels() {
+  let mut logs = Logger::new(Vec::new());
+  assert_eq!(set_component_level("test-levels", Some(Level::Warn)), level());
+  logs.at(Level::Info, "test-levels", "hidden".to_string());
+  logs.at(Level::Warn, "test-levels", "shown".to_string());
+  logs.msg("always".to_string());
+  assert_eq!(set_component_level("test-levels", Some(Level::Debug)), Level::Warn);
+  logs.at(Level::Debug, "test-levels", "detail".to_string());
+  assert_eq!(set_component_level("test-levels", None), Level::Debug);
+  assert_eq!(component_level("test-levels"), level());
+
+  let written = String::from_utf8(logs.sinks[0].writer.clone()).unwrap();
+  assert!(!written.contains("hidden"));
+  assert!(written.contains("shown") && written.contains("always") && written.contains("detail"));
+  assert_eq!(Level::parse("WARN"), Some(Level::Warn));
+  assert_eq!(Level::parse("verbose"), None);
+}
+
+#[test]
+fn test_alert_hook() {
+  let alerted = Arc::new(Mutex::new(Vec::new()));
+  let hook = alerted.clone();
+  set_alert_hook(Some(Arc::new(move |message: &str| {
+    // Logging from the hook doesn't call it again
+    Logger::new(Vec::new()).msg(format!("ERROR: while alerting on {}", message));
+    hook.lock().unwrap().push(message.to_string());
+  })));
+  let mut logs = Logger::new(Vec::new());
+  logs.msg("ERROR: test-alert upstream down".to_string());
+  logs.msg("WARNING: test-alert slow".to_string());
+  logs.at(Level::Error, "test-alert", "test-alert cache file unreadable".to_string());
+  logs.at(Level::Warn, "test-alert", "test-alert retrying".to_string());
+  set_alert_hook(None);
+  logs.msg("FATAL: test-alert after the hook".to_string());
+
+  let alerted: Vec<String> = alerted.lock().unwrap().iter()
+    .filter(|m| m.contains("test-alert")).cloned().collect();
+  assert_eq!(alerted, ["ERROR: test-alert upstream down", "test-alert cache file unreadable"]);
+}
@@ -1,192 +1,4115 @@
+mod access_log;
+mod activation;
+mod admin_api;
+mod alerts;
+mod body_log;
 mod brew;
+mod build_info;
 mod cache;
 mod config;
+mod diagnostics;
+mod digests;
 pub mod hteapot;
 mod logger;
+mod noise;
+mod preflight;
+mod proxy;
+mod recorder;
+mod selftest;
+mod service;
+mod signals;
+mod single_flight;
+mod state;
+mod timing;
+mod upstream_stats;
 
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
 use std::fs;
-use std::io;
-use std::path::Path;
-use std::sync::Mutex;
-
-use brew::fetch;
-use cache::Cache;
-use config::Config;
-use hteapot::{Hteapot, HttpResponse, HttpStatus};
-
-use logger::Logger;
-
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-
-fn is_proxy(config: &Config, path: String) -> Option<String> {
-    for proxy_path in config.proxy_rules.keys() {
-        let path_proxy = path.strip_prefix(proxy_path);
-        if path_proxy.is_some() {
-            let path_proxy = path_proxy.unwrap();
-            let url = config.proxy_rules.get(proxy_path).unwrap();
-            let separator = if path_proxy.starts_with('/') || url.ends_with('/') {
-                ""
-            } else {
-                "/"
-            };
-            let url = format!("{}{}{}", url, separator, path_proxy);
-            return Some(url);
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Stdout};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use access_log::{AccessEntry, AccessLog};
+use admin_api::Controls;
+use alerts::Alerts;
+use brew::{open_upstream_sockets, raw_status, BrewError};
+use cache::{Cache, IDEMPOTENCY_PREFIX};
+use config::{AuthRule, Config, Overrides};
+use digests::DigestKey;
+use hteapot::{
+    authorize, base64_encode, bind_host, format_addr, sha256, CacheLifetime, DiskFs, FileSource,
+    Hteapot, HttpMethod, HttpRequest, HttpResponse, HttpStatus, InFlight, InjectHtml,
+    RequestLimits, ServerStats, ShutdownHook, QUEUE_WAIT_BUCKETS_MS,
+};
+
+use logger::{Level, Logger};
+use noise::NoiseResponse;
+use proxy::{sticky_cookie, sticky_ip, ProxyRule, Sticky, STICKY_COOKIE};
+use recorder::Recorder;
+use service::ServiceCommand;
+use state::SharedState;
+use timing::{timed, Timings};
+use upstream_stats::Outcome;
+
+// Biggest payload accepted by --serve -
+const MAX_STDIN_PAYLOAD: u64 = 10 * 1024 * 1024;
+// Errors and fatal events reported within this long are sent to the webhook together
+const ALERT_WINDOW: Duration = Duration::from_secs(5);
+// Counters of the listening server, for the metrics endpoint
+static SERVER_STATS: OnceLock<Arc<ServerStats>> = OnceLock::new();
+
+// Find the proxy rule for a path, returning its prefix and the rule
+fn is_proxy<'a>(config: &'a Config, path: &str) -> Option<(&'a str, &'a ProxyRule)> {
+    for (proxy_path, rule) in config.proxy_rules.iter() {
+        if path.starts_with(proxy_path.as_str()) {
+            return Some((proxy_path, rule));
         }
     }
     None
 }
 
-fn serve_proxy(proxy_url: String) -> HttpResponse {
-    let raw_response = fetch(&proxy_url);
+// The [dev_router] rule for the Host of a request, its port ignored
+fn dev_route<'a>(config: &'a Config, req: &HttpRequest) -> Option<&'a ProxyRule> {
+    if config.dev_routes.is_empty() {
+        return None;
+    }
+    let (host, _) = split_host_port(req.headers.get("Host")?);
+    config.dev_routes.get(&host.to_ascii_lowercase())
+}
+
+// The [auth] rule for the longest prefix of a path, with the prefix
+fn auth_rule<'a>(config: &'a Config, path: &str) -> Option<(&'a str, &'a AuthRule)> {
+    config
+        .auth
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(prefix, rule)| (prefix.as_str(), rule))
+}
+
+// The 401 for a request its [auth] rule doesn't let in
+fn refuse_unauthorized(
+    config: &Config,
+    req: &HttpRequest,
+    trace: &mut Trace,
+) -> Option<HttpResponse> {
+    let (prefix, rule) = auth_rule(config, &req.path)?;
+    let refused = authorize(rule.backend.as_ref(), req, &rule.realm);
+    note(trace, || {
+        let outcome = if refused.is_some() {
+            "401 without valid credentials"
+        } else {
+            "credentials accepted"
+        };
+        format!("[auth] {}: {} {}", prefix, rule.backend.scheme(), outcome)
+    });
+    refused
+}
+
+// Add a header right after the status line of a raw response
+fn insert_raw_header(raw: &mut Vec<u8>, header: &str) {
+    let position = raw.windows(2).position(|w| w == b"\r\n");
+    if let Some(position) = position {
+        let header = format!("\r\n{}", header);
+        raw.splice(position..position, header.bytes());
+    }
+}
+
+// End of the head of a raw response, before the blank line
+fn raw_head_end(raw: &[u8]) -> usize {
+    raw.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(raw.len())
+}
+
+// Header lines of a raw response with their byte ranges, status line excluded
+fn raw_header_lines(raw: &[u8]) -> Vec<(std::ops::Range<usize>, String)> {
+    let end = raw_head_end(raw);
+    let mut lines = Vec::new();
+    let mut start = match raw[..end].windows(2).position(|w| w == b"\r\n") {
+        Some(position) => position + 2,
+        None => return lines,
+    };
+    while start < end {
+        let line_end = raw[start..end]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map_or(end, |p| start + p);
+        let name = String::from_utf8_lossy(&raw[start..line_end])
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        lines.push((start..(line_end + 2).min(raw.len()), name));
+        start = line_end + 2;
+    }
+    lines
+}
+
+// Value of the first line of a header in a raw response
+fn raw_header_value(raw: &[u8], name: &str) -> Option<String> {
+    raw_header_lines(raw)
+        .into_iter()
+        .find(|(_, n)| n.eq_ignore_ascii_case(name))
+        .map(|(range, _)| {
+            let line = String::from_utf8_lossy(&raw[range]);
+            let value = line.split_once(':').map_or("", |(_, value)| value);
+            value.trim().to_string()
+        })
+}
+
+// What the upstream's Cache-Control allows, rule defaults and force_no_store already applied
+fn raw_cache_lifetime(raw: &[u8]) -> CacheLifetime {
+    raw_header_value(raw, "Cache-Control").map_or(CacheLifetime::Default, |value| {
+        CacheLifetime::from_cache_control(&value)
+    })
+}
+
+fn has_raw_header(raw: &[u8], name: &str) -> bool {
+    raw_header_lines(raw)
+        .iter()
+        .any(|(_, n)| n.eq_ignore_ascii_case(name))
+}
+
+// Drop every line of a header from the head of a raw response
+fn remove_raw_header(raw: &mut Vec<u8>, name: &str) {
+    for (range, _) in raw_header_lines(raw)
+        .into_iter()
+        .rev()
+        .filter(|(_, n)| n.eq_ignore_ascii_case(name))
+    {
+        raw.drain(range);
+    }
+}
+
+// Caching headers the rule sets on upstream responses, before they are stored or sent
+fn apply_cache_policy(rule: &ProxyRule, raw: &mut Vec<u8>) {
+    if rule.force_no_store {
+        for name in ["Cache-Control", "Expires", "Pragma"] {
+            remove_raw_header(raw, name);
+        }
+        insert_raw_header(raw, "Cache-Control: no-store");
+    } else if let Some(value) = &rule.default_cache_control {
+        if !has_raw_header(raw, "Cache-Control") && !has_raw_header(raw, "Expires") {
+            insert_raw_header(raw, &format!("Cache-Control: {}", value));
+        }
+    }
+}
+
+// Largest upstream body searched for its own urls, bigger ones are sent as they are
+const REWRITE_MAX_BODY: usize = 1 << 20;
+
+// Point links to the upstream, like http://localhost:3000/app.css, at the rule prefix instead
+// Only plain html and css bodies with a length, compressed or chunked ones can't be searched
+fn rewrite_body_urls(rule: &ProxyRule, prefix: &str, raw: &mut Vec<u8>) {
+    let head_end = raw_head_end(raw);
+    let body_start = (head_end + 4).min(raw.len());
+    if !rule.rewrite_body_urls
+        || body_start == raw.len()
+        || raw.len() - body_start > REWRITE_MAX_BODY
+    {
+        return;
+    }
+    let media_type = raw_header_value(raw, "Content-Type").map(|value| {
+        value
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    });
+    if !matches!(media_type.as_deref(), Some("text/html") | Some("text/css")) {
+        return;
+    }
+    let encoded = raw_header_value(raw, "Content-Encoding")
+        .is_some_and(|value| !value.eq_ignore_ascii_case("identity"));
+    if encoded || raw_header_value(raw, "Transfer-Encoding").is_some() {
+        return;
+    }
+    let public = prefix.trim_end_matches('/');
+    let mut body = raw.split_off(body_start);
+    for upstream in rule.upstreams.iter().filter(|u| u.starts_with("http")) {
+        body = replace_bytes(
+            &body,
+            upstream.trim_end_matches('/').as_bytes(),
+            public.as_bytes(),
+        );
+    }
+    remove_raw_header(raw, "Content-Length");
+    insert_raw_header(raw, &format!("Content-Length: {}", body.len()));
+    raw.extend_from_slice(&body);
+}
+
+// Every occurrence of from replaced, left to right without overlaps
+fn replace_bytes(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(haystack.len());
+    let mut i = 0;
+    while i < haystack.len() {
+        if !from.is_empty() && haystack[i..].starts_with(from) {
+            out.extend_from_slice(to);
+            i += from.len();
+        } else {
+            out.push(haystack[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn serve_proxy(
+    req: &HttpRequest,
+    prefix: &str,
+    rule: &ProxyRule,
+    config: &Config,
+    cache: &Mutex<Cache>,
+    logger: &Mutex<Logger<Stdout>>,
+) -> HttpResponse {
+    // With caching on, concurrent GETs for the same upstream url share one fetch
+    let loads = if config.cache && req.method == HttpMethod::GET {
+        Some(cache.lock().expect("Error locking cache").upstream_loads())
+    } else {
+        None
+    };
+    let headers = forwarded_for(req);
+    // Only requests actually forwarded are timed, not the ones waiting for a shared fetch
+    let fetch = |url: &str| {
+        let timed = || {
+            let started = Instant::now();
+            let result = rule.fetch(url, &headers);
+            rule.stats.record(started.elapsed(), outcome(&result));
+            result
+        };
+        match &loads {
+            Some(loads) => loads.run(url, timed),
+            None => timed(),
+        }
+    };
+    let path = &req.path[prefix.len()..];
+    let hint = sticky_hint(req, rule);
+    let forward = || {
+        let mut index = rule.select(hint);
+        let mut raw_response = fetch(&rule.url(index, path));
+        rule.report(index, raw_response.is_ok());
+        if raw_response.is_err() && hint.is_some() && rule.upstreams.len() > 1 {
+            // The pinned upstream is down, fall back to round robin
+            index = rule.select(None);
+            if Some(index) == hint {
+                index = rule.select(None);
+            }
+            raw_response = fetch(&rule.url(index, path));
+            rule.report(index, raw_response.is_ok());
+        }
+        // Requests are only ever forwarded without a body, so any idempotent one can be sent again
+        let idempotent = matches!(
+            req.method,
+            HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS
+        ) || req.headers.contains_key("Idempotency-Key");
+        let mut attempts = 0;
+        while idempotent
+            && attempts < rule.retries
+            && should_retry(&raw_response, &rule.retry_on)
+            && rule.take_retry()
+        {
+            attempts += 1;
+            // Another upstream when there is one
+            let failed = index;
+            index = rule.select(None);
+            if index == failed && rule.upstreams.len() > 1 {
+                index = rule.select(None);
+            }
+            raw_response = fetch(&rule.url(index, path));
+            rule.report(index, raw_response.is_ok());
+        }
+        raw_response.map(|mut raw| {
+            apply_cache_policy(rule, &mut raw);
+            rewrite_body_urls(rule, prefix, &mut raw);
+            if rule.sticky == Some(Sticky::Cookie) && hint != Some(index) {
+                let cookie = format!(
+                    "Set-Cookie: {}={}; Path={}; HttpOnly",
+                    STICKY_COOKIE, index, prefix
+                );
+                insert_raw_header(&mut raw, &cookie);
+            }
+            raw
+        })
+    };
+    if rule.retries > 0 {
+        rule.earn_retry();
+    }
+    let idempotency_key = req.headers.get("Idempotency-Key");
+    let raw_response = match (rule.idempotency_window, idempotency_key) {
+        (Some(window), Some(key)) => {
+            let key = format!(
+                "{}{} {} {} {}",
+                IDEMPOTENCY_PREFIX,
+                client_identity(req),
+                key,
+                req.method.to_str(),
+                req.path
+            );
+            forward_once(&key, window, cache, forward)
+        }
+        _ => forward(),
+    };
+    let failed = match &raw_response {
+        Ok(raw) => raw_status(raw).is_none_or(|status| status >= 500),
+        Err(_) => true,
+    };
+    if let (Some(window), false, Ok(raw)) = (rule.stale_if_error, failed, &raw_response) {
+        if stale_storable(req, raw) {
+            let mut cache = cache.lock().expect("Error locking cache");
+            cache.set_with_ttl(stale_key(config, req), raw.clone(), window);
+        }
+    }
+    if let Err(e) = &raw_response {
+        logger.lock().expect("this doesnt work :C").at(
+            Level::Error,
+            "proxy",
+            format!(
+                "Proxy error for {}: {} ({} upstream sockets open)",
+                req.path,
+                e,
+                open_upstream_sockets()
+            ),
+        );
+    }
+    if failed {
+        if let Some(response) = serve_degraded(req, rule, config, cache, logger) {
+            return response;
+        }
+    }
     match raw_response {
         Ok(raw) => HttpResponse::new_raw(raw),
-        Err(_) => HttpResponse::new(HttpStatus::NotFound, "not found", None),
+        // Answered, but not with something that can be forwarded
+        Err(BrewError::BadHeaders(_)) => {
+            HttpResponse::new(HttpStatus::BadGateway, "Bad Gateway", None)
+        }
+        Err(_) => error_page(req, HttpStatus::NotFound, "not found"),
+    }
+}
+
+// Whether a good response may be kept for stale_if_error, the copy goes to anyone asking
+// for the same path, so nothing answered for one client in particular is kept:
+// requests with credentials, responses setting cookies or varying on request headers
+// Freshness doesn't bound a stale copy, only an upstream saying not to keep it does,
+// with no-store or private, which also covers a rule's force_no_store
+fn stale_storable(req: &HttpRequest, raw: &[u8]) -> bool {
+    req.method == HttpMethod::GET
+        && !personal_request(req)
+        && !has_raw_header(raw, "Set-Cookie")
+        && !has_raw_header(raw, "Vary")
+        && raw_cache_lifetime(raw) != CacheLifetime::NoStore
+}
+
+// Requests whose answer may be meant for this client only
+fn personal_request(req: &HttpRequest) -> bool {
+    req.headers.contains_key("Authorization") || req.headers.contains_key("Cookie")
+}
+
+// Where the last good response for a GET is kept, per path and query
+fn stale_key(config: &Config, req: &HttpRequest) -> String {
+    format!("stale:{}?{}", req.path, cache_query(config, &req.query))
+}
+
+// Query as used in cache keys, without the parameters configured not to change the answer
+// The rest is sorted and encoded one way, so equivalent queries share an entry
+fn cache_query(config: &Config, query: &str) -> String {
+    if config.cache_ignore_query {
+        return String::new();
+    }
+    let mut params: Vec<(String, String)> = parse_form(query)
+        .into_iter()
+        .filter(|(name, _)| !config.cache_ignore_params.contains(name))
+        .collect();
+    params.sort();
+    let encode = |s: &str| -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    };
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+// Instead of the failure, the last good response while it is within stale_if_error,
+// else the fallback_file of the rule
+fn serve_degraded(
+    req: &HttpRequest,
+    rule: &ProxyRule,
+    config: &Config,
+    cache: &Mutex<Cache>,
+    logger: &Mutex<Logger<Stdout>>,
+) -> Option<HttpResponse> {
+    // Nor is an anonymous copy handed to a client with credentials
+    let stale = match (rule.stale_if_error, &req.method) {
+        (Some(_), HttpMethod::GET) if !personal_request(req) => cache
+            .lock()
+            .expect("Error locking cache")
+            .get(stale_key(config, req)),
+        _ => None,
+    };
+    let (served, response) = if let Some(raw) = stale {
+        let mut raw = raw.to_vec();
+        insert_raw_header(&mut raw, "Warning: 111 - \"Revalidation Failed\"");
+        ("stale response", HttpResponse::new_raw(raw))
+    } else {
+        let file = rule.fallback_file.as_ref()?;
+        let content = match fs::read(file) {
+            Ok(content) => content,
+            Err(e) => {
+                logger.lock().expect("this doesnt work :C").at(
+                    Level::Error,
+                    "proxy",
+                    format!("ERROR: can't read fallback_file {}: {}", file, e),
+                );
+                return None;
+            }
+        };
+        let mimetype = content_type(config, file, &content);
+        let response = HttpResponse::new(
+            HttpStatus::ServiceUnavailable,
+            content,
+            headers!("Content-Type" => mimetype, "Cache-Control" => "no-store"),
+        );
+        ("fallback_file", response)
+    };
+    logger.lock().expect("this doesnt work :C").at(
+        Level::Warn,
+        "proxy",
+        format!(
+            "WARNING: upstream failed for {}, serving the {}",
+            req.path, served
+        ),
+    );
+    Some(response)
+}
+
+// The client added to the X-Forwarded-For it came with
+fn forwarded_for(req: &HttpRequest) -> Vec<(&'static str, String)> {
+    let earlier = req.headers.get("X-Forwarded-For");
+    let client = req.peer_ip().map(|ip| ip.to_string());
+    let chain = match (earlier, client) {
+        (Some(earlier), Some(client)) => format!("{}, {}", earlier, client),
+        (Some(earlier), None) => earlier.clone(),
+        (None, Some(client)) => client,
+        (None, None) => return Vec::new(),
+    };
+    vec![("X-Forwarded-For", chain)]
+}
+
+fn outcome(response: &Result<Vec<u8>, BrewError>) -> Outcome {
+    match response {
+        Ok(raw) if raw_status(raw).is_some_and(|status| status >= 500) => Outcome::ServerError,
+        Ok(_) => Outcome::Ok,
+        Err(BrewError::Connect) => Outcome::Connect,
+        Err(_) => Outcome::Response,
+    }
+}
+
+// Connection errors and the configured statuses are worth another attempt
+// A response that broke the header rules would come back the same
+fn should_retry(response: &Result<Vec<u8>, BrewError>, retry_on: &[u16]) -> bool {
+    match response {
+        Ok(raw) => raw_status(raw).is_some_and(|status| retry_on.contains(&status)),
+        Err(BrewError::BadHeaders(_)) | Err(BrewError::TooLarge) => false,
+        Err(_) => true,
+    }
+}
+
+// Who sent a request, so one client's Idempotency-Key never replays another's response
+// A digest of the credentials when there are some, else the peer address
+fn client_identity(req: &HttpRequest) -> String {
+    if let Some(authorization) = req.headers.get("Authorization") {
+        let digest = sha256(authorization.as_bytes());
+        let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        return format!("auth:{}", hex);
     }
+    req.peer_ip()
+        .map_or("unknown".to_string(), |ip| format!("ip:{}", ip))
+}
+
+// Repeats of a request with the same Idempotency-Key get the stored response instead of
+// reaching the upstream again, concurrent ones wait for the first
+fn forward_once(
+    key: &str,
+    window: Duration,
+    cache: &Mutex<Cache>,
+    forward: impl FnOnce() -> Result<Vec<u8>, BrewError>,
+) -> Result<Vec<u8>, BrewError> {
+    let stored = |cache: &Mutex<Cache>| {
+        let mut cache = cache.lock().expect("Error locking cache");
+        cache.get(key.to_string())
+    };
+    let loads = cache.lock().expect("Error locking cache").upstream_loads();
+    let mut forwarded = false;
+    let result = match stored(cache) {
+        Some(raw) => Ok(raw.to_vec()),
+        // Checked again in the flight, the first one may have finished in between
+        None => loads.run(key, || match stored(cache) {
+            Some(raw) => Ok(raw.to_vec()),
+            None => {
+                forwarded = true;
+                let result = forward();
+                if let Ok(raw) = &result {
+                    let mut cache = cache.lock().expect("Error locking cache");
+                    cache.set_with_ttl(key.to_string(), raw.clone(), window);
+                }
+                result
+            }
+        }),
+    };
+    result.map(|mut raw| {
+        if !forwarded {
+            insert_raw_header(&mut raw, "Idempotent-Replay: true");
+        }
+        raw
+    })
 }
 
-fn get_mime_tipe(path: &String) -> String {
-    let extension = Path::new(path.as_str())
+fn get_mime_tipe(path: &str) -> Option<String> {
+    let extension = Path::new(path)
         .extension()
-        .unwrap()
-        .to_str()
-        .unwrap();
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
     let mimetipe = match extension {
         "js" => "text/javascript",
         "json" => "application/json",
         "css" => "text/css",
         "html" => "text/html",
         "ico" => "image/x-icon",
-        _ => "text/plain",
+        _ => return None,
     };
 
-    mimetipe.to_string()
+    Some(mimetipe.to_string())
 }
 
-fn serve_file(path: &String) -> Option<Vec<u8>> {
-    let r = fs::read(path);
-    if r.is_ok() {
-        Some(r.unwrap())
+// Bytes looked at when guessing a type from the content
+const SNIFF_LEN: usize = 512;
+
+// Only types whose signature can't be mistaken for anything else
+const MAGIC_TYPES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xFF\xD8\xFF", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x7fELF", "application/octet-stream"),
+];
+
+// Type for a file without a known extension, printable UTF-8 is taken as text
+fn sniff_mime_type(content: &[u8]) -> &'static str {
+    let head = &content[..content.len().min(SNIFF_LEN)];
+    if let Some((_, mime)) = MAGIC_TYPES
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+    {
+        return mime;
+    }
+    // The sample may end in the middle of a character
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap(),
+        Err(_) => return "application/octet-stream",
+    };
+    let printable = text
+        .chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace());
+    if printable {
+        "text/plain"
     } else {
-        None
+        "application/octet-stream"
     }
 }
 
-fn main() {
-    let args = std::env::args().collect::<Vec<String>>();
-    let mut serving_path = None;
-    if args.len() >= 2 {
-        match args[1].as_str() {
-            "--help" | "-h" => {
-                println!("Hteapot {}", VERSION);
-                println!("usage: {} <config file>", args[0]);
-                return;
-            }
-            "--version" | "-v" => {
-                println!("Hteapot {}", VERSION);
-                return;
-            }
-            "--serve" | "-s" => {
-                serving_path = Some(args.get(2).unwrap().clone());
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Add a charset to text types that lack one
+// A UTF-8 BOM wins over the configured default and over a declared charset, the bytes
+// say what they are and a client honouring the BOM would disagree with the header anyway
+fn with_charset(config: &Config, content_type: &str, content: &[u8]) -> String {
+    let declared = content_type.contains("charset=");
+    if !content_type.starts_with("text/") && !declared {
+        return content_type.to_string();
+    }
+    if content.starts_with(UTF8_BOM) {
+        let params: Vec<&str> = content_type
+            .split(';')
+            .map(str::trim)
+            .filter(|param| !param.starts_with("charset="))
+            .collect();
+        return format!("{}; charset=utf-8", params.join("; "));
+    }
+    match config.default_charset.as_deref() {
+        Some(charset) if !declared => format!("{}; charset={}", content_type, charset),
+        _ => content_type.to_string(),
+    }
+}
+
+// Content type for a file, [mime] overrides come before the built in table
+// and the content is only looked at when neither knows the extension
+fn content_type(config: &Config, path: &str, content: &[u8]) -> String {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    let content_type = match config.mime_types.get(&extension) {
+        Some(content_type) => content_type.clone(),
+        None => get_mime_tipe(path).unwrap_or_else(|| {
+            if config.mime_sniffing {
+                sniff_mime_type(content).to_string()
+            } else {
+                "text/plain".to_string()
             }
-            _ => (),
-        };
+        }),
+    };
+    with_charset(config, &content_type, content)
+}
+
+// Read error kept as kind and message so it can be shared between waiting requests
+pub type FileError = (io::ErrorKind, String);
+// Content of a file and its modification time, read before it
+// A change while reading shows as a newer time on the next read, never as an old time for new bytes
+pub type LoadedFile = (Arc<Vec<u8>>, Option<SystemTime>);
+
+fn serve_file(files: &dyn FileSource, path: &str) -> Result<Arc<Vec<u8>>, FileError> {
+    files.read(path).map_err(|e| (e.kind(), e.to_string()))
+}
+
+fn load_file(files: &dyn FileSource, path: &str) -> Result<LoadedFile, FileError> {
+    let modified = files.modified(path);
+    serve_file(files, path).map(|content| (content, modified))
+}
+
+// Error with its short message, as JSON for clients preferring it to text like API clients
+// Both say they depend on Accept, so a cache in front doesn't hand one to the other
+fn error_page(req: &HttpRequest, status: HttpStatus, message: &str) -> HttpResponse {
+    if req.prefers(&["text/plain", "application/json"]) != Some("application/json") {
+        return HttpResponse::new(status, message, headers!("Vary" => "Accept"));
     }
+    HttpResponse::new(
+        status,
+        format!("{{\"status\":{},\"error\":\"{}\"}}", status as u16, message),
+        headers!("Content-Type" => "application/json", "Vary" => "Accept"),
+    )
+}
 
-    let config = if args.len() == 2 {
-        config::Config::load_config(&args[1])
-    } else if serving_path.is_some() {
-        let serving_path_str = serving_path.unwrap();
-        let serving_path_str = serving_path_str.as_str();
-        let serving_path = Path::new(serving_path_str);
-        let mut c = config::Config::new_default();
-        c.host = "0.0.0.0".to_string();
-        if serving_path.is_dir() {
-            c.root = serving_path.to_str().unwrap_or_default().to_string();
-        } else {
-            c.index = serving_path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap_or_default()
-                .to_string();
-            c.root = serving_path
-                .parent()
-                .unwrap_or(Path::new("./"))
-                .to_str()
-                .unwrap_or_default()
-                .to_string();
-        }
-        c
-    } else {
-        config::Config::new_default()
+// Response for a file that could not be read, logging why
+fn file_error(
+    req: &HttpRequest,
+    path: &str,
+    error: &FileError,
+    logger: &Mutex<Logger<Stdout>>,
+) -> HttpResponse {
+    let (kind, message) = error;
+    let (status, body, level) = match kind {
+        io::ErrorKind::NotFound => (HttpStatus::NotFound, "Not found", None),
+        io::ErrorKind::PermissionDenied => (
+            HttpStatus::Forbidden,
+            "Forbidden",
+            Some((Level::Warn, "WARNING")),
+        ),
+        _ => (
+            HttpStatus::InternalServerError,
+            "Internal Server Error",
+            Some((Level::Error, "ERROR")),
+        ),
     };
+    if let Some((level, label)) = level {
+        logger.lock().expect("this doesnt work :C").at(
+            level,
+            "files",
+            format!("{}: can't read {}: {}", label, path, message),
+        );
+    }
+    error_page(req, status, body)
+}
 
-    let proxy_only = config.proxy_rules.get("/").is_some();
-    let logger = Mutex::new(Logger::new(io::stdout()));
-    let cache: Mutex<Cache> = Mutex::new(Cache::new(config.cache_ttl as u64));
-    let server = Hteapot::new_threaded(config.host.as_str(), config.port, config.threads);
-    logger.lock().expect("this doesnt work :C").msg(format!(
-        "Server started at http://{}:{}",
-        config.host, config.port
-    ));
-    if config.cache {
-        logger
-            .lock()
-            .expect("this doesnt work :C")
-            .msg("Cache Enabled".to_string());
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+// Serve the key authorization for an ACME HTTP-01 challenge token
+fn serve_acme_challenge(
+    config: &Config,
+    req: &HttpRequest,
+    logger: &Mutex<Logger<Stdout>>,
+    trace: &mut Trace,
+) -> Option<HttpResponse> {
+    let challenge_dir = config.acme_challenge_dir.as_ref()?;
+    let token = req.path.strip_prefix(ACME_CHALLENGE_PATH)?;
+    if let Some(stand_in) = traced(trace, || {
+        format!("acme_challenge_dir: token {} in {}", token, challenge_dir)
+    }) {
+        return Some(stand_in);
     }
-    if proxy_only {
-        logger
-            .lock()
-            .expect("this doesnt work :C")
-            .msg("WARNING: All requests are proxied to /. Local paths won’t be used.".to_string());
+    let valid_token = !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_token {
+        return Some(error_page(req, HttpStatus::NotFound, "Not found"));
     }
+    let path = format!("/{}", token);
+    match serve_file(&DiskFs::new(challenge_dir), &path) {
+        Ok(c) => Some(HttpResponse::new(
+            HttpStatus::OK,
+            c.as_slice(),
+            headers!("Content-Type" => "text/plain"),
+        )),
+        Err(e) => Some(file_error(
+            req,
+            &format!("{}{}", challenge_dir, path),
+            &e,
+            logger,
+        )),
+    }
+}
 
-    server.listen(move |req| {
-        // SERVER CORE
-        // for each request
+// Build and version info as JSON, only on the configured path
+fn serve_version(config: &Config, req: &HttpRequest) -> Option<HttpResponse> {
+    if config.version_path.as_ref() != Some(&req.path) {
+        return None;
+    }
+    Some(HttpResponse::new(
+        HttpStatus::OK,
+        build_info::to_json(config.threads),
+        headers!("Content-Type" => "application/json", "Cache-Control" => "no-store"),
+    ))
+}
 
-        logger.lock().expect("this doesnt work :C").msg(format!(
-            "Request {} {}",
-            req.method.to_str(),
-            req.path
-        ));
+// Answer how route_request would handle the request described in a POSTed form or JSON object
+// Form fields are method (GET by default), host, path with its query, and header, repeated,
+// as "Name: value". JSON takes the same as strings, with "headers" a list of "Name: value"
+fn serve_route_test(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    req: &HttpRequest,
+    logger: &Mutex<Logger<Stdout>>,
+) -> Option<HttpResponse> {
+    if config.route_test_path.as_ref() != Some(&req.path) {
+        return None;
+    }
+    let json = req
+        .headers
+        .get("Content-Type")
+        .is_some_and(|t| t.contains("application/json"));
+    let described = match (&req.method, json) {
+        (HttpMethod::POST, false) => described_request(parse_form(&req.body)),
+        (HttpMethod::POST, true) => described_request(json_fields(&req.body)),
+        _ => Err("POST a form or JSON with method, host, path and header fields".to_string()),
+    };
+    Some(match described {
+        Ok(described) => HttpResponse::new(
+            HttpStatus::OK,
+            route_trace(config, cache, logger, &described).join("\n") + "\n",
+            headers!("Content-Type" => "text/plain", "Cache-Control" => "no-store"),
+        ),
+        Err(e) => HttpResponse::new(HttpStatus::BadRequest, e, None),
+    })
+}
+
+// The route test fields of a JSON body, named as the form would name them
+fn json_fields(body: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = ["method", "host", "path"]
+        .iter()
+        .filter_map(|name| Some((name.to_string(), admin_api::field(body, name)?.to_string())))
+        .collect();
+    for header in admin_api::strings(body, "headers").unwrap_or_default() {
+        fields.push(("header".to_string(), header.to_string()));
+    }
+    fields
+}
 
-        let is_proxy = is_proxy(&config, req.path.clone());
+// Request built from the route test fields, parsed like one coming from a client
+fn described_request(fields: Vec<(String, String)>) -> Result<HttpRequest, String> {
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+    if fields.iter().any(|(_, v)| v.contains(['\r', '\n'])) {
+        return Err("Line breaks are not allowed in the fields".to_string());
+    }
+    let path = field("path").ok_or("Missing path")?;
+    let mut raw = format!("{} {} HTTP/1.1\r\n", field("method").unwrap_or("GET"), path);
+    if let Some(host) = field("host") {
+        raw.push_str(&format!("Host: {}\r\n", host));
+    }
+    for (_, header) in fields.iter().filter(|(k, _)| k == "header") {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or(format!("Invalid header {}", header))?;
+        raw.push_str(&format!("{}: {}\r\n", name.trim(), value.trim()));
+    }
+    raw.push_str("\r\n");
+    Hteapot::request_parser(raw)
+}
 
-        if proxy_only || is_proxy.is_some() {
-            return serve_proxy(is_proxy.unwrap());
+// Name and value pairs of an application/x-www-form-urlencoded body
+fn parse_form(form: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        let mut bytes = Vec::new();
+        let mut rest = s.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+            match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                Some(decoded) if b == b'%' => {
+                    bytes.push(decoded);
+                    rest = &tail[2..];
+                }
+                _ => {
+                    bytes.push(b);
+                    rest = tail;
+                }
+            }
         }
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    form.trim()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+// Steps route_request takes for a request, without reading files or contacting upstreams
+// noise_paths is answered before route_request is reached, so it is looked at here
+fn route_trace(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    logger: &Mutex<Logger<Stdout>>,
+    req: &HttpRequest,
+) -> Vec<String> {
+    let query = if req.query.is_empty() {
+        "".to_string()
+    } else {
+        format!("?{}", req.query)
+    };
+    let mut trace = Some(vec![
+        format!(
+            "request: {} {}{} host {}",
+            req.method.to_str(),
+            req.raw_path,
+            query,
+            req.headers.get("Host").map_or("(none)", |h| h.as_str())
+        ),
+        format!("path: {}", req.path),
+    ]);
+    if config.noise.matches(req) {
+        note(&mut trace, || match config.noise.response {
+            NoiseResponse::Close => "noise_paths: closed without a response".to_string(),
+            NoiseResponse::NotFound => "noise_paths: 404".to_string(),
+        });
+    } else {
+        route_request(req, config, cache, logger, &mut trace);
+    }
+    trace.unwrap_or_default()
+}
+
+// Upstream a request is pinned to by the rule's sticky mode, if any
+fn sticky_hint(req: &HttpRequest, rule: &ProxyRule) -> Option<usize> {
+    match rule.sticky {
+        Some(Sticky::Cookie) => req.headers.get("Cookie").and_then(|c| sticky_cookie(c)),
+        Some(Sticky::IpHash) => req
+            .peer_ip()
+            .and_then(|ip| sticky_ip(ip, rule.upstreams.len())),
+        None => None,
+    }
+}
+
+// The proxy rule and the upstreams it would try, the one a sticky mode pins marked
+fn proxy_steps(req: &HttpRequest, prefix: &str, rule: &ProxyRule) -> String {
+    let path = &req.path[prefix.len()..];
+    let pinned = sticky_hint(req, rule);
+    let pinned_by = match rule.sticky {
+        Some(Sticky::IpHash) => " (pinned by client address)",
+        _ => " (pinned by cookie)",
+    };
+    let mut steps = vec![format!("[proxy] {}", prefix)];
+    for index in 0..rule.upstreams.len() {
+        let pin = if pinned == Some(index) { pinned_by } else { "" };
+        steps.push(format!("upstream: {}{}", rule.url(index, path), pin));
+    }
+    steps.join("\n")
+}
+
+// Steps of route_request, kept when it is only asked what it would do
+// Nothing is read, forwarded or logged then, the trace is the answer
+type Trace = Option<Vec<String>>;
+
+fn note(trace: &mut Trace, step: impl FnOnce() -> String) {
+    if let Some(steps) = trace {
+        steps.push(step());
+    }
+}
+
+// When tracing, note the step and return what stands in for a response that would read
+// or forward something, None when the request is really served
+fn traced(trace: &mut Trace, step: impl FnOnce() -> String) -> Option<HttpResponse> {
+    trace.as_ref()?;
+    note(trace, step);
+    Some(HttpResponse::new(HttpStatus::NoContent, "", None))
+}
+
+// Status and Location of a redirect, for the trace
+fn redirect_step(response: &HttpResponse) -> String {
+    format!(
+        "{} {}",
+        response.status as u16,
+        response.headers.get("Location").unwrap_or("")
+    )
+}
 
-        let mut full_path = format!("{}{}", config.root, req.path.clone());
-        if Path::new(full_path.as_str()).is_dir() {
-            let separator = if full_path.ends_with('/') { "" } else { "/" };
-            full_path = format!("{}{}{}", full_path, separator, config.index);
+// Upstream stats of every proxy rule and the worker pool of the server, for Prometheus
+fn serve_metrics<W: io::Write>(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    req: &HttpRequest,
+    logger: &Mutex<Logger<W>>,
+) -> Option<HttpResponse> {
+    if config.metrics_path.as_ref() != Some(&req.path) {
+        return None;
+    }
+    let mut metrics = upstream_stats::to_prometheus(
+        sorted_rules(config).map(|(prefix, rule)| (prefix, rule.stats.snapshot())),
+    );
+    if let Some(stats) = SERVER_STATS.get() {
+        metrics.push_str(&pool_metrics(stats));
+        for (name, bytes) in memory_usage(cache, stats) {
+            let name = format!("hteapot_{}_bytes", name.replace(' ', "_"));
+            let _ = writeln!(metrics, "# TYPE {} gauge\n{} {}", name, name, bytes);
         }
-        if !Path::new(full_path.as_str()).exists() {
-            logger
-                .lock()
-                .expect("this doesnt work :C")
-                .msg(format!("path {} does not exist", req.path));
-            return HttpResponse::new(HttpStatus::NotFound, "Not found", None);
+    }
+    if !config.noise.is_empty() {
+        let _ = writeln!(
+            metrics,
+            "# TYPE hteapot_noise_requests_total counter\nhteapot_noise_requests_total {}",
+            noise::matched()
+        );
+    }
+    // Since the log writer last worked, both go back to 0 once it does
+    let health = logger.lock().expect("this doesnt work :C").health();
+    let _ = write!(
+        metrics,
+        "# TYPE hteapot_log_write_failures gauge\nhteapot_log_write_failures {}\n\
+         # TYPE hteapot_log_dropped_messages gauge\nhteapot_log_dropped_messages {}\n",
+        health.failures, health.dropped
+    );
+    Some(HttpResponse::new(
+        HttpStatus::OK,
+        metrics,
+        headers!("Content-Type" => "text/plain; version=0.0.4", "Cache-Control" => "no-store"),
+    ))
+}
+
+// Queue depth and wait, and connections per worker, in Prometheus text format
+fn pool_metrics(stats: &ServerStats) -> String {
+    let mut metrics = format!(
+        "# TYPE hteapot_queue_depth gauge\nhteapot_queue_depth {}\n\
+         # TYPE hteapot_connections_per_ip_rejected_total counter\n\
+         hteapot_connections_per_ip_rejected_total {}\n\
+         # TYPE hteapot_connections_busiest_ip gauge\nhteapot_connections_busiest_ip {}\n\
+         # TYPE hteapot_connections_tracked_ips gauge\nhteapot_connections_tracked_ips {}\n\
+         # TYPE hteapot_worker_connections gauge\n",
+        stats.queue_depth(),
+        stats.ip_rejected(),
+        stats.busiest_ip_connections(),
+        stats.tracked_ips()
+    );
+    for (worker, connections) in stats.worker_connections().iter().enumerate() {
+        let _ = writeln!(
+            metrics,
+            "hteapot_worker_connections{{worker=\"{}\"}} {}",
+            worker, connections
+        );
+    }
+    let waits = stats.queue_waits();
+    metrics.push_str("# TYPE hteapot_queue_wait_seconds histogram\n");
+    let mut cumulative = 0;
+    for (i, count) in waits.buckets.iter().enumerate() {
+        cumulative += count;
+        let le = match QUEUE_WAIT_BUCKETS_MS.get(i) {
+            Some(ms) => format!("{}", *ms as f64 / 1000.0),
+            None => "+Inf".to_string(),
+        };
+        let _ = writeln!(
+            metrics,
+            "hteapot_queue_wait_seconds_bucket{{le=\"{}\"}} {}",
+            le, cumulative
+        );
+    }
+    let _ = writeln!(
+        metrics,
+        "hteapot_queue_wait_seconds_sum {}\nhteapot_queue_wait_seconds_count {}",
+        waits.total.as_secs_f64(),
+        waits.count
+    );
+    metrics
+}
+
+// How often held memory is compared with the memory_warning thresholds
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Bytes held by the cache, requests being read and responses being written
+// Counted where they are stored rather than estimated, responses sharing a cached body count it again
+fn memory_usage(cache: &Mutex<Cache>, stats: &ServerStats) -> [(&'static str, usize); 3] {
+    [
+        ("cache", cache.lock().expect("Error locking cache").bytes()),
+        ("read buffer", stats.read_buffer_bytes()),
+        ("response buffer", stats.response_buffer_bytes()),
+    ]
+}
+
+// Summary of the usage when any figure is past its threshold, in the same order
+fn memory_warning(usage: &[(&str, usize); 3], thresholds: &[Option<u64>; 3]) -> Option<String> {
+    let over: Vec<&str> = usage
+        .iter()
+        .zip(thresholds.iter())
+        .filter(|((_, bytes), threshold)| threshold.is_some_and(|t| *bytes as u64 > t))
+        .map(|((name, _), _)| *name)
+        .collect();
+    if over.is_empty() {
+        return None;
+    }
+    let held: Vec<String> = usage
+        .iter()
+        .map(|(name, bytes)| format!("{} {} bytes", name, bytes))
+        .collect();
+    Some(format!(
+        "WARNING: Memory held: {}, over the {} warning",
+        held.join(", "),
+        over.join(" and ")
+    ))
+}
+
+// Log a summary when memory crosses a threshold, again only once it drops back and crosses again
+fn spawn_memory_monitor(
+    thresholds: [Option<u64>; 3],
+    cache: Arc<Mutex<Cache>>,
+    stats: Arc<ServerStats>,
+) {
+    std::thread::spawn(move || {
+        let mut logger = Logger::new(io::stdout());
+        let mut warned = false;
+        loop {
+            std::thread::sleep(MEMORY_CHECK_INTERVAL);
+            let warning = memory_warning(&memory_usage(&cache, &stats), &thresholds);
+            if let Some(warning) = warning.as_ref().filter(|_| !warned) {
+                logger.msg(warning.clone());
+            }
+            warned = warning.is_some();
         }
-        let mimetype = get_mime_tipe(&full_path);
-        let content: Option<Vec<u8>> = if config.cache {
-            let mut cachee = cache.lock().expect("Error locking cache");
-            let mut r = cachee.get(req.path.clone());
-            if r.is_none() {
-                r = serve_file(&full_path);
-                if r.is_some() {
-                    cachee.set(req.path.clone(), r.clone().unwrap());
-                }
+    });
+}
+
+// Proxy rules by prefix, so stats keep their order between reads
+fn sorted_rules(config: &Config) -> impl Iterator<Item = (&str, &ProxyRule)> {
+    let mut rules: Vec<(&str, &ProxyRule)> = config
+        .proxy_rules
+        .iter()
+        .map(|(prefix, rule)| (prefix.as_str(), rule))
+        .collect();
+    rules.sort_by_key(|(prefix, _)| *prefix);
+    rules.into_iter()
+}
+
+// Log the stats of every proxy rule each interval, counted since the rules were loaded
+fn spawn_upstream_summary(interval: Duration, state: Arc<SharedState>) {
+    std::thread::spawn(move || {
+        let mut logger = Logger::new(io::stdout());
+        loop {
+            std::thread::sleep(interval);
+            let snapshot = state.snapshot();
+            for (prefix, rule) in sorted_rules(&snapshot.config) {
+                logger.msg(format!(
+                    "Upstream {}: {}",
+                    prefix,
+                    rule.stats.snapshot().summary()
+                ));
             }
-            r
-        } else {
-            serve_file(&full_path)
-        };
-        match content {
-            Some(c) => HttpResponse::new(HttpStatus::OK, c, headers!("Content-Type" => mimetype)),
-            None => HttpResponse::new(HttpStatus::NotFound, "Not found", None),
         }
     });
 }
+
+// Endpoints for operators, moved to their own listener when admin_port is set
+// Under an [auth] prefix they need credentials like any other path
+fn serve_admin(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    req: &HttpRequest,
+    logger: &Mutex<Logger<Stdout>>,
+    trace: &mut Trace,
+) -> Option<HttpResponse> {
+    if !is_admin_path(config, &req.path) {
+        return None;
+    }
+    if let Some(stand_in) = traced(trace, || "admin endpoint".to_string()) {
+        return Some(stand_in);
+    }
+    if let Some(refused) = refuse_unauthorized(config, req, &mut None) {
+        return Some(refused);
+    }
+    serve_version(config, req)
+        .or_else(|| serve_route_test(config, cache, req, logger))
+        .or_else(|| serve_metrics(config, cache, req, logger))
+}
+
+fn is_admin_path(config: &Config, path: &str) -> bool {
+    let api = config.admin_api_path.as_ref().is_some_and(|base| {
+        path.strip_prefix(base.as_str())
+            .is_some_and(|rest| rest.starts_with('/'))
+    });
+    api || [
+        &config.version_path,
+        &config.route_test_path,
+        &config.metrics_path,
+    ]
+    .iter()
+    .any(|p| p.as_deref() == Some(path))
+}
+
+// Listener answering only the admin endpoints and the runtime controls, everything else is a 404
+fn spawn_admin(
+    host: &str,
+    port: u16,
+    state: Arc<SharedState>,
+    cache: Arc<Mutex<Cache>>,
+    recorder: Arc<Recorder>,
+) {
+    let server = Hteapot::new(host, port);
+    let address = format!("{}:{}", host, port);
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    std::thread::spawn(move || {
+        let listening = server.listen(move |req| {
+            let snapshot = state.snapshot();
+            let config = &snapshot.config;
+            let controls = Controls {
+                cache: &cache,
+                state: &state,
+                logger: &logger,
+                recorder: &recorder,
+            };
+            serve_admin(config, &cache, &req, &logger, &mut None)
+                .or_else(|| admin_api::serve(config.admin_api_path.as_ref()?, &req, &controls))
+                .unwrap_or_else(|| HttpResponse::new(HttpStatus::NotFound, "Not found", None))
+        });
+        // Port in use or address not available, nothing else stops it
+        if let Err(e) = listening {
+            Logger::new(io::stdout()).msg(format!("FATAL: admin listener on {}: {}", address, e));
+            std::process::exit(1);
+        }
+    });
+}
+
+// Answer requests matching noise_paths before anything else looks at them
+// Logged only at debug for the "noise" component, so the rest of the log stays readable
+fn serve_noise(
+    config: &Config,
+    req: &HttpRequest,
+    logger: &Mutex<Logger<Stdout>>,
+) -> Option<HttpResponse> {
+    if !config.noise.matches(req) {
+        return None;
+    }
+    logger.lock().expect("this doesnt work :C").at(
+        Level::Debug,
+        "noise",
+        format!(
+            "Noise {} {} from {}",
+            req.method.to_str(),
+            req.raw_path,
+            req.peer_ip().map_or("-".to_string(), |ip| ip.to_string())
+        ),
+    );
+    Some(config.noise.answer())
+}
+
+// Methods answered for local paths, files and [responses] ignore anything else
+const LOCAL_METHODS: &str = "GET, HEAD, OPTIONS";
+
+fn is_local_method(req: &HttpRequest) -> bool {
+    matches!(
+        req.method,
+        HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS
+    )
+}
+
+// A method outside HttpMethod, only here when allow_methods_extra lets it through
+fn is_extra_method(req: &HttpRequest) -> bool {
+    matches!(req.method, HttpMethod::Other(_))
+}
+
+fn method_not_allowed() -> HttpResponse {
+    HttpResponse::new(
+        HttpStatus::MethodNotAllowed,
+        "Method Not Allowed",
+        headers!("Allow" => LOCAL_METHODS),
+    )
+}
+
+// OPTIONS for a local path, with the CORS preflight answer when cors_origin is set
+fn serve_options(config: &Config, req: &HttpRequest) -> HttpResponse {
+    let mut headers = hteapot::Headers::new();
+    headers.insert("Allow", LOCAL_METHODS);
+    let preflight = req.headers.contains_key("Origin")
+        && req.headers.contains_key("Access-Control-Request-Method");
+    if config.cors_origin.is_some() && preflight {
+        headers.insert("Access-Control-Allow-Methods", LOCAL_METHODS);
+        if let Some(requested) = req.headers.get("Access-Control-Request-Headers") {
+            headers.insert("Access-Control-Allow-Headers", requested.as_str());
+        }
+        headers.insert("Access-Control-Max-Age", "600");
+    }
+    HttpResponse::new(HttpStatus::NoContent, "", Some(headers))
+}
+
+// Split the port off a Host value, keeping IPv6 literals intact
+fn split_host_port(host: &str) -> (&str, Option<&str>) {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => (&host[..i], Some(&host[i + 1..])),
+        _ => (host, None),
+    }
+}
+
+fn missing_host() -> HttpResponse {
+    HttpResponse::new(HttpStatus::BadRequest, "Missing Host header", None)
+}
+
+// Permanent redirect to the same path and query on another origin
+fn redirect_to(origin: &str, req: &HttpRequest) -> HttpResponse {
+    let query = if req.query.is_empty() {
+        "".to_string()
+    } else {
+        format!("?{}", req.query)
+    };
+    let location = format!("{}{}{}", origin, req.path, query);
+    HttpResponse::new(
+        HttpStatus::MovedPermanently,
+        "",
+        headers!("Location" => location),
+    )
+}
+
+// Scheme and port for redirects, https when the server redirects there anyway
+fn redirect_scheme(config: &Config, port: Option<&str>) -> (&'static str, String) {
+    if config.redirect_to_https {
+        let port = if config.https_port == 443 {
+            "".to_string()
+        } else {
+            format!(":{}", config.https_port)
+        };
+        ("https", port)
+    } else {
+        ("http", port.map(|p| format!(":{}", p)).unwrap_or_default())
+    }
+}
+
+fn redirect_https(config: &Config, req: &HttpRequest) -> Option<HttpResponse> {
+    if !config.redirect_to_https {
+        return None;
+    }
+    let host = match req.headers.get("Host") {
+        Some(host) => host,
+        None => return Some(missing_host()),
+    };
+    let (host, _) = split_host_port(host);
+    let (scheme, port) = redirect_scheme(config, None);
+    Some(redirect_to(&format!("{}://{}{}", scheme, host, port), req))
+}
+
+// Send requests for any other host name, or a different case of it, to canonical_host
+fn redirect_canonical_host(config: &Config, req: &HttpRequest) -> Option<HttpResponse> {
+    let canonical = config.canonical_host.as_ref()?;
+    let host = match req.headers.get("Host") {
+        Some(host) => host,
+        None => return Some(missing_host()),
+    };
+    let (host, port) = split_host_port(host);
+    if host == canonical {
+        return None;
+    }
+    let (scheme, port) = redirect_scheme(config, port);
+    Some(redirect_to(
+        &format!("{}://{}{}", scheme, canonical, port),
+        req,
+    ))
+}
+
+fn serve_static(config: &Config, req: &HttpRequest) -> Option<HttpResponse> {
+    let static_response = config.responses.get(&req.path)?;
+    let mut hasher = DefaultHasher::new();
+    static_response.body.hash(&mut hasher);
+    let etag = format!("\"{:x}\"", hasher.finish());
+    if none_match(req, &etag) {
+        return Some(HttpResponse::new(
+            HttpStatus::NotModified,
+            "",
+            headers!("ETag" => etag),
+        ));
+    }
+    Some(HttpResponse::new(
+        HttpStatus::from_u16(static_response.status),
+        static_response.body.as_str(),
+        headers!(
+            "Content-Type" => with_charset(config, &static_response.content_type, static_response.body.as_bytes()),
+            "ETag" => etag
+        ),
+    ))
+}
+
+// Sent by a page on another origin, the Origin host differs from Host
+fn cross_origin(req: &HttpRequest) -> bool {
+    let origin = match req.headers.get("Origin") {
+        Some(origin) => origin,
+        None => return false,
+    };
+    let origin_host = origin
+        .split_once("://")
+        .map_or(origin.as_str(), |(_, host)| host);
+    req.headers.get("Host").map(|h| h.as_str()) != Some(origin_host)
+}
+
+// SERVER CORE
+// for each request
+fn handle_request(
+    mut req: HttpRequest,
+    config: &Config,
+    cache: &Mutex<Cache>,
+    logger: &Mutex<Logger<Stdout>>,
+) -> HttpResponse {
+    let cors = config.cors_origin.is_some() && req.headers.contains_key("Origin");
+    let keep_alive =
+        config.keep_alive && is_proxy(config, &req.path).is_none_or(|(_, rule)| rule.keep_alive);
+    let started = Instant::now();
+    // Other origins only see the timings when timing_allow_origin lets them
+    if config.server_timing && (!cross_origin(&req) || config.timing_allow_origin.is_some()) {
+        req.extensions.insert(Timings::default());
+    }
+    let mut response = route_request(&req, config, cache, logger, &mut None);
+    if config.log_request_body > 0 {
+        let max_bytes = config.log_request_body as usize;
+        if let Some(line) = body_log::describe(&req, max_bytes, &config.log_request_body_exclude) {
+            logger
+                .lock()
+                .expect("this doesnt work :C")
+                .at(Level::Debug, "body", line);
+        }
+    }
+    if let Some(timings) = req.extensions.get::<Timings>() {
+        response.add_header("Server-Timing", &timings.header(started.elapsed()));
+        if let Some(origin) = &config.timing_allow_origin {
+            response.add_header("Timing-Allow-Origin", origin);
+        }
+    }
+    if !keep_alive {
+        response.close_connection();
+    }
+    // Proxied responses keep the upstream headers as they are
+    if !response.is_raw() {
+        response
+            .headers
+            .insert("X-Content-Type-Options".to_string(), "nosniff".to_string());
+        if let Some(origin) = config.cors_origin.as_ref().filter(|_| cors) {
+            response
+                .headers
+                .insert("Access-Control-Allow-Origin", origin.as_str());
+        }
+    }
+    response
+}
+
+// With a trace, each decision is noted and nothing is read or forwarded, see route_trace
+fn route_request(
+    req: &HttpRequest,
+    config: &Config,
+    cache: &Mutex<Cache>,
+    logger: &Mutex<Logger<Stdout>>,
+    trace: &mut Trace,
+) -> HttpResponse {
+    if trace.is_none() {
+        logger.lock().expect("this doesnt work :C").at(
+            Level::Info,
+            "request",
+            format!("Request {} {}", req.method.to_str(), req.path),
+        );
+    }
+
+    if let Some(response) = serve_acme_challenge(config, req, logger, trace) {
+        return response;
+    }
+
+    if config.admin_port.is_some() && is_admin_path(config, &req.path) {
+        note(trace, || {
+            "admin endpoint: only on admin_port, 404 here".to_string()
+        });
+        return HttpResponse::new(HttpStatus::NotFound, "Not found", None);
+    }
+    if let Some(response) = serve_admin(config, cache, req, logger, trace) {
+        return response;
+    }
+
+    // A [dev_router] host takes every path, before redirects, files and proxy rules
+    if let Some(rule) = dev_route(config, req) {
+        if let Some(stand_in) = traced(trace, || {
+            format!("[dev_router]: {}", rule.url(0, &req.path))
+        }) {
+            return stand_in;
+        }
+        return timed(req, "proxy", || {
+            serve_proxy(req, "", rule, config, cache, logger)
+        });
+    }
+
+    // Answered here unless a proxy rule takes the path, preflights can't follow redirects
+    if req.method == HttpMethod::OPTIONS && is_proxy(config, &req.path).is_none() {
+        note(trace, || {
+            format!("OPTIONS: answered locally, Allow: {}", LOCAL_METHODS)
+        });
+        return serve_options(config, req);
+    }
+
+    // Methods let through by allow_methods_extra, nothing here declares any yet
+    // Proxies send every request as a GET, so they can't take them either
+    // Files and [responses] take no POST, PUT or DELETE
+    if is_extra_method(req) || (!is_local_method(req) && is_proxy(config, &req.path).is_none()) {
+        note(trace, || {
+            format!("{}: 405, Allow: {}", req.method.to_str(), LOCAL_METHODS)
+        });
+        return method_not_allowed();
+    }
+
+    if let Some(response) = redirect_canonical_host(config, req) {
+        note(trace, || {
+            format!("canonical_host: {}", redirect_step(&response))
+        });
+        return response;
+    }
+
+    if let Some(response) = redirect_https(config, req) {
+        note(trace, || {
+            format!("redirect_to_https: {}", redirect_step(&response))
+        });
+        return response;
+    }
+
+    if let Some(refused) = refuse_unauthorized(config, req, trace) {
+        return refused;
+    }
+
+    if let Some(response) = serve_static(config, req) {
+        note(trace, || {
+            let content_type = response.headers.get("Content-Type").unwrap_or("");
+            format!(
+                "[responses] {}: {} {}",
+                req.path, response.status as u16, content_type
+            )
+        });
+        return response;
+    }
+
+    if let Some((prefix, rule)) = is_proxy(config, &req.path) {
+        if let Some(stand_in) = traced(trace, || proxy_steps(req, prefix, rule)) {
+            return stand_in;
+        }
+        return timed(req, "proxy", || {
+            serve_proxy(req, prefix, rule, config, cache, logger)
+        });
+    }
+
+    if !config.serve_files {
+        note(trace, || "serve_files off: 404".to_string());
+        return error_page(req, HttpStatus::NotFound, "Not found");
+    }
+
+    let files = DiskFs::new(&config.root);
+    let path = match files.resolve(&req.path, &config.index) {
+        Some(path) => path,
+        None if trace.is_some() => {
+            note(trace, || {
+                format!("file: nothing under {}, 404", config.root)
+            });
+            return error_page(req, HttpStatus::NotFound, "Not found");
+        }
+        None => {
+            logger.lock().expect("this doesnt work :C").at(
+                Level::Info,
+                "files",
+                format!("path {} does not exist", req.path),
+            );
+            return error_page(req, HttpStatus::NotFound, "Not found");
+        }
+    };
+    let full_path = format!("{}{}", config.root, path);
+    if let Some(stand_in) = traced(trace, || format!("file: {}", full_path)) {
+        return stand_in;
+    }
+    // Cached files are shared with every response sending them, not copied
+    let use_cache = config.cache && cache.lock().expect("Error locking cache").enabled();
+    let content: Result<LoadedFile, FileError> = if use_cache {
+        let (cached, loads) = timed(req, "cache", || {
+            let mut cachee = cache.lock().expect("Error locking cache");
+            (cachee.get_file(req.path.clone()), cachee.file_loads())
+        });
+        // Concurrent misses wait for a single read of the file
+        match cached {
+            Some(c) => Ok(c),
+            None => loads.run(&req.path, || {
+                let r = timed(req, "fs", || load_file(&files, &path));
+                if let Ok(c) = &r {
+                    let mut cachee = cache.lock().expect("Error locking cache");
+                    cachee.set_file(req.path.clone(), c.clone());
+                }
+                r
+            }),
+        }
+    } else {
+        timed(req, "fs", || load_file(&files, &path))
+    };
+    match content {
+        Ok((c, modified)) => {
+            let mimetype = content_type(config, &full_path, &c);
+            // The time read with the content, a cached copy may be older than the file
+            let key = DigestKey {
+                path: full_path.clone(),
+                modified,
+                len: c.len(),
+            };
+            let digests = || cache.lock().expect("Error locking cache").digests();
+            let digest = if config.etag_from_content {
+                Some(digests().wait(key, &c))
+            } else if config.emit_digest {
+                digests().get(key, &c)
+            } else {
+                None
+            };
+            let response =
+                HttpResponse::from_shared(HttpStatus::OK, c, headers!("Content-Type" => mimetype));
+            match digest {
+                Some(digest) => with_digest(config, req, &digest, response),
+                None => response,
+            }
+        }
+        Err(e) => file_error(req, &full_path, &e, logger),
+    }
+}
+
+// The client holds etag, If-None-Match lists it or is *
+// Compared weakly as RFC 9110 says for If-None-Match, a W/ prefix is ignored
+fn none_match(req: &HttpRequest, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    req.headers.get("If-None-Match").is_some_and(|tags| {
+        tags.split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    })
+}
+
+// Repr-Digest and the older Digest header with emit_digest, the ETag with etag_from_content
+// A client already holding that ETag gets a 304 instead
+fn with_digest(
+    config: &Config,
+    req: &HttpRequest,
+    digest: &[u8; 32],
+    mut response: HttpResponse,
+) -> HttpResponse {
+    if config.etag_from_content {
+        let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        let etag = format!("\"{}\"", hex);
+        if none_match(req, &etag) {
+            return HttpResponse::new(HttpStatus::NotModified, "", headers!("ETag" => etag));
+        }
+        response.headers.insert("ETag", etag);
+    }
+    if config.emit_digest {
+        let encoded = base64_encode(digest);
+        response
+            .headers
+            .insert("Repr-Digest", format!("sha-256=:{}:", encoded));
+        response
+            .headers
+            .insert("Digest", format!("SHA-256={}", encoded));
+    }
+    response
+}
+
+// Close kept connections and wait for the requests in flight, at most grace
+// Returns at once when there are none, the line to log says how long it took
+fn drain(in_flight: &InFlight, grace: Duration) -> String {
+    let started = Instant::now();
+    in_flight.close_connections();
+    if in_flight.wait_idle(grace) {
+        format!(
+            "Requests in flight drained in {}ms",
+            started.elapsed().as_millis()
+        )
+    } else {
+        format!(
+            "WARNING: Stopping with {} requests still in flight after {}s",
+            in_flight.count(),
+            grace.as_secs_f64()
+        )
+    }
+}
+
+// Read the payload for --serve - from stdin
+fn read_stdin_payload() -> Result<String, String> {
+    let mut payload = Vec::new();
+    io::stdin()
+        .take(MAX_STDIN_PAYLOAD + 1)
+        .read_to_end(&mut payload)
+        .map_err(|e| format!("Error reading stdin: {}", e))?;
+    if payload.len() as u64 > MAX_STDIN_PAYLOAD {
+        return Err(format!("stdin is bigger than {} bytes", MAX_STDIN_PAYLOAD));
+    }
+    String::from_utf8(payload).map_err(|_| "stdin is not valid UTF-8".to_string())
+}
+
+fn main() {
+    let mut args = std::env::args().collect::<Vec<String>>();
+    // Config problems found at startup are fatal instead of logged
+    let strict = args.iter().any(|a| a == "--strict");
+    args.retain(|a| a != "--strict");
+    let overrides = match Overrides::new(std::env::vars(), &mut args) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut serving_path = None;
+    let mut payload = None;
+    // Value following a flag, like --content-type text/html
+    let flag_value = |flag: &str| {
+        args.iter()
+            .position(|a| a == flag)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    if args.len() >= 2 {
+        match args[1].as_str() {
+            "--help" | "-h" => {
+                println!("Hteapot {}", build_info::VERSION);
+                println!("usage: {} <config file>", args[0]);
+                println!("       {} --serve <path>", args[0]);
+                println!("       {} --serve - [--content-type <type>]", args[0]);
+                println!("       {} --serve-text <text>", args[0]);
+                println!(
+                    "       {} --service install|run <config file> [--log-file <path>]",
+                    args[0]
+                );
+                println!("       {} --service uninstall", args[0]);
+                println!(
+                    "       {} --self-test <config file> [--self-test-offline]",
+                    args[0]
+                );
+                println!("       {} --replay <recording> <target url>", args[0]);
+                println!("       --port, --host, --root and --threads override the config file,");
+                println!(
+                    "                as do HTEAPOT_PORT, HTEAPOT_HOST and so on, the flags win"
+                );
+                println!("       --strict fails on config problems instead of logging them,");
+                println!(
+                    "                and answers 500 for responses with a wrong Content-Length"
+                );
+                return;
+            }
+            "--version" | "-v" => {
+                println!("Hteapot {}", build_info::summary());
+                println!("features: {}", build_info::features().join(", "));
+                return;
+            }
+            "--serve" | "-s" if args.get(2).map(|a| a.as_str()) == Some("-") => {
+                payload = Some(read_stdin_payload());
+            }
+            "--serve" | "-s" => {
+                serving_path = Some(args.get(2).cloned().unwrap_or_default());
+            }
+            "--serve-text" => {
+                payload = Some(Ok(args.get(2).cloned().unwrap_or_default()));
+            }
+            "--self-test" => {
+                let offline = args.iter().any(|a| a == "--self-test-offline");
+                let config = args
+                    .get(2)
+                    .map(|path| Config::load_config(path, &overrides));
+                let config = match config {
+                    Some(Ok(config)) => config,
+                    Some(Err(e)) => {
+                        eprintln!("Invalid config: {}", e);
+                        std::process::exit(1);
+                    }
+                    None => {
+                        eprintln!("--self-test needs a config file");
+                        std::process::exit(1);
+                    }
+                };
+                // Only problems are logged, not every synthetic request
+                logger::set_level(Level::Warn);
+                match selftest::run(config, offline) {
+                    Ok(report) if report.passed() => println!("{}", report),
+                    Ok(report) => {
+                        println!("{}", report);
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("Error starting the self-test: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            "--replay" => {
+                let (path, target) = match (args.get(2), args.get(3)) {
+                    (Some(path), Some(target)) => (path, target),
+                    _ => {
+                        eprintln!("--replay needs a recording and a target url");
+                        std::process::exit(1);
+                    }
+                };
+                let exchanges = match recorder::read_recording(Path::new(path)) {
+                    Ok(exchanges) => exchanges,
+                    Err(e) => {
+                        eprintln!("Invalid recording: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+                let mut different = 0;
+                for exchange in exchanges.iter() {
+                    let replayed = recorder::replay(exchange, target);
+                    if !replayed.same() {
+                        different += 1;
+                    }
+                    println!("{}", replayed);
+                }
+                println!(
+                    "{} replayed, {} with another status",
+                    exchanges.len(),
+                    different
+                );
+                if different > 0 {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            "--service" => {
+                if let Err(e) = run_service(&args[2..], strict, overrides) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            _ => (),
+        };
+    }
+
+    let config = if let Some(payload) = payload {
+        payload.and_then(|body| Config::new_payload(body, flag_value("--content-type"), &overrides))
+    } else if let Some(serving_path) = serving_path {
+        config::Config::new_serve(&serving_path, &overrides)
+    } else if args.len() == 2 {
+        config::Config::load_config(&args[1], &overrides)
+    } else {
+        overrides
+            .apply(Config::builder())
+            .and_then(|builder| builder.build())
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    serve(config, strict);
+}
+
+// --service install, uninstall or run, only on Windows
+fn run_service(args: &[String], strict: bool, overrides: Overrides) -> Result<(), String> {
+    match service::parse_args(args)? {
+        ServiceCommand::Install { config, log_file } => {
+            Config::load_config(&config, &overrides)
+                .map_err(|e| format!("Invalid config: {}", e))?;
+            service::install(&config, &log_file)?;
+            println!(
+                "Installed service {}, logging to {}",
+                service::SERVICE_NAME,
+                log_file
+            );
+        }
+        ServiceCommand::Uninstall => {
+            service::uninstall()?;
+            println!("Removed service {}", service::SERVICE_NAME);
+        }
+        ServiceCommand::Run { config, log_file } => {
+            service::run(&config, &log_file, move |config| match Config::load_config(
+                config, &overrides,
+            ) {
+                Ok(config) => serve(config, strict),
+                Err(e) => eprintln!("Invalid config: {}", e),
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// Run the server until it is stopped
+fn serve(config: Config, strict: bool) {
+    let issues = preflight::check(&config);
+    if strict && !issues.is_empty() {
+        for issue in issues.iter() {
+            eprintln!("Invalid config: {}", issue);
+        }
+        std::process::exit(1);
+    }
+
+    let proxy_only = config.proxy_rules.contains_key("/");
+    logger::set_level(config.log_level);
+    let logger = Arc::new(Mutex::new(Logger::new(io::stdout())));
+    for issue in issues.iter() {
+        logger
+            .lock()
+            .expect("this doesnt work :C")
+            .msg(format!("WARNING: {}", issue));
+    }
+    let passed = match activation::listeners() {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("Invalid socket activation: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let default = Config::new_default();
+    if !passed.is_empty() && (config.host != default.host || config.port != default.port) {
+        logger.lock().expect("this doesnt work :C").msg(format!(
+            "WARNING: Using the sockets passed by systemd, host {} and port {} are ignored",
+            config.host, config.port
+        ));
+    }
+    // Bound here rather than in listen so a taken port can be explained
+    let hosts = config.hosts();
+    let listeners = if passed.is_empty() {
+        hosts
+            .iter()
+            .flat_map(|host| match bind_host(host, config.port) {
+                Ok(listeners) => listeners,
+                Err(e) => {
+                    let (message, code) = diagnostics::bind_error(host, config.port, &e);
+                    eprintln!("{}", message);
+                    std::process::exit(code);
+                }
+            })
+            .collect()
+    } else {
+        passed
+    };
+    let mut cache = Cache::new(config.cache_ttl as u64);
+    let cache_file = config
+        .cache_file
+        .as_ref()
+        .filter(|_| config.cache)
+        .map(PathBuf::from);
+    if let Some(path) = cache_file.as_ref().filter(|path| path.exists()) {
+        let message = match cache.load(path) {
+            Ok(loaded) => format!(
+                "Loaded {} cache entries from {}, {} bytes",
+                loaded,
+                path.display(),
+                cache.bytes()
+            ),
+            Err(e) => format!("WARNING: Discarding cache file {}: {}", path.display(), e),
+        };
+        logger.lock().expect("this doesnt work :C").msg(message);
+    }
+    let cache = Arc::new(Mutex::new(cache));
+    let mut server = Hteapot::new_threaded(&hosts[0], config.port, config.threads);
+    for host in hosts.iter().skip(1) {
+        server.add_listener(host, config.port);
+    }
+    server.set_strict_lengths(strict);
+    if config.keep_alive_ttl > 0 {
+        server.set_keep_alive_ttl(Duration::from_secs(config.keep_alive_ttl as u64));
+    }
+    server.set_header_read_timeout(match config.header_read_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs as u64)),
+    });
+    if config.keep_alive_max_requests > 0 {
+        server.set_keep_alive_max_requests(config.keep_alive_max_requests as usize);
+    }
+    if config.connection_max_lifetime > 0 {
+        server.set_connection_max_lifetime(Duration::from_secs(
+            config.connection_max_lifetime as u64,
+        ));
+    }
+    if let Some(rate) = config.max_bandwidth {
+        server.set_max_bandwidth(rate);
+    }
+    if let Some(rate) = config.max_bandwidth_per_conn {
+        server.set_max_bandwidth_per_conn(rate);
+    }
+    if config.max_connections > 0 {
+        server.set_max_connections(
+            config.max_connections as usize,
+            config.max_connections_overflow,
+        );
+    }
+    if config.max_connections_per_ip > 0 {
+        server.set_max_connections_per_ip(
+            config.max_connections_per_ip as usize,
+            config.max_connections_per_ip_exempt.clone(),
+        );
+    }
+    if config.accept_queue_limit > 0 {
+        server.set_accept_queue_limit(config.accept_queue_limit as usize);
+    }
+    server.set_worker_restart_budget(config.worker_restart_budget as usize);
+    let _ = SERVER_STATS.set(server.stats());
+    server.set_queue_warnings(
+        Duration::from_millis(config.queue_wait_warning_ms as u64),
+        config.queue_depth_warning as usize,
+    );
+    server.set_log_handshake_failures(config.log_handshake_failures);
+    server.set_proxy_protocol(config.proxy_protocol);
+    server.set_parsing_rules(config.parsing);
+    server.set_request_limits(RequestLimits {
+        max_head_bytes: config.max_request_head_bytes as usize,
+        max_line_bytes: config.max_request_line_bytes as usize,
+        max_header_count: config.max_header_count as usize,
+    });
+    server.set_extra_methods(config.allow_methods_extra.clone());
+    if config.max_body_size > 0 {
+        server.set_max_body_size(config.max_body_size as usize);
+    }
+    if config.stream_workers > 0 {
+        server.set_hijack_workers(config.stream_workers as usize, config.stream_queue as usize);
+    }
+    // Sorted so links matching several patterns keep a stable order
+    let mut preloads: Vec<(&String, &String)> = config.preloads.iter().collect();
+    preloads.sort();
+    for (pattern, link) in preloads {
+        server.add_preload(pattern, link);
+    }
+    if let Some(snippet) = &config.inject_html_before_end {
+        server.add_post_processor("text/html", InjectHtml::new(snippet));
+    }
+    if let Some(chaos) = config.chaos.clone() {
+        logger.lock().expect("this doesnt work :C").msg(format!(
+            "WARNING: Chaos mode enabled, responses will fail on purpose: {:?}",
+            chaos
+        ));
+        server.set_chaos(chaos);
+    }
+    let addresses: Vec<String> = listeners
+        .iter()
+        .map(|listener| match listener.local_addr() {
+            Ok(address) => format!("http://{}", address),
+            Err(_) => format!("http://{}", format_addr(&config.host, config.port)),
+        })
+        .collect();
+    logger.lock().expect("this doesnt work :C").msg(format!(
+        "Hteapot {} started at {} with {} threads",
+        build_info::summary(),
+        addresses.join(", "),
+        config.threads
+    ));
+    if config.cache {
+        logger
+            .lock()
+            .expect("this doesnt work :C")
+            .msg("Cache Enabled".to_string());
+    }
+    if proxy_only {
+        logger
+            .lock()
+            .expect("this doesnt work :C")
+            .msg("WARNING: All requests are proxied to /. Local paths won’t be used.".to_string());
+    }
+
+    if let Some(path) = cache_file {
+        let cache = cache.clone();
+        server.add_shutdown_hook(ShutdownHook::new("cache", move || {
+            let saved = cache.lock().expect("Error locking cache").save(&path);
+            match saved {
+                Ok(saved) => println!("Saved {} cache entries to {}", saved, path.display()),
+                Err(e) => eprintln!("Error saving cache to {}: {}", path.display(), e),
+            }
+        }));
+    }
+    if let Some(url) = &config.alert_webhook_url {
+        let source = format_addr(&hosts[0], config.port);
+        let alerts = Alerts::new(url, &source, ALERT_WINDOW).start();
+        let reported = alerts.clone();
+        logger::set_alert_hook(Some(Arc::new(move |message| reported.report(message))));
+        let reported = alerts.clone();
+        server.set_fatal_hook(move |message| reported.report(message));
+        // Stopping on a fatal event goes through here too, the batch would be lost on exit
+        server.add_shutdown_hook(ShutdownHook::new("alerts", move || alerts.flush()));
+    }
+    let hooks = server.shutdown_hooks();
+    let in_flight = server.in_flight();
+    let grace = Duration::from_secs(config.shutdown_grace_secs as u64);
+    let stop_logger = logger.clone();
+    signals::on_stop(move || {
+        let log = |message: String| {
+            stop_logger
+                .lock()
+                .expect("this doesnt work :C")
+                .msg(message)
+        };
+        log(format!("Received {}, stopping", signals::stop_reason()));
+        // Hooks like the cache save run once the last response is out, or grace is over
+        log(drain(&in_flight, grace));
+        log(format!("Stopping, {}", hooks.run(false)));
+        service::report_stopped();
+        std::process::exit(0);
+    });
+
+    let access_log = Arc::new(AccessLog::new(
+        config.access_log.clone(),
+        &config.access_logs,
+    ));
+    #[cfg(unix)]
+    {
+        let access_log = access_log.clone();
+        signals::on_reopen(move || access_log.reopen());
+    }
+    let recorder = Arc::new(Recorder::new(
+        config.record_dir.clone(),
+        config.record_sample_rate,
+        config.record_paths.clone(),
+        config.record_redact_headers.clone(),
+    ));
+    if let Some(dir) = &config.record_dir {
+        logger.lock().expect("this doesnt work :C").msg(format!(
+            "WARNING: Recording {} of requests to {}",
+            config.record_sample_rate, dir
+        ));
+    }
+    let admin = config
+        .admin_port
+        .map(|port| (config.admin_host.clone(), port));
+    let summary_interval = config.upstream_stats_interval as u64 * 60;
+    let memory_thresholds = [
+        config.memory_warning_cache,
+        config.memory_warning_read,
+        config.memory_warning_response,
+    ];
+    if memory_thresholds.iter().any(|t| t.is_some()) {
+        spawn_memory_monitor(memory_thresholds, cache.clone(), server.stats());
+    }
+    let state = Arc::new(SharedState::new(config));
+    if summary_interval > 0 {
+        spawn_upstream_summary(Duration::from_secs(summary_interval), state.clone());
+    }
+    if let Some((host, port)) = admin {
+        logger
+            .lock()
+            .expect("this doesnt work :C")
+            .msg(format!("Admin endpoints at http://{}:{}", host, port));
+        spawn_admin(&host, port, state.clone(), cache.clone(), recorder.clone());
+    }
+    let handler = move |req| {
+        let snapshot = state.snapshot();
+        // Scanner noise stays out of the access log and the recorder
+        if let Some(response) = serve_noise(&snapshot.config, &req, &logger) {
+            return response;
+        }
+        let entry = AccessEntry::new(&req);
+        let captured = recorder.capture(&req);
+        let mut response = handle_request(req, &snapshot.config, &cache, &logger);
+        // Clients reconnect elsewhere while the balancer takes this server out
+        if state.draining() {
+            response.close_connection();
+        }
+        access_log.record(&entry, &response);
+        recorder.record(captured, &response);
+        response
+    };
+    server.listen_on_all(listeners, handler);
+}
+
+#[cfg(test)]
+fn test_request(path: &str, headers: &str) -> HttpRequest {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+        path, headers
+    );
+    Hteapot::request_parser(request).unwrap()
+}
+
+#[test]
+fn test_forwarded_for() {
+    let mut req = test_request("/", "");
+    assert!(forwarded_for(&req).is_empty());
+    req.remote_addr = Some("203.0.113.7:51234".parse().unwrap());
+    assert_eq!(
+        forwarded_for(&req),
+        vec![("X-Forwarded-For", "203.0.113.7".to_string())]
+    );
+    let mut req = test_request("/", "X-Forwarded-For: 198.51.100.1\r\n");
+    req.remote_addr = Some("[2001:db8::1]:443".parse().unwrap());
+    assert_eq!(
+        forwarded_for(&req),
+        vec![("X-Forwarded-For", "198.51.100.1, 2001:db8::1".to_string())]
+    );
+}
+
+#[test]
+fn test_auth_prefixes() {
+    let mut config = Config::new_default();
+    config.root = "public".to_string();
+    for (prefix, response) in [("/api", "api"), ("/api/public", "public")] {
+        config.responses.insert(
+            format!("{}/x", prefix),
+            config::StaticResponse {
+                body: response.to_string(),
+                status: 200,
+                content_type: "text/plain".to_string(),
+            },
+        );
+    }
+    let tokens = |tokens: &[&str]| AuthRule {
+        realm: "api".to_string(),
+        backend: Arc::new(hteapot::TokenList::new(
+            tokens.iter().map(|t| t.to_string()).collect(),
+        )),
+    };
+    config.auth.insert("/api".to_string(), tokens(&["secret"]));
+    // The longest prefix picks the backend
+    config
+        .auth
+        .insert("/api/public".to_string(), tokens(&["shared"]));
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let get = |path: &str, headers: &str| {
+        handle_request(test_request(path, headers), &config, &cache, &logger)
+    };
+
+    let refused = get("/api/x", "");
+    assert_eq!(refused.status as u16, 401);
+    assert_eq!(
+        refused.headers.get("WWW-Authenticate"),
+        Some("Bearer realm=\"api\"")
+    );
+    assert_eq!(
+        get("/api/x", "Authorization: Bearer secret\r\n").content,
+        b"api"
+    );
+    assert_eq!(
+        get("/api/public/x", "Authorization: Bearer secret\r\n").status as u16,
+        401
+    );
+    assert_eq!(
+        get("/api/public/x", "Authorization: Bearer shared\r\n").content,
+        b"public"
+    );
+    // Files outside the prefixes need nothing
+    assert_eq!(get("/index.html", "").status as u16, 200);
+
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let trace = route_trace(&config, &cache, &logger, &test_request("/api/x", ""));
+    assert_eq!(
+        trace.last().unwrap(),
+        "[auth] /api: Bearer 401 without valid credentials"
+    );
+}
+
+#[test]
+fn test_static_response_precedence() {
+    let mut config = Config::new_default();
+    config.root = "public".to_string();
+    config.responses.insert(
+        "/index.html".to_string(),
+        config::StaticResponse {
+            body: "inline".to_string(),
+            status: 200,
+            content_type: "text/plain".to_string(),
+        },
+    );
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let response = handle_request(test_request("/index.html", ""), &config, &cache, &logger);
+    assert_eq!(response.content, b"inline");
+    let etag = response.headers.get("ETag").unwrap().to_string();
+
+    let headers = format!("If-None-Match: {}\r\n", etag);
+    let response = handle_request(
+        test_request("/index.html", &headers),
+        &config,
+        &cache,
+        &logger,
+    );
+    assert_eq!(response.status as u16, 304);
+    assert!(response.content.is_empty());
+
+    // One of several, weak or not, or any
+    for held in [
+        format!("\"a\", W/{}, \"b\"", etag),
+        format!("\"a\",{}", etag),
+        "*".to_string(),
+    ] {
+        let headers = format!("If-None-Match: {}\r\n", held);
+        let response = handle_request(
+            test_request("/index.html", &headers),
+            &config,
+            &cache,
+            &logger,
+        );
+        assert_eq!(response.status as u16, 304, "{}", held);
+    }
+    let response = handle_request(
+        test_request("/index.html", "If-None-Match: \"a\", \"b\"\r\n"),
+        &config,
+        &cache,
+        &logger,
+    );
+    assert_eq!(response.status as u16, 200);
+}
+
+#[test]
+fn test_content_type_charset() {
+    let plain = Config::new_default();
+    let latin1 = Config::builder()
+        .default_charset("iso-8859-1".to_string())
+        .mime_type(".TXT", "text/plain; charset=windows-1252")
+        .mime_type("md", "text/markdown")
+        .mime_type("xml", "application/xml; charset=utf-16")
+        .build()
+        .unwrap();
+    let bom = b"\xEF\xBB\xBFhola";
+    let cases = [
+        (&plain, "a.css", &b"a"[..], "text/css"),
+        (&plain, "a.json", b"{}", "application/json"),
+        (&plain, "a.html", bom, "text/html; charset=utf-8"),
+        (&plain, "a.md", b"#", "text/plain"),
+        (&plain, "LICENSE", b"MIT", "text/plain"),
+        (&latin1, "a.js", b"a", "text/javascript; charset=iso-8859-1"),
+        (&latin1, "a.js", bom, "text/javascript; charset=utf-8"),
+        (&latin1, "a.ico", b"a", "image/x-icon"),
+        (&latin1, "a.txt", b"a", "text/plain; charset=windows-1252"),
+        (&latin1, "a.txt", bom, "text/plain; charset=utf-8"),
+        (&latin1, "a.xml", b"<a/>", "application/xml; charset=utf-16"),
+        (&latin1, "a.xml", bom, "application/xml; charset=utf-8"),
+        (&latin1, "a.MD", b"#", "text/markdown; charset=iso-8859-1"),
+    ];
+    for (config, path, content, expected) in cases.iter() {
+        assert_eq!(content_type(config, path, content), *expected, "{}", path);
+    }
+
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let response = handle_request(test_request("/missing", ""), &plain, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+    assert_eq!(&response.headers["X-Content-Type-Options"], "nosniff");
+}
+
+#[test]
+fn test_file_digest() {
+    let root = std::env::temp_dir().join(format!("hteapot-digest-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("release.tar"), "abc").unwrap();
+    let builder = || {
+        Config::builder()
+            .root(root.to_str().unwrap().to_string())
+            .cache(false)
+    };
+    let config = builder()
+        .emit_digest(true)
+        .etag_from_content(true)
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(60));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let get = |config: &Config, headers: &str| {
+        handle_request(
+            test_request("/release.tar", headers),
+            config,
+            &cache,
+            &logger,
+        )
+    };
+
+    // SHA-256 of "abc"
+    let response = get(&config, "");
+    assert_eq!(response.status as u16, 200);
+    assert_eq!(
+        response.headers.get("Repr-Digest"),
+        Some("sha-256=:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=:")
+    );
+    assert_eq!(
+        response.headers.get("Digest"),
+        Some("SHA-256=ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=")
+    );
+    let etag = "\"ba7816bf8f01cfea414140de5dae2223\"";
+    assert_eq!(response.headers.get("ETag"), Some(etag));
+    let held = format!("If-None-Match: \"other\", {}\r\n", etag);
+    assert_eq!(get(&config, &held).status as u16, 304);
+
+    // Changed on disk, hashed again
+    fs::write(root.join("release.tar"), "abcd").unwrap();
+    let response = get(&config, &held);
+    assert_eq!(response.status as u16, 200);
+    assert_eq!(
+        response.headers.get("Digest"),
+        Some("SHA-256=iNQmb9TmM40TuEX88olXnSCciXgjuSF9o+Fhk28DFYk=")
+    );
+
+    // Neither header by default
+    let response = get(&builder().build().unwrap(), "");
+    assert!(!response.headers.contains_key("Digest"));
+    assert!(!response.headers.contains_key("ETag"));
+
+    // A cached copy older than the file keeps the ETag of its own bytes, and the file's
+    // time isn't tied to them, the new bytes get theirs once read
+    let config = builder()
+        .cache(true)
+        .etag_from_content(true)
+        .build()
+        .unwrap();
+    let etag_of = |content: &[u8]| {
+        let hex: String = sha256(content)[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("\"{}\"", hex)
+    };
+    fs::write(root.join("release.tar"), "old!").unwrap();
+    assert_eq!(
+        get(&config, "").headers.get("ETag"),
+        Some(etag_of(b"old!").as_str())
+    );
+    fs::write(root.join("release.tar"), "new!").unwrap();
+    // Same length, a time of its own even where the clock is coarse
+    fs::File::options()
+        .write(true)
+        .open(root.join("release.tar"))
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    let response = get(&config, "");
+    assert_eq!(response.body(), b"old!");
+    assert_eq!(
+        response.headers.get("ETag"),
+        Some(etag_of(b"old!").as_str())
+    );
+    cache.lock().unwrap().clear();
+    let response = get(&config, "");
+    assert_eq!(response.body(), b"new!");
+    assert_eq!(
+        response.headers.get("ETag"),
+        Some(etag_of(b"new!").as_str())
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_drain() {
+    use std::thread;
+
+    // Nothing in flight, no waiting for the grace period
+    let in_flight = Arc::new(InFlight::default());
+    let started = Instant::now();
+    let line = drain(&in_flight, Duration::from_secs(10));
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert!(
+        line.starts_with("Requests in flight drained in "),
+        "{}",
+        line
+    );
+    assert!(in_flight.closing());
+
+    // Done before the grace period ends
+    let request = in_flight.start();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(request);
+    });
+    let started = Instant::now();
+    assert!(drain(&in_flight, Duration::from_secs(10)).starts_with("Requests in flight drained"));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // A request that never ends is given up on once it is over
+    let _stuck = in_flight.start();
+    let started = Instant::now();
+    let line = drain(&in_flight, Duration::from_millis(200));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        line,
+        "WARNING: Stopping with 1 requests still in flight after 0.2s"
+    );
+}
+
+#[test]
+fn test_mime_sniffing() {
+    let config = Config::new_default();
+    let cases: [(&[u8], &str); 10] = [
+        (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"),
+        (b"\xFF\xD8\xFF\xE0\0\x10JFIF", "image/jpeg"),
+        (b"GIF89a\x01\0\x01\0", "image/gif"),
+        (b"%PDF-1.7\n", "application/pdf"),
+        (b"\x1f\x8b\x08\0", "application/gzip"),
+        (b"PK\x03\x04\x14\0", "application/zip"),
+        (b"\x7fELF\x02\x01\x01", "application/octet-stream"),
+        (b"\0\x01\x02\xfe\xff random", "application/octet-stream"),
+        (b"FROM rust:1\nRUN cargo build\n", "text/plain"),
+        ("Licencia año 2024\n".as_bytes(), "text/plain"),
+    ];
+    for (content, expected) in cases.iter() {
+        assert_eq!(content_type(&config, "assets/3f9a1c", content), *expected);
+    }
+    // Text cut in the middle of a character at the sample limit is still text
+    let mut long = vec![b'a'; SNIFF_LEN - 1];
+    long.extend("ñ".as_bytes());
+    assert_eq!(content_type(&config, "README", &long), "text/plain");
+
+    // A known extension always wins over the content
+    assert_eq!(content_type(&config, "a.css", b"%PDF-1.7"), "text/css");
+    let off = Config::builder().mime_sniffing(false).build().unwrap();
+    assert_eq!(
+        content_type(&off, "logo", b"\x89PNG\r\n\x1a\n"),
+        "text/plain"
+    );
+}
+
+#[test]
+fn test_payload_only() {
+    let config =
+        Config::new_payload("<h1>hi</h1>".to_string(), None, &Overrides::default()).unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let response = handle_request(test_request("/", ""), &config, &cache, &logger);
+    assert_eq!(response.content, b"<h1>hi</h1>");
+    assert_eq!(&response.headers["Content-Type"], "text/html");
+    // Files next to the binary are never served
+    let response = handle_request(test_request("/readme.md", ""), &config, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+
+    let config = Config::new_payload(
+        "{}".to_string(),
+        Some("text/plain".to_string()),
+        &Overrides::default(),
+    )
+    .unwrap();
+    let response = handle_request(test_request("/", ""), &config, &cache, &logger);
+    assert_eq!(&response.headers["Content-Type"], "text/plain");
+    let config = Config::new_payload("[1]".to_string(), None, &Overrides::default()).unwrap();
+    let response = handle_request(test_request("/", ""), &config, &cache, &logger);
+    assert_eq!(&response.headers["Content-Type"], "application/json");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unreadable_file_is_forbidden() {
+    use std::os::unix::fs::PermissionsExt;
+    let root = std::env::temp_dir().join(format!("hteapot-perm-{}", std::process::id()));
+    fs::create_dir_all(root.join("dir/index")).unwrap();
+    let secret = root.join("secret.html");
+    fs::write(&secret, "secret").unwrap();
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+    let config = Config::builder()
+        .root(root.to_str().unwrap().to_string())
+        .index("index".to_string())
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let status = |path: &str| {
+        let response = handle_request(test_request(path, ""), &config, &cache, &logger);
+        response.status as u16
+    };
+
+    // Root reads anything, the 403 case only shows up for other users
+    if fs::read(&secret).is_err() {
+        assert_eq!(status("/secret.html"), 403);
+    }
+    assert_eq!(status("/nothing.html"), 404);
+    // The index is itself a directory, reading it fails with something else
+    assert_eq!(status("/dir"), 500);
+    let error = (io::ErrorKind::PermissionDenied, "denied".to_string());
+    let req = test_request("/x", "");
+    assert_eq!(file_error(&req, "/x", &error, &logger).status as u16, 403);
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_json_error_pages() {
+    let config = Config::new_default();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let missing = |accept: &str| {
+        let req = test_request("/missing.html", accept);
+        handle_request(req, &config, &cache, &logger)
+    };
+
+    let response = missing("Accept: application/json\r\n");
+    assert_eq!(response.status as u16, 404);
+    assert_eq!(&response.headers["Content-Type"], "application/json");
+    assert_eq!(&response.headers["Vary"], "Accept");
+    assert_eq!(response.body(), b"{\"status\":404,\"error\":\"Not found\"}");
+    for accept in [
+        "",
+        "Accept: */*\r\n",
+        "Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n",
+        "Accept: application/json;q=0.5, text/*\r\n",
+    ] {
+        let response = missing(accept);
+        assert_eq!(response.body(), b"Not found", "{}", accept);
+        assert!(!response.headers.contains_key("Content-Type"));
+        assert_eq!(&response.headers["Vary"], "Accept");
+    }
+}
+
+#[test]
+fn test_acme_challenge_precedence() {
+    let challenge_dir = std::env::temp_dir().join(format!("hteapot-acme-{}", std::process::id()));
+    fs::create_dir_all(&challenge_dir).unwrap();
+    fs::write(challenge_dir.join("token-1"), "token-1.key").unwrap();
+    let mut config = Config::new_default();
+    config.acme_challenge_dir = Some(challenge_dir.to_str().unwrap().to_string());
+    config.redirect_to_https = true;
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let req = test_request("/.well-known/acme-challenge/token-1", "");
+    let response = handle_request(req, &config, &cache, &logger);
+    assert_eq!(response.status as u16, 200);
+    assert_eq!(response.content, b"token-1.key");
+
+    let req = test_request("/.well-known/acme-challenge/..%2Fsecret", "");
+    let response = handle_request(req, &config, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+
+    let req = test_request("/index.html", "");
+    let response = handle_request(req, &config, &cache, &logger);
+    assert_eq!(response.status as u16, 301);
+    assert_eq!(
+        response.headers.get("Location").unwrap(),
+        "https://localhost/index.html"
+    );
+    fs::remove_dir_all(challenge_dir).unwrap();
+}
+
+#[test]
+fn test_route_test_endpoint() {
+    let config = Config::builder()
+        .route_test_path("/_route".to_string())
+        .canonical_host("example.com".to_string())
+        .proxy_rule("/app", ProxyRule::from_list("http://a, http://b"))
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let route = |form: &str| {
+        let raw = format!("POST /_route HTTP/1.1\r\nHost: example.com\r\n\r\n{}", form);
+        let req = Hteapot::request_parser(raw).unwrap();
+        let response = handle_request(req, &config, &cache, &logger);
+        (
+            response.status as u16,
+            String::from_utf8(response.content).unwrap(),
+        )
+    };
+
+    // Nothing is fetched, both upstreams are only listed
+    let (status, trace) = route("host=example.com&path=%2Fapp%2F.%2Fx%3Fy%3D1");
+    assert_eq!(status, 200);
+    assert!(trace.contains("path: /app/x\n"), "{}", trace);
+    assert!(trace.contains("[proxy] /app\n"), "{}", trace);
+    assert!(
+        trace.contains("upstream: http://a/x\nupstream: http://b/x\n"),
+        "{}",
+        trace
+    );
+
+    let (_, trace) = route("host=www.example.com&path=/docs");
+    assert!(
+        trace.contains("canonical_host: 301 http://example.com/docs"),
+        "{}",
+        trace
+    );
+    let (_, trace) = route("method=OPTIONS&host=example.com&path=/");
+    assert!(trace.contains("OPTIONS: answered locally"), "{}", trace);
+    let (_, trace) = route("host=example.com&path=/missing.html");
+    assert!(trace.contains("file: nothing under ./, 404"), "{}", trace);
+
+    assert_eq!(route("host=example.com").0, 400);
+    assert_eq!(route("path=/&header=X-Evil%0D%0AHost: b").0, 400);
+    assert_eq!(route("path=/&header=NoColon").0, 400);
+
+    // The same as JSON, through the same routing as a real request
+    let raw =
+        "POST /_route HTTP/1.1\r\nHost: example.com\r\nContent-Type: application/json\r\n\r\n\
+               {\"host\":\"example.com\",\"path\":\"/app/x\",\"headers\":[\"Cookie: a=1\"]}";
+    let req = Hteapot::request_parser(raw.to_string()).unwrap();
+    let response = handle_request(req, &config, &cache, &logger);
+    let trace = String::from_utf8(response.content).unwrap();
+    assert!(
+        trace.contains("[proxy] /app\nupstream: http://a/x\n"),
+        "{}",
+        trace
+    );
+    let raw =
+        "POST /_route HTTP/1.1\r\nHost: example.com\r\nContent-Type: application/json\r\n\r\n{}";
+    let req = Hteapot::request_parser(raw.to_string()).unwrap();
+    assert_eq!(
+        handle_request(req, &config, &cache, &logger).status as u16,
+        400
+    );
+}
+
+#[test]
+fn test_server_timing() {
+    let root = std::env::temp_dir().join(format!("hteapot-timing-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("a.txt"), "a").unwrap();
+    let mut config = Config::builder()
+        .root(root.to_str().unwrap().to_string())
+        .cache(true)
+        .server_timing(true)
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(60));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let timing = |config: &Config, headers: &str| {
+        let response = handle_request(test_request("/a.txt", headers), config, &cache, &logger);
+        response.headers.get("Server-Timing").map(String::from)
+    };
+    let phases = |value: String| -> Vec<String> {
+        value
+            .split(", ")
+            .map(|phase| {
+                let (name, dur) = phase.split_once(";dur=").unwrap();
+                assert!(dur.parse::<f64>().is_ok(), "{}", phase);
+                name.to_string()
+            })
+            .collect()
+    };
+
+    // Read from disk first, from the cache after
+    assert_eq!(
+        phases(timing(&config, "").unwrap()),
+        ["cache", "fs", "total"]
+    );
+    assert_eq!(phases(timing(&config, "").unwrap()), ["cache", "total"]);
+    // The same origin, then another one
+    assert!(timing(&config, "Origin: http://localhost\r\n").is_some());
+    assert_eq!(timing(&config, "Origin: https://other.example\r\n"), None);
+    config.timing_allow_origin = Some("https://other.example".to_string());
+    assert!(timing(&config, "Origin: https://other.example\r\n").is_some());
+
+    config.server_timing = false;
+    assert_eq!(timing(&config, ""), None);
+
+    // Proxied responses get it in their raw head
+    let upstream = ::brew::canned_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let config = Config::builder()
+        .server_timing(true)
+        .proxy_rule(
+            "/app",
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", upstream)),
+        )
+        .build()
+        .unwrap();
+    let response = handle_request(test_request("/app/x", ""), &config, &cache, &logger);
+    let raw = String::from_utf8(response.to_bytes()).unwrap();
+    assert!(raw.contains("\r\nServer-Timing: proxy;dur="), "{}", raw);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_version_endpoint() {
+    let mut config = Config::new_default();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let response = handle_request(test_request("/_version", ""), &config, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+
+    config.version_path = Some("/_version".to_string());
+    let response = handle_request(test_request("/_version", ""), &config, &cache, &logger);
+    assert_eq!(response.status as u16, 200);
+    assert_eq!(&response.headers["Content-Type"], "application/json");
+    let body = String::from_utf8(response.content).unwrap();
+    assert!(body.contains(&format!("\"version\":\"{}\"", build_info::VERSION)));
+    assert!(body.contains(&format!("\"threads\":{}", config.threads)));
+}
+
+#[test]
+fn test_admin_port() {
+    use std::io::Write;
+    let admin_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::new_default();
+    config.version_path = Some("/_version".to_string());
+    config.admin_port = Some(admin_port);
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let response = handle_request(test_request("/_version", ""), &config, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+
+    spawn_admin(
+        "127.0.0.1",
+        admin_port,
+        Arc::new(SharedState::new(config)),
+        Arc::new(cache),
+        Arc::new(Recorder::new(None, 1.0, vec![], vec![])),
+    );
+    std::thread::sleep(Duration::from_millis(100));
+    let get = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", admin_port)).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = get("/_version");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains(build_info::VERSION));
+    assert!(get("/index.html").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn test_admin_api() {
+    use std::io::Write;
+    let admin_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let root = std::env::temp_dir().join(format!("hteapot-admin-api-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("a.txt"), "old").unwrap();
+    let config = Config::builder()
+        .root(root.to_str().unwrap().to_string())
+        .cache(true)
+        .admin_port(admin_port)
+        .admin_api_path("/_admin".to_string())
+        .auth(
+            "/_admin",
+            AuthRule {
+                realm: "admin".to_string(),
+                backend: Arc::new(hteapot::TokenList::new(vec!["s3cret".to_string()])),
+            },
+        )
+        .build()
+        .unwrap();
+    let cache = Arc::new(Mutex::new(Cache::new(60)));
+    let state = Arc::new(SharedState::new(config));
+    let snapshot = state.snapshot();
+    let config = &snapshot.config;
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let body = || {
+        let response = handle_request(test_request("/a.txt", ""), config, &cache, &logger);
+        String::from_utf8(response.body().to_vec()).unwrap()
+    };
+
+    // Only on the admin listener
+    let response = handle_request(test_request("/_admin/cache", ""), config, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+
+    let recorder = Arc::new(Recorder::new(None, 1.0, vec![], vec![]));
+    spawn_admin(
+        "127.0.0.1",
+        admin_port,
+        state.clone(),
+        cache.clone(),
+        recorder.clone(),
+    );
+    std::thread::sleep(Duration::from_millis(100));
+    let send = |method: &str, path: &str, token: &str, json: &str| {
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", admin_port)).unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            token,
+            json.len(),
+            json
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let post = |path: &str, json: &str| send("POST", path, "s3cret", json);
+
+    assert!(
+        send("POST", "/_admin/cache", "wrong", "{\"enabled\":false}").starts_with("HTTP/1.1 401")
+    );
+    assert!(send("GET", "/_admin/cache", "s3cret", "").starts_with("HTTP/1.1 405"));
+    assert!(post("/_admin/missing", "{}").starts_with("HTTP/1.1 404"));
+    assert!(post("/_admin/log-level", "{\"level\":\"loud\"}").starts_with("HTTP/1.1 400"));
+
+    // Cached while enabled, read from disk again once the cache is off and purged
+    assert_eq!(body(), "old");
+    fs::write(root.join("a.txt"), "new").unwrap();
+    assert_eq!(body(), "old");
+    let response = post("/_admin/cache", "{\"enabled\": false, \"purge\": true}");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("{\"previous\":true,\"current\":false,\"purged\":1}"));
+    assert_eq!(body(), "new");
+    assert!(cache.lock().unwrap().get("/a.txt".to_string()).is_none());
+    let response = post("/_admin/cache", "{\"enabled\":true}");
+    assert!(response.ends_with("{\"previous\":false,\"current\":true,\"purged\":0}"));
+    assert_eq!(body(), "new");
+    assert!(cache.lock().unwrap().get("/a.txt".to_string()).is_some());
+
+    let response = post(
+        "/_admin/log-level",
+        "{\"level\":\"debug\",\"component\":\"body\"}",
+    );
+    assert!(response.ends_with("{\"previous\":\"info\",\"current\":\"debug\"}"));
+    assert_eq!(logger::component_level("body"), Level::Debug);
+    let response = post(
+        "/_admin/log-level",
+        "{\"level\":null,\"component\":\"body\"}",
+    );
+    assert!(response.ends_with("{\"previous\":\"debug\",\"current\":\"info\"}"));
+    assert!(post("/_admin/log-level", "{\"level\":\"info\"}").ends_with("\"current\":\"info\"}"));
+
+    assert!(post("/_admin/drain", "{\"draining\":true}")
+        .ends_with("{\"previous\":false,\"current\":true}"));
+    assert!(state.draining());
+    let response = post("/_admin/record", "{\"enabled\":true}");
+    assert!(response.starts_with("HTTP/1.1 400") && response.contains("record_dir is not set"));
+    assert!(post("/_admin/record", "{\"enabled\":false}")
+        .ends_with("{\"previous\":false,\"current\":false}"));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_normalized_paths_route() {
+    let mut config = Config::new_default();
+    config.proxy_rules.insert(
+        "/api".to_string(),
+        ProxyRule::from_list("http://127.0.0.1:1"),
+    );
+    let cases = [
+        ("//api/users", Some("/api")),
+        ("/static/../api/users", Some("/api")),
+        ("/./api", Some("/api")),
+        ("/static//api", None),
+    ];
+    for (raw, rule) in cases.iter() {
+        let req = test_request(raw, "");
+        assert_eq!(
+            is_proxy(&config, &req.path).map(|(p, _)| p),
+            *rule,
+            "{}",
+            raw
+        );
+    }
+}
+
+#[test]
+fn test_options_for_local_paths() {
+    let mut config = Config::new_default();
+    config.proxy_rules.insert(
+        "/api".to_string(),
+        ProxyRule::from_list("http://127.0.0.1:1"),
+    );
+    config.redirect_to_https = true;
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let options = |path: &str, headers: &str, config: &Config| {
+        let raw = format!(
+            "OPTIONS {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            path, headers
+        );
+        let req = Hteapot::request_parser(raw).unwrap();
+        handle_request(req, config, &cache, &logger)
+    };
+
+    // A static path with no proxy rule is answered here, not proxied or redirected
+    let response = options("/index.html", "", &config);
+    assert_eq!(response.status as u16, 204);
+    assert_eq!(&response.headers["Allow"], "GET, HEAD, OPTIONS");
+    assert!(response.content.is_empty());
+    assert!(!response.headers.contains_key("Access-Control-Allow-Origin"));
+
+    let preflight = "Origin: https://app.example\r\nAccess-Control-Request-Method: GET\r\nAccess-Control-Request-Headers: X-Token\r\n";
+    config.cors_origin = Some("https://app.example".to_string());
+    let response = options("/index.html", preflight, &config);
+    assert_eq!(response.status as u16, 204);
+    assert_eq!(
+        &response.headers["Access-Control-Allow-Origin"],
+        "https://app.example"
+    );
+    assert_eq!(&response.headers["Access-Control-Allow-Headers"], "X-Token");
+
+    // Proxied paths still go upstream, here a dead one
+    let response = options("/api/users", preflight, &config);
+    assert_ne!(response.status as u16, 204);
+}
+
+#[test]
+fn test_extra_methods_routing() {
+    let (port, hits) = flaky_upstream(0);
+    // Only allow_methods_extra gets them past the engine
+    let config = Config::builder()
+        .proxy_rule(
+            "/dav",
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", port)),
+        )
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let request = |method: &str, path: &str| {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        let req = Hteapot::request_parser(raw).unwrap();
+        handle_request(req, &config, &cache, &logger)
+    };
+
+    // Neither files nor proxies serve a method they don't declare
+    let response = request("PROPFIND", "/index.html");
+    assert_eq!(response.status as u16, 405);
+    assert_eq!(&response.headers["Allow"], LOCAL_METHODS);
+    assert_eq!(request("PROPFIND", "/dav/file").status as u16, 405);
+    assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+    // Standard ones too for files, proxies still forward them
+    for method in ["POST", "PUT", "DELETE"] {
+        let response = request(method, "/index.html");
+        assert_eq!(response.status as u16, 405, "{}", method);
+        assert_eq!(&response.headers["Allow"], LOCAL_METHODS);
+    }
+    assert_ne!(request("HEAD", "/index.html").status as u16, 405);
+    assert_ne!(request("POST", "/dav/file").status as u16, 405);
+    assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_https_redirect() {
+    let mut config = Config::new_default();
+    config.redirect_to_https = true;
+    config.https_port = 8443;
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let request = "GET /a/b?x=1&y=2 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n";
+    let req = Hteapot::request_parser(request.to_string()).unwrap();
+    let response = handle_request(req, &config, &cache, &logger);
+    assert_eq!(response.status as u16, 301);
+    assert_eq!(
+        response.headers.get("Location").unwrap(),
+        "https://example.com:8443/a/b?x=1&y=2"
+    );
+
+    let request = "GET / HTTP/1.1\r\n\r\n";
+    let req = Hteapot::request_parser(request.to_string()).unwrap();
+    let response = handle_request(req, &config, &cache, &logger);
+    assert_eq!(response.status as u16, 400);
+}
+
+#[test]
+fn test_canonical_host_redirect() {
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let redirect = |config: &Config, host: &str, path: &str| {
+        let headers = format!("Host: {}\r\n", host);
+        let request = format!("GET {} HTTP/1.1\r\n{}\r\n", path, headers);
+        let req = Hteapot::request_parser(request).unwrap();
+        let response = handle_request(req, config, &cache, &logger);
+        response.headers.get("Location").map(|l| l.to_string())
+    };
+
+    let apex = Config::builder()
+        .canonical_host("Example.com".to_string())
+        .build()
+        .unwrap();
+    assert_eq!(
+        redirect(&apex, "www.example.com:8080", "/a?x=1&y=2").as_deref(),
+        Some("http://example.com:8080/a?x=1&y=2")
+    );
+    assert_eq!(
+        redirect(&apex, "EXAMPLE.com", "/").as_deref(),
+        Some("http://example.com/")
+    );
+    assert_eq!(redirect(&apex, "example.com:8080", "/"), None);
+
+    let mut www = Config::builder()
+        .canonical_host("www.example.com".to_string())
+        .redirect_to_https(true)
+        .build()
+        .unwrap();
+    assert_eq!(
+        redirect(&www, "example.com", "/b?q").as_deref(),
+        Some("https://www.example.com/b?q")
+    );
+    www.acme_challenge_dir = Some("/nonexistent".to_string());
+    assert_eq!(
+        redirect(&www, "example.com", "/.well-known/acme-challenge/t"),
+        None
+    );
+    let req = Hteapot::request_parser("GET / HTTP/1.1\r\n\r\n".to_string()).unwrap();
+    let response = handle_request(req, &www, &cache, &logger);
+    assert_eq!(response.status as u16, 400);
+}
+
+#[test]
+fn test_reload_keeps_in_flight_snapshot() {
+    // Upstream that answers after a delay
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            use std::io::{Read, Write};
+            let mut stream = stream.unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            std::thread::sleep(Duration::from_millis(300));
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow");
+        }
+    });
+    let config = Config::builder()
+        .proxy_rule(
+            "/slow",
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", port)),
+        )
+        .root("public".to_string())
+        .cache(true)
+        .build()
+        .unwrap();
+    let state = Arc::new(SharedState::new(config));
+    let cache = Arc::new(Mutex::new(Cache::new(3600)));
+    let logger = Arc::new(Mutex::new(Logger::new(io::stdout())));
+
+    let in_flight = {
+        let (state, cache, logger) = (state.clone(), cache.clone(), logger.clone());
+        std::thread::spawn(move || {
+            let snapshot = state.snapshot();
+            handle_request(test_request("/slow", ""), &snapshot.config, &cache, &logger)
+        })
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    cache
+        .lock()
+        .unwrap()
+        .set("/x".to_string(), b"cached".to_vec());
+    let reloaded = Config::builder()
+        .root("public".to_string())
+        .cache(true)
+        .build()
+        .unwrap();
+    assert_eq!(state.reload(reloaded, &cache), 1);
+    // Same root, cached files are still valid
+    assert!(cache.lock().unwrap().get("/x".to_string()).is_some());
+
+    let response = in_flight.join().unwrap();
+    assert!(response.to_bytes().ends_with(b"slow"));
+    let snapshot = state.snapshot();
+    assert_eq!(snapshot.generation, 1);
+    let response = handle_request(test_request("/slow", ""), &snapshot.config, &cache, &logger);
+    assert_eq!(response.status as u16, 404);
+
+    let moved = Config::builder().root("other".to_string()).build().unwrap();
+    state.reload(moved, &cache);
+    assert!(cache.lock().unwrap().get("/x".to_string()).is_none());
+}
+
+#[test]
+fn test_reload_verifies_upstreams() {
+    let (live, _) = flaky_upstream(0);
+    let dead = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let dir = std::env::temp_dir().join(format!("hteapot-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hteapot.toml");
+    let path = path.to_str().unwrap();
+    let write = |upstream: &str, verify: bool| {
+        let toml = format!(
+            "[HTEAPOT]\nhost = \"127.0.0.1\"\nreload_verify_upstreams = {}\n[proxy]\n\"/api\" = \"{}\"\n",
+            verify, upstream
+        );
+        fs::write(path, toml).unwrap();
+    };
+    write(&format!("http://127.0.0.1:{}", live), false);
+    let state = SharedState::new(Config::load_config(path, &Overrides::default()).unwrap());
+    let cache = Mutex::new(Cache::new(60));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let recorder = Recorder::new(None, 1.0, vec![], vec![]);
+    let controls = admin_api::Controls {
+        cache: &cache,
+        state: &state,
+        logger: &logger,
+        recorder: &recorder,
+    };
+    let send = |method: &str| {
+        let raw = format!(
+            "{} /_admin/reload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{{}}",
+            method
+        );
+        let req = Hteapot::request_parser(raw).unwrap();
+        let response = admin_api::serve("/_admin", &req, &controls).unwrap();
+        (
+            response.status as u16,
+            String::from_utf8(response.content).unwrap(),
+        )
+    };
+    let upstream = || state.snapshot().config.proxy_rules["/api"].upstreams[0].clone();
+    assert_eq!(
+        send("GET"),
+        (
+            200,
+            "{\"ok\":null,\"at\":null,\"generation\":0,\"errors\":[]}".to_string()
+        )
+    );
+
+    // A typo is refused and the running rules stay
+    write("http//127.0.0.1:3000", false);
+    assert_eq!(send("POST").0, 400);
+    assert_eq!(upstream(), format!("http://127.0.0.1:{}", live));
+    let (_, status) = send("GET");
+    assert!(status.starts_with("{\"ok\":false,"), "{}", status);
+    assert!(status.contains("\"generation\":0,\"errors\":[\"proxy /api -> http//127.0.0.1:3000: "));
+
+    // Well formed but down, only refused when upstreams are verified
+    write(&format!("http://127.0.0.1:{}", dead), true);
+    assert_eq!(send("POST").0, 400);
+    assert!(send("GET").1.contains("Cannot connect to 127.0.0.1:"));
+    write(&format!("http://127.0.0.1:{}", live), true);
+    assert_eq!(send("POST").0, 200);
+    assert_eq!(state.snapshot().generation, 1);
+    assert!(send("GET").1.starts_with("{\"ok\":true,"));
+
+    // A file that can't be read anymore is refused too, not taken as the defaults
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(send("POST").0, 400);
+    assert_eq!(upstream(), format!("http://127.0.0.1:{}", live));
+}
+
+// Upstream stub answering every request with its name after a delay, counting the hits
+#[cfg(test)]
+fn upstream_stub(
+    name: &'static str,
+    delay: Duration,
+) -> (u16, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let hits_clone = hits.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer);
+            hits_clone.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(delay);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                name.len(),
+                name
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    (port, hits)
+}
+
+#[test]
+fn test_coalesced_upstream_fetches() {
+    use std::sync::atomic::Ordering;
+    let (port, hits) = upstream_stub("hot", Duration::from_millis(300));
+    let config = Config::builder()
+        .cache(true)
+        .proxy_rule(
+            "/hot",
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", port)),
+        )
+        .build()
+        .unwrap();
+    let shared = Arc::new((config, Mutex::new(Cache::new(0))));
+    let logger = Arc::new(Mutex::new(Logger::new(io::stdout())));
+    let requests: Vec<_> = (0..50)
+        .map(|_| {
+            let (shared, logger) = (shared.clone(), logger.clone());
+            std::thread::spawn(move || {
+                let (config, cache) = &*shared;
+                let response =
+                    handle_request(test_request("/hot/page", ""), config, cache, &logger);
+                response.to_bytes()
+            })
+        })
+        .collect();
+    for request in requests {
+        assert!(request.join().unwrap().ends_with(b"hot"));
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_bad_upstream_headers() {
+    let port = brew::canned_upstream(b"HTTP/1.1 200 OK\r\nX-A: 1\nX-B: 2\r\n\r\nbody");
+    let mut config = Config::new_default();
+    let rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    config.proxy_rules.insert("/app".to_string(), rule);
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let response = handle_request(test_request("/app/x", ""), &config, &cache, &logger);
+    assert_eq!(response.status as u16, 502);
+    assert!(!response.is_raw());
+}
+
+#[test]
+fn test_idempotency_keys() {
+    use std::sync::atomic::Ordering;
+    let (port, hits) = upstream_stub("order", Duration::from_millis(200));
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.idempotency_window = Some(Duration::from_secs(1));
+    let config = Config::builder()
+        .proxy_rule("/orders", rule)
+        .build()
+        .unwrap();
+    let shared = Arc::new((config, Mutex::new(Cache::new(0))));
+    let logger = Arc::new(Mutex::new(Logger::new(io::stdout())));
+    let post_from = move |key: &str, client: &str| {
+        let (shared, logger) = (shared.clone(), logger.clone());
+        let raw = format!(
+            "POST /orders HTTP/1.1\r\nHost: localhost\r\nIdempotency-Key: {}\r\n\r\n",
+            key
+        );
+        let client = client.parse().unwrap();
+        std::thread::spawn(move || {
+            let (config, cache) = &*shared;
+            let mut req = Hteapot::request_parser(raw).unwrap();
+            req.remote_addr = Some(client);
+            let response = handle_request(req, config, cache, &logger).to_bytes();
+            String::from_utf8(response).unwrap()
+        })
+    };
+    let post = |key: &str| post_from(key, "127.0.0.1:40000");
+
+    // Concurrent duplicates wait for the first one
+    let first: Vec<_> = (0..5).map(|_| post("a")).collect();
+    let responses: Vec<String> = first.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert!(responses.iter().all(|r| r.ends_with("order")));
+    let replays = responses
+        .iter()
+        .filter(|r| r.contains("Idempotent-Replay: true"))
+        .count();
+    assert_eq!(replays, 4);
+
+    // A later retry is replayed, another key goes upstream
+    assert!(post("a")
+        .join()
+        .unwrap()
+        .contains("Idempotent-Replay: true"));
+    assert!(!post("b").join().unwrap().contains("Idempotent-Replay"));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    // The same key from another client is that client's own request
+    let other = post_from("a", "192.0.2.9:40000").join().unwrap();
+    assert!(!other.contains("Idempotent-Replay"), "{}", other);
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+    // Once the window is over the request is forwarded again
+    std::thread::sleep(Duration::from_millis(1100));
+    assert!(!post("a").join().unwrap().contains("Idempotent-Replay"));
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_client_identity() {
+    let mut req = test_request("/", "");
+    assert_eq!(client_identity(&req), "unknown");
+    req.remote_addr = Some("203.0.113.7:51234".parse().unwrap());
+    assert_eq!(client_identity(&req), "ip:203.0.113.7");
+    // Credentials win over the address, and never show up in the key as they are
+    let mut alice = test_request("/", "Authorization: Basic YWxpY2U6eA==\r\n");
+    alice.remote_addr = req.remote_addr;
+    let bob = test_request("/", "Authorization: Basic Ym9iOng=\r\n");
+    assert!(client_identity(&alice).starts_with("auth:"));
+    assert!(!client_identity(&alice).contains("YWxpY2U6eA"));
+    assert_ne!(client_identity(&alice), client_identity(&bob));
+}
+
+#[test]
+fn test_keep_alive_per_rule() {
+    let (port, _) = upstream_stub("events", Duration::ZERO);
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.keep_alive = false;
+    let mut config = Config::builder().proxy_rule("/poll", rule).build().unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let request = |path: &str, config: &Config| {
+        let req = test_request(path, "Connection: keep-alive\r\n");
+        handle_request(req, config, &cache, &logger)
+    };
+
+    let response = request("/poll/events", &config);
+    assert!(response.is_raw());
+    assert!(response.closes_connection());
+    assert!(!request("/index.html", &config).closes_connection());
+
+    config.keep_alive = false;
+    assert!(request("/index.html", &config).closes_connection());
+}
+
+// Upstream answering 503 to its first failures requests and 200 after, counting the hits
+#[cfg(test)]
+fn flaky_upstream(failures: usize) -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let hits = Arc::new(AtomicUsize::new(0));
+    let hits_clone = hits.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer);
+            let hit = hits_clone.fetch_add(1, Ordering::SeqCst);
+            let status = if hit < failures {
+                "503 Service Unavailable"
+            } else {
+                "200 OK"
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                status
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    (port, hits)
+}
+
+#[test]
+fn test_dev_router() {
+    use std::io::Write;
+    // Answers with its name and the path it was asked for
+    let backend = |name: &'static str| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0; 1024];
+                let n = stream.read(&mut buffer).unwrap_or(0);
+                let head = String::from_utf8_lossy(&buffer[..n]).to_string();
+                let path = head.split(' ').nth(1).unwrap_or_default();
+                let body = format!("{} {}", name, path);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port
+    };
+    let config = Config::builder()
+        .dev_route("app", backend("app"))
+        .dev_route("API.localhost", backend("api"))
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let get = |host: &str, path: &str| {
+        let req = test_request(path, &format!("Host: {}\r\n", host));
+        let response = handle_request(req, &config, &cache, &logger);
+        String::from_utf8_lossy(&response.to_bytes()).to_string()
+    };
+
+    assert!(get("app.localhost:8081", "/a/b").ends_with("app /a/b"));
+    assert!(get("api.localhost", "/v1/users").ends_with("api /v1/users"));
+    assert!(get("App.Localhost", "/").ends_with("app /"));
+    // Other hosts get the files as usual
+    assert!(!get("other.localhost", "/").contains("app /"));
+}
+
+#[test]
+fn test_proxy_rewrite_body_urls() {
+    use std::io::Write;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let origin = format!("http://127.0.0.1:{}", port);
+    let origin_clone = origin.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer).unwrap_or(0);
+            let head = String::from_utf8_lossy(&buffer[..n]).to_string();
+            let o = &origin_clone;
+            let (headers, body) = match head.split(' ').nth(1).unwrap_or_default() {
+                "/page" => (
+                    "Content-Type: text/html; charset=utf-8",
+                    format!(
+                        "<a href=\"{o}/docs\">{o}/docs</a><div style=\"background: url({o}/bg.png)\"></div>",
+                        o = o
+                    ),
+                ),
+                "/site.css" => ("Content-Type: text/css", format!("body {{ background: url('{}/bg.png') }}", o)),
+                "/data" => ("Content-Type: application/json", format!("{{\"next\": \"{}/data\"}}", o)),
+                _ => ("Content-Type: text/html\r\nContent-Encoding: gzip", format!("<a href=\"{}/x\">", o)),
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                headers,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let config_for = |rewrite: bool| {
+        let mut rule = ProxyRule::from_list(&origin);
+        rule.rewrite_body_urls = rewrite;
+        Config::builder().proxy_rule("/app", rule).build().unwrap()
+    };
+    let get = |config: &Config, path: &str| {
+        let response = handle_request(test_request(path, ""), config, &cache, &logger);
+        let raw = String::from_utf8_lossy(&response.to_bytes()).to_string();
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        assert!(
+            head.contains(&format!("Content-Length: {}\r\n", body.len())),
+            "{}",
+            head
+        );
+        body.to_string()
+    };
+
+    let config = config_for(true);
+    // Attributes, inline css and text
+    assert_eq!(
+        get(&config, "/app/page"),
+        "<a href=\"/app/docs\">/app/docs</a><div style=\"background: url(/app/bg.png)\"></div>"
+    );
+    assert_eq!(
+        get(&config, "/app/site.css"),
+        "body { background: url('/app/bg.png') }"
+    );
+    // Other types and compressed bodies are left alone
+    assert!(get(&config, "/app/data").contains(&origin));
+    assert!(get(&config, "/app/packed").contains(&origin));
+    // Only when the rule asks for it
+    assert!(get(&config_for(false), "/app/page").contains(&origin));
+}
+
+#[test]
+fn test_proxy_retries() {
+    use std::sync::atomic::Ordering;
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let config_for = |rule: ProxyRule| Config::builder().proxy_rule("/app", rule).build().unwrap();
+    let status = |config: &Config, method: &str, headers: &str| {
+        let raw = format!(
+            "{} /app/x HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            method, headers
+        );
+        let req = Hteapot::request_parser(raw).unwrap();
+        let response = handle_request(req, config, &cache, &logger);
+        raw_status(&response.to_bytes())
+    };
+
+    // The first attempt fails and the second one succeeds
+    let (port, hits) = flaky_upstream(1);
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.retries = 2;
+    let config = config_for(rule);
+    assert_eq!(status(&config, "GET", ""), Some(200));
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // Not idempotent, unless it carries a key
+    let (port, hits) = flaky_upstream(2);
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.retries = 1;
+    let config = config_for(rule);
+    assert_eq!(status(&config, "POST", ""), Some(503));
+    assert_eq!(status(&config, "POST", "Idempotency-Key: 1\r\n"), Some(200));
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+    // A refused connection moves on to the other upstream
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_port = dead.local_addr().unwrap().port();
+    drop(dead);
+    let (port, _) = flaky_upstream(0);
+    let mut rule = ProxyRule::from_list(&format!(
+        "http://127.0.0.1:{}, http://127.0.0.1:{}",
+        dead_port, port
+    ));
+    rule.retries = 1;
+    let config = config_for(rule);
+    for _ in 0..4 {
+        assert_eq!(status(&config, "GET", ""), Some(200));
+    }
+
+    // A dead upstream gets the burst of retries and then only the requests themselves
+    let (port, hits) = flaky_upstream(usize::MAX);
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.retries = 3;
+    rule.retry_ratio = 0.0;
+    let config = config_for(rule);
+    for _ in 0..20 {
+        assert_eq!(status(&config, "GET", ""), Some(503));
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 20 + 10);
+}
+
+#[test]
+fn test_upstream_metrics() {
+    let (port, _) = flaky_upstream(1);
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead_port = dead.local_addr().unwrap().port();
+    drop(dead);
+    let config = Config::builder()
+        .metrics_path("/_metrics".to_string())
+        .proxy_rule(
+            "/app",
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", port)),
+        )
+        .proxy_rule(
+            "/gone",
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", dead_port)),
+        )
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    for path in ["/app/a", "/app/b", "/app/c", "/gone/a"] {
+        handle_request(test_request(path, ""), &config, &cache, &logger);
+    }
+
+    let response = handle_request(test_request("/_metrics", ""), &config, &cache, &logger);
+    let metrics = String::from_utf8(response.content).unwrap();
+    for line in [
+        "hteapot_upstream_requests_total{rule=\"/app\"} 3",
+        "hteapot_upstream_errors_total{rule=\"/app\",class=\"5xx\"} 1",
+        "hteapot_upstream_errors_total{rule=\"/gone\",class=\"connect\"} 1",
+        "hteapot_upstream_latency_seconds_count{rule=\"/app\"} 3",
+    ] {
+        assert!(metrics.contains(line), "{}\n{}", line, metrics);
+    }
+    let summary = config.proxy_rules["/app"].stats.snapshot().summary();
+    assert!(summary.starts_with("3 requests, 1 errors"), "{}", summary);
+}
+
+#[test]
+fn test_pool_metrics() {
+    let server = Hteapot::new("127.0.0.1", 0);
+    let stats = server.stats();
+    let handle = server
+        .run_background(|_req| HttpResponse::new(HttpStatus::OK, "Hello", None))
+        .unwrap();
+    let url = format!("http://{}/", handle.addr());
+    ::brew::fetch_with_headers(&url, &[]).unwrap();
+
+    let metrics = pool_metrics(&stats);
+    for line in [
+        "hteapot_queue_depth 0",
+        "hteapot_connections_per_ip_rejected_total 0",
+        "hteapot_connections_tracked_ips 0",
+        "hteapot_worker_connections{worker=\"0\"}",
+        "hteapot_queue_wait_seconds_bucket{le=\"+Inf\"} 1",
+        "hteapot_queue_wait_seconds_count 1",
+    ] {
+        assert!(metrics.contains(line), "{}\n{}", line, metrics);
+    }
+    handle.stop();
+}
+
+#[test]
+fn test_noise_requests() {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let config = |response: &str| {
+        Config::builder()
+            .root("public".to_string())
+            .noise_paths("/index.html, *.php".to_string())
+            .noise_response(response.to_string())
+            .metrics_path("/_metrics".to_string())
+            .build()
+            .unwrap()
+    };
+    let (close, not_found) = (Arc::new(config("close")), Arc::new(config("404")));
+    let cache = Arc::new(Mutex::new(Cache::new(0)));
+    let logger = Arc::new(Mutex::new(Logger::new(io::stdout())));
+    let start = |config: Arc<Config>| {
+        let (cache, logger) = (cache.clone(), logger.clone());
+        Hteapot::new("127.0.0.1", 0)
+            .run_background(move |req| {
+                serve_noise(&config, &req, &logger)
+                    .unwrap_or_else(|| handle_request(req, &config, &cache, &logger))
+            })
+            .unwrap()
+    };
+    let get = |addr: std::net::SocketAddr, path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    let matched = noise::matched();
+    let handle = start(close.clone());
+    // The file is there, the request never gets to it and nothing at all comes back
+    assert_eq!(get(handle.addr(), "/index.html"), "");
+    assert_eq!(get(handle.addr(), "/wp-login.PHP"), "");
+    assert!(get(handle.addr(), "/index.html?").is_empty());
+    assert!(get(handle.addr(), "/").starts_with("HTTP/1.1 200"));
+    let handle = start(not_found.clone());
+    let response = get(handle.addr(), "/index.html");
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n"));
+    assert_eq!(noise::matched() - matched, 4);
+    assert_eq!(
+        route_trace(&close, &cache, &logger, &test_request("/x.php", ""))
+            .last()
+            .unwrap(),
+        "noise_paths: closed without a response"
+    );
+
+    let metrics = serve_metrics(&close, &cache, &test_request("/_metrics", ""), &logger).unwrap();
+    let metrics = String::from_utf8(metrics.content).unwrap();
+    assert!(metrics.contains("# TYPE hteapot_noise_requests_total counter\n"));
+    let off = Config::builder()
+        .metrics_path("/_metrics".to_string())
+        .build()
+        .unwrap();
+    let metrics = serve_metrics(&off, &cache, &test_request("/_metrics", ""), &logger).unwrap();
+    assert!(!String::from_utf8(metrics.content)
+        .unwrap()
+        .contains("noise"));
+}
+
+#[test]
+fn test_memory_warning() {
+    let usage = [
+        ("cache", 2048),
+        ("read buffer", 10),
+        ("response buffer", 4096),
+    ];
+    assert_eq!(memory_warning(&usage, &[None, None, None]), None);
+    assert_eq!(
+        memory_warning(&usage, &[Some(4096), Some(0), None]),
+        Some(
+            "WARNING: Memory held: cache 2048 bytes, read buffer 10 bytes, response buffer 4096 bytes, over the read buffer warning".to_string()
+        )
+    );
+    let warning = memory_warning(&usage, &[Some(1024), None, Some(1024)]).unwrap();
+    assert!(warning.ends_with("over the cache and response buffer warning"));
+
+    // The gauges are in the metrics under their names
+    let config = Config::builder()
+        .metrics_path("/metrics".to_string())
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(60));
+    cache
+        .lock()
+        .unwrap()
+        .set("/index.html".to_string(), vec![0; 100]);
+    let _ = SERVER_STATS.set(Arc::new(ServerStats::default()));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let response = serve_metrics(&config, &cache, &test_request("/metrics", ""), &logger).unwrap();
+    let metrics = String::from_utf8(response.content).unwrap();
+    for line in [
+        "hteapot_cache_bytes 111",
+        "hteapot_read_buffer_bytes ",
+        "hteapot_response_buffer_bytes ",
+    ] {
+        assert!(metrics.contains(line), "{}\n{}", line, metrics);
+    }
+}
+
+// Log writer whose disk is always full
+#[cfg(test)]
+struct FullDisk;
+
+#[cfg(test)]
+impl io::Write for FullDisk {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("No space left on device"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_log_health_metrics() {
+    let config = Config::builder()
+        .metrics_path("/metrics".to_string())
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(60));
+    let logger = Mutex::new(Logger::new(FullDisk));
+    let metrics = |logger: &Mutex<Logger<FullDisk>>| {
+        let response = serve_metrics(&config, &cache, &test_request("/metrics", ""), logger);
+        String::from_utf8(response.unwrap().content).unwrap()
+    };
+    assert!(metrics(&logger).contains("\nhteapot_log_write_failures 0\n"));
+
+    // Below the failures that also go to stderr
+    logger.lock().unwrap().msg("lost".to_string());
+    logger.lock().unwrap().msg("lost".to_string());
+    let metrics = metrics(&logger);
+    assert!(
+        metrics.contains("\nhteapot_log_write_failures 2\n"),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains("\nhteapot_log_dropped_messages 0\n"));
+    let health = logger.lock().unwrap().health();
+    assert!(health.last_error.unwrap().contains("No space"));
+}
+
+#[test]
+fn test_proxy_cache_policy() {
+    let bare = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+    let cached = b"HTTP/1.1 200 OK\r\ncache-control: public, max-age=60\r\nExpires: Thu, 01 Jan 2099 00:00:00 GMT\r\nContent-Length: 2\r\n\r\nok".to_vec();
+    let expires = b"HTTP/1.1 200 OK\r\nExpires: 0\r\n\r\n".to_vec();
+    let apply = |rule: &ProxyRule, raw: &[u8]| {
+        let mut raw = raw.to_vec();
+        apply_cache_policy(rule, &mut raw);
+        String::from_utf8(raw).unwrap()
+    };
+
+    let mut rule = ProxyRule::from_list("http://a");
+    assert_eq!(apply(&rule, &bare).as_bytes(), &bare[..]);
+    rule.default_cache_control = Some("max-age=300".to_string());
+    assert_eq!(
+        apply(&rule, &bare),
+        "HTTP/1.1 200 OK\r\nCache-Control: max-age=300\r\nContent-Length: 2\r\n\r\nok"
+    );
+    // Either header means the upstream made its choice
+    assert_eq!(apply(&rule, &cached).as_bytes(), &cached[..]);
+    assert_eq!(apply(&rule, &expires).as_bytes(), &expires[..]);
+
+    rule.force_no_store = true;
+    assert_eq!(
+        apply(&rule, &cached),
+        "HTTP/1.1 200 OK\r\nCache-Control: no-store\r\nContent-Length: 2\r\n\r\nok"
+    );
+    assert_eq!(
+        apply(&rule, &expires),
+        "HTTP/1.1 200 OK\r\nCache-Control: no-store\r\n\r\n"
+    );
+
+    // Through the proxy, ahead of the idempotency store
+    let upstream = ::brew::canned_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", upstream));
+    rule.default_cache_control = Some("max-age=300".to_string());
+    let config = Config::builder().proxy_rule("/app", rule).build().unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let response = handle_request(test_request("/app/x", ""), &config, &cache, &logger);
+    let raw = String::from_utf8(response.to_bytes()).unwrap();
+    assert!(
+        raw.contains("\r\nCache-Control: max-age=300\r\n"),
+        "{}",
+        raw
+    );
+}
+
+#[test]
+fn test_proxy_revalidate() {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let not_modified = Arc::new(AtomicUsize::new(0));
+    let upstream_not_modified = not_modified.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer).unwrap();
+            let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+            let response = if request.contains("If-None-Match: \"v1\"") {
+                upstream_not_modified.fetch_add(1, Ordering::SeqCst);
+                "HTTP/1.1 304 Not Modified\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\n\r\nfeed"
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.revalidate = true;
+    let config = Config::builder().proxy_rule("/feed", rule).build().unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    for _ in 0..2 {
+        let response = handle_request(test_request("/feed", ""), &config, &cache, &logger);
+        let raw = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200"), "{}", raw);
+        assert!(raw.ends_with("\r\n\r\nfeed"), "{}", raw);
+    }
+    // The second one went upstream conditionally, the client still got the body
+    assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_stale_if_error() {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let down = Arc::new(AtomicBool::new(false));
+    let upstream_down = down.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer).unwrap_or(0);
+            let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+            let response: &[u8] = if upstream_down.load(Ordering::SeqCst) {
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\ndown"
+            } else if request.contains("/private") {
+                b"HTTP/1.1 200 OK\r\nCache-Control: private\r\nContent-Length: 4\r\n\r\ngood"
+            } else if request.contains("/session") {
+                b"HTTP/1.1 200 OK\r\nSet-Cookie: id=1\r\nContent-Length: 4\r\n\r\ngood"
+            } else if request.contains("/vary") {
+                b"HTTP/1.1 200 OK\r\nVary: Accept-Language\r\nContent-Length: 4\r\n\r\ngood"
+            } else {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ngood"
+            };
+            let _ = stream.write_all(response);
+        }
+    });
+    let dead_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let fallback =
+        std::env::temp_dir().join(format!("hteapot-fallback-{}.html", std::process::id()));
+    fs::write(&fallback, "<h1>back soon</h1>").unwrap();
+
+    let mut rule = ProxyRule::from_list(&format!("http://127.0.0.1:{}", port));
+    rule.stale_if_error = Some(Duration::from_secs(2));
+    let mut dead = ProxyRule::from_list(&format!("http://127.0.0.1:{}", dead_port));
+    dead.stale_if_error = Some(Duration::from_secs(60));
+    dead.fallback_file = Some(fallback.to_str().unwrap().to_string());
+    let config = Config::builder()
+        .proxy_rule("/app", rule)
+        .proxy_rule("/dead", dead)
+        .build()
+        .unwrap();
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let get_with = |path: &str, headers: &str| {
+        let response = handle_request(test_request(path, headers), &config, &cache, &logger);
+        String::from_utf8(response.to_bytes()).unwrap()
+    };
+    let get = |path: &str| get_with(path, "");
+
+    // Primed while the upstream is up, served with a warning once it fails
+    assert!(get("/app/x").ends_with("good"));
+    assert!(get("/app/private").ends_with("good"));
+    assert!(get("/app/session").ends_with("good"));
+    assert!(get("/app/vary").ends_with("good"));
+    assert!(get_with("/app/mine", "Authorization: Basic YTpi\r\n").ends_with("good"));
+    assert!(get_with("/app/cookie", "Cookie: id=1\r\n").ends_with("good"));
+    down.store(true, Ordering::SeqCst);
+    let stale = get("/app/x");
+    assert!(stale.starts_with("HTTP/1.1 200"), "{}", stale);
+    assert!(stale.contains("\r\nWarning: 111 - \"Revalidation Failed\"\r\n"));
+    assert!(stale.ends_with("good"));
+    // Nothing kept for a path never answered, or answered as not to be stored
+    assert!(get("/app/y").ends_with("down"));
+    assert!(get("/app/private").ends_with("down"));
+    // Nor for one answered to a client in particular
+    assert!(get("/app/session").ends_with("down"));
+    assert!(get("/app/vary").ends_with("down"));
+    assert!(get("/app/mine").ends_with("down"));
+    assert!(get("/app/cookie").ends_with("down"));
+    // And a client with credentials isn't handed the anonymous copy
+    let answer = get_with("/app/x", "Authorization: Basic YTpi\r\n");
+    assert!(answer.ends_with("down"), "{}", answer);
+
+    // Past the window the failure goes through
+    std::thread::sleep(Duration::from_millis(2100));
+    assert!(get("/app/x").ends_with("down"));
+    down.store(false, Ordering::SeqCst);
+    assert!(!get("/app/x").contains("Warning"));
+
+    // Without anything stale the fallback file answers
+    let response = get("/dead/x");
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    assert!(response.contains("Content-Type: text/html"));
+    assert!(response.ends_with("<h1>back soon</h1>"));
+    fs::remove_file(&fallback).unwrap();
+}
+
+#[test]
+fn test_cache_key_normalization() {
+    let mut config = Config::builder()
+        .cache_ignore_params("utm_source, utm_medium,fbclid".to_string())
+        .build()
+        .unwrap();
+    let key = |config: &Config, path: &str| stale_key(config, &test_request(path, ""));
+
+    let plain = key(&config, "/app/list?page=2&sort=name");
+    for same in [
+        "/app/list?sort=name&page=2",
+        "/app/list?page=2&utm_source=news&sort=name&fbclid=abc",
+        "/app/list?utm_medium=mail&page=%32&sort=name",
+    ] {
+        assert_eq!(key(&config, same), plain, "{}", same);
+    }
+    for different in [
+        "/app/list?page=3&sort=name",
+        "/app/list?page=2",
+        "/app/other?page=2&sort=name",
+    ] {
+        assert_ne!(key(&config, different), plain, "{}", different);
+    }
+    assert_eq!(
+        key(&config, "/a?q=caf%C3%A9+bar&x=a/b"),
+        "stale:/a?q=caf%C3%A9%20bar&x=a%2Fb"
+    );
+    assert_eq!(key(&config, "/a?utm_source=x"), key(&config, "/a"));
+
+    // The request itself keeps every parameter
+    let req = test_request("/app/list?page=2&utm_source=news", "");
+    assert_eq!(req.query, "page=2&utm_source=news");
+
+    config.cache_ignore_query = true;
+    assert_eq!(key(&config, "/app/list?page=3"), "stale:/app/list?");
+    assert_eq!(key(&config, "/app/list?page=3"), key(&config, "/app/list"));
+}
+
+#[test]
+fn test_sticky_cookie_upstreams() {
+    use std::sync::atomic::Ordering;
+    let (port_a, hits_a) = upstream_stub("A", Duration::ZERO);
+    let (port_b, hits_b) = upstream_stub("B", Duration::ZERO);
+    let dead_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::new_default();
+    let mut rule = ProxyRule::from_list(&format!(
+        "http://127.0.0.1:{}, http://127.0.0.1:{}",
+        port_a, port_b
+    ));
+    rule.sticky = Some(Sticky::Cookie);
+    config.proxy_rules.insert("/app".to_string(), rule);
+    let mut rule = ProxyRule::from_list(&format!(
+        "http://127.0.0.1:{}, http://127.0.0.1:{}",
+        dead_port, port_b
+    ));
+    rule.sticky = Some(Sticky::Cookie);
+    config.proxy_rules.insert("/flaky".to_string(), rule);
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    let response = handle_request(test_request("/app/x", ""), &config, &cache, &logger);
+    let raw = String::from_utf8(response.to_bytes()).unwrap();
+    assert!(raw.contains("Set-Cookie: hteapot_upstream=0; Path=/app; HttpOnly"));
+    for _ in 0..4 {
+        let req = test_request("/app/x", "Cookie: hteapot_upstream=0\r\n");
+        let response = handle_request(req, &config, &cache, &logger);
+        let raw = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(!raw.contains("Set-Cookie"));
+        assert!(raw.ends_with("A"));
+    }
+    assert_eq!(hits_a.load(Ordering::SeqCst), 5);
+    assert_eq!(hits_b.load(Ordering::SeqCst), 0);
+
+    // Pinned to a dead upstream, falls back and moves the cookie
+    let req = test_request("/flaky/x", "Cookie: hteapot_upstream=0\r\n");
+    let response = handle_request(req, &config, &cache, &logger);
+    let raw = String::from_utf8(response.to_bytes()).unwrap();
+    assert!(raw.contains("Set-Cookie: hteapot_upstream=1; Path=/flaky; HttpOnly"));
+    assert_eq!(hits_b.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_sticky_ip_hash_upstreams() {
+    use std::net::IpAddr;
+    use std::sync::atomic::Ordering;
+    let (port_a, hits_a) = upstream_stub("A", Duration::ZERO);
+    let (port_b, hits_b) = upstream_stub("B", Duration::ZERO);
+    let dead_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::new_default();
+    let mut rule = ProxyRule::from_list(&format!(
+        "http://127.0.0.1:{}, http://127.0.0.1:{}",
+        port_a, port_b
+    ));
+    rule.sticky = Some(Sticky::IpHash);
+    config.proxy_rules.insert("/app".to_string(), rule);
+    let mut rule = ProxyRule::from_list(&format!(
+        "http://127.0.0.1:{}, http://127.0.0.1:{}",
+        dead_port, port_b
+    ));
+    rule.sticky = Some(Sticky::IpHash);
+    config.proxy_rules.insert("/flaky".to_string(), rule);
+    let cache = Mutex::new(Cache::new(0));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+
+    // One client address hashed to each upstream
+    let client = |upstream| {
+        (1..=255u8)
+            .map(|i| IpAddr::from([198, 51, 100, i]))
+            .find(|ip| sticky_ip(*ip, 2) == Some(upstream))
+            .unwrap()
+    };
+    let request = |path: &str, ip: IpAddr| {
+        let mut req = test_request(path, "");
+        req.remote_addr = Some((ip, 40000).into());
+        let response = handle_request(req, &config, &cache, &logger);
+        String::from_utf8(response.to_bytes()).unwrap()
+    };
+    for _ in 0..3 {
+        let raw = request("/app/x", client(0));
+        assert!(raw.ends_with("A"));
+        // Nothing to remember, the address is the same next time
+        assert!(!raw.contains("Set-Cookie"));
+        assert!(request("/app/x", client(1)).ends_with("B"));
+    }
+    assert_eq!(hits_a.load(Ordering::SeqCst), 3);
+    assert_eq!(hits_b.load(Ordering::SeqCst), 3);
+
+    // Hashed to a dead upstream, falls back to the live one
+    assert!(request("/flaky/x", client(0)).ends_with("B"));
+    assert_eq!(hits_b.load(Ordering::SeqCst), 4);
+}
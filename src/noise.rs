@@ -0,0 +1,133 @@
+// Scanner traffic, like /wp-login.php or /.env on a server that has neither
+// Answered before routing, the access log and the recorder see it, and only counted
+// Patterns are "/exact", "/prefix*" or "*suffix", matched without case on the path as sent
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(test)]
+use hteapot::Hteapot;
+use hteapot::{HttpMethod, HttpRequest, HttpResponse, HttpStatus};
+
+// Noise requests answered since start, for the metrics endpoint
+static MATCHED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn matched() -> usize {
+    MATCHED.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseResponse {
+    Close,    // Connection closed without a byte, like nginx's 444
+    NotFound, // A bare 404
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+}
+
+impl Pattern {
+    fn parse(text: &str) -> Pattern {
+        if let Some(prefix) = text.strip_suffix('*') {
+            Pattern::Prefix(prefix.to_string())
+        } else if let Some(suffix) = text.strip_prefix('*') {
+            Pattern::Suffix(suffix.to_string())
+        } else {
+            Pattern::Exact(text.to_string())
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let path = path.as_bytes();
+        match self {
+            Pattern::Exact(exact) => path.eq_ignore_ascii_case(exact.as_bytes()),
+            Pattern::Prefix(prefix) => path
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix.as_bytes())),
+            Pattern::Suffix(suffix) => path
+                .len()
+                .checked_sub(suffix.len())
+                .is_some_and(|at| path[at..].eq_ignore_ascii_case(suffix.as_bytes())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoisePolicy {
+    patterns: Vec<Pattern>,
+    pub response: NoiseResponse,
+}
+
+impl NoisePolicy {
+    pub fn new(patterns: &[String], response: NoiseResponse) -> NoisePolicy {
+        NoisePolicy {
+            patterns: patterns.iter().map(|p| Pattern::parse(p)).collect(),
+            response,
+        }
+    }
+
+    // Off, nothing counts as noise
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    // CONNECT too once there are patterns, this server proxies nothing that way
+    pub fn matches(&self, req: &HttpRequest) -> bool {
+        !self.is_empty()
+            && (req.method == HttpMethod::CONNECT
+                || self.patterns.iter().any(|p| p.matches(&req.raw_path)))
+    }
+
+    // The answer to a noise request, counted
+    pub fn answer(&self) -> HttpResponse {
+        MATCHED.fetch_add(1, Ordering::Relaxed);
+        match self.response {
+            NoiseResponse::Close => HttpResponse::no_response(),
+            NoiseResponse::NotFound => {
+                let mut response = HttpResponse::new(HttpStatus::NotFound, "", None);
+                response.close_connection();
+                response
+            }
+        }
+    }
+}
+
+impl Default for NoisePolicy {
+    fn default() -> Self {
+        NoisePolicy::new(&[], NoiseResponse::Close)
+    }
+}
+
+#[test]
+fn test_patterns() {
+    let patterns: Vec<String> = ["/.env", "/wp-*", "*.php"]
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    let policy = NoisePolicy::new(&patterns, NoiseResponse::Close);
+    let noise = |method: &str, path: &str| {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: a\r\n\r\n", method, path);
+        policy.matches(&Hteapot::request_parser(raw).unwrap())
+    };
+    for path in [
+        "/.env",
+        "/.ENV",
+        "/wp-login.php",
+        "/wp-admin/",
+        "/admin/x.PHP",
+    ] {
+        assert!(noise("GET", path), "{}", path);
+    }
+    for path in ["/", "/.env.example", "/blog/wp-", "/php", "/index.html"] {
+        assert!(!noise("GET", path), "{}", path);
+    }
+    assert!(noise("CONNECT", "example.com:443"));
+    // The path as sent, not the one routing sees after normalizing it
+    assert!(!noise("GET", "/a/../.env"));
+
+    let off = NoisePolicy::default();
+    let raw = "CONNECT example.com:443 HTTP/1.1\r\nHost: a\r\n\r\n".to_string();
+    assert!(!off.matches(&Hteapot::request_parser(raw).unwrap()));
+}
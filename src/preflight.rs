@@ -0,0 +1,203 @@
+// Problems in the config found once at startup instead of on the first request
+// They are logged, or stop the server when started with --strict
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use brew::{check_url, probe_url};
+use config::Config;
+
+#[derive(Debug, PartialEq)]
+pub struct ValidationIssue {
+    pub component: &'static str, // Part of the server that would fail, like "proxy"
+    pub subject: String,         // What is wrong, like a rule prefix or a path
+    pub message: String,
+}
+
+impl ValidationIssue {
+    fn new(component: &'static str, subject: &str, message: &str) -> ValidationIssue {
+        ValidationIssue {
+            component,
+            subject: subject.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.component, self.subject, self.message)
+    }
+}
+
+// Longest wait for an upstream to take a connection when reload_verify_upstreams is on
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A reloaded config only replaces the running one without any of these
+// With reload_verify_upstreams each valid upstream is also resolved and connected to
+pub fn check_reload(config: &Config) -> Vec<ValidationIssue> {
+    let mut issues = check_proxy(config);
+    if !config.reload_verify_upstreams {
+        return issues;
+    }
+    let mut prefixes: Vec<&String> = config.proxy_rules.keys().collect();
+    prefixes.sort();
+    for prefix in prefixes {
+        let upstreams = config.proxy_rules[prefix].upstreams.iter();
+        for upstream in upstreams.filter(|u| check_url(u).is_ok()) {
+            if let Err(e) = probe_url(upstream, PROBE_TIMEOUT) {
+                let subject = format!("{} -> {}", prefix, upstream);
+                issues.push(ValidationIssue::new("proxy", &subject, &e));
+            }
+        }
+    }
+    issues
+}
+
+pub fn check(config: &Config) -> Vec<ValidationIssue> {
+    let mut issues = check_proxy(config);
+    issues.extend(check_files(config));
+    issues.extend(check_acme(config));
+    issues.extend(check_admin_api(config));
+    issues.extend(check_alerts(config));
+    issues
+}
+
+// Every upstream url must be one brew can fetch
+fn check_proxy(config: &Config) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let mut prefixes: Vec<&String> = config.proxy_rules.keys().collect();
+    prefixes.sort();
+    for prefix in prefixes {
+        for upstream in config.proxy_rules[prefix].upstreams.iter() {
+            if let Err(e) = check_url(upstream) {
+                let subject = format!("{} -> {}", prefix, upstream);
+                issues.push(ValidationIssue::new("proxy", &subject, e));
+            }
+        }
+        if let Some(file) = &config.proxy_rules[prefix].fallback_file {
+            if !Path::new(file).is_file() {
+                let subject = format!("{} fallback_file {}", prefix, file);
+                issues.push(ValidationIssue::new("proxy", &subject, "File not found"));
+            }
+        }
+    }
+    issues
+}
+
+// The root has to be a directory, and the index should be in it
+fn check_files(config: &Config) -> Vec<ValidationIssue> {
+    if !config.serve_files || config.proxy_rules.contains_key("/") {
+        return Vec::new();
+    }
+    let root = Path::new(&config.root);
+    if !root.is_dir() {
+        return vec![ValidationIssue::new(
+            "files",
+            &config.root,
+            "Root is not a directory",
+        )];
+    }
+    if !root.join(&config.index).is_file() {
+        return vec![ValidationIssue::new(
+            "files",
+            &config.index,
+            "Index not found in root, / will be a 404",
+        )];
+    }
+    Vec::new()
+}
+
+fn check_acme(config: &Config) -> Vec<ValidationIssue> {
+    match &config.acme_challenge_dir {
+        Some(dir) if !Path::new(dir).is_dir() => vec![ValidationIssue::new(
+            "acme",
+            dir,
+            "Challenge directory not found",
+        )],
+        _ => Vec::new(),
+    }
+}
+
+// The runtime controls change the whole server, they should ask for credentials
+fn check_admin_api(config: &Config) -> Vec<ValidationIssue> {
+    match &config.admin_api_path {
+        Some(base)
+            if !config
+                .auth
+                .keys()
+                .any(|prefix| base.starts_with(prefix.as_str())) =>
+        {
+            vec![ValidationIssue::new(
+                "admin",
+                base,
+                "No [auth] rule covers it, anyone reaching admin_port can use it",
+            )]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Alerts that can't be sent are only found out when something already went wrong
+fn check_alerts(config: &Config) -> Vec<ValidationIssue> {
+    match &config.alert_webhook_url {
+        Some(url) => match check_url(url) {
+            Ok(()) => Vec::new(),
+            Err(e) => vec![ValidationIssue::new("alerts", url, e)],
+        },
+        None => Vec::new(),
+    }
+}
+
+#[test]
+fn test_preflight_checks() {
+    use config::AuthRule;
+    use hteapot::TokenList;
+    use proxy::ProxyRule;
+    let dir = std::env::temp_dir().join(format!("hteapot-preflight-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::new_default();
+    config.root = dir.to_str().unwrap().to_string();
+    config.acme_challenge_dir = Some(dir.join("acme").to_str().unwrap().to_string());
+    config.proxy_rules.insert(
+        "/api".to_string(),
+        ProxyRule::from_list("http://127.0.0.1:3000, https://secure, 127.0.0.1, http://a:port"),
+    );
+
+    let issues = check(&config);
+    let found: Vec<(&str, &str)> = issues
+        .iter()
+        .map(|i| (i.component, i.subject.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("proxy", "/api -> https://secure"),
+            ("proxy", "/api -> 127.0.0.1"),
+            ("proxy", "/api -> http://a:port"),
+            ("files", "index.html"),
+            ("acme", config.acme_challenge_dir.as_deref().unwrap()),
+        ]
+    );
+    assert!(issues[0].to_string().contains("not supported"));
+
+    config.admin_api_path = Some("/_admin".to_string());
+    assert_eq!(check_admin_api(&config)[0].subject, "/_admin");
+    config.auth.insert(
+        "/_admin".to_string(),
+        AuthRule {
+            realm: "admin".to_string(),
+            backend: std::sync::Arc::new(TokenList::new(vec!["t".to_string()])),
+        },
+    );
+    assert!(check_admin_api(&config).is_empty());
+
+    std::fs::write(dir.join("index.html"), "hi").unwrap();
+    std::fs::create_dir_all(dir.join("acme")).unwrap();
+    config.proxy_rules.clear();
+    assert!(check(&config).is_empty());
+    config.root = dir.join("missing").to_str().unwrap().to_string();
+    assert_eq!(check(&config)[0].message, "Root is not a directory");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
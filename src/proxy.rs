@@ -0,0 +1,346 @@
+// Proxy rules: which upstreams serve a path prefix and how one is picked
+// Upstreams are used smooth weighted round robin, optionally pinned to a client
+
+use brew::{fetch_with_headers, BrewClient, BrewError, Fetched, UNIX_PREFIX};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use upstream_stats::UpstreamStats;
+
+pub const STICKY_COOKIE: &str = "hteapot_upstream";
+
+// Retries saved up while an upstream is healthy, spent before the ratio limits them
+const RETRY_BURST: f64 = 10.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sticky {
+    Cookie, // Remember the upstream in a cookie
+    IpHash, // Same upstream for the same client address
+}
+
+// Selection state of one upstream
+#[derive(Clone, Debug, Default)]
+struct Balance {
+    current: i64,               // Smooth weighted round robin counter
+    down: bool,                 // Last request failed, gets weight 1 until one succeeds
+    recovered: Option<Instant>, // Start of the slow start ramp
+}
+
+#[derive(Debug)]
+pub struct ProxyRule {
+    pub upstreams: Vec<String>,
+    pub weights: Vec<u32>, // Per upstream, 0 when the weight given is invalid
+    pub sticky: Option<Sticky>,
+    pub slow_start: Duration, // Time a recovered upstream takes to get back to its weight
+    pub idempotency_window: Option<Duration>, // Responses kept per Idempotency-Key for this long
+    pub keep_alive: bool,     // Off closes client connections after responses from this rule
+    pub retries: u32,         // Extra attempts for idempotent requests that failed
+    pub retry_on: Vec<u16>,   // Upstream statuses retried like connection errors
+    pub retry_ratio: f64, // Retries allowed per request on average, so a dead upstream isn't flooded
+    pub default_cache_control: Option<String>, // Sent when the upstream sets no caching headers
+    pub force_no_store: bool, // Replace whatever caching headers the upstream sets with no-store
+    pub stale_if_error: Option<Duration>, // Last good GET response kept this long, served when the upstream fails
+    pub fallback_file: Option<String>, // Served with a 503 when the upstream fails and nothing stale is kept
+    pub rewrite_body_urls: bool, // Upstream urls in html and css bodies replaced by the rule prefix
+    pub revalidate: bool, // Conditional GETs upstream, an unchanged response is sent from the copy kept
+    pub stats: UpstreamStats,
+    validators: BrewClient,
+    balance: Mutex<Vec<Balance>>,
+    retry_budget: Mutex<f64>,
+}
+
+impl ProxyRule {
+    pub fn new(upstreams: Vec<String>, weights: Vec<u32>) -> ProxyRule {
+        let balance = vec![Balance::default(); upstreams.len()];
+        ProxyRule {
+            upstreams,
+            weights,
+            sticky: None,
+            slow_start: Duration::ZERO,
+            idempotency_window: None,
+            keep_alive: true,
+            retries: 0,
+            retry_on: vec![502, 503, 504],
+            retry_ratio: 0.2,
+            default_cache_control: None,
+            force_no_store: false,
+            stale_if_error: None,
+            fallback_file: None,
+            rewrite_body_urls: false,
+            revalidate: false,
+            stats: UpstreamStats::default(),
+            validators: BrewClient::new(),
+            balance: Mutex::new(balance),
+            retry_budget: Mutex::new(RETRY_BURST),
+        }
+    }
+
+    // Parse a comma separated list of upstream urls, each optionally followed by weight=N
+    pub fn from_list(list: &str) -> ProxyRule {
+        let mut upstreams = Vec::new();
+        let mut weights = Vec::new();
+        for entry in list.split(',') {
+            let mut parts = entry.split_whitespace();
+            let url = match parts.next() {
+                Some(url) => url,
+                None => continue,
+            };
+            let mut weight = 1;
+            for option in parts {
+                weight = match option.strip_prefix("weight=") {
+                    Some(w) => w.parse().unwrap_or(0),
+                    None => 0,
+                };
+            }
+            upstreams.push(url.to_string());
+            weights.push(weight);
+        }
+        ProxyRule::new(upstreams, weights)
+    }
+
+    // Raw response of an upstream url, sent conditionally with revalidate
+    pub fn fetch(&self, url: &str, headers: &[(&str, String)]) -> Result<Vec<u8>, BrewError> {
+        if !self.revalidate {
+            return fetch_with_headers(url, headers);
+        }
+        self.validators.fetch(url, headers).map(Fetched::into_raw)
+    }
+
+    // Pick the upstream for a request, the hint wins when it is a valid index
+    // unless that upstream is down or still ramping back up, then it is picked by weight
+    pub fn select(&self, hint: Option<usize>) -> usize {
+        self.select_at(hint, Instant::now())
+    }
+
+    fn select_at(&self, hint: Option<usize>, now: Instant) -> usize {
+        let mut balance = self.balance.lock().expect("Error locking upstreams");
+        if let Some(i) = hint.filter(|i| *i < self.upstreams.len()) {
+            let ramping = balance[i]
+                .recovered
+                .is_some_and(|since| now.duration_since(since) < self.slow_start);
+            if !balance[i].down && !ramping {
+                return i;
+            }
+        }
+        let mut total = 0;
+        let mut best = 0;
+        for i in 0..balance.len() {
+            let weight = self.effective_weight(i, &balance[i], now);
+            balance[i].current += weight;
+            total += weight;
+            if balance[i].current > balance[best].current {
+                best = i;
+            }
+        }
+        balance[best].current -= total;
+        best
+    }
+
+    // Configured weight, lowered while an upstream is down or ramping up again
+    fn effective_weight(&self, index: usize, balance: &Balance, now: Instant) -> i64 {
+        let weight = self.weights[index].max(1) as i64;
+        if balance.down {
+            return 1;
+        }
+        match balance.recovered {
+            Some(since) if now.duration_since(since) < self.slow_start => {
+                let ramp = now.duration_since(since).as_secs_f64() / self.slow_start.as_secs_f64();
+                1 + ((weight - 1) as f64 * ramp) as i64
+            }
+            _ => weight,
+        }
+    }
+
+    // Record how a request to an upstream went, a success after a failure starts the slow start
+    pub fn report(&self, index: usize, ok: bool) {
+        self.report_at(index, ok, Instant::now());
+    }
+
+    fn report_at(&self, index: usize, ok: bool, now: Instant) {
+        let mut balance = self.balance.lock().expect("Error locking upstreams");
+        let upstream = match balance.get_mut(index) {
+            Some(upstream) => upstream,
+            None => return,
+        };
+        if !ok {
+            upstream.down = true;
+            upstream.recovered = None;
+        } else if upstream.down {
+            upstream.down = false;
+            upstream.recovered = Some(now);
+        }
+    }
+
+    // Every request adds retry_ratio to the budget, up to RETRY_BURST
+    pub fn earn_retry(&self) {
+        let mut budget = self
+            .retry_budget
+            .lock()
+            .expect("Error locking retry budget");
+        *budget = (*budget + self.retry_ratio).min(RETRY_BURST);
+    }
+
+    // Spend one retry, false once the budget is used up
+    pub fn take_retry(&self) -> bool {
+        let mut budget = self
+            .retry_budget
+            .lock()
+            .expect("Error locking retry budget");
+        if *budget < 1.0 {
+            return false;
+        }
+        *budget -= 1.0;
+        true
+    }
+
+    // Build the upstream url for the rest of the path after the rule prefix
+    pub fn url(&self, index: usize, path: &str) -> String {
+        let upstream = &self.upstreams[index];
+        if upstream.starts_with(UNIX_PREFIX) && !upstream[UNIX_PREFIX.len()..].contains(':') {
+            // The request path goes after a ':' following the socket path
+            let separator = if path.starts_with('/') { ":" } else { ":/" };
+            return format!("{}{}{}", upstream, separator, path);
+        }
+        let separator = if path.starts_with('/') || upstream.ends_with('/') {
+            ""
+        } else {
+            "/"
+        };
+        format!("{}{}{}", upstream, separator, path)
+    }
+}
+
+// Upstream for a client address with ip_hash, stable across restarts and workers
+// FNV-1a over the address bytes, so it doesn't depend on the std hasher
+pub fn sticky_ip(ip: IpAddr, upstreams: usize) -> Option<usize> {
+    if upstreams == 0 {
+        return None;
+    }
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let hash = octets.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    Some((hash % upstreams as u64) as usize)
+}
+
+// Upstream index stored in the sticky cookie, if any
+pub fn sticky_cookie(cookies: &str) -> Option<usize> {
+    cookies
+        .split(';')
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == STICKY_COOKIE)
+        .and_then(|(_, value)| value.trim().parse().ok())
+}
+
+#[test]
+fn test_select_round_robin_and_hint() {
+    let rule = ProxyRule::from_list("http://a, http://b");
+    assert_eq!(rule.upstreams, vec!["http://a", "http://b"]);
+    assert_eq!(rule.select(None), 0);
+    assert_eq!(rule.select(None), 1);
+    assert_eq!(rule.select(None), 0);
+    assert_eq!(rule.select(Some(1)), 1);
+    assert_eq!(rule.select(Some(5)), 1);
+    assert_eq!(rule.url(0, "x/y"), "http://a/x/y");
+    let rule = ProxyRule::from_list("unix:/run/app.sock, unix:/run/app.sock:/api");
+    assert_eq!(rule.url(0, "x"), "unix:/run/app.sock:/x");
+    assert_eq!(rule.url(1, "/x"), "unix:/run/app.sock:/api/x");
+    assert_eq!(sticky_cookie("a=1; hteapot_upstream=1"), Some(1));
+    assert_eq!(sticky_cookie("hteapot_upstream=x"), None);
+}
+
+#[test]
+fn test_hint_skips_failing_upstream() {
+    let mut rule = ProxyRule::from_list("http://a, http://b");
+    rule.slow_start = Duration::from_secs(10);
+    let start = Instant::now();
+    rule.report_at(1, false, start);
+    assert_eq!(rule.select_at(Some(1), start), 0);
+    assert_eq!(rule.select_at(Some(0), start), 0);
+
+    // Back up, the hint waits for the slow start to end
+    rule.report_at(1, true, start);
+    let ramp = start + Duration::from_secs(5);
+    assert_ne!(
+        (0..4)
+            .map(|_| rule.select_at(Some(1), ramp))
+            .collect::<Vec<_>>(),
+        vec![1; 4]
+    );
+    assert_eq!(rule.select_at(Some(1), start + Duration::from_secs(10)), 1);
+}
+
+#[test]
+fn test_sticky_ip() {
+    let a: IpAddr = "203.0.113.7".parse().unwrap();
+    let b: IpAddr = "2001:db8::1".parse().unwrap();
+    assert_eq!(sticky_ip(a, 3), sticky_ip(a, 3));
+    assert!(sticky_ip(a, 3).unwrap() < 3);
+    assert!(sticky_ip(b, 3).unwrap() < 3);
+    assert_eq!(sticky_ip(a, 1), Some(0));
+    assert_eq!(sticky_ip(a, 0), None);
+    // Spread over the upstreams, not all on one
+    let picked: std::collections::HashSet<_> = (0..32u8)
+        .map(|i| sticky_ip(IpAddr::from([10, 0, 0, i]), 4).unwrap())
+        .collect();
+    assert!(picked.len() > 1);
+}
+
+#[cfg(test)]
+fn distribution(rule: &ProxyRule, now: Instant, selections: usize) -> Vec<usize> {
+    let mut counts = vec![0; rule.upstreams.len()];
+    for _ in 0..selections {
+        counts[rule.select_at(None, now)] += 1;
+    }
+    counts
+}
+
+#[test]
+fn test_weighted_selection() {
+    let rule = ProxyRule::from_list("http://a weight=3, http://b weight=1, http://c");
+    assert_eq!(rule.upstreams, vec!["http://a", "http://b", "http://c"]);
+    assert_eq!(rule.weights, vec![3, 1, 1]);
+    let now = Instant::now();
+    assert_eq!(distribution(&rule, now, 10_000), vec![6000, 2000, 2000]);
+    // Smooth: the heavy upstream is spread out instead of picked in a row
+    let order: Vec<usize> = (0..5).map(|_| rule.select_at(None, now)).collect();
+    assert_eq!(order, vec![0, 1, 0, 2, 0]);
+
+    assert_eq!(ProxyRule::from_list("http://a weight=x").weights, vec![0]);
+    assert_eq!(ProxyRule::from_list("http://a wieght=2").weights, vec![0]);
+}
+
+#[test]
+fn test_slow_start() {
+    let mut rule = ProxyRule::from_list("http://a weight=9, http://b weight=9");
+    rule.slow_start = Duration::from_secs(10);
+    let start = Instant::now();
+    rule.report_at(0, false, start);
+    let counts = distribution(&rule, start, 10_000);
+    assert_eq!(counts, vec![1000, 9000]);
+
+    // Ramps from 1 back to 9 over the window
+    rule.report_at(0, true, start);
+    let halfway = distribution(&rule, start + Duration::from_secs(5), 10_000);
+    let share = halfway[0] as f64 / 10_000.0;
+    assert!((share - 5.0 / 14.0).abs() < 0.01, "{:?}", halfway);
+    let after = distribution(&rule, start + Duration::from_secs(10), 10_000);
+    assert!((after[0] as i64 - 5000).abs() <= 10, "{:?}", after);
+}
+
+#[test]
+fn test_retry_budget() {
+    let mut rule = ProxyRule::from_list("http://a");
+    rule.retry_ratio = 0.5;
+    let taken = (0..20).filter(|_| rule.take_retry()).count();
+    assert_eq!(taken, RETRY_BURST as usize);
+    // Two requests pay for one retry
+    rule.earn_retry();
+    assert!(!rule.take_retry());
+    rule.earn_retry();
+    assert!(rule.take_retry());
+    assert!(!rule.take_retry());
+}
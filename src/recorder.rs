@@ -0,0 +1,448 @@
+// Whole exchanges written to disk for reproducing a client's bug, then sent again with --replay
+// One append-only file per start, each exchange framed by a line giving the sizes of both halves:
+// "EXCHANGE <unix millis> <client> <request bytes> <response bytes>\n", the request, the response, "\n"
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use brew::fetch_request;
+use hteapot::{HttpRequest, HttpResponse};
+use raw_status;
+
+const FRAME: &str = "EXCHANGE";
+// Put in place of the value of a redacted header
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exchange {
+    pub time: u64,      // Unix time in milliseconds
+    pub client: String, // Address of the client, or "-"
+    pub request: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+impl Exchange {
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let frame = format!(
+            "{} {} {} {} {}\n",
+            FRAME,
+            self.time,
+            self.client,
+            self.request.len(),
+            self.response.len()
+        );
+        let mut bytes = frame.into_bytes();
+        bytes.extend_from_slice(&self.request);
+        bytes.extend_from_slice(&self.response);
+        bytes.push(b'\n');
+        // A single write, so exchanges from several workers don't interleave
+        out.write_all(&bytes)
+    }
+
+    // The next exchange of a recording, None at its end
+    pub fn read_from(input: &mut impl BufRead) -> Result<Option<Exchange>, String> {
+        let mut frame = String::new();
+        match input.read_line(&mut frame) {
+            Ok(0) => return Ok(None),
+            Ok(_) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+        let fields: Vec<&str> = frame.trim_end().split(' ').collect();
+        let parsed = match fields[..] {
+            [FRAME, time, client, request, response] => {
+                match (time.parse(), request.parse(), response.parse()) {
+                    (Ok(time), Ok(request), Ok(response)) => {
+                        Some((time, client, request, response))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let (time, client, request_len, response_len): (u64, &str, usize, usize) =
+            parsed.ok_or(format!("Invalid exchange frame \"{}\"", frame.trim_end()))?;
+        let mut request = vec![0; request_len];
+        let mut response = vec![0; response_len];
+        let mut end = [0];
+        input
+            .read_exact(&mut request)
+            .and_then(|_| input.read_exact(&mut response))
+            .and_then(|_| input.read_exact(&mut end))
+            .map_err(|_| "Recording cut in the middle of an exchange".to_string())?;
+        if end != *b"\n" {
+            return Err("Exchange longer than its frame".to_string());
+        }
+        Ok(Some(Exchange {
+            time,
+            client: client.to_string(),
+            request,
+            response,
+        }))
+    }
+}
+
+// Every exchange of a recording file
+pub fn read_recording(path: &Path) -> Result<Vec<Exchange>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut input = io::BufReader::new(file);
+    let mut exchanges = Vec::new();
+    while let Some(exchange) = Exchange::read_from(&mut input)? {
+        exchanges.push(exchange);
+    }
+    Ok(exchanges)
+}
+
+// Method, target, headers and body of a recorded request, to send it again
+type RecordedRequest = (String, String, Vec<(String, String)>, Vec<u8>);
+
+// Time, client and bytes of a request to record once it is answered
+type Captured = (u64, String, Vec<u8>);
+
+fn parse_request(raw: &[u8]) -> Option<RecordedRequest> {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&raw[..end]).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Some((method, target, headers, raw[end + 4..].to_vec()))
+}
+
+// A recorded request sent again, with the status it got then and now
+pub struct Replayed {
+    pub request: String, // Method and target
+    pub recorded: Option<u16>,
+    pub replayed: Result<u16, String>,
+}
+
+impl Replayed {
+    pub fn same(&self) -> bool {
+        self.replayed.as_ref().ok() == self.recorded.as_ref()
+    }
+}
+
+impl std::fmt::Display for Replayed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let recorded = self.recorded.map_or("-".to_string(), |s| s.to_string());
+        match &self.replayed {
+            Ok(_) if self.same() => write!(f, "SAME {} {}", recorded, self.request),
+            Ok(status) => write!(f, "DIFF {} -> {} {}", recorded, status, self.request),
+            Err(e) => write!(f, "FAIL {}: {}", self.request, e),
+        }
+    }
+}
+
+// Send a recorded request to target, a base url like http://localhost:8080
+// Headers brew sets itself and redacted ones are left out
+pub fn replay(exchange: &Exchange, target: &str) -> Replayed {
+    let recorded = raw_status(&exchange.response);
+    let (method, path, headers, body) = match parse_request(&exchange.request) {
+        Some(parsed) => parsed,
+        None => {
+            return Replayed {
+                request: "?".to_string(),
+                recorded,
+                replayed: Err("Invalid recorded request".to_string()),
+            }
+        }
+    };
+    let skipped = ["Host", "Connection", "Content-Length", "Transfer-Encoding"];
+    let headers: Vec<(&str, String)> = headers
+        .iter()
+        .filter(|(key, value)| {
+            value != REDACTED && !skipped.iter().any(|s| s.eq_ignore_ascii_case(key))
+        })
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .collect();
+    let url = format!("{}{}", target.trim_end_matches('/'), path);
+    let replayed = fetch_request(&url, &method, &headers, &body)
+        .map_err(|e| e.to_string())
+        .and_then(|raw| raw_status(&raw).ok_or("Invalid response".to_string()));
+    Replayed {
+        request: format!("{} {}", method, path),
+        recorded,
+        replayed,
+    }
+}
+
+// Request as the client sent it, more or less: header order and case aren't kept
+fn raw_request(req: &HttpRequest) -> Vec<u8> {
+    let target = if req.query.is_empty() {
+        req.raw_path.clone()
+    } else {
+        format!("{}?{}", req.raw_path, req.query)
+    };
+    let mut headers: Vec<(&String, &String)> = req.headers.iter().collect();
+    headers.sort();
+    let mut raw = format!("{} {} HTTP/1.1\r\n", req.method.to_str(), target);
+    for (key, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    raw.push_str("\r\n");
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(req.body.as_bytes());
+    raw
+}
+
+// Replace the values of the named headers in a request or response head, the body is left alone
+fn redact(raw: &[u8], names: &[String]) -> Vec<u8> {
+    let end = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return raw.to_vec(),
+    };
+    let mut redacted = Vec::with_capacity(raw.len());
+    for (i, line) in raw[..end].split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            redacted.push(b'\n');
+        }
+        let (line, cr) = match line.strip_suffix(b"\r") {
+            Some(line) => (line, &b"\r"[..]),
+            None => (line, &b""[..]),
+        };
+        let name = line
+            .iter()
+            .position(|&b| b == b':')
+            .map(|colon| String::from_utf8_lossy(&line[..colon]).trim().to_string());
+        match name.filter(|name| names.iter().any(|n| n.eq_ignore_ascii_case(name))) {
+            Some(name) if i > 0 => {
+                redacted.extend_from_slice(format!("{}: {}", name, REDACTED).as_bytes())
+            }
+            _ => redacted.extend_from_slice(line),
+        }
+        redacted.extend_from_slice(cr);
+    }
+    redacted.extend_from_slice(&raw[end..]);
+    redacted
+}
+
+// Writes sampled exchanges under some path prefixes to the file of this start
+pub struct Recorder {
+    dir: Option<PathBuf>,
+    enabled: AtomicBool,              // Switched at runtime through the admin api
+    sample_rate: f64,                 // Share of matching requests recorded, from 0 to 1
+    paths: Vec<String>,               // Prefixes recorded, every path when empty
+    redact: Vec<String>,              // Headers whose values never reach the disk
+    file: Mutex<(u64, Option<File>)>, // Matching requests seen, for sampling, and the open file
+}
+
+impl Recorder {
+    pub fn new(
+        dir: Option<String>,
+        sample_rate: f64,
+        paths: Vec<String>,
+        redact: Vec<String>,
+    ) -> Recorder {
+        Recorder {
+            enabled: AtomicBool::new(dir.is_some()),
+            dir: dir.map(PathBuf::from),
+            sample_rate,
+            paths,
+            redact,
+            file: Mutex::new((0, None)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    // Returns whether it was recording before, only possible with a record_dir
+    pub fn set_enabled(&self, enabled: bool) -> Result<bool, String> {
+        if enabled && self.dir.is_none() {
+            return Err("record_dir is not set".to_string());
+        }
+        Ok(self.enabled.swap(enabled, Ordering::Relaxed))
+    }
+
+    // The request to record, taken before the handler consumes it
+    // Sampling is spread evenly, a rate of 0.25 records every fourth matching request
+    pub fn capture(&self, req: &HttpRequest) -> Option<Captured> {
+        if !self.enabled() {
+            return None;
+        }
+        if !self.paths.is_empty() && !self.paths.iter().any(|p| req.path.starts_with(p.as_str())) {
+            return None;
+        }
+        let seen = {
+            let mut file = self.file.lock().expect("Error locking recording");
+            file.0 += 1;
+            file.0
+        };
+        let sampled = (seen as f64 * self.sample_rate).floor()
+            > ((seen - 1) as f64 * self.sample_rate).floor();
+        if !sampled {
+            return None;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let client = req.remote_addr.map_or("-".to_string(), |a| a.to_string());
+        Some((time, client, redact(&raw_request(req), &self.redact)))
+    }
+
+    // Write a captured request with its response, the file is created on the first one
+    pub fn record(&self, captured: Option<Captured>, response: &HttpResponse) {
+        let (time, client, request) = match captured {
+            Some(captured) => captured,
+            None => return,
+        };
+        let exchange = Exchange {
+            time,
+            client,
+            request,
+            response: redact(&response.to_bytes(), &self.redact),
+        };
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let mut file = self.file.lock().expect("Error locking recording");
+        if file.1.is_none() {
+            let path = dir.join(format!("hteapot-{}.rec", time / 1000));
+            let opened = fs::create_dir_all(dir)
+                .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+            match opened {
+                Ok(opened) => file.1 = Some(opened),
+                Err(e) => {
+                    eprintln!("WARNING: error opening recording {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        if let Some(Err(e)) = file.1.as_mut().map(|f| exchange.write_to(f)) {
+            eprintln!(
+                "WARNING: error writing recording in {}: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+}
+
+#[test]
+fn test_recording_format() {
+    use hteapot::{Hteapot, HttpStatus};
+
+    let exchanges = vec![
+        Exchange {
+            time: 1712345678901,
+            client: "127.0.0.1:5000".to_string(),
+            request: b"POST /hook HTTP/1.1\r\nHost: a\r\n\r\nEXCHANGE 1 - 2 3\n".to_vec(),
+            response: b"HTTP/1.1 204 No Content\r\n\r\n".to_vec(),
+        },
+        Exchange {
+            time: 1,
+            client: "-".to_string(),
+            request: vec![0, 159, 255, b'\n'],
+            response: vec![],
+        },
+    ];
+    let mut stream = Vec::new();
+    for exchange in exchanges.iter() {
+        exchange.write_to(&mut stream).unwrap();
+    }
+    let mut input = &stream[..];
+    let mut read = Vec::new();
+    while let Some(exchange) = Exchange::read_from(&mut input).unwrap() {
+        read.push(exchange);
+    }
+    assert_eq!(read, exchanges);
+    assert!(Exchange::read_from(&mut &stream[..stream.len() - 2]).is_ok());
+    assert!(Exchange::read_from(&mut &stream[..20]).is_err());
+    assert!(Exchange::read_from(&mut &b"EXCHANGE x - 1 1\nab\n"[..]).is_err());
+
+    let mut req = Hteapot::request_parser(
+        "POST /login?next=%2F HTTP/1.1\r\nHost: a\r\nAuthorization: Basic YTpi\r\nCookie: s=1\r\n\r\n"
+            .to_string(),
+    )
+    .unwrap();
+    req.body = "user=a".to_string();
+    let redacted = vec!["authorization".to_string(), "Set-Cookie".to_string()];
+    let recorder = Recorder::new(
+        Some("unused".to_string()),
+        0.5,
+        vec!["/login".to_string()],
+        redacted,
+    );
+    assert!(recorder.capture(&req).is_none());
+    let (_, client, request) = recorder.capture(&req).unwrap();
+    assert_eq!(client, "-");
+    let (method, target, headers, body) = parse_request(&request).unwrap();
+    assert_eq!(
+        (method.as_str(), target.as_str()),
+        ("POST", "/login?next=%2F")
+    );
+    assert!(headers.contains(&("Authorization".to_string(), REDACTED.to_string())));
+    assert!(headers.contains(&("Cookie".to_string(), "s=1".to_string())));
+    assert_eq!(body, b"user=a");
+
+    let mut response = HttpResponse::new(HttpStatus::OK, "Set-Cookie: kept", None);
+    response.add_header("Set-Cookie", "session=secret");
+    let raw = redact(&response.to_bytes(), &recorder.redact);
+    let raw = String::from_utf8(raw).unwrap();
+    assert!(raw.contains(&format!("Set-Cookie: {}\r\n", REDACTED)));
+    assert!(!raw.contains("secret") && raw.ends_with("\r\n\r\nSet-Cookie: kept"));
+
+    req.path = "/other".to_string();
+    assert!(recorder.capture(&req).is_none());
+    assert_eq!(recorder.set_enabled(false), Ok(true));
+    assert!(Recorder::new(None, 1.0, vec![], vec![])
+        .set_enabled(true)
+        .is_err());
+}
+
+#[test]
+fn test_record_and_replay() {
+    use hteapot::{Hteapot, HttpMethod, HttpStatus};
+    use std::net::TcpListener;
+
+    // Created only for POSTs with the right body, so a replay sending another one differs
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        Hteapot::new("127.0.0.1", 0).listen_on(listener, |req| {
+            let created = req.method == HttpMethod::POST && req.body == "name=tea";
+            let redacted = req.headers.values().any(|v| v == REDACTED);
+            match (created, redacted) {
+                (true, false) => HttpResponse::new(HttpStatus::Created, "", None),
+                _ => HttpResponse::new(HttpStatus::BadRequest, "", None),
+            }
+        });
+    });
+
+    let dir = std::env::temp_dir().join(format!("hteapot-recordings-{}", std::process::id()));
+    let redact = vec!["Authorization".to_string()];
+    let recorder = Recorder::new(Some(dir.to_string_lossy().to_string()), 1.0, vec![], redact);
+    for (body, status) in [
+        ("name=tea", HttpStatus::Created),
+        ("name=coffee", HttpStatus::OK),
+    ] {
+        let mut req = Hteapot::request_parser(
+            "POST /pots?kind=clay HTTP/1.1\r\nHost: a\r\nAuthorization: Bearer x\r\n\r\n"
+                .to_string(),
+        )
+        .unwrap();
+        req.body = body.to_string();
+        let captured = recorder.capture(&req);
+        recorder.record(captured, &HttpResponse::new(status, "", None));
+    }
+
+    let file = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    let exchanges = read_recording(&file).unwrap();
+    assert_eq!(exchanges.len(), 2);
+    let replayed: Vec<String> = exchanges
+        .iter()
+        .map(|exchange| replay(exchange, &target).to_string())
+        .collect();
+    assert_eq!(replayed[0], "SAME 201 POST /pots?kind=clay");
+    assert_eq!(replayed[1], "DIFF 200 -> 400 POST /pots?kind=clay");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,269 @@
+// Requests derived from a config, sent to it running on an ephemeral port before it takes traffic
+// Meant as a deploy gate: hteapot --self-test config.toml exits with 1 when any check fails
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use auth_rule;
+use brew::fetch_with_headers;
+use cache::Cache;
+use config::Config;
+use handle_request;
+use hteapot::Hteapot;
+use is_proxy;
+use logger::Logger;
+use raw_status;
+
+// Statuses a check accepts
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expect {
+    Class(u16),    // Like 2 for any 2xx
+    Success,       // 2xx or 3xx
+    NoServerError, // Anything below 500
+}
+
+impl Expect {
+    fn matches(&self, status: u16) -> bool {
+        match self {
+            Expect::Class(class) => status / 100 == *class,
+            Expect::Success => (200..400).contains(&status),
+            Expect::NoServerError => status < 500,
+        }
+    }
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expect::Class(class) => write!(f, "{}xx", class),
+            Expect::Success => write!(f, "2xx or 3xx"),
+            Expect::NoServerError => write!(f, "no 5xx"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass(u16),
+    Fail(String),
+    Skip(&'static str),
+}
+
+struct Check {
+    name: String, // What the path stands for, like "index" or "proxy /api"
+    path: String, // Requested from the server, or a whole upstream url
+    expect: Expect,
+    upstream: bool, // Sent straight to a proxy upstream, skipped offline
+}
+
+pub struct Report {
+    pub results: Vec<(String, String, Outcome)>, // Name, path or url and outcome of every check
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|(_, _, outcome)| matches!(outcome, Outcome::Fail(_)))
+    }
+
+    fn count(&self, wanted: fn(&Outcome) -> bool) -> usize {
+        self.results.iter().filter(|(_, _, o)| wanted(o)).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, path, outcome) in self.results.iter() {
+            let (label, detail) = match outcome {
+                Outcome::Pass(status) => ("PASS", status.to_string()),
+                Outcome::Fail(reason) => ("FAIL", reason.clone()),
+                Outcome::Skip(reason) => ("SKIP", reason.to_string()),
+            };
+            writeln!(f, "{}  {} {}: {}", label, name, path, detail)?;
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(|o| matches!(o, Outcome::Pass(_))),
+            self.count(|o| matches!(o, Outcome::Fail(_))),
+            self.count(|o| matches!(o, Outcome::Skip(_)))
+        )
+    }
+}
+
+// Start the config on a local ephemeral port and run every check against it
+// Offline skips the checks reaching proxy upstreams
+pub fn run(config: Config, offline: bool) -> io::Result<Report> {
+    let checks = checks(&config);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let cache = Mutex::new(Cache::new(config.cache_ttl as u64));
+    let logger = Mutex::new(Logger::new(io::stdout()));
+    let config = Arc::new(config);
+    thread::spawn(move || {
+        Hteapot::new("127.0.0.1", 0).listen_on(listener, move |req| {
+            handle_request(req, &config, &cache, &logger)
+        });
+    });
+
+    let mut results = Vec::new();
+    for check in checks {
+        let outcome = if check.upstream && offline {
+            Outcome::Skip("offline")
+        } else {
+            let url = if check.upstream {
+                check.path.clone()
+            } else {
+                format!("http://{}{}", address, check.path)
+            };
+            match fetch_with_headers(&url, &[]).map(|raw| raw_status(&raw)) {
+                Ok(Some(status)) if check.expect.matches(status) => Outcome::Pass(status),
+                Ok(Some(status)) => Outcome::Fail(format!("{}, expected {}", status, check.expect)),
+                Ok(None) => Outcome::Fail("invalid response".to_string()),
+                Err(e) => Outcome::Fail(e.to_string()),
+            }
+        };
+        results.push((check.name, check.path, outcome));
+    }
+    Ok(Report { results })
+}
+
+// What to request and what to expect, from the parts of the config that answer requests
+fn checks(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut add = |name: String, path: &str, expect: Expect, upstream: bool| {
+        // Credentials aren't sent, so a protected path must ask for them
+        let expect = if auth_rule(config, path).is_some() {
+            Expect::Class(4)
+        } else {
+            expect
+        };
+        checks.push(Check {
+            name,
+            path: path.to_string(),
+            expect,
+            upstream,
+        });
+    };
+    if config.serve_files && is_proxy(config, "/").is_none() {
+        add("index".to_string(), "/", Expect::Success, false);
+        let root = Path::new(&config.root);
+        if let Some(file) = first_file(root, "", &config.index) {
+            if is_proxy(config, &file).is_none() {
+                add("file".to_string(), &file, Expect::Class(2), false);
+            }
+        }
+    }
+    let mut paths: Vec<&String> = config.responses.keys().collect();
+    paths.sort();
+    for path in paths {
+        let class = config.responses[path].status / 100;
+        add("response".to_string(), path, Expect::Class(class), false);
+    }
+    // Every request is redirected before it is routed
+    if config.redirect_to_https || config.canonical_host.is_some() {
+        for check in checks.iter_mut() {
+            check.expect = Expect::Class(3);
+        }
+    }
+    // Each upstream asked directly, the server answers for one it can't reach itself
+    let mut prefixes: Vec<&String> = config.proxy_rules.keys().collect();
+    prefixes.sort();
+    for prefix in prefixes {
+        let rule = &config.proxy_rules[prefix];
+        for index in 0..rule.upstreams.len() {
+            checks.push(Check {
+                name: format!("proxy {}", prefix),
+                path: rule.url(index, "/"),
+                expect: Expect::NoServerError,
+                upstream: true,
+            });
+        }
+    }
+    checks
+}
+
+// Url path of the first file under dir in name order, hidden files, the index
+// and names that would need escaping left out
+fn first_file(dir: &Path, prefix: &str, index: &str) -> Option<String> {
+    let mut entries: Vec<_> = fs::read_dir(dir).ok()?.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut subdirs = Vec::new();
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let plain = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.~".contains(c));
+        if name.starts_with('.') || !plain {
+            continue;
+        }
+        let path = format!("{}/{}", prefix, name);
+        match entry.file_type() {
+            Ok(kind) if kind.is_file() && name != index => return Some(path),
+            Ok(kind) if kind.is_dir() => subdirs.push((entry.path(), path)),
+            _ => (),
+        }
+    }
+    subdirs
+        .into_iter()
+        .find_map(|(dir, path)| first_file(&dir, &path, index))
+}
+
+#[test]
+fn test_self_test() {
+    use proxy::ProxyRule;
+
+    let root = std::env::temp_dir().join(format!("hteapot-selftest-{}", std::process::id()));
+    fs::create_dir_all(root.join("css")).unwrap();
+    fs::write(root.join("index.html"), "<h1>Hi</h1>").unwrap();
+    fs::write(root.join(".secret"), "hidden").unwrap();
+    fs::write(root.join("css").join("site.css"), "body {}").unwrap();
+    // Nothing listens there, so the proxy check fails
+    let dead_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = || {
+        let mut config = Config::new_default();
+        config.root = root.to_string_lossy().to_string();
+        config.proxy_rules.insert(
+            "/api".to_string(),
+            ProxyRule::from_list(&format!("http://127.0.0.1:{}", dead_port)),
+        );
+        config
+    };
+
+    let report = run(config(), false).unwrap();
+    assert!(!report.passed());
+    assert_eq!(
+        report.results[0],
+        ("index".to_string(), "/".to_string(), Outcome::Pass(200))
+    );
+    assert_eq!(report.results[1].1, "/css/site.css");
+    assert_eq!(report.results[1].2, Outcome::Pass(200));
+    assert_eq!(report.results[2].0, "proxy /api");
+    assert_eq!(
+        report.results[2].1,
+        format!("http://127.0.0.1:{}/", dead_port)
+    );
+    assert!(matches!(&report.results[2].2, Outcome::Fail(reason) if reason == "Error fetching"));
+    assert!(report
+        .to_string()
+        .ends_with("2 passed, 1 failed, 0 skipped"));
+
+    let report = run(config(), true).unwrap();
+    assert!(report.passed());
+    assert_eq!(report.results[2].2, Outcome::Skip("offline"));
+    fs::remove_dir_all(&root).unwrap();
+}
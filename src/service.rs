@@ -0,0 +1,443 @@
+// Windows service mode, so the server outlives the console session that started it
+// --service install registers the binary with the Service Control Manager, which then starts
+// it with --service run. Stop and shutdown controls go through the same graceful stop as Ctrl-C
+
+pub const SERVICE_NAME: &str = "hteapot";
+#[cfg(windows)]
+const DISPLAY_NAME: &str = "HTeaPot HTTP server";
+
+// Where output goes when no log file is given, next to the config
+const DEFAULT_LOG_FILE: &str = "hteapot.log";
+
+#[derive(Debug, PartialEq)]
+pub enum ServiceCommand {
+    Install { config: String, log_file: String },
+    Uninstall,
+    Run { config: String, log_file: String },
+}
+
+// From the arguments following --service, like "install config.toml --log-file out.log"
+pub fn parse_args(args: &[String]) -> Result<ServiceCommand, String> {
+    let usage =
+        "usage: --service install|run <config file> [--log-file <path>] or --service uninstall";
+    let mut args = args.iter().map(|a| a.as_str());
+    let action = args.next().ok_or(usage)?;
+    let rest: Vec<&str> = args.collect();
+    if action == "uninstall" {
+        if !rest.is_empty() {
+            return Err(format!("Unexpected arguments {}", rest.join(" ")));
+        }
+        return Ok(ServiceCommand::Uninstall);
+    }
+    let (config, log_file) = match rest.as_slice() {
+        [config] => (config.to_string(), None),
+        [config, "--log-file", log_file] => (config.to_string(), Some(log_file.to_string())),
+        _ => return Err(usage.to_string()),
+    };
+    let log_file = log_file.unwrap_or_else(|| default_log_file(&config));
+    match action {
+        "install" => Ok(ServiceCommand::Install { config, log_file }),
+        "run" => Ok(ServiceCommand::Run { config, log_file }),
+        _ => Err(format!("Unknown service action {}, {}", action, usage)),
+    }
+}
+
+fn default_log_file(config: &str) -> String {
+    let dir = std::path::Path::new(config)
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new(""));
+    dir.join(DEFAULT_LOG_FILE).to_string_lossy().into_owned()
+}
+
+// Command line the Service Control Manager starts, with every path made absolute
+// since services start in the system directory
+#[cfg(any(windows, test))]
+fn service_command_line(exe: &str, config: &str, log_file: &str) -> String {
+    format!(
+        "\"{}\" --service run \"{}\" --log-file \"{}\"",
+        exe, config, log_file
+    )
+}
+
+#[cfg(windows)]
+mod scm {
+    use std::ffi::c_void;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    type Handle = *mut c_void;
+    type ServiceMain = extern "system" fn(u32, *mut *mut u16);
+    type ControlHandler = extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+    #[repr(C)]
+    struct ServiceTableEntry {
+        name: *mut u16,
+        main: Option<ServiceMain>,
+    }
+
+    #[repr(C)]
+    struct ServiceStatus {
+        service_type: u32,
+        current_state: u32,
+        controls_accepted: u32,
+        win32_exit_code: u32,
+        service_specific_exit_code: u32,
+        check_point: u32,
+        wait_hint: u32,
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn OpenSCManagerW(machine: *const u16, database: *const u16, access: u32) -> Handle;
+        fn CreateServiceW(
+            scm: Handle,
+            name: *const u16,
+            display_name: *const u16,
+            access: u32,
+            service_type: u32,
+            start_type: u32,
+            error_control: u32,
+            binary_path: *const u16,
+            load_order_group: *const u16,
+            tag_id: *mut u32,
+            dependencies: *const u16,
+            account: *const u16,
+            password: *const u16,
+        ) -> Handle;
+        fn OpenServiceW(scm: Handle, name: *const u16, access: u32) -> Handle;
+        fn DeleteService(service: Handle) -> i32;
+        fn CloseServiceHandle(handle: Handle) -> i32;
+        fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+        fn RegisterServiceCtrlHandlerExW(
+            name: *const u16,
+            handler: ControlHandler,
+            context: *mut c_void,
+        ) -> Handle;
+        fn SetServiceStatus(handle: Handle, status: *mut ServiceStatus) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetStdHandle(std_handle: u32, handle: Handle) -> i32;
+    }
+
+    const SC_MANAGER_CONNECT: u32 = 0x0001;
+    const SC_MANAGER_CREATE_SERVICE: u32 = 0x0002;
+    const SERVICE_ALL_ACCESS: u32 = 0xF01FF;
+    const DELETE: u32 = 0x10000;
+    const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+    const SERVICE_AUTO_START: u32 = 2;
+    const SERVICE_ERROR_NORMAL: u32 = 1;
+
+    const SERVICE_STOPPED: u32 = 1;
+    const SERVICE_START_PENDING: u32 = 2;
+    const SERVICE_STOP_PENDING: u32 = 3;
+    const SERVICE_RUNNING: u32 = 4;
+    const SERVICE_ACCEPT_STOP: u32 = 0x1;
+    const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+    const SERVICE_CONTROL_STOP: u32 = 1;
+    const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+    const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+    const NO_ERROR: u32 = 0;
+    const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+
+    const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+    const STD_ERROR_HANDLE: u32 = -12i32 as u32;
+
+    // Hints how long a pending state may last before the SCM gives up, in milliseconds
+    const PENDING_WAIT_HINT: u32 = 30_000;
+
+    // Set while running under the SCM, SetServiceStatus takes it from any thread
+    static STATUS_HANDLE: AtomicUsize = AtomicUsize::new(0);
+    // The server, run by service_main once the SCM has called it
+    static SERVE: Mutex<Option<Box<dyn FnOnce() + Send>>> = Mutex::new(None);
+
+    fn wide(s: &str) -> Vec<u16> {
+        std::ffi::OsStr::new(s)
+            .encode_wide()
+            .chain(Some(0))
+            .collect()
+    }
+
+    fn last_error(action: &str) -> String {
+        format!("Error {}: {}", action, io::Error::last_os_error())
+    }
+
+    pub fn install(command_line: &str) -> Result<(), String> {
+        let name = wide(super::SERVICE_NAME);
+        let display_name = wide(super::DISPLAY_NAME);
+        let command_line = wide(command_line);
+        unsafe {
+            let scm = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CREATE_SERVICE);
+            if scm.is_null() {
+                return Err(last_error("opening the service manager"));
+            }
+            let service = CreateServiceW(
+                scm,
+                name.as_ptr(),
+                display_name.as_ptr(),
+                SERVICE_ALL_ACCESS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command_line.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            );
+            let result = if service.is_null() {
+                Err(last_error("creating the service"))
+            } else {
+                CloseServiceHandle(service);
+                Ok(())
+            };
+            CloseServiceHandle(scm);
+            result
+        }
+    }
+
+    pub fn uninstall() -> Result<(), String> {
+        let name = wide(super::SERVICE_NAME);
+        unsafe {
+            let scm = OpenSCManagerW(ptr::null(), ptr::null(), SC_MANAGER_CONNECT);
+            if scm.is_null() {
+                return Err(last_error("opening the service manager"));
+            }
+            let service = OpenServiceW(scm, name.as_ptr(), DELETE);
+            let result = if service.is_null() {
+                Err(last_error("opening the service"))
+            } else {
+                // Removed once it stops, if it is running now
+                let deleted = DeleteService(service) != 0;
+                CloseServiceHandle(service);
+                if deleted {
+                    Ok(())
+                } else {
+                    Err(last_error("deleting the service"))
+                }
+            };
+            CloseServiceHandle(scm);
+            result
+        }
+    }
+
+    // Send stdout and stderr to log_file, a service has no console to write to
+    pub fn redirect_output(log_file: &str) -> Result<(), String> {
+        use std::os::windows::io::IntoRawHandle;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .map_err(|e| format!("Error opening {}: {}", log_file, e))?;
+        // Kept open for the life of the process
+        let handle = file.into_raw_handle() as Handle;
+        unsafe {
+            if SetStdHandle(STD_OUTPUT_HANDLE, handle) == 0
+                || SetStdHandle(STD_ERROR_HANDLE, handle) == 0
+            {
+                return Err(last_error("redirecting output"));
+            }
+        }
+        Ok(())
+    }
+
+    // Hand the thread to the SCM, returns once the service has stopped
+    pub fn run(serve: Box<dyn FnOnce() + Send>) -> Result<(), String> {
+        *SERVE.lock().expect("Error locking service") = Some(serve);
+        let mut name = wide(super::SERVICE_NAME);
+        let table = [
+            ServiceTableEntry {
+                name: name.as_mut_ptr(),
+                main: Some(service_main),
+            },
+            ServiceTableEntry {
+                name: ptr::null_mut(),
+                main: None,
+            },
+        ];
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            return Err(last_error("connecting to the service manager"));
+        }
+        Ok(())
+    }
+
+    extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+        let name = wide(super::SERVICE_NAME);
+        let handle =
+            unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), on_control, ptr::null_mut()) };
+        if handle.is_null() {
+            eprintln!("{}", last_error("registering the service control handler"));
+            return;
+        }
+        STATUS_HANDLE.store(handle as usize, Ordering::SeqCst);
+        report(SERVICE_START_PENDING);
+        let serve = SERVE.lock().expect("Error locking service").take();
+        report(SERVICE_RUNNING);
+        if let Some(serve) = serve {
+            serve();
+        }
+        report(SERVICE_STOPPED);
+    }
+
+    extern "system" fn on_control(
+        control: u32,
+        _event_type: u32,
+        _event_data: *mut c_void,
+        _context: *mut c_void,
+    ) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                report(SERVICE_STOP_PENDING);
+                ::signals::request_stop();
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    pub fn report(state: u32) {
+        let handle = STATUS_HANDLE.load(Ordering::SeqCst) as Handle;
+        if handle.is_null() {
+            return;
+        }
+        let pending = state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING;
+        let mut status = ServiceStatus {
+            service_type: SERVICE_WIN32_OWN_PROCESS,
+            current_state: state,
+            controls_accepted: if state == SERVICE_RUNNING {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            } else {
+                0
+            },
+            win32_exit_code: NO_ERROR,
+            service_specific_exit_code: 0,
+            check_point: if pending { 1 } else { 0 },
+            wait_hint: if pending { PENDING_WAIT_HINT } else { 0 },
+        };
+        unsafe {
+            SetServiceStatus(handle, &mut status);
+        }
+    }
+
+    pub fn report_stopped() {
+        report(SERVICE_STOPPED);
+    }
+}
+
+// Register the service, started at boot with the given config and log file
+#[cfg(windows)]
+pub fn install(config: &str, log_file: &str) -> Result<(), String> {
+    let absolute = |path: &str| {
+        std::fs::canonicalize(path)
+            .or_else(|_| std::env::current_dir().map(|dir| dir.join(path)))
+            .map(|p| p.to_string_lossy().into_owned())
+            .map_err(|e| format!("Error resolving {}: {}", path, e))
+    };
+    let exe = std::env::current_exe().map_err(|e| format!("Error finding the binary: {}", e))?;
+    let command_line = service_command_line(
+        &exe.to_string_lossy(),
+        &absolute(config)?,
+        &absolute(log_file)?,
+    );
+    scm::install(&command_line)
+}
+
+#[cfg(windows)]
+pub fn uninstall() -> Result<(), String> {
+    scm::uninstall()
+}
+
+// Run serve as the service with the absolute config path, output going to log_file
+// Relative paths in the config are resolved from its directory
+#[cfg(windows)]
+pub fn run(
+    config: &str,
+    log_file: &str,
+    serve: impl FnOnce(&str) + Send + 'static,
+) -> Result<(), String> {
+    scm::redirect_output(log_file)?;
+    let config =
+        std::fs::canonicalize(config).map_err(|e| format!("Error resolving {}: {}", config, e))?;
+    if let Some(dir) = config.parent() {
+        std::env::set_current_dir(dir)
+            .map_err(|e| format!("Error entering {}: {}", dir.display(), e))?;
+    }
+    let config = config.to_string_lossy().into_owned();
+    scm::run(Box::new(move || serve(&config)))
+}
+
+// Tell the SCM the service stopped, right before the process exits, nothing outside a service
+#[cfg(windows)]
+pub fn report_stopped() {
+    scm::report_stopped();
+}
+
+#[cfg(not(windows))]
+const NOT_WINDOWS: &str = "Service mode is only available on Windows";
+
+#[cfg(not(windows))]
+pub fn install(_config: &str, _log_file: &str) -> Result<(), String> {
+    Err(NOT_WINDOWS.to_string())
+}
+
+#[cfg(not(windows))]
+pub fn uninstall() -> Result<(), String> {
+    Err(NOT_WINDOWS.to_string())
+}
+
+#[cfg(not(windows))]
+pub fn run(
+    _config: &str,
+    _log_file: &str,
+    _serve: impl FnOnce(&str) + Send + 'static,
+) -> Result<(), String> {
+    Err(NOT_WINDOWS.to_string())
+}
+
+#[cfg(not(windows))]
+pub fn report_stopped() {}
+
+#[test]
+fn test_service_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let log_file = default_log_file("conf/site.toml");
+    assert_eq!(
+        parse_args(&args("install conf/site.toml")),
+        Ok(ServiceCommand::Install {
+            config: "conf/site.toml".to_string(),
+            log_file: log_file.clone(),
+        })
+    );
+    assert!(log_file.starts_with("conf") && log_file.ends_with(DEFAULT_LOG_FILE));
+    assert_eq!(
+        parse_args(&args("run site.toml --log-file out.log")),
+        Ok(ServiceCommand::Run {
+            config: "site.toml".to_string(),
+            log_file: "out.log".to_string(),
+        })
+    );
+    assert_eq!(
+        parse_args(&args("uninstall")),
+        Ok(ServiceCommand::Uninstall)
+    );
+    for invalid in [
+        "",
+        "install",
+        "start site.toml",
+        "uninstall site.toml",
+        "run site.toml --log-file",
+        "run site.toml out.log",
+    ] {
+        assert!(parse_args(&args(invalid)).is_err(), "{}", invalid);
+    }
+    assert_eq!(
+        service_command_line("C:\\hteapot.exe", "C:\\site\\a.toml", "C:\\site\\a.log"),
+        "\"C:\\hteapot.exe\" --service run \"C:\\site\\a.toml\" --log-file \"C:\\site\\a.log\""
+    );
+}
@@ -0,0 +1,213 @@
+// Graceful stop on SIGINT, SIGTERM and SIGQUIT, so shutdown hooks get to run before exiting
+// SIGTERM is what docker stop and systemd send, without it they'd wait and then SIGKILL us
+// On Windows the console control events and the service control handler ask for the stop instead
+// SIGUSR1 asks for the log files to be reopened, what logrotate sends after moving them
+
+#[cfg(unix)]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Stops asked for, from the first signal on, a second one while stopping gives up on the hooks
+static STOP_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(unix)]
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Index in REASONS of what asked for the stop, kept for the shutdown log
+static STOP_REASON: AtomicUsize = AtomicUsize::new(0);
+
+const REASONS: [&str; 8] = [
+    "a service stop",
+    "SIGINT",
+    "SIGTERM",
+    "SIGQUIT",
+    "CTRL_C_EVENT",
+    "CTRL_BREAK_EVENT",
+    "CTRL_CLOSE_EVENT",
+    "CTRL_SHUTDOWN_EVENT",
+];
+
+// How often the watcher looks for a signal, the handler itself can only set a flag
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Ask for the stop, a second request while stopping gives up on the hooks
+// Only counts it, so it is safe from a signal handler
+#[cfg(windows)]
+pub fn request_stop() {
+    request_stop_by(0);
+}
+
+// Nothing but atomics here, exiting or logging isn't safe in a signal handler
+// The watcher from on_stop does both
+// The reason goes in first, the watcher may read it as soon as the count is up
+fn request_stop_by(reason: usize) {
+    if !stop_requested() {
+        STOP_REASON.store(reason, Ordering::SeqCst);
+    }
+    STOP_REQUESTS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn stop_requested() -> bool {
+    STOP_REQUESTS.load(Ordering::SeqCst) > 0
+}
+
+// What asked for the stop, like "SIGTERM"
+pub fn stop_reason() -> &'static str {
+    REASONS[STOP_REASON.load(Ordering::SeqCst)]
+}
+
+// Run stop on its own thread once a stop is requested, it is expected to exit the process
+// A second request before it does ends the process right away, from the watcher thread
+pub fn on_stop(stop: impl FnOnce() + Send + 'static) {
+    #[cfg(unix)]
+    listen_for_signals();
+    #[cfg(windows)]
+    {
+        if !listen_for_console() {
+            eprintln!(
+                "WARNING: Console handler not registered, Ctrl+C will skip the shutdown hooks"
+            );
+        }
+    }
+    thread::spawn(move || {
+        while !stop_requested() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        thread::spawn(stop);
+        while STOP_REQUESTS.load(Ordering::SeqCst) < 2 {
+            thread::sleep(POLL_INTERVAL);
+        }
+        eprintln!("WARNING: Stop requested again, exiting without waiting for the hooks");
+        std::process::exit(1);
+    });
+}
+
+// Run reopen on a thread of its own each time SIGUSR1 comes in
+#[cfg(unix)]
+pub fn on_reopen(reopen: impl Fn() + Send + 'static) {
+    extern "C" fn on_signal(_signum: i32) {
+        REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        signal(SIGUSR1, on_signal);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if REOPEN_REQUESTED.swap(false, Ordering::SeqCst) {
+            reopen();
+        }
+    });
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+#[cfg(unix)]
+const SIGINT: i32 = 2;
+#[cfg(unix)]
+const SIGQUIT: i32 = 3;
+#[cfg(unix)]
+const SIGTERM: i32 = 15;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const SIGUSR1: i32 = 10;
+// The BSDs and macOS
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub const SIGUSR1: i32 = 30;
+
+#[cfg(unix)]
+fn listen_for_signals() {
+    extern "C" fn on_signal(signum: i32) {
+        request_stop_by(match signum {
+            SIGINT => 1,
+            SIGTERM => 2,
+            SIGQUIT => 3,
+            _ => 0,
+        });
+    }
+
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+        signal(SIGQUIT, on_signal);
+    }
+}
+
+#[cfg(windows)]
+const CTRL_C_EVENT: u32 = 0;
+#[cfg(windows)]
+const CTRL_BREAK_EVENT: u32 = 1;
+#[cfg(windows)]
+const CTRL_CLOSE_EVENT: u32 = 2;
+#[cfg(windows)]
+const CTRL_SHUTDOWN_EVENT: u32 = 6;
+
+// False when the handler couldn't be registered, Ctrl+C then ends the process right away
+#[cfg(windows)]
+fn listen_for_console() -> bool {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+
+    unsafe { SetConsoleCtrlHandler(Some(on_console_event), 1) != 0 }
+}
+
+// Only asks for the stop, the watcher from on_stop runs the hooks and exits
+// 1 tells Windows the event was handled, 0 leaves it to the next handler
+#[cfg(windows)]
+extern "system" fn on_console_event(event: u32) -> i32 {
+    let reason = match event {
+        CTRL_C_EVENT => 4,
+        CTRL_BREAK_EVENT => 5,
+        CTRL_CLOSE_EVENT => 6,
+        CTRL_SHUTDOWN_EVENT => 7,
+        _ => return 0,
+    };
+    if event == CTRL_C_EVENT || event == CTRL_BREAK_EVENT {
+        request_stop_by(reason);
+        return 1;
+    }
+    // The process is ended as soon as this returns, so hold it while the hooks run
+    // A service gets the shutdown through its control handler too, that one isn't a second request
+    if !stop_requested() {
+        request_stop_by(reason);
+    }
+    loop {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sigterm_requests_stop() {
+    extern "C" {
+        fn raise(signum: i32) -> i32;
+    }
+
+    // Only the handler, a watcher from on_stop would exit the test process
+    listen_for_signals();
+    assert!(!stop_requested());
+    assert_eq!(unsafe { raise(SIGTERM) }, 0);
+    assert!(stop_requested());
+    assert_eq!(stop_reason(), "SIGTERM");
+    // A second one is only counted, the handler itself never exits
+    assert_eq!(unsafe { raise(SIGINT) }, 0);
+    assert_eq!(STOP_REQUESTS.load(Ordering::SeqCst), 2);
+    assert_eq!(stop_reason(), "SIGTERM");
+}
+
+#[cfg(windows)]
+#[test]
+fn test_console_handler() {
+    assert!(listen_for_console());
+    // Events it doesn't take go on to the default handler, untouched
+    assert_eq!(on_console_event(5), 0);
+    assert!(!stop_requested());
+    // Called directly, no console needed, and nothing exits as on_stop isn't watching
+    assert_eq!(on_console_event(CTRL_C_EVENT), 1);
+    assert!(stop_requested());
+    assert_eq!(stop_reason(), "CTRL_C_EVENT");
+}
@@ -0,0 +1,105 @@
+// Coalesce concurrent loads of the same key
+// The first caller does the work, the others wait for its result instead of repeating it
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+struct Flight<V> {
+    result: Mutex<Option<V>>,
+    done: Condvar,
+}
+
+pub struct SingleFlight<V: Clone> {
+    in_flight: Mutex<HashMap<String, Arc<Flight<V>>>>,
+    timeout: Duration, // Waiting longer than this, a caller does the work itself
+}
+
+impl<V: Clone> SingleFlight<V> {
+    pub fn new(timeout: Duration) -> Self {
+        SingleFlight {
+            in_flight: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+
+    pub fn run(&self, key: &str, work: impl FnOnce() -> V) -> V {
+        let (flight, leader) = {
+            let mut in_flight = self.in_flight.lock().expect("Error locking flights");
+            match in_flight.get(key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    in_flight.insert(key.to_string(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            let result = flight.result.lock().expect("Error locking flight");
+            let (result, _) = flight
+                .done
+                .wait_timeout_while(result, self.timeout, |r| r.is_none())
+                .expect("Error waiting on flight");
+            if let Some(value) = result.as_ref() {
+                return value.clone();
+            }
+            drop(result);
+            return work();
+        }
+
+        // Removed even if the work panics, so later callers don't wait on it
+        let _landing = Landing {
+            flights: self,
+            key,
+            flight: flight.clone(),
+        };
+        let value = work();
+        *flight.result.lock().expect("Error locking flight") = Some(value.clone());
+        value
+    }
+}
+
+struct Landing<'a, V: Clone> {
+    flights: &'a SingleFlight<V>,
+    key: &'a str,
+    flight: Arc<Flight<V>>,
+}
+
+impl<'a, V: Clone> Drop for Landing<'a, V> {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.flights.in_flight.lock() {
+            in_flight.remove(self.key);
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+#[test]
+fn test_single_flight() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let flights = Arc::new(SingleFlight::new(Duration::from_secs(5)));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..20)
+        .map(|_| {
+            let (flights, calls) = (flights.clone(), calls.clone());
+            std::thread::spawn(move || {
+                flights.run("key", || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(200));
+                    42
+                })
+            })
+        })
+        .collect();
+    for t in threads {
+        assert_eq!(t.join().unwrap(), 42);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    // Finished flights are forgotten
+    assert_eq!(flights.run("key", || 7), 7);
+}
@@ -0,0 +1,106 @@
+// Runtime state shared by every request, swapped as a whole on reload
+// A request keeps the snapshot it started with until it is answered
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use cache::Cache;
+use config::Config;
+use preflight;
+
+pub struct RuntimeState {
+    pub config: Config,
+    pub generation: u64, // Incremented on every reload
+}
+
+// How the last reload went, for the admin api
+#[derive(Clone, Debug)]
+pub struct ReloadStatus {
+    pub at: SystemTime,
+    pub generation: u64,     // Of the config running after it
+    pub errors: Vec<String>, // Why it was refused, empty when it was swapped in
+}
+
+pub struct SharedState {
+    current: RwLock<Arc<RuntimeState>>,
+    draining: AtomicBool, // Connections closed after each response, kept across reloads
+    last_reload: Mutex<Option<ReloadStatus>>,
+}
+
+impl SharedState {
+    pub fn new(config: Config) -> SharedState {
+        SharedState {
+            current: RwLock::new(Arc::new(RuntimeState {
+                config,
+                generation: 0,
+            })),
+            draining: AtomicBool::new(false),
+            last_reload: Mutex::new(None),
+        }
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    // Returns whether it was draining before
+    pub fn set_draining(&self, draining: bool) -> bool {
+        self.draining.swap(draining, Ordering::Relaxed)
+    }
+
+    // State to use for a whole request
+    pub fn snapshot(&self) -> Arc<RuntimeState> {
+        self.current.read().expect("Error locking state").clone()
+    }
+
+    pub fn last_reload(&self) -> Option<ReloadStatus> {
+        self.last_reload
+            .lock()
+            .expect("Error locking state")
+            .clone()
+    }
+
+    // Reload only a config that was read and whose proxy rules all pass preflight::check_reload
+    // Otherwise the running one stays, the errors are returned and kept for last_reload
+    pub fn try_reload(
+        &self,
+        config: Result<Config, String>,
+        cache: &Mutex<Cache>,
+    ) -> Result<u64, Vec<String>> {
+        let result = match config {
+            Ok(config) => {
+                let issues = preflight::check_reload(&config);
+                if issues.is_empty() {
+                    Ok(self.reload(config, cache))
+                } else {
+                    Err(issues.iter().map(|issue| issue.to_string()).collect())
+                }
+            }
+            Err(e) => Err(vec![e]),
+        };
+        *self.last_reload.lock().expect("Error locking state") = Some(ReloadStatus {
+            at: SystemTime::now(),
+            generation: self.snapshot().generation,
+            errors: result.clone().err().unwrap_or_default(),
+        });
+        result
+    }
+
+    // Replace the state, requests already running keep the old one
+    // Cached files are only dropped when they could now map to other files
+    pub fn reload(&self, config: Config, cache: &Mutex<Cache>) -> u64 {
+        let mut current = self.current.write().expect("Error locking state");
+        let old = &current.config;
+        {
+            let mut cache = cache.lock().expect("Error locking cache");
+            if old.root != config.root || old.index != config.index || !config.cache {
+                cache.clear();
+            }
+            cache.set_max_ttl(config.cache_ttl as u64);
+        }
+        let generation = current.generation + 1;
+        *current = Arc::new(RuntimeState { config, generation });
+        generation
+    }
+}
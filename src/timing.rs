@@ -0,0 +1,63 @@
+// Time spent in each phase of a request, sent back in a Server-Timing header
+// Kept in the request extensions, so code handling the request adds to it through &HttpRequest
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use hteapot::HttpRequest;
+
+#[derive(Default)]
+pub struct Timings {
+    phases: RefCell<Vec<(&'static str, Duration)>>, // In the order they first ran
+}
+
+impl Timings {
+    // A phase running more than once adds up
+    pub fn add(&self, phase: &'static str, duration: Duration) {
+        let mut phases = self.phases.borrow_mut();
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => phases.push((phase, duration)),
+        }
+    }
+
+    // Header value like "cache;dur=0.2, fs;dur=1.4, total;dur=2.1", in milliseconds
+    pub fn header(&self, total: Duration) -> String {
+        let millis = |d: &Duration| d.as_secs_f64() * 1000.0;
+        self.phases
+            .borrow()
+            .iter()
+            .chain(Some(&("total", total)))
+            .map(|(name, duration)| format!("{};dur={:.1}", name, millis(duration)))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+// Run f, adding how long it took to the request timings when they are collected
+pub fn timed<T>(req: &HttpRequest, phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let timings = match req.extensions.get::<Timings>() {
+        Some(timings) => timings,
+        None => return f(),
+    };
+    let started = Instant::now();
+    let result = f();
+    timings.add(phase, started.elapsed());
+    result
+}
+
+#[test]
+fn test_server_timing_header() {
+    let timings = Timings::default();
+    timings.add("cache", Duration::from_micros(200));
+    timings.add("fs", Duration::from_micros(1000));
+    timings.add("fs", Duration::from_micros(400));
+    assert_eq!(
+        timings.header(Duration::from_micros(2060)),
+        "cache;dur=0.2, fs;dur=1.4, total;dur=2.1"
+    );
+    assert_eq!(
+        Timings::default().header(Duration::from_millis(3)),
+        "total;dur=3.0"
+    );
+}
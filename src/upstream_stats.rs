@@ -0,0 +1,196 @@
+// Request counts, errors and latency of the upstreams behind a proxy rule
+// Latency is from forwarding the request to the last byte of the response, in fixed buckets
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds of the latency buckets in milliseconds, slower ones go in a last open bucket
+const BUCKETS_MS: [u64; 14] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Connect,     // The upstream couldn't be reached
+    Response,    // Reached, but the response failed or was unusable
+    ServerError, // Answered with a 5xx
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub requests: u64,
+    pub connect_errors: u64,
+    pub response_errors: u64,
+    pub server_errors: u64,
+    pub buckets: Vec<u64>, // Per BUCKETS_MS, then the open bucket
+    pub total_ms: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    current: Mutex<Snapshot>,
+}
+
+impl UpstreamStats {
+    pub fn record(&self, latency: Duration, outcome: Outcome) {
+        let ms = latency.as_millis() as u64;
+        let mut stats = self.current.lock().expect("Error locking upstream stats");
+        if stats.buckets.is_empty() {
+            stats.buckets = vec![0; BUCKETS_MS.len() + 1];
+        }
+        stats.requests += 1;
+        stats.total_ms += ms;
+        match outcome {
+            Outcome::Ok => (),
+            Outcome::Connect => stats.connect_errors += 1,
+            Outcome::Response => stats.response_errors += 1,
+            Outcome::ServerError => stats.server_errors += 1,
+        }
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKETS_MS.len());
+        stats.buckets[bucket] += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.current
+            .lock()
+            .expect("Error locking upstream stats")
+            .clone()
+    }
+}
+
+impl Snapshot {
+    pub fn errors(&self) -> u64 {
+        self.connect_errors + self.response_errors + self.server_errors
+    }
+
+    // Upper bound of the bucket holding the quantile, None without requests
+    // or when it falls in the open bucket
+    pub fn quantile_ms(&self, quantile: f64) -> Option<u64> {
+        if self.requests == 0 {
+            return None;
+        }
+        let rank = (quantile * self.requests as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKETS_MS.get(i).copied();
+            }
+        }
+        None
+    }
+
+    // One line for the log, like "120 requests, 3 errors (connect 1, response 0, 5xx 2), p50 25ms ..."
+    pub fn summary(&self) -> String {
+        let quantile = |q: f64| match self.quantile_ms(q) {
+            Some(ms) => format!("{}ms", ms),
+            None if self.requests == 0 => "-".to_string(),
+            None => format!(">{}ms", BUCKETS_MS[BUCKETS_MS.len() - 1]),
+        };
+        format!(
+            "{} requests, {} errors (connect {}, response {}, 5xx {}), p50 {} p95 {} p99 {}",
+            self.requests,
+            self.errors(),
+            self.connect_errors,
+            self.response_errors,
+            self.server_errors,
+            quantile(0.5),
+            quantile(0.95),
+            quantile(0.99)
+        )
+    }
+}
+
+// Prometheus text format for the stats of every rule, labelled by rule prefix
+pub fn to_prometheus<'a>(rules: impl Iterator<Item = (&'a str, Snapshot)>) -> String {
+    let mut requests = String::new();
+    let mut errors = String::new();
+    let mut latency = String::new();
+    for (prefix, stats) in rules {
+        let rule = prefix.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(
+            requests,
+            "hteapot_upstream_requests_total{{rule=\"{}\"}} {}",
+            rule, stats.requests
+        );
+        for (class, count) in [
+            ("connect", stats.connect_errors),
+            ("response", stats.response_errors),
+            ("5xx", stats.server_errors),
+        ] {
+            let _ = writeln!(
+                errors,
+                "hteapot_upstream_errors_total{{rule=\"{}\",class=\"{}\"}} {}",
+                rule, class, count
+            );
+        }
+        let mut cumulative = 0;
+        for (i, count) in stats.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match BUCKETS_MS.get(i) {
+                Some(ms) => format!("{}", *ms as f64 / 1000.0),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                latency,
+                "hteapot_upstream_latency_seconds_bucket{{rule=\"{}\",le=\"{}\"}} {}",
+                rule, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            latency,
+            "hteapot_upstream_latency_seconds_sum{{rule=\"{}\"}} {}",
+            rule,
+            stats.total_ms as f64 / 1000.0
+        );
+        let _ = writeln!(
+            latency,
+            "hteapot_upstream_latency_seconds_count{{rule=\"{}\"}} {}",
+            rule, stats.requests
+        );
+    }
+    format!(
+        "# TYPE hteapot_upstream_requests_total counter\n{}\
+         # TYPE hteapot_upstream_errors_total counter\n{}\
+         # TYPE hteapot_upstream_latency_seconds histogram\n{}",
+        requests, errors, latency
+    )
+}
+
+#[test]
+fn test_upstream_stats() {
+    let stats = UpstreamStats::default();
+    assert_eq!(stats.snapshot().quantile_ms(0.5), None);
+    for _ in 0..90 {
+        stats.record(Duration::from_millis(20), Outcome::Ok);
+    }
+    for _ in 0..8 {
+        stats.record(Duration::from_millis(400), Outcome::ServerError);
+    }
+    stats.record(Duration::from_millis(3), Outcome::Connect);
+    stats.record(Duration::from_secs(60), Outcome::Response);
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.requests, 100);
+    assert_eq!(snapshot.errors(), 10);
+    assert_eq!(snapshot.quantile_ms(0.5), Some(25));
+    assert_eq!(snapshot.quantile_ms(0.95), Some(500));
+    // The slowest one is past the last bound
+    assert_eq!(snapshot.quantile_ms(1.0), None);
+    assert_eq!(
+        snapshot.summary(),
+        "100 requests, 10 errors (connect 1, response 1, 5xx 8), p50 25ms p95 500ms p99 500ms"
+    );
+
+    let text = to_prometheus(vec![("/a\"pi", snapshot)].into_iter());
+    assert!(text.contains("hteapot_upstream_requests_total{rule=\"/a\\\"pi\"} 100\n"));
+    assert!(text.contains("hteapot_upstream_errors_total{rule=\"/a\\\"pi\",class=\"5xx\"} 8\n"));
+    assert!(text.contains("_bucket{rule=\"/a\\\"pi\",le=\"0.025\"} 91\n"));
+    assert!(text.contains("_bucket{rule=\"/a\\\"pi\",le=\"+Inf\"} 100\n"));
+    assert!(text.contains("_count{rule=\"/a\\\"pi\"} 100\n"));
+}
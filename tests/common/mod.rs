@@ -0,0 +1,112 @@
+// Helpers to run a real server and talk to it over raw sockets
+// Each integration test file includes them with `mod common;`
+
+#![allow(dead_code)]
+
+use hteapot::{Hteapot, HttpRequest, HttpResponse};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+// Start a server on a free port with this many worker threads, returns its address
+pub fn start_test_server(
+    threads: u16,
+    action: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Hteapot::new_threaded("127.0.0.1", addr.port(), threads);
+    thread::spawn(move || server.listen_on(listener, action));
+    addr
+}
+
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+}
+
+// A parsed response, read exactly as far as its Content-Length
+pub struct RawResponse {
+    pub status: u16,
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl RawResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+pub fn read_response(stream: &mut TcpStream) -> RawResponse {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let mut response = RawResponse {
+        status,
+        head,
+        body: Vec::new(),
+    };
+    let length: usize = response
+        .header("Content-Length")
+        .map(|l| l.parse().unwrap())
+        .unwrap_or(0);
+    response.body = vec![0; length];
+    stream.read_exact(&mut response.body).unwrap();
+    response
+}
+
+// Send raw bytes on a new connection and read one response
+pub fn raw_request(addr: SocketAddr, request: &[u8]) -> RawResponse {
+    let mut stream = connect(addr);
+    stream.write_all(request).unwrap();
+    read_response(&mut stream)
+}
+
+pub fn get(path: &str, connection: &str) -> Vec<u8> {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: {}\r\n\r\n",
+        path, connection
+    )
+    .into_bytes()
+}
+
+// Empty directory for one test, removed when dropped
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("hteapot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn file(&self, name: &str, content: &[u8]) {
+        fs::write(self.0.join(name), content).unwrap();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
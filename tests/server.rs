@@ -0,0 +1,133 @@
+// End to end tests against a real listener
+
+extern crate hteapot;
+
+mod common;
+
+use common::*;
+use hteapot::{HttpResponse, HttpStatus};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::thread;
+
+// Serve files from a directory, like the binary does without its extras
+fn file_server(root: PathBuf) -> impl Fn(hteapot::HttpRequest) -> HttpResponse + Send + Sync {
+    move |req| match std::fs::read(root.join(req.path.trim_start_matches('/'))) {
+        Ok(content) => HttpResponse::new(HttpStatus::OK, content, None),
+        Err(_) => HttpResponse::new(HttpStatus::NotFound, "Not found", None),
+    }
+}
+
+#[test]
+fn keep_alive_reuses_the_connection() {
+    let dir = TempDir::new("keep-alive");
+    dir.file("a.txt", b"first");
+    dir.file("b.txt", b"second");
+    let addr = start_test_server(1, file_server(dir.0.clone()));
+
+    let mut stream = connect(addr);
+    for (path, body) in [
+        ("/a.txt", "first"),
+        ("/b.txt", "second"),
+        ("/a.txt", "first"),
+    ]
+    .iter()
+    {
+        stream.write_all(&get(path, "keep-alive")).unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, body.as_bytes());
+        assert_ne!(response.header("Connection"), Some("close"));
+    }
+
+    stream.write_all(&get("/b.txt", "close")).unwrap();
+    let response = read_response(&mut stream);
+    assert_eq!(response.header("Connection"), Some("close"));
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn content_length_frames_every_size() {
+    let dir = TempDir::new("framing");
+    // Big enough for the socket to take it in several writes
+    let sizes = [0, 1, 1023, 1024, 1025, 4 * 1024 * 1024];
+    for size in sizes.iter() {
+        let content: Vec<u8> = (0..*size).map(|i| (i % 251) as u8).collect();
+        dir.file(&format!("{}.bin", size), &content);
+    }
+    let addr = start_test_server(2, file_server(dir.0.clone()));
+
+    let mut stream = connect(addr);
+    for size in sizes.iter() {
+        stream
+            .write_all(&get(&format!("/{}.bin", size), "keep-alive"))
+            .unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(
+            response.header("Content-Length"),
+            Some(size.to_string().as_str())
+        );
+        assert_eq!(response.body.len(), *size);
+        assert!(response
+            .body
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == (i % 251) as u8));
+    }
+}
+
+#[test]
+fn missing_files_are_404() {
+    let dir = TempDir::new("missing");
+    let addr = start_test_server(1, file_server(dir.0.clone()));
+    let response = raw_request(addr, &get("/nope.html", "close"));
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body, b"Not found");
+}
+
+#[test]
+fn concurrent_clients() {
+    let dir = TempDir::new("concurrent");
+    for i in 0..8 {
+        dir.file(&format!("{}.txt", i), format!("file {}", i).as_bytes());
+    }
+    let addr = start_test_server(4, file_server(dir.0.clone()));
+
+    let clients: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let path = format!("/{}.txt", i);
+                for _ in 0..20 {
+                    let response = raw_request(addr, &get(&path, "close"));
+                    assert_eq!(response.body, format!("file {}", i).as_bytes());
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+#[test]
+fn paths_above_root_are_400() {
+    let addr = start_test_server(1, |req| HttpResponse::new(HttpStatus::OK, req.path, None));
+    let response = raw_request(addr, &get("/a/../../etc/passwd", "keep-alive"));
+    assert_eq!(response.status, 400);
+    assert_eq!(response.header("Connection"), Some("close"));
+    let response = raw_request(addr, &get("/a//b/../c", "close"));
+    assert_eq!(response.body, b"/a/c");
+}
+
+#[test]
+fn request_body_reaches_the_handler() {
+    let addr = start_test_server(1, |req| HttpResponse::new(HttpStatus::OK, req.body, None));
+    let response = raw_request(
+        addr,
+        b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello",
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"hello");
+}
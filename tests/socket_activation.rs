@@ -0,0 +1,57 @@
+// Runs the binary the way systemd does with socket activation, on a socket bound here
+#![cfg(unix)]
+
+extern crate hteapot;
+
+mod common;
+
+use common::{connect, read_response};
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+extern "C" {
+    fn dup(fd: i32) -> i32;
+    fn dup2(old: i32, new: i32) -> i32;
+    fn close(fd: i32) -> i32;
+}
+
+#[test]
+fn serves_on_passed_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fd = listener.as_raw_fd();
+
+    // The shell's pid is the server's after exec, as LISTEN_PID must be
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" --serve-text activated")
+        .arg(env!("CARGO_BIN_EXE_hteapot"))
+        .stdout(Stdio::null());
+    unsafe {
+        // Passed as fd 3 without close-on-exec, dup first in case it already is 3
+        command.pre_exec(move || {
+            let copy = dup(fd);
+            if copy == -1 || dup2(copy, 3) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            close(copy);
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    drop(listener);
+
+    let mut stream = connect(addr);
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut stream);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"activated");
+}